@@ -4,10 +4,37 @@ CREATE TABLE users (
 	hashed_password VARCHAR NOT NULL,
 	active BOOLEAN DEFAULT TRUE,
 	super_user BOOLEAN DEFAULT FALSE,
-	storage_used BIGINT DEFAULT 0
+	storage_used BIGINT DEFAULT 0,
+	vault_public_key BYTEA,
+	vault_private_key BYTEA
 );
 
-CREATE TYPE FILETYPE as ENUM ('media', 'document', 'other', 'folder');
+CREATE TYPE FILETYPE as ENUM ('media', 'document', 'other', 'folder', 'archive',
+	'spreadsheet', 'presentation', 'code', 'executable');
+
+CREATE TYPE SCANSTATUS as ENUM ('unscanned', 'pending', 'clean', 'infected', 'failed');
+CREATE TYPE COLOURLABEL as ENUM ('red', 'orange', 'yellow', 'green', 'blue', 'purple', 'grey');
+
+CREATE TABLE user_keys (
+	key_id UUID PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	wrapped_key BYTEA NOT NULL,
+	master_key_id VARCHAR NOT NULL,
+	active BOOLEAN NOT NULL DEFAULT TRUE,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	retired_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX idx_user_keys_active ON user_keys(user_id) WHERE active;
+
+CREATE TABLE blobs (
+	hash VARCHAR PRIMARY KEY,
+	size BIGINT NOT NULL,
+	ref_count INT NOT NULL DEFAULT 0,
+	key_id UUID REFERENCES user_keys(key_id),
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	last_referenced TIMESTAMPTZ DEFAULT NOW()
+);
 
 CREATE TABLE files (
 	file_id UUID PRIMARY KEY,
@@ -20,9 +47,321 @@ CREATE TABLE files (
 	created_at TIMESTAMPTZ DEFAULT NOW(),
 	last_modified TIMESTAMPTZ DEFAULT NOW(),
 	url VARCHAR,
-	shared_with UUID[]
+	shared_with UUID[],
+	blob_hash VARCHAR REFERENCES blobs(hash),
+	checksum_sha256 VARCHAR,
+	checksum_md5 VARCHAR,
+	verified_at TIMESTAMPTZ,
+	corrupted BOOLEAN NOT NULL DEFAULT FALSE,
+	key_id UUID REFERENCES user_keys(key_id),
+	vault BOOLEAN NOT NULL DEFAULT FALSE,
+	wrapped_key BYTEA,
+	encrypted_metadata BYTEA,
+	mime_type VARCHAR,
+	scan_status SCANSTATUS NOT NULL DEFAULT 'unscanned',
+	scan_result VARCHAR,
+	scanned_at TIMESTAMPTZ,
+	media_metadata JSONB,
+	colour_label COLOURLABEL,
+	starred BOOLEAN NOT NULL DEFAULT FALSE
 );	
 
+CREATE TABLE thumbnails (
+	file_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	size VARCHAR NOT NULL,
+	format VARCHAR NOT NULL,
+	bucket VARCHAR NOT NULL,
+	key VARCHAR NOT NULL,
+	width INT NOT NULL,
+	height INT NOT NULL,
+	key_id UUID REFERENCES user_keys(key_id),
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	PRIMARY KEY (file_id, size, format)
+);
+
+CREATE TABLE thumbnail_jobs (
+	file_id UUID PRIMARY KEY REFERENCES files(file_id) ON DELETE CASCADE,
+	kind VARCHAR NOT NULL,
+	attempts INT NOT NULL DEFAULT 0,
+	last_error VARCHAR,
+	available_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE tags (
+	tag_id UUID PRIMARY KEY,
+	owner_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	name VARCHAR NOT NULL,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	UNIQUE (owner_id, name)
+);
+
+CREATE TABLE file_tags (
+	file_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	tag_id UUID REFERENCES tags(tag_id) ON DELETE CASCADE NOT NULL,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	PRIMARY KEY (file_id, tag_id)
+);
+
+CREATE TABLE comments (
+	comment_id UUID PRIMARY KEY,
+	file_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	parent_id UUID REFERENCES comments(comment_id) ON DELETE CASCADE,
+	author_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	body TEXT NOT NULL,
+	mentions UUID[] NOT NULL DEFAULT '{}',
+	resolved BOOLEAN NOT NULL DEFAULT FALSE,
+	resolved_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+	resolved_at TIMESTAMPTZ,
+	deleted BOOLEAN NOT NULL DEFAULT FALSE,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	edited_at TIMESTAMPTZ
+);
+
+CREATE TABLE file_contents (
+	file_id UUID PRIMARY KEY REFERENCES files(file_id) ON DELETE CASCADE,
+	content TEXT NOT NULL,
+	search_vector TSVECTOR GENERATED ALWAYS AS (to_tsvector('english', content)) STORED,
+	indexed_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE index_jobs (
+	file_id UUID PRIMARY KEY REFERENCES files(file_id) ON DELETE CASCADE,
+	kind VARCHAR NOT NULL,
+	attempts INT NOT NULL DEFAULT 0,
+	last_error VARCHAR,
+	available_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE scan_jobs (
+	file_id UUID PRIMARY KEY REFERENCES files(file_id) ON DELETE CASCADE,
+	attempts INT NOT NULL DEFAULT 0,
+	last_error VARCHAR,
+	available_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
 CREATE INDEX idx_files_owner ON files(owner_id);
+CREATE INDEX idx_files_quarantined ON files(scanned_at) WHERE scan_status = 'infected';
+CREATE INDEX idx_files_media_metadata ON files USING GIN (media_metadata);
+CREATE INDEX idx_files_name_search ON files USING GIN (to_tsvector('english', file_name));
+CREATE INDEX idx_file_contents_search ON file_contents USING GIN (search_vector);
+CREATE INDEX idx_file_tags_tag ON file_tags(tag_id);
+CREATE INDEX idx_comments_file ON comments(file_id, created_at);
+CREATE INDEX idx_files_starred ON files(owner_id) WHERE starred;
 CREATE INDEX idx_files_parent ON files(parent_id);
+CREATE INDEX idx_files_blob ON files(blob_hash);
+CREATE INDEX idx_blobs_unreferenced ON blobs(last_referenced) WHERE ref_count <= 0;
+
+CREATE TABLE vault_item_keys (
+	file_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	wrapped_key BYTEA NOT NULL,
+	granted_by UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	PRIMARY KEY (file_id, user_id)
+);
+
+CREATE INDEX idx_vault_item_keys_user ON vault_item_keys(user_id);
+
+
+CREATE TYPE SHAREMODE as ENUM ('view', 'download');
+
+-- password_failures counts wrong passwords in a row, past the limit the link
+-- refuses guesses until locked_until
+CREATE TABLE share_links (
+	link_id UUID PRIMARY KEY,
+	token VARCHAR UNIQUE NOT NULL,
+	file_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	owner_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	mode SHAREMODE NOT NULL,
+	expires_at TIMESTAMPTZ,
+	hashed_password VARCHAR,
+	max_downloads INT,
+	download_count INT NOT NULL DEFAULT 0,
+	view_count INT NOT NULL DEFAULT 0,
+	password_failures INT NOT NULL DEFAULT 0,
+	locked_until TIMESTAMPTZ,
+	revoked BOOLEAN NOT NULL DEFAULT FALSE,
+	created_at TIMESTAMPTZ DEFAULT NOW(),
+	last_accessed TIMESTAMPTZ
+);
+
+CREATE INDEX idx_share_links_owner ON share_links(owner_id);
+
+CREATE TABLE file_requests (
+	request_id UUID PRIMARY KEY,
+	token VARCHAR UNIQUE NOT NULL,
+	owner_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	folder_id UUID REFERENCES files(file_id) ON DELETE CASCADE NOT NULL,
+	title VARCHAR NOT NULL,
+	max_file_size BIGINT,
+	allowed_types VARCHAR[] NOT NULL DEFAULT '{}',
+	deadline TIMESTAMPTZ,
+	revoked BOOLEAN NOT NULL DEFAULT FALSE,
+	upload_count INT NOT NULL DEFAULT 0,
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE file_request_uploads (
+	upload_id UUID PRIMARY KEY,
+	request_id UUID REFERENCES file_requests(request_id) ON DELETE CASCADE NOT NULL,
+	file_id UUID REFERENCES files(file_id) ON DELETE SET NULL,
+	uploader_name VARCHAR,
+	uploader_email VARCHAR,
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE notifications (
+	notification_id UUID PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	kind VARCHAR NOT NULL,
+	message VARCHAR NOT NULL,
+	file_id UUID REFERENCES files(file_id) ON DELETE SET NULL,
+	read BOOLEAN NOT NULL DEFAULT FALSE,
+	created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE INDEX idx_file_requests_owner ON file_requests(owner_id);
+CREATE INDEX idx_notifications_user ON notifications(user_id);
+
+-- append only, no foreign keys so events outlive the users and files they mention
+CREATE TABLE audit_events (
+	event_id BIGSERIAL PRIMARY KEY,
+	actor_id UUID,
+	action VARCHAR NOT NULL,
+	file_id UUID,
+	owner_id UUID,
+	ip VARCHAR,
+	user_agent VARCHAR,
+	details JSONB,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE FUNCTION audit_events_append_only() RETURNS TRIGGER AS $$
+BEGIN
+	RAISE EXCEPTION 'audit_events is append only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_changes
+	BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_events
+	FOR EACH STATEMENT EXECUTE FUNCTION audit_events_append_only();
+
+CREATE INDEX idx_audit_events_actor ON audit_events(actor_id, event_id);
+CREATE INDEX idx_audit_events_owner ON audit_events(owner_id, event_id);
+CREATE INDEX idx_audit_events_created ON audit_events(created_at);
+
+CREATE TABLE webhooks (
+	webhook_id UUID PRIMARY KEY,
+	owner_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	url VARCHAR NOT NULL,
+	secret VARCHAR NOT NULL,
+	-- empty for every event type
+	event_types VARCHAR[] NOT NULL DEFAULT '{}',
+	folder_id UUID REFERENCES files(file_id) ON DELETE CASCADE,
+	active BOOLEAN NOT NULL DEFAULT TRUE,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
 
+-- the delivery queue, and the log once delivered or failed
+CREATE TABLE webhook_deliveries (
+	delivery_id UUID PRIMARY KEY,
+	webhook_id UUID REFERENCES webhooks(webhook_id) ON DELETE CASCADE NOT NULL,
+	event_type VARCHAR NOT NULL,
+	payload JSONB NOT NULL,
+	status VARCHAR NOT NULL DEFAULT 'pending',
+	attempts INT NOT NULL DEFAULT 0,
+	last_status_code INT,
+	last_error VARCHAR,
+	next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	delivered_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_webhooks_owner ON webhooks(owner_id) WHERE active;
+CREATE INDEX idx_webhook_deliveries_due ON webhook_deliveries(next_attempt_at) WHERE status = 'pending';
+CREATE INDEX idx_webhook_deliveries_webhook ON webhook_deliveries(webhook_id, created_at);
+
+-- sync journal, one row per change per user that can see the file
+CREATE TABLE changes (
+	change_id BIGSERIAL PRIMARY KEY,
+	user_id UUID NOT NULL,
+	file_id UUID NOT NULL,
+	-- created, updated or deleted
+	kind VARCHAR NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_changes_user ON changes(user_id, change_id);
+
+CREATE TABLE api_keys (
+	key_id UUID PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	name VARCHAR NOT NULL,
+	prefix VARCHAR NOT NULL,
+	-- sha-256 of the key, the key itself is never stored
+	key_hash VARCHAR UNIQUE NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	last_used_at TIMESTAMPTZ,
+	revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_api_keys_user ON api_keys(user_id) WHERE revoked_at IS NULL;
+
+-- s3 gateway credentials. sigv4 needs the secret itself, so it is sealed
+-- rather than hashed: under master_key_id when encryption is on, or under a
+-- key derived from SECRET_KEY ('secret-key')
+CREATE TABLE s3_access_keys (
+	access_key_id VARCHAR PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	name VARCHAR NOT NULL,
+	secret BYTEA NOT NULL,
+	master_key_id VARCHAR NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	last_used_at TIMESTAMPTZ,
+	revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_s3_access_keys_user ON s3_access_keys(user_id) WHERE revoked_at IS NULL;
+
+-- multipart uploads in progress, the parts wait in the user's bucket
+CREATE TABLE s3_uploads (
+	upload_id UUID PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	bucket VARCHAR NOT NULL,
+	object_key VARCHAR NOT NULL,
+	content_type VARCHAR NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE s3_upload_parts (
+	upload_id UUID REFERENCES s3_uploads(upload_id) ON DELETE CASCADE NOT NULL,
+	part_number INT NOT NULL,
+	size BIGINT NOT NULL,
+	-- md5 of the part as uploaded
+	etag VARCHAR NOT NULL,
+	-- data key when encryption is on
+	key_id UUID,
+	uploaded_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	PRIMARY KEY (upload_id, part_number)
+);
+
+CREATE INDEX idx_s3_uploads_user ON s3_uploads(user_id, bucket);
+
+-- public keys for the sftp server, in authorized_keys form. root_id chroots the
+-- key to a folder, read_only keys can list and download but nothing else
+CREATE TABLE ssh_keys (
+	key_id UUID PRIMARY KEY,
+	user_id UUID REFERENCES users(user_id) ON DELETE CASCADE NOT NULL,
+	name VARCHAR NOT NULL,
+	-- algorithm and base64 blob, the comment is dropped
+	public_key VARCHAR NOT NULL,
+	fingerprint VARCHAR NOT NULL,
+	read_only BOOLEAN NOT NULL DEFAULT FALSE,
+	root_id UUID REFERENCES files(file_id) ON DELETE CASCADE,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+	last_used_at TIMESTAMPTZ,
+	UNIQUE (user_id, public_key)
+);
@@ -0,0 +1,245 @@
+// the request and response types of the worker's http api, shared by the
+// worker and servr-client. nothing here needs the server's dependencies; the
+// `server` feature adds the derives the worker uses for its rows and its spec
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(sqlx::Type, schemars::JsonSchema))]
+#[cfg_attr(feature = "server", sqlx(type_name="FILETYPE", rename_all="lowercase"))]
+#[serde(rename_all = "lowercase")] //for deserializing
+pub enum FileType { Media, Document, Other, Folder, Archive, Spreadsheet, Presentation, Code, Executable }
+
+// unscanned covers folders, vault ciphertext and anything from before scanning
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(sqlx::Type, schemars::JsonSchema))]
+#[cfg_attr(feature = "server", sqlx(type_name="SCANSTATUS", rename_all="lowercase"))]
+#[serde(rename_all = "lowercase")]
+pub enum ScanStatus { Unscanned, Pending, Clean, Infected, Failed }
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(sqlx::Type, schemars::JsonSchema))]
+#[cfg_attr(feature = "server", sqlx(type_name="COLOURLABEL", rename_all="lowercase"))]
+#[serde(rename_all = "lowercase")]
+pub enum ColourLabel { Red, Orange, Yellow, Green, Blue, Purple, Grey }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct FileResponse {
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub file_name: String,
+    pub extension: Option<String>,
+    pub size: i64,
+    pub file_type: FileType,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub shared_with: Vec<Uuid>,
+    pub url: Option<String>,
+    pub blob_hash: Option<String>,
+    pub checksum_sha256: Option<String>,
+    pub checksum_md5: Option<String>,
+    // vault items have no readable name, see vault.rs. binary fields are base64
+    pub vault: bool,
+    pub wrapped_key: Option<String>,
+    pub encrypted_metadata: Option<String>,
+    // sniffed from the content, not the type the client sent
+    pub mime_type: Option<String>,
+    pub scan_status: ScanStatus,
+    // exif, tags and stream info, see media_metadata.rs
+    pub media_metadata: Option<serde_json::Value>,
+    pub colour_label: Option<ColourLabel>,
+    pub starred: bool,
+    pub tags: Vec<String>,
+}
+
+// uploading
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct CreateFolderForm {
+    //pub owner_id: String,
+    pub folder_name: String,
+    pub parent_id: String,
+}
+//deleting
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct DeleteFileForm {
+    pub owner_id: String,
+    pub file_id: String,
+}
+//renaming
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct RenameFileForm {
+    pub owner_id: String,
+    pub file_id: String,
+    pub file_name: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct MoveFileForm {
+    pub file_id: String,
+    // empty for the top level
+    pub parent_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct DownloadFileForm {
+    pub owner_id: String,
+    pub file_id: String,
+    pub file_extension: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(sqlx::Type, schemars::JsonSchema))]
+#[cfg_attr(feature = "server", sqlx(type_name="SHAREMODE", rename_all="lowercase"))]
+#[serde(rename_all = "lowercase")]
+pub enum ShareMode { View, Download }
+
+// share links
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct CreateShareLinkForm {
+    pub file_id: String,
+    pub mode: ShareMode,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub password: Option<String>,
+    pub max_downloads: Option<i32>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct RevokeShareLinkForm {
+    pub link_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(sqlx::FromRow, schemars::JsonSchema))]
+pub struct ShareLinkResponse {
+    pub link_id: Uuid,
+    pub token: String,
+    pub file_id: Uuid,
+    pub mode: ShareMode,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub has_password: bool,
+    pub max_downloads: Option<i32>,
+    pub download_count: i32,
+    pub view_count: i32,
+    pub revoked: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_accessed: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct SearchForm {
+    // websearch syntax: "exact phrase", or, -exclude
+    pub query: String,
+    // from 1
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(sqlx::FromRow, schemars::JsonSchema))]
+pub struct SearchHit {
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub file_name: String,
+    pub extension: Option<String>,
+    pub size: i64,
+    pub file_type: FileType,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub rank: f32,
+    // html, matches wrapped in <mark>
+    pub name_highlight: String,
+    pub snippet: Option<String>,
+    #[serde(skip)]
+    pub total: i64,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct SearchResponse {
+    pub total: i64,
+    pub page: i64,
+    pub per_page: i64,
+    pub results: Vec<SearchHit>,
+}
+
+// GET /changes?cursor=&wait=&limit=
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct ChangesQuery {
+    // cursor from the last response, leave out to get the current one
+    pub cursor: Option<i64>,
+    // seconds to hold the request open when there is nothing new
+    pub wait: Option<u64>,
+    pub limit: Option<i64>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(sqlx::FromRow, schemars::JsonSchema))]
+pub struct ChangeEntry {
+    pub change_id: i64,
+    pub file_id: Uuid,
+    // created, updated or deleted
+    pub kind: String,
+    pub changed_at: DateTime<Utc>,
+    // the file as it is now, none once it has been deleted
+    pub owner_id: Option<Uuid>,
+    pub parent_id: Option<Uuid>,
+    pub file_name: Option<String>,
+    pub file_type: Option<FileType>,
+    pub size: Option<i64>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub checksum_sha256: Option<String>,
+    pub vault: Option<bool>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct ChangesResponse {
+    pub cursor: i64,
+    pub has_more: bool,
+    pub changes: Vec<ChangeEntry>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct CreateApiKeyForm {
+    pub name: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct ApiKeyIdForm {
+    pub key_id: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct ApiKeySignInForm {
+    pub api_key: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(sqlx::FromRow, schemars::JsonSchema))]
+pub struct ApiKeyResponse {
+    pub key_id: Uuid,
+    pub name: String,
+    // first characters of the key, to tell keys apart
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    // only when the key is created, it is not shown again
+    #[cfg_attr(feature = "server", sqlx(default))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct SignInForm {
+    pub email: String,
+    pub password: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+pub struct SignUpForm {
+    pub email: String,
+    pub password: String,
+}
@@ -0,0 +1,184 @@
+use axum::{extract::State, Json, http::StatusCode};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+
+use crate::models::{AppState,
+                    QuarantineActionForm,
+                    QuarantinedFileResponse,
+                    AuditQueryForm,
+                    AuditEventResponse,
+                    ScanStatus,
+                    ServerError};
+use crate::auth_methods::get_current_user;
+use crate::methods::{remove_file, update_cached_files};
+use crate::scanner::queue_scan;
+use crate::msc_actions::notify_user;
+use crate::audit::page_size;
+
+// super_user on the users table, there is no other admin role
+pub(crate) async fn require_admin(state: &AppState, jar: CookieJar) -> Result<Uuid, ServerError> {
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let is_admin: Option<bool> = sqlx::query_scalar(r#"SELECT super_user FROM users
+                                                       WHERE user_id = ($1) AND active;"#)
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if is_admin != Some(true) {
+        return Err(ServerError::Forbidden("Admins only".to_string()));
+    }
+    Ok(user_id)
+}
+
+pub async fn get_quarantine(State(state): State<AppState>,
+                            jar: CookieJar,
+) -> Result<Json<Vec<QuarantinedFileResponse>>, ServerError> {
+
+    require_admin(&state, jar).await?;
+    let files = sqlx::query_as::<_, QuarantinedFileResponse>(r#"SELECT f.file_id, f.owner_id,
+                       u.email AS owner_email, f.file_name, f.extension, f.size, f.mime_type,
+                       f.scan_result, f.scanned_at, f.created_at
+                       FROM files f JOIN users u ON u.user_id = f.owner_id
+                       WHERE f.scan_status = 'infected'
+                       ORDER BY f.scanned_at DESC;"#)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(files))
+}
+
+// false positive, the file becomes downloadable again
+pub async fn release_quarantined(State(state): State<AppState>,
+                                 jar: CookieJar,
+                                 payload: Json<QuarantineActionForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let admin_id = require_admin(&state, jar).await?;
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let owner_id: Option<Uuid> = sqlx::query_scalar(r#"UPDATE files SET scan_status = ($1),
+                                                       scan_result = 'released by ' || ($2)::text
+                                                       WHERE file_id = ($3) AND scan_status = 'infected'
+                                                       RETURNING owner_id;"#)
+        .bind(ScanStatus::Clean)
+        .bind(admin_id)
+        .bind(file_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let owner_id = owner_id
+        .ok_or(ServerError::NotFound("File is not quarantined".to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        files.entry(file_id).and_modify(|f| f.scan_status = ScanStatus::Clean);
+    }).await;
+    if let Err(e) = notify_user(&state.pool, &owner_id, "quarantine_released",
+                                "A quarantined file was released", Some(file_id)).await {
+        eprintln!("Error {:?}", e);
+    }
+    Ok(StatusCode::OK)
+}
+
+pub async fn delete_quarantined(State(state): State<AppState>,
+                                jar: CookieJar,
+                                payload: Json<QuarantineActionForm>,
+) -> Result<StatusCode, ServerError> {
+
+    require_admin(&state, jar).await?;
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let owner_id: Option<Uuid> = sqlx::query_scalar(r#"SELECT owner_id FROM files
+                                                       WHERE file_id = ($1) AND scan_status = 'infected';"#)
+        .bind(file_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let owner_id = owner_id
+        .ok_or(ServerError::NotFound("File is not quarantined".to_string()))?;
+    remove_file(&state, owner_id, file_id).await?;
+    Ok(StatusCode::OK)
+}
+
+// after a signature update, or to retry a failed scan
+pub async fn rescan_file(State(state): State<AppState>,
+                         jar: CookieJar,
+                         payload: Json<QuarantineActionForm>,
+) -> Result<StatusCode, ServerError> {
+
+    require_admin(&state, jar).await?;
+    if state.scanning.scanner.is_none() {
+        return Err(ServerError::BadRequest("Scanning is turned off".to_string()));
+    }
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // a released file stays downloadable while it is rescanned
+    let owner_id: Option<Uuid> = sqlx::query_scalar(r#"UPDATE files
+                                                       SET scan_status = CASE WHEN scan_status = 'clean'
+                                                           THEN scan_status ELSE ($1) END
+                                                       WHERE file_id = ($2) AND file_type != 'folder'
+                                                       AND NOT vault
+                                                       RETURNING owner_id;"#)
+        .bind(ScanStatus::Pending)
+        .bind(file_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let owner_id = owner_id
+        .ok_or(ServerError::NotFound("File not found".to_string()))?;
+    queue_scan(&mut tx, &file_id).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        files.entry(file_id).and_modify(|f| if f.scan_status != ScanStatus::Clean {
+            f.scan_status = ScanStatus::Pending;
+            f.url = None;
+        });
+    }).await;
+    Ok(StatusCode::OK)
+}
+
+// the whole audit log, narrowed by time range, actions, actor or file.
+// newest first, paged by event_id like get-activity
+pub async fn get_audit_events(State(state): State<AppState>,
+                              jar: CookieJar,
+                              payload: Json<AuditQueryForm>,
+) -> Result<Json<Vec<AuditEventResponse>>, ServerError> {
+
+    require_admin(&state, jar).await?;
+    let mut query = sqlx::QueryBuilder::new(r#"SELECT a.event_id, a.actor_id, u.email AS actor_email,
+                                               a.action, a.file_id, a.owner_id, a.ip, a.user_agent,
+                                               a.details, a.created_at
+                                               FROM audit_events a
+                                               LEFT JOIN users u ON u.user_id = a.actor_id
+                                               WHERE TRUE"#);
+    if let Some(from) = payload.from {
+        query.push(" AND a.created_at >= ").push_bind(from);
+    }
+    if let Some(to) = payload.to {
+        query.push(" AND a.created_at < ").push_bind(to);
+    }
+    if let Some(actions) = &payload.actions && !actions.is_empty() {
+        query.push(" AND a.action = ANY(").push_bind(actions.clone()).push(")");
+    }
+    if let Some(actor_id) = payload.actor_id {
+        query.push(" AND a.actor_id = ").push_bind(actor_id);
+    }
+    if let Some(file_id) = payload.file_id {
+        query.push(" AND a.file_id = ").push_bind(file_id);
+    }
+    if let Some(before) = payload.before {
+        query.push(" AND a.event_id < ").push_bind(before);
+    }
+    query.push(" ORDER BY a.event_id DESC LIMIT ").push_bind(page_size(payload.limit));
+    let events = query.build_query_as::<AuditEventResponse>()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(events))
+}
@@ -0,0 +1,159 @@
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+// append only record of who did what, audit_events refuses updates and deletes
+// at the database. ids are kept without foreign keys so events outlive the
+// users and files they are about. `details` holds before/after values for
+// renames and anything else worth keeping, e.g. {"before": "a", "after": "b"}
+
+// where a request came from: the socket peer, unless the peer is one of our
+// own proxies, then the nearest X-Forwarded-For hop that isnt. anyone can send
+// the header, so it only counts when a trusted proxy appended to it
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// TRUSTED_PROXIES=10.0.0.2,172.16.0.0/12 lists the reverse proxies in front
+// of the worker, addresses or cidr ranges. unset means none
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+static TRUSTED_PROXIES: LazyLock<TrustedProxies> = LazyLock::new(|| {
+    TrustedProxies::parse(&env::var("TRUSTED_PROXIES").unwrap_or_default())
+});
+
+impl TrustedProxies {
+    pub fn parse(list: &str) -> Self {
+        let mut networks = Vec::new();
+        for entry in list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((addr, prefix)) => (addr, prefix.parse::<u8>().ok()),
+                None => (entry, None),
+            };
+            match addr.parse::<IpAddr>() {
+                Ok(ip) => {
+                    let max = if ip.is_ipv4() { 32 } else { 128 };
+                    networks.push((ip, prefix.unwrap_or(max).min(max)));
+                },
+                Err(_) => eprintln!("Ignoring trusted proxy {}", entry),
+            }
+        }
+        TrustedProxies { networks }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // a v4 peer can show up mapped on a dual stack socket
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        self.networks.iter().any(|(net, prefix)| match (net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        })
+    }
+}
+
+// hops are read right to left, each one was appended by the proxy before it,
+// so the first that isnt ours is the client as far as we can tell
+pub fn client_ip(peer: Option<IpAddr>,
+                 forwarded_for: Option<&str>,
+                 real_ip: Option<&str>,
+                 trusted: &TrustedProxies,
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted.contains(peer) {
+        return Some(peer);
+    }
+    if let Some(forwarded_for) = forwarded_for {
+        let mut client = peer;
+        for hop in forwarded_for.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !trusted.contains(ip) {
+                        break;
+                    }
+                },
+                // garbage further left is whatever the client made up
+                Err(_) => break,
+            }
+        }
+        return Some(client);
+    }
+    real_ip.and_then(|ip| ip.trim().parse().ok()).or(Some(peer))
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| parts.headers.get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let ip = client_ip(peer, header("x-forwarded-for").as_deref(),
+                           header("x-real-ip").as_deref(), &TRUSTED_PROXIES)
+            .map(|ip| ip.to_string());
+        let user_agent = header("user-agent")
+            .map(|ua| ua.chars().take(512).collect());
+        Ok(RequestInfo { ip, user_agent })
+    }
+}
+
+// one event, everything but the action optional
+#[derive(Debug, Default)]
+pub struct AuditEvent<'a> {
+    pub actor_id: Option<Uuid>,
+    pub action: &'a str,
+    pub file_id: Option<Uuid>,
+    // whose file it is, so owners see what others did with their files
+    pub owner_id: Option<Uuid>,
+    pub details: Option<Value>,
+}
+
+// a lost audit row is logged, it doesnt fail the request that caused it
+pub async fn record(pool: &PgPool, info: &RequestInfo, event: AuditEvent<'_>) {
+    let result = sqlx::query(r#"INSERT INTO audit_events (actor_id, action, file_id, owner_id,
+                                ip, user_agent, details)
+                                VALUES ($1,$2,$3,$4,$5,$6,$7);"#)
+        .bind(event.actor_id)
+        .bind(event.action)
+        .bind(event.file_id)
+        .bind(event.owner_id)
+        .bind(&info.ip)
+        .bind(&info.user_agent)
+        .bind(&event.details)
+        .execute(pool)
+        .await;
+    if let Err(e) = result {
+        eprintln!("Failed to record {} event. Error {:?}", event.action, e);
+    }
+}
+
+// how many events a page holds unless asked for fewer
+pub const MAX_PAGE: i64 = 200;
+
+pub fn page_size(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(50).clamp(1, MAX_PAGE)
+}
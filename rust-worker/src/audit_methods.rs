@@ -0,0 +1,36 @@
+use axum::{extract::State, Json};
+use axum_extra::extract::cookie::CookieJar;
+
+use crate::models::{AppState,
+                    ActivityForm,
+                    AuditEventResponse,
+                    ServerError};
+use crate::auth_methods::current_user;
+use crate::audit::page_size;
+
+// what the user did, and what anyone did to files they own. newest first, pass
+// the last event_id back as `before` for the next page
+pub async fn get_activity(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<ActivityForm>,
+) -> Result<Json<Vec<AuditEventResponse>>, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let events = sqlx::query_as::<_, AuditEventResponse>(r#"SELECT a.event_id, a.actor_id,
+                                                           u.email AS actor_email, a.action, a.file_id,
+                                                           a.owner_id, a.ip, a.user_agent, a.details,
+                                                           a.created_at
+                                                           FROM audit_events a
+                                                           LEFT JOIN users u ON u.user_id = a.actor_id
+                                                           WHERE (a.actor_id = ($1) OR a.owner_id = ($1))
+                                                           AND (($2)::bigint IS NULL OR a.event_id < ($2))
+                                                           ORDER BY a.event_id DESC
+                                                           LIMIT ($3);"#)
+        .bind(user_id)
+        .bind(payload.before)
+        .bind(page_size(payload.limit))
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(events))
+}
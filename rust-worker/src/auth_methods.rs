@@ -7,13 +7,18 @@ use crate::models::{ServerError,
                     TestToken,
                     Claims,
                     FileCache,
-                    FileResponse};
+                    FileResponse,
+                    CreateApiKeyForm,
+                    ApiKeyIdForm,
+                    ApiKeySignInForm,
+                    ApiKeyResponse};
 use jsonwebtoken::{encode, decode, Header, Algorithm, EncodingKey,
                    DecodingKey, Validation};
 use sha2::{Sha256, Digest};
 use uuid::Uuid;
-use crate::msc_actions::{get_user_id, hash_algorithm, create_bucket_func};
+use crate::msc_actions::{get_user_id, hash_algorithm, create_bucket_func, generate_token};
 use sqlx::Acquire;
+use crate::audit::{RequestInfo, AuditEvent, record};
 
 use moka::future::Cache;
 use std::collections::HashMap;
@@ -39,14 +44,27 @@ fn create_token(
 pub async fn login_user(
     jar: CookieJar,
     State(state): State<AppState>,
+    info: RequestInfo,
     payload: Json<SignInForm>,
 ) -> Result<CookieJar, ServerError> {
     println!("{}", payload.email);
     let email = payload.email.clone();
-    let user_id = get_user_id(&email, &payload.password, &state.pool)
-        .await
-        .map_err(
-        |e| ServerError::InternalError("Error getting user id".to_string()))?; 
+    let user_id = match get_user_id(&email, &payload.password, &state.pool).await {
+        Ok(id) => id,
+        Err(_) => {
+            record(&state.pool, &info, AuditEvent {
+                action: "login_failed",
+                details: Some(serde_json::json!({"email": email})),
+                ..Default::default()
+            }).await;
+            return Err(ServerError::InternalError("Error getting user id".to_string()));
+        },
+    };
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "login",
+        ..Default::default()
+    }).await;
     let token = create_token(user_id.to_string(), 300, &state.key);
     let cookie = Cookie::build(("session", token))
         .path("/")
@@ -74,12 +92,21 @@ Ok(user.sub)
 pub async fn logout_user(
     State(state): State<AppState>,
     jar: CookieJar,
+    info: RequestInfo,
 ) -> Result<CookieJar, ServerError> {
     
+    if let Ok(user_id) = current_user(&state, jar.clone()).await {
+        record(&state.pool, &info, AuditEvent {
+            actor_id: Some(user_id),
+            action: "logout",
+            ..Default::default()
+        }).await;
+    }
     Ok(jar.remove(Cookie::from("session")))
 }
 pub async fn create_user(
     State(state): State<AppState>,
+    info: RequestInfo,
     payload: Json<SignUpForm>,
 ) -> Result<StatusCode, ServerError> {
     println!("{}", payload.email);
@@ -112,10 +139,16 @@ pub async fn create_user(
         .await
         .map_err(|e| ServerError::DatabaseError(format!("Failed to create user. Error: {}", e)))?;
    
-    create_bucket_func(state.client, &user_id.to_string())
+    create_bucket_func(&state.store, &state.layout.bucket(&user_id.to_string()))
         .await.map_err(|e| ServerError::InternalError("Failed to create user bucket".to_string()))?;
     tx.commit()
         .await.map_err(|e| ServerError::DatabaseError(e.to_string()))?; 
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "user_created",
+        details: Some(serde_json::json!({"email": email})),
+        ..Default::default()
+    }).await;
         
     Ok(StatusCode::CREATED)
 }
@@ -149,4 +182,147 @@ pub async fn get_current_user(
     Ok("NOT VALID".to_string())
 }
 
+// the signed in user's id, for handlers that dont need the raw string
+pub(crate) async fn current_user(state: &AppState, jar: CookieJar) -> Result<Uuid, ServerError> {
+    if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))
+    } else {
+        Err(ServerError::Unauthorized("No session token found".to_string()))
+    }
+}
+
+
+// api keys stand in for a password in scripts and tools: they can be exchanged
+// for a session at /sign-in-key. only their sha-256 is stored
+pub const API_KEY_PREFIX: &str = "srv_";
+
+// the active user a key belongs to, and marks it used
+pub(crate) async fn api_key_user(pool: &sqlx::PgPool, api_key: &str) -> Result<Option<Uuid>, ServerError> {
+    if !api_key.starts_with(API_KEY_PREFIX) {
+        return Ok(None);
+    }
+    sqlx::query_scalar(r#"UPDATE api_keys k SET last_used_at = NOW()
+                          FROM users u
+                          WHERE k.key_hash = ($1) AND k.revoked_at IS NULL
+                          AND u.user_id = k.user_id AND u.active
+                          RETURNING k.user_id;"#)
+        .bind(hash_algorithm(api_key))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))
+}
+
+// the key is only in this response
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<CreateApiKeyForm>,
+) -> Result<Json<ApiKeyResponse>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(ServerError::BadRequest("Key names are 1 to 100 characters".to_string()));
+    }
+    let api_key = format!("{}{}", API_KEY_PREFIX, generate_token());
+    let mut key = sqlx::query_as::<_, ApiKeyResponse>(r#"INSERT INTO api_keys (key_id, user_id, name,
+                                                        prefix, key_hash)
+                                                        VALUES ($1,$2,$3,$4,$5)
+                                                        RETURNING key_id, name, prefix, created_at,
+                                                        last_used_at;"#)
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(name)
+        .bind(&api_key[..API_KEY_PREFIX.len() + 8])
+        .bind(hash_algorithm(&api_key))
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "api_key_created",
+        details: Some(serde_json::json!({"key_id": key.key_id, "name": name})),
+        ..Default::default()
+    }).await;
+    key.key = Some(api_key);
+    Ok(Json(key))
+}
+
+pub async fn get_api_keys(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Vec<ApiKeyResponse>>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let keys = sqlx::query_as::<_, ApiKeyResponse>(r#"SELECT key_id, name, prefix, created_at, last_used_at
+                                                      FROM api_keys
+                                                      WHERE user_id = ($1) AND revoked_at IS NULL
+                                                      ORDER BY created_at;"#)
+        .bind(user_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(keys))
+}
+
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<ApiKeyIdForm>,
+) -> Result<StatusCode, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let key_id = Uuid::parse_str(&payload.key_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let result = sqlx::query(r#"UPDATE api_keys SET revoked_at = NOW()
+                                WHERE key_id = ($1) AND user_id = ($2) AND revoked_at IS NULL;"#)
+        .bind(key_id)
+        .bind(user_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("API key not found".to_string()));
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "api_key_revoked",
+        details: Some(serde_json::json!({"key_id": key_id})),
+        ..Default::default()
+    }).await;
+    Ok(StatusCode::OK)
+}
 
+// same session cookie as /sign-in
+pub async fn login_with_api_key(
+    jar: CookieJar,
+    State(state): State<AppState>,
+    info: RequestInfo,
+    payload: Json<ApiKeySignInForm>,
+) -> Result<CookieJar, ServerError> {
+    let user_id = match api_key_user(&state.pool, payload.api_key.trim()).await? {
+        Some(id) => id,
+        None => {
+            record(&state.pool, &info, AuditEvent {
+                action: "login_failed",
+                details: Some(serde_json::json!({"api_key": true})),
+                ..Default::default()
+            }).await;
+            return Err(ServerError::Unauthorized("Invalid API key".to_string()));
+        },
+    };
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "login",
+        details: Some(serde_json::json!({"api_key": true})),
+        ..Default::default()
+    }).await;
+    let token = create_token(user_id.to_string(), 300, &state.key);
+    let cookie = Cookie::build(("session", token))
+        .path("/")
+        .http_only(true)
+        .secure(false)
+        .build();
+    Ok(jar.add(cookie))
+}
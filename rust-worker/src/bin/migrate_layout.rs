@@ -0,0 +1,57 @@
+// moves bucket-per-user storage into the shared bucket layout
+// usage: migrate_layout [--dry-run] [--delete-source]
+// afterwards run the worker with STORAGE_LAYOUT=shared and the same SHARED_BUCKET
+use rust_worker::storage::{store_from_env, migrate::migrate_to_shared};
+use sqlx::postgres::PgPoolOptions;
+use uuid::Uuid;
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = env::args().collect();
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let delete_source = args.iter().any(|a| a == "--delete-source");
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let shared_bucket = env::var("SHARED_BUCKET").unwrap_or("servr-storage".to_string());
+    let key = env::var("SECRET_KEY").unwrap_or_default();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+    let store = store_from_env(&key).await;
+
+    let user_ids: Vec<Uuid> = sqlx::query_scalar("SELECT user_id FROM users;")
+        .fetch_all(&pool)
+        .await
+        .expect("Failed to fetch users");
+    let user_ids: Vec<String> = user_ids.iter().map(|id| id.to_string()).collect();
+
+    let report = match migrate_to_shared(store.as_ref(), &user_ids, &shared_bucket,
+                                         delete_source, dry_run).await {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            std::process::exit(1);
+        },
+    };
+    println!("Users: {}, copied: {}, already there: {}, failed: {}",
+             report.users, report.copied, report.skipped, report.failed.len());
+    for f in &report.failed {
+        eprintln!("Failed: {}", f);
+    }
+
+    if !dry_run && report.failed.is_empty() {
+        // cached presigned urls point at the old buckets
+        sqlx::query("UPDATE files SET url = NULL;")
+            .execute(&pool)
+            .await
+            .expect("Failed to reset urls");
+    }
+    if !report.failed.is_empty() {
+        std::process::exit(1);
+    }
+}
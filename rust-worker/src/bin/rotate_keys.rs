@@ -0,0 +1,62 @@
+// key rotation without re-encrypting objects
+// usage: rotate_keys [--new-data-keys]
+//   default: re-wraps every data key and s3 access key secret under MASTER_KEY,
+//            run after moving the old master key into OLD_MASTER_KEYS. once it
+//            reports 0 the old key can go
+//   --new-data-keys: also retires every user's active data key so new uploads
+//            get a fresh one, existing objects keep decrypting with the old keys
+use rust_worker::encryption::{Encryption, rewrap_data_keys, retire_data_keys};
+use rust_worker::s3_gateway::reseal_secrets;
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let new_data_keys = env::args().any(|a| a == "--new-data-keys");
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let encryption = match Encryption::from_env() {
+        Ok(Some(e)) => e,
+        Ok(None) => {
+            eprintln!("MASTER_KEY not set, nothing to rotate");
+            std::process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            std::process::exit(1);
+        },
+    };
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+
+    match rewrap_data_keys(&pool, &encryption).await {
+        Ok(n) => println!("Re-wrapped {} data keys under {}", n, encryption.active_id()),
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            std::process::exit(1);
+        },
+    }
+    // access keys made before encryption was on are sealed under SECRET_KEY
+    // and move under the master key too
+    let key = env::var("SECRET_KEY").expect("SECRET_KEY not set");
+    match reseal_secrets(&pool, &key, &encryption).await {
+        Ok(n) => println!("Re-sealed {} access key secrets under {}", n, encryption.active_id()),
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            std::process::exit(1);
+        },
+    }
+    if new_data_keys {
+        match retire_data_keys(&pool).await {
+            Ok(n) => println!("Retired {} data keys", n),
+            Err(e) => {
+                eprintln!("Error {:?}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+}
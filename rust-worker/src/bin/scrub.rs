@@ -0,0 +1,45 @@
+// re-reads stored objects and flags files whose content no longer matches
+// their checksum. usage: scrub [--limit N], meant for cron
+use rust_worker::storage::{store_from_env, StorageLayout};
+use rust_worker::dedup::DedupConfig;
+use rust_worker::integrity::scrub;
+use rust_worker::encryption::Encryption;
+use sqlx::postgres::PgPoolOptions;
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = env::args().collect();
+    let limit: i64 = args.iter()
+        .position(|a| a == "--limit")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let key = env::var("SECRET_KEY").unwrap_or_default();
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&database_url)
+        .await
+        .expect("Failed to create pool");
+    let store = store_from_env(&key).await;
+    let layout = StorageLayout::from_env();
+    let dedup = DedupConfig::from_env(&layout);
+    let encryption = Encryption::from_env().expect("Invalid encryption config");
+
+    match scrub(&pool, &store, &layout, &dedup, encryption.as_ref(), limit).await {
+        Ok(report) => {
+            println!("Checked: {}, corrupted: {}, missing: {}",
+                     report.checked, report.corrupted, report.missing);
+            if report.corrupted > 0 {
+                std::process::exit(2);
+            }
+        },
+        Err(e) => {
+            eprintln!("Error {:?}", e);
+            std::process::exit(1);
+        },
+    }
+}
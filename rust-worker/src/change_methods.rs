@@ -0,0 +1,79 @@
+use axum::{extract::{self, State}, Json};
+use axum_extra::extract::cookie::CookieJar;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, Instant, timeout_at};
+
+use uuid::Uuid;
+
+use crate::models::{AppState,
+                    ChangesQuery,
+                    ChangeEntry,
+                    ChangesResponse,
+                    ServerError};
+use crate::auth_methods::current_user;
+
+// longest a request is held open for
+const MAX_WAIT_SECS: u64 = 60;
+
+async fn changes_since(state: &AppState,
+                       user_id: &Uuid,
+                       cursor: i64,
+                       limit: i64,
+) -> Result<Vec<ChangeEntry>, ServerError> {
+    sqlx::query_as::<_, ChangeEntry>(r#"SELECT c.change_id, c.file_id, c.kind,
+                                       c.created_at AS changed_at, f.owner_id, f.parent_id,
+                                       f.file_name, f.file_type, f.size, f.last_modified,
+                                       f.checksum_sha256, f.vault
+                                       FROM changes c
+                                       LEFT JOIN files f ON f.file_id = c.file_id AND c.kind != 'deleted'
+                                       WHERE c.user_id = ($1) AND c.change_id > ($2)
+                                       ORDER BY c.change_id
+                                       LIMIT ($3);"#)
+        .bind(user_id)
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))
+}
+
+// what changed for the user since the cursor, oldest first. start with
+// get_files and a request without a cursor, then keep passing the returned
+// cursor back. entries for a folder stand for its contents too: a created or
+// shared folder has to be listed, a deleted one takes everything below it
+pub async fn get_changes(State(state): State<AppState>,
+                         jar: CookieJar,
+                         extract::Query(query): extract::Query<ChangesQuery>,
+) -> Result<Json<ChangesResponse>, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let cursor = match query.cursor {
+        Some(c) => c.max(0),
+        None => {
+            let head: Option<i64> = sqlx::query_scalar("SELECT MAX(change_id) FROM changes WHERE user_id = ($1);")
+                .bind(user_id)
+                .fetch_one(&state.pool)
+                .await
+                .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+            return Ok(Json(ChangesResponse { cursor: head.unwrap_or(0), has_more: false, changes: Vec::new() }));
+        },
+    };
+    let limit = query.limit.unwrap_or(500).clamp(1, 1000);
+    let deadline = Instant::now() + Duration::from_secs(query.wait.unwrap_or(0).min(MAX_WAIT_SECS));
+    // subscribed before the first look, so nothing lands in between unseen
+    let mut receiver = state.events.subscribe();
+
+    let mut changes = changes_since(&state, &user_id, cursor, limit + 1).await?;
+    while changes.is_empty() && Instant::now() < deadline {
+        match timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(event)) if !event.visible_to(&user_id) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+            // something for this user, or missed events, either way look again
+            Ok(_) => changes = changes_since(&state, &user_id, cursor, limit + 1).await?,
+        }
+    }
+    let has_more = changes.len() as i64 > limit;
+    changes.truncate(limit as usize);
+    let cursor = changes.last().map(|c| c.change_id).unwrap_or(cursor);
+    Ok(Json(ChangesResponse { cursor, has_more, changes }))
+}
@@ -0,0 +1,71 @@
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::models::ServerError;
+use crate::events::ChangeKind;
+use crate::msc_actions::file_audience;
+
+// durable side of the event bus: every change also lands in the changes
+// table, one row per user that can see it, for sync clients to page through
+// with /changes. the rows are written in the transaction that makes the
+// change, so they commit or roll back with it.
+//
+// cursors are per user, so change_id only has to grow in commit order per
+// user. each write takes a transaction level lock for every user it journals
+// to, in a fixed order, before drawing ids: two writes for the same user
+// commit in id order and a cursor never skips rows, writes for different
+// users dont wait on each other
+
+// what a sync client has to do with its copy
+pub fn journal_kind(kind: ChangeKind) -> &'static str {
+    match kind {
+        ChangeKind::Created => "created",
+        ChangeKind::Deleted => "deleted",
+        ChangeKind::Modified | ChangeKind::Renamed | ChangeKind::Moved | ChangeKind::Shared
+            | ChangeKind::Unshared => "updated",
+    }
+}
+
+// journals a change to everyone who can see the file as of the transaction,
+// plus a delete for anyone in `before` (who could see it ahead of the change)
+// that no longer can. deletes go in before the row does, while the audience
+// can still be worked out. returns everyone that got a row
+pub async fn record_change(tx: &mut Transaction<'_, Postgres>,
+                           file_id: &Uuid,
+                           kind: ChangeKind,
+                           before: &[Uuid],
+) -> Result<Vec<Uuid>, ServerError> {
+    let audience = file_audience(&mut **tx, file_id).await?;
+    let mut rows: Vec<(Uuid, &str)> = audience.iter()
+        .map(|user_id| (*user_id, journal_kind(kind)))
+        .collect();
+    for user_id in before {
+        if !audience.contains(user_id) {
+            rows.push((*user_id, "deleted"));
+        }
+    }
+    if rows.is_empty() {
+        return Ok(Vec::new());
+    }
+    rows.sort_by_key(|(user_id, _)| *user_id);
+    rows.dedup_by_key(|(user_id, _)| *user_id);
+    let (users, kinds): (Vec<Uuid>, Vec<&str>) = rows.into_iter().unzip();
+    // taken in array order, which is sorted, so two writes locking the same
+    // users cant deadlock on each other
+    sqlx::query(r#"SELECT pg_advisory_xact_lock(hashtextextended(u.user_id::text, 0))
+                   FROM UNNEST($1::uuid[]) AS u(user_id);"#)
+        .bind(&users)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    sqlx::query(r#"INSERT INTO changes (user_id, file_id, kind)
+                   SELECT u.user_id, ($1), u.kind
+                   FROM UNNEST($2::uuid[], $3::varchar[]) AS u(user_id, kind);"#)
+        .bind(file_id)
+        .bind(&users)
+        .bind(&kinds)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(users)
+}
@@ -0,0 +1,259 @@
+use axum::{extract::State, Json, http::StatusCode};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+
+use crate::models::{AppState,
+                    AddCommentForm,
+                    GetCommentsForm,
+                    EditCommentForm,
+                    DeleteCommentForm,
+                    ResolveCommentForm,
+                    CommentResponse,
+                    ServerError};
+use crate::auth_methods::current_user;
+use crate::msc_actions::{file_audience, notify_user};
+
+// comment threads on files. anyone who can see the file can read, post, reply
+// and resolve; only authors edit or delete their own comments
+const MAX_COMMENT_SIZE: usize = 10_000;
+
+fn comment_body(body: &str) -> Result<String, ServerError> {
+    let body = body.trim();
+    if body.is_empty() || body.len() > MAX_COMMENT_SIZE {
+        return Err(ServerError::BadRequest("Comments are 1 to 10000 characters".to_string()));
+    }
+    Ok(body.to_string())
+}
+
+// "@alice@example.com," -> alice@example.com, lowercased
+pub fn parse_mentions(body: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = body.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|word| word.trim_end_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .filter(|email| email.contains('@'))
+        .collect();
+    mentions.sort();
+    mentions.dedup();
+    mentions
+}
+
+// mentioned users that can see the file, others are left as plain text
+async fn resolve_mentions(state: &AppState,
+                          body: &str,
+                          audience: &[Uuid],
+) -> Result<Vec<Uuid>, ServerError> {
+    let emails = parse_mentions(body);
+    if emails.is_empty() {
+        return Ok(Vec::new());
+    }
+    let users: Vec<Uuid> = sqlx::query_scalar(r#"SELECT user_id FROM users
+                                                 WHERE LOWER(email) = ANY($1) AND active;"#)
+        .bind(&emails)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(users.into_iter().filter(|u| audience.contains(u)).collect())
+}
+
+async fn notify_mentions(state: &AppState,
+                         author_id: &Uuid,
+                         file_id: &Uuid,
+                         mentions: &[Uuid],
+) {
+    for user_id in mentions.iter().filter(|u| *u != author_id) {
+        if let Err(e) = notify_user(&state.pool, user_id, "comment_mention",
+                                    "You were mentioned in a comment", Some(*file_id)).await {
+            eprintln!("Error {:?}", e);
+        }
+    }
+}
+
+// (file_id, author_id, parent_id) of a comment the user can see
+async fn visible_comment(state: &AppState,
+                         user_id: &Uuid,
+                         comment_id: &Uuid,
+) -> Result<(Uuid, Uuid, Option<Uuid>), ServerError> {
+    let comment: Option<(Uuid, Uuid, Option<Uuid>)> =
+        sqlx::query_as(r#"SELECT file_id, author_id, parent_id FROM comments
+                          WHERE comment_id = ($1) AND NOT deleted;"#)
+        .bind(comment_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let comment = comment.ok_or(ServerError::NotFound("Comment not found".to_string()))?;
+    // same answer as for a comment that doesnt exist
+    if !file_audience(&state.pool, &comment.0).await?.contains(user_id) {
+        return Err(ServerError::NotFound("Comment not found".to_string()));
+    }
+    Ok(comment)
+}
+
+pub async fn add_comment(State(state): State<AppState>,
+                         jar: CookieJar,
+                         payload: Json<AddCommentForm>,
+) -> Result<Json<CommentResponse>, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let body = comment_body(&payload.body)?;
+    let audience = file_audience(&state.pool, &file_id).await?;
+    if !audience.contains(&user_id) {
+        return Err(ServerError::NotFound("File not found".to_string()));
+    }
+    let (parent_id, parent_author) = match &payload.parent_id {
+        Some(id) => {
+            let parent_id = Uuid::parse_str(id)
+                .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+            let (parent_file, author, _) = visible_comment(&state, &user_id, &parent_id).await?;
+            if parent_file != file_id {
+                return Err(ServerError::BadRequest("Replies have to be on the same file".to_string()));
+            }
+            (Some(parent_id), Some(author))
+        },
+        None => (None, None),
+    };
+    let mentions = resolve_mentions(&state, &body, &audience).await?;
+
+    let comment = sqlx::query_as::<_, CommentResponse>(r#"WITH inserted AS (
+                                                              INSERT INTO comments (comment_id, file_id,
+                                                              parent_id, author_id, body, mentions)
+                                                              VALUES ($1,$2,$3,$4,$5,$6)
+                                                              RETURNING *
+                                                          )
+                                                          SELECT i.comment_id, i.file_id, i.parent_id,
+                                                          i.author_id, u.email AS author_email, i.body,
+                                                          i.mentions, i.resolved, i.resolved_by, i.deleted,
+                                                          i.created_at, i.edited_at
+                                                          FROM inserted i
+                                                          JOIN users u ON u.user_id = i.author_id;"#)
+        .bind(Uuid::new_v4())
+        .bind(file_id)
+        .bind(parent_id)
+        .bind(user_id)
+        .bind(&body)
+        .bind(&mentions)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    notify_mentions(&state, &user_id, &file_id, &mentions).await;
+    // the author of the comment replied to hears about it, unless mentioned already
+    if let Some(author) = parent_author && author != user_id && !mentions.contains(&author)
+    && let Err(e) = notify_user(&state.pool, &author, "comment_reply",
+                                "Someone replied to your comment", Some(file_id)).await {
+        eprintln!("Error {:?}", e);
+    }
+    Ok(Json(comment))
+}
+
+pub async fn get_comments(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<GetCommentsForm>,
+) -> Result<Json<Vec<CommentResponse>>, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    if !file_audience(&state.pool, &file_id).await?.contains(&user_id) {
+        return Err(ServerError::NotFound("File not found".to_string()));
+    }
+    let comments = sqlx::query_as::<_, CommentResponse>(r#"SELECT c.comment_id, c.file_id, c.parent_id,
+                                                           c.author_id, u.email AS author_email,
+                                                           c.body, c.mentions, c.resolved, c.resolved_by,
+                                                           c.deleted, c.created_at, c.edited_at
+                                                           FROM comments c
+                                                           JOIN users u ON u.user_id = c.author_id
+                                                           WHERE c.file_id = ($1)
+                                                           ORDER BY c.created_at;"#)
+        .bind(file_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(comments))
+}
+
+pub async fn edit_comment(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<EditCommentForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let comment_id = Uuid::parse_str(&payload.comment_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let body = comment_body(&payload.body)?;
+    let (file_id, author_id, _) = visible_comment(&state, &user_id, &comment_id).await?;
+    if author_id != user_id {
+        return Err(ServerError::Forbidden("Only the author can edit a comment".to_string()));
+    }
+    let audience = file_audience(&state.pool, &file_id).await?;
+    let mentions = resolve_mentions(&state, &body, &audience).await?;
+    // only people not mentioned before get notified again
+    let previous: Vec<Uuid> = sqlx::query_scalar(r#"SELECT mentions FROM comments
+                                                    WHERE comment_id = ($1);"#)
+        .bind(comment_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    sqlx::query(r#"UPDATE comments SET body = ($1), mentions = ($2), edited_at = NOW()
+                   WHERE comment_id = ($3);"#)
+        .bind(&body)
+        .bind(&mentions)
+        .bind(comment_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let new_mentions: Vec<Uuid> = mentions.into_iter().filter(|m| !previous.contains(m)).collect();
+    notify_mentions(&state, &user_id, &file_id, &new_mentions).await;
+    Ok(StatusCode::OK)
+}
+
+// the row stays, emptied, so replies keep their thread
+pub async fn delete_comment(State(state): State<AppState>,
+                            jar: CookieJar,
+                            payload: Json<DeleteCommentForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let comment_id = Uuid::parse_str(&payload.comment_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let (_, author_id, _) = visible_comment(&state, &user_id, &comment_id).await?;
+    if author_id != user_id {
+        return Err(ServerError::Forbidden("Only the author can delete a comment".to_string()));
+    }
+    sqlx::query(r#"UPDATE comments SET deleted = TRUE, body = '', mentions = '{}'
+                   WHERE comment_id = ($1);"#)
+        .bind(comment_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(StatusCode::OK)
+}
+
+// threads are resolved as a whole, through their first comment
+pub async fn resolve_comment(State(state): State<AppState>,
+                             jar: CookieJar,
+                             payload: Json<ResolveCommentForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let comment_id = Uuid::parse_str(&payload.comment_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let (_, _, parent_id) = visible_comment(&state, &user_id, &comment_id).await?;
+    if parent_id.is_some() {
+        return Err(ServerError::BadRequest("Only threads can be resolved, not replies".to_string()));
+    }
+    sqlx::query(r#"UPDATE comments
+                   SET resolved = ($1),
+                   resolved_by = CASE WHEN ($1) THEN ($2) END,
+                   resolved_at = CASE WHEN ($1) THEN NOW() END
+                   WHERE comment_id = ($3);"#)
+        .bind(payload.resolved)
+        .bind(user_id)
+        .bind(comment_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(StatusCode::OK)
+}
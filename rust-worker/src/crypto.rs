@@ -0,0 +1,35 @@
+use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+
+// the primitives shared by storage links, webhooks and the s3 gateway, so
+// none of them has to reach into another for them
+
+// hmac-sha256 (rfc 2104)
+pub fn hmac_sha256(secret: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut key = [0u8; BLOCK_SIZE];
+    if secret.len() > BLOCK_SIZE {
+        for (k, b) in key.iter_mut().zip(Sha256::digest(secret).iter()) {
+            *k = *b;
+        }
+    } else {
+        key[..secret.len()].copy_from_slice(secret);
+    }
+    let mut inner = Sha256::new();
+    inner.update(key.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(key.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// as lowercase hex
+pub fn sign(secret: &[u8], message: &[u8]) -> String {
+    hmac_sha256(secret, message).iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+// for signatures and secrets, takes the same time however much of the two agree
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
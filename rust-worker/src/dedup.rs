@@ -0,0 +1,151 @@
+use sha2::{Sha256, Digest};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::env;
+use std::sync::Arc;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, StorageLayout};
+
+// content addressed storage. with DEDUP=true uploads are stored once per sha256
+// and files rows point at a blob through blob_hash, rows without one are legacy
+// per-file objects
+#[derive(Debug, Clone)]
+pub struct DedupConfig {
+    pub enabled: bool,
+    // DEDUP_SAVES_QUOTA=true charges a user once per distinct blob they own
+    pub saves_quota: bool,
+    pub bucket: String,
+    prefix: String,
+}
+
+impl DedupConfig {
+    pub fn from_env(layout: &StorageLayout) -> Self {
+        let (bucket, prefix) = match layout {
+            StorageLayout::SharedBucket(b) => (b.clone(), "blobs/".to_string()),
+            StorageLayout::BucketPerUser => (env::var("BLOB_BUCKET")
+                .unwrap_or("servr-blobs".to_string()), "".to_string()),
+        };
+        DedupConfig {
+            enabled: env::var("DEDUP").map(|v| v == "true").unwrap_or(false),
+            saves_quota: env::var("DEDUP_SAVES_QUOTA").map(|v| v == "true").unwrap_or(false),
+            bucket,
+            prefix,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        DedupConfig { enabled: false, saves_quota: false,
+                      bucket: "servr-blobs".to_string(), prefix: "".to_string() }
+    }
+
+    // fanned out on the first byte so no prefix gets huge
+    pub fn blob_location(&self, hash: &str) -> (String, String) {
+        (self.bucket.clone(), format!("{}{}/{}", self.prefix, &hash[..2], hash))
+    }
+}
+
+pub fn content_hash(data: &[u8]) -> String {
+    let hash = Sha256::digest(data);
+    hash.iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+// the blob a file with this sha256 goes in. with encryption on, a blob is
+// written under its owner's data key, so another user's copy can't point at
+// it: the hash covers the owner as well and blobs are shared per user
+pub fn blob_hash(sha256: &str, owner_id: &uuid::Uuid, encrypted: bool) -> String {
+    match encrypted {
+        true => content_hash(format!("{}:{}", owner_id, sha256).as_bytes()),
+        false => sha256.to_string(),
+    }
+}
+
+// takes a reference on the blob, true when the row is new and the object still
+// has to be written. a concurrent gc holds the row lock until its delete is done
+pub async fn reference_blob(tx: &mut Transaction<'_, Postgres>,
+                            hash: &str,
+                            size: i64,
+) -> Result<bool, ServerError> {
+    let inserted: bool = sqlx::query_scalar(r#"INSERT INTO blobs (hash, size, ref_count)
+                                               VALUES ($1, $2, 1)
+                                               ON CONFLICT (hash) DO UPDATE
+                                               SET ref_count = blobs.ref_count + 1,
+                                               last_referenced = NOW()
+                                               RETURNING (xmax = 0);"#)
+        .bind(hash)
+        .bind(size)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(inserted)
+}
+
+// the object itself is left for collect_garbage
+pub async fn release_blob(tx: &mut Transaction<'_, Postgres>,
+                          hash: &str,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"UPDATE blobs SET ref_count = ref_count - 1,
+                   last_referenced = NOW()
+                   WHERE hash = ($1);"#)
+        .bind(hash)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// whether the user is charged for another copy of this blob
+pub async fn charged_size(tx: &mut Transaction<'_, Postgres>,
+                          config: &DedupConfig,
+                          owner_id: &uuid::Uuid,
+                          hash: &str,
+                          size: i64,
+) -> Result<i64, ServerError> {
+    if !config.saves_quota {
+        return Ok(size);
+    }
+    let owned: bool = sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM files
+                                            WHERE owner_id = ($1) AND blob_hash = ($2));"#)
+        .bind(owner_id)
+        .bind(hash)
+        .fetch_one(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(if owned { 0 } else { size })
+}
+
+// drops blobs nobody has referenced for `grace`, returns how many went
+pub async fn collect_garbage(pool: &PgPool,
+                             store: &Arc<dyn ObjectStore>,
+                             config: &DedupConfig,
+                             grace: chrono::Duration,
+) -> Result<usize, ServerError> {
+    let candidates: Vec<String> = sqlx::query_scalar(r#"SELECT hash FROM blobs
+                                                        WHERE ref_count <= 0
+                                                        AND last_referenced < ($1)
+                                                        LIMIT 500;"#)
+        .bind(chrono::Utc::now() - grace)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut removed = 0;
+    for hash in candidates {
+        let mut tx = pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let deleted: Option<String> = sqlx::query_scalar(r#"DELETE FROM blobs
+                                                            WHERE hash = ($1) AND ref_count <= 0
+                                                            RETURNING hash;"#)
+            .bind(&hash)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        if deleted.is_none() {
+            // picked up a new reference in the meantime
+            continue;
+        }
+        let (bucket, key) = config.blob_location(&hash);
+        store.delete(&bucket, &key).await?;
+        tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
@@ -0,0 +1,379 @@
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, rand_core::RngCore};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::env;
+use uuid::Uuid;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectBody, ObjectStream};
+
+// envelope encryption. every user has a data key (dek) that encrypts their
+// objects, stored in user_keys wrapped by a master key from the environment.
+// rotating the master key only re-wraps the deks, objects stay as they are.
+//
+// object format: "SVE1" | 7 byte nonce prefix | chunks
+// each chunk is 64KiB of plaintext sealed with AES-256-GCM (16 byte tag) under
+// nonce prefix | u32 counter | last flag, so chunks cant be reordered or dropped
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const CHUNK_CT_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+const MAGIC: &[u8; 4] = b"SVE1";
+const PREFIX_SIZE: usize = 7;
+pub const HEADER_SIZE: usize = MAGIC.len() + PREFIX_SIZE;
+
+pub struct Encryption {
+    active_id: String,
+    master_keys: HashMap<String, [u8; 32]>,
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; 32], ServerError> {
+    let bytes = STANDARD.decode(encoded.trim())
+        .map_err(|e| ServerError::InternalError(format!("Invalid master key. Error: {}", e)))?;
+    bytes.try_into()
+        .map_err(|_| ServerError::InternalError("Master key must be 32 bytes".to_string()))
+}
+
+fn crypto_error(_: aes_gcm::Error) -> ServerError {
+    ServerError::InternalError("Encryption failure".to_string())
+}
+
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+impl Encryption {
+    pub fn new(active_id: String, active_key: [u8; 32], old_keys: Vec<(String, [u8; 32])>) -> Self {
+        let mut master_keys: HashMap<String, [u8; 32]> = old_keys.into_iter().collect();
+        master_keys.insert(active_id.clone(), active_key);
+        Encryption { active_id, master_keys }
+    }
+
+    // MASTER_KEY (base64, 32 bytes) turns encryption on, MASTER_KEY_ID names it.
+    // keys being rotated out stay readable through OLD_MASTER_KEYS=id:base64,...
+    pub fn from_env() -> Result<Option<Self>, ServerError> {
+        let active_key = match env::var("MASTER_KEY") {
+            Ok(k) if !k.is_empty() => decode_key(&k)?,
+            _ => return Ok(None),
+        };
+        let active_id = env::var("MASTER_KEY_ID").unwrap_or("1".to_string());
+        let mut old_keys: Vec<(String, [u8; 32])> = Vec::new();
+        for entry in env::var("OLD_MASTER_KEYS").unwrap_or_default().split(',') {
+            if let Some((id, key)) = entry.split_once(':') {
+                old_keys.push((id.trim().to_string(), decode_key(key)?));
+            }
+        }
+        Ok(Some(Encryption::new(active_id, active_key, old_keys)))
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.active_id
+    }
+
+    // nonce | sealed dek
+    pub fn wrap(&self, dek: &[u8; 32]) -> Result<Vec<u8>, ServerError> {
+        self.seal(dek)
+    }
+
+    pub fn unwrap(&self, master_key_id: &str, wrapped: &[u8]) -> Result<[u8; 32], ServerError> {
+        self.open(master_key_id, wrapped)?.try_into()
+            .map_err(|_| ServerError::InternalError("Data key must be 32 bytes".to_string()))
+    }
+
+    // any small secret under the active master key, nonce | sealed
+    pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>, ServerError> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.master_keys[&self.active_id]));
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = cipher.encrypt(Nonce::from_slice(&nonce), plain)
+            .map_err(crypto_error)?;
+        Ok([nonce.as_slice(), sealed.as_slice()].concat())
+    }
+
+    pub fn open(&self, master_key_id: &str, sealed: &[u8]) -> Result<Vec<u8>, ServerError> {
+        let master = self.master_keys.get(master_key_id)
+            .ok_or(ServerError::InternalError(format!("Master key {} not configured", master_key_id)))?;
+        if sealed.len() < 12 {
+            return Err(ServerError::InternalError("Wrapped key too short".to_string()));
+        }
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(master));
+        cipher.decrypt(Nonce::from_slice(&sealed[..12]), &sealed[12..])
+            .map_err(crypto_error)
+    }
+}
+
+pub fn generate_data_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+struct EncryptState {
+    inner: ObjectStream,
+    buf: BytesMut,
+    cipher: Aes256Gcm,
+    prefix: [u8; PREFIX_SIZE],
+    counter: u32,
+    header_sent: bool,
+    done: bool,
+}
+
+impl EncryptState {
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Bytes, std::io::Error> {
+        let nonce = chunk_nonce(&self.prefix, self.counter, last);
+        self.counter += 1;
+        self.cipher.encrypt(Nonce::from_slice(&nonce), chunk)
+            .map(Bytes::from)
+            .map_err(|_| std::io::Error::other("Encryption failure"))
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, std::io::Error>> {
+        if !self.header_sent {
+            self.header_sent = true;
+            return Some(Ok(Bytes::from([MAGIC.as_slice(), self.prefix.as_slice()].concat())));
+        }
+        loop {
+            if self.done {
+                return None;
+            }
+            // same rule as decrypting, a full chunk is only the last one once
+            // the input has ended behind it
+            if self.buf.len() > CHUNK_SIZE {
+                let chunk = self.buf.split_to(CHUNK_SIZE);
+                let sealed = self.seal(&chunk, false);
+                self.done = sealed.is_err();
+                return Some(sealed);
+            }
+            match self.inner.next().await {
+                Some(Ok(bytes)) => self.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+                None => {
+                    self.done = true;
+                    // empty files still get one (empty) final chunk
+                    let chunk = self.buf.split();
+                    return Some(self.seal(&chunk, true));
+                },
+            }
+        }
+    }
+}
+
+// encrypts as the plaintext arrives, holding at most a chunk or so of it
+pub fn encrypt_stream(dek: &[u8; 32], inner: ObjectStream) -> ObjectStream {
+    let mut prefix = [0u8; PREFIX_SIZE];
+    OsRng.fill_bytes(&mut prefix);
+    let state = EncryptState {
+        inner,
+        buf: BytesMut::new(),
+        cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(dek)),
+        prefix,
+        counter: 0,
+        header_sent: false,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        state.next_chunk().await.map(|chunk| (chunk, state))
+    }).boxed()
+}
+
+pub fn encrypted_size(plain: usize) -> usize {
+    let chunks = plain.div_ceil(CHUNK_SIZE).max(1);
+    HEADER_SIZE + plain + chunks * TAG_SIZE
+}
+
+pub fn plaintext_size(encrypted: i64) -> i64 {
+    let body = (encrypted - HEADER_SIZE as i64).max(0);
+    let chunks = ((body + CHUNK_CT_SIZE as i64 - 1) / CHUNK_CT_SIZE as i64).max(1);
+    (body - chunks * TAG_SIZE as i64).max(0)
+}
+
+struct DecryptState {
+    inner: ObjectStream,
+    buf: BytesMut,
+    cipher: Aes256Gcm,
+    prefix: Option<[u8; PREFIX_SIZE]>,
+    counter: u32,
+    done: bool,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl DecryptState {
+    fn open(&mut self, sealed: &[u8], last: bool) -> Result<Bytes, std::io::Error> {
+        let prefix = self.prefix.ok_or(invalid("Missing header"))?;
+        let nonce = chunk_nonce(&prefix, self.counter, last);
+        self.counter += 1;
+        self.cipher.decrypt(Nonce::from_slice(&nonce), sealed)
+            .map(Bytes::from)
+            .map_err(|_| invalid("Object failed authentication"))
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, std::io::Error>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if self.prefix.is_none() && self.buf.len() >= HEADER_SIZE {
+                let header = self.buf.split_to(HEADER_SIZE);
+                if &header[..MAGIC.len()] != MAGIC {
+                    self.done = true;
+                    return Some(Err(invalid("Not an encrypted object")));
+                }
+                let mut prefix = [0u8; PREFIX_SIZE];
+                prefix.copy_from_slice(&header[MAGIC.len()..]);
+                self.prefix = Some(prefix);
+            }
+            // a full chunk with more bytes behind it cant be the last one
+            if self.prefix.is_some() && self.buf.len() > CHUNK_CT_SIZE {
+                let sealed = self.buf.split_to(CHUNK_CT_SIZE);
+                let opened = self.open(&sealed, false);
+                self.done = opened.is_err();
+                return Some(opened);
+            }
+            match self.inner.next().await {
+                Some(Ok(bytes)) => self.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+                None => {
+                    self.done = true;
+                    let sealed = self.buf.split();
+                    return Some(self.open(&sealed, true));
+                },
+            }
+        }
+    }
+}
+
+pub fn decrypt_stream(dek: &[u8; 32], inner: ObjectStream) -> ObjectStream {
+    let state = DecryptState {
+        inner,
+        buf: BytesMut::new(),
+        cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(dek)),
+        prefix: None,
+        counter: 0,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        state.next_chunk().await.map(|chunk| (chunk, state))
+    }).boxed()
+}
+
+// wraps a stored object so callers read plaintext, key_id None means it was
+// written before encryption was turned on
+pub async fn decrypting(pool: &PgPool,
+                        encryption: Option<&Encryption>,
+                        key_id: Option<Uuid>,
+                        mut body: ObjectBody,
+) -> Result<ObjectBody, ServerError> {
+    let key_id = match key_id {
+        Some(id) => id,
+        None => return Ok(body),
+    };
+    let encryption = encryption
+        .ok_or(ServerError::InternalError("File is encrypted but no master key is configured".to_string()))?;
+    let dek = data_key(pool, encryption, &key_id).await?;
+    body.meta.size = plaintext_size(body.meta.size);
+    body.stream = decrypt_stream(&dek, body.stream);
+    Ok(body)
+}
+
+pub async fn data_key(pool: &PgPool,
+                      encryption: &Encryption,
+                      key_id: &Uuid,
+) -> Result<[u8; 32], ServerError> {
+    let (wrapped, master_key_id): (Vec<u8>, String) =
+        sqlx::query_as(r#"SELECT wrapped_key, master_key_id FROM user_keys
+                          WHERE key_id = ($1);"#)
+        .bind(key_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(format!("Failed to get data key. Error: {}", e)))?;
+    encryption.unwrap(&master_key_id, &wrapped)
+}
+
+// the key new uploads for this user are encrypted with, created on first use
+pub async fn active_data_key(pool: &PgPool,
+                             encryption: &Encryption,
+                             user_id: &Uuid,
+) -> Result<(Uuid, [u8; 32]), ServerError> {
+    let existing: Option<(Uuid, Vec<u8>, String)> =
+        sqlx::query_as(r#"SELECT key_id, wrapped_key, master_key_id FROM user_keys
+                          WHERE user_id = ($1) AND active;"#)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if let Some((key_id, wrapped, master_key_id)) = existing {
+        return Ok((key_id, encryption.unwrap(&master_key_id, &wrapped)?));
+    }
+    let dek = generate_data_key();
+    let key_id = Uuid::new_v4();
+    // the partial unique index settles two first uploads racing each other
+    let inserted = sqlx::query(r#"INSERT INTO user_keys (key_id, user_id, wrapped_key,
+                                  master_key_id, active)
+                                  VALUES ($1,$2,$3,$4,TRUE)
+                                  ON CONFLICT DO NOTHING;"#)
+        .bind(key_id)
+        .bind(user_id)
+        .bind(encryption.wrap(&dek)?)
+        .bind(encryption.active_id())
+        .execute(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if inserted.rows_affected() == 0 {
+        return Box::pin(active_data_key(pool, encryption, user_id)).await;
+    }
+    Ok((key_id, dek))
+}
+
+// re-wraps every data key not under the active master key, returns how many
+pub async fn rewrap_data_keys(pool: &PgPool, encryption: &Encryption) -> Result<usize, ServerError> {
+    let keys: Vec<(Uuid, Vec<u8>, String)> =
+        sqlx::query_as(r#"SELECT key_id, wrapped_key, master_key_id FROM user_keys
+                          WHERE master_key_id != ($1);"#)
+        .bind(encryption.active_id())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let mut rewrapped = 0;
+    for (key_id, wrapped, master_key_id) in keys {
+        let dek = encryption.unwrap(&master_key_id, &wrapped)?;
+        sqlx::query(r#"UPDATE user_keys SET wrapped_key = ($1), master_key_id = ($2)
+                       WHERE key_id = ($3) AND master_key_id = ($4);"#)
+            .bind(encryption.wrap(&dek)?)
+            .bind(encryption.active_id())
+            .bind(key_id)
+            .bind(&master_key_id)
+            .execute(pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        rewrapped += 1;
+    }
+    Ok(rewrapped)
+}
+
+// retires every active data key, new uploads get a fresh one. old keys stay
+// around for the objects already encrypted with them
+pub async fn retire_data_keys(pool: &PgPool) -> Result<u64, ServerError> {
+    let result = sqlx::query(r#"UPDATE user_keys SET active = FALSE, retired_at = NOW()
+                                WHERE active;"#)
+        .execute(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,40 @@
+use axum::{extract::State, response::sse::{Event, KeepAlive, Sse}};
+use axum_extra::extract::cookie::CookieJar;
+use futures::stream::{self, Stream};
+use tokio::sync::broadcast::error::RecvError;
+
+use std::convert::Infallible;
+
+use crate::models::{AppState, ServerError};
+use crate::auth_methods::current_user;
+
+// server-sent events for changes to anything the user can see. the event name
+// is the kind (created, renamed, ...) and the data the json ChangeEvent.
+// a "resync" event means events were missed and get_files should be refetched
+pub async fn subscribe_events(State(state): State<AppState>,
+                              jar: CookieJar,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ServerError> {
+
+    let user_id = current_user(&state, jar).await?;
+    let receiver = state.events.subscribe();
+    let events = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(change) if change.visible_to(&user_id) => {
+                    let event = Event::default()
+                        .event(change.kind.as_str())
+                        .json_data(&*change)
+                        .unwrap_or_else(|_| Event::default().event("resync"));
+                    return Some((Ok(event), receiver));
+                },
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    let event = Event::default().event("resync").data(missed.to_string());
+                    return Some((Ok(event), receiver));
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use schemars::JsonSchema;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use std::sync::Arc;
+
+use crate::models::AppState;
+use crate::msc_actions::file_audience;
+
+// in process bus for file changes, handlers publish once their change is
+// committed and every open /events stream picks out what its user can see.
+// the bus itself stores nothing, changes.rs keeps the journal for /changes and
+// is written inside the change's transaction, before anything is published
+
+// events a slow subscriber can fall behind by before it is told to resync
+pub const BUS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    // new content, same file
+    Modified,
+    Renamed,
+    Moved,
+    Deleted,
+    Shared,
+    Unshared,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Renamed => "renamed",
+            ChangeKind::Moved => "moved",
+            ChangeKind::Deleted => "deleted",
+            ChangeKind::Shared => "shared",
+            ChangeKind::Unshared => "unshared",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    // new name for renames, none for vault items
+    pub file_name: Option<String>,
+    // none for anonymous uploads through file requests
+    pub actor_id: Option<Uuid>,
+    pub at: DateTime<Utc>,
+    // who gets it, owner plus everyone it is shared with through any folder above
+    #[serde(skip)]
+    pub audience: Vec<Uuid>,
+}
+
+impl ChangeEvent {
+    pub fn new(kind: ChangeKind,
+               actor_id: Option<Uuid>,
+               file_id: Uuid,
+               owner_id: Uuid,
+               parent_id: Option<Uuid>,
+               file_name: Option<String>,
+    ) -> Self {
+        ChangeEvent { kind, file_id, owner_id, parent_id, file_name, actor_id,
+                      at: Utc::now(), audience: Vec::new() }
+    }
+
+    pub fn visible_to(&self, user_id: &Uuid) -> bool {
+        self.audience.contains(user_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ChangeEvent>> {
+        self.sender.subscribe()
+    }
+
+    // no subscribers is not an error
+    pub fn publish(&self, event: ChangeEvent) {
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+// looks up who can see the file now and publishes to them, on top of anyone
+// already in the audience (e.g. someone just unshared). deletes take their
+// audience before the row is gone and go through emit
+pub async fn announce(state: &AppState, mut event: ChangeEvent) {
+    let audience = match file_audience(&state.pool, &event.file_id).await {
+        Ok(audience) => audience,
+        Err(e) => {
+            eprintln!("Failed to announce {} of {}. Error {:?}", event.kind.as_str(), event.file_id, e);
+            return;
+        },
+    };
+    for user_id in audience {
+        if !event.audience.contains(&user_id) {
+            event.audience.push(user_id);
+        }
+    }
+    state.events.publish(event);
+}
+
+// for events whose audience is already complete
+pub async fn emit(state: &AppState, event: ChangeEvent) {
+    state.events.publish(event);
+}
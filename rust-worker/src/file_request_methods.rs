@@ -0,0 +1,366 @@
+use axum::{extract, extract::State, Json, http::StatusCode, http::HeaderMap};
+use axum_extra::extract::Multipart;
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+use chrono::Utc;
+use bytes::Bytes;
+use std::path::Path;
+
+use crate::models::{AppState,
+                    CreateFileRequestForm,
+                    RevokeFileRequestForm,
+                    FileRequestResponse,
+                    PublicFileRequest,
+                    NotificationResponse,
+                    FileType,
+                    ServerError};
+use crate::auth_methods::get_current_user;
+use crate::methods::{NewFile, store_file, STORAGE_LIMIT};
+use crate::events::{ChangeEvent, ChangeKind, announce};
+use crate::integrity::{Checksums, ExpectedChecksums, read_file_field_limited};
+use crate::msc_actions::{generate_token, notify_user};
+use crate::mime::detect_mime;
+
+// what the /r/{token} route accepts before the handler sees it. nothing bigger
+// fits the quota, the rest is room for the text fields and multipart framing
+pub const FILE_REQUEST_BODY_LIMIT: usize = STORAGE_LIMIT as usize + 64 * 1024;
+
+fn type_allowed(allowed_types: &[String], filename: &str, content_type: &str) -> bool {
+    if allowed_types.is_empty() {
+        return true;
+    }
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    allowed_types.iter().any(|t| {
+        let t = t.trim().trim_start_matches('.').to_lowercase();
+        if t.ends_with('/') {
+            content_type.starts_with(&t)
+        } else {
+            t == extension || t == content_type
+        }
+    })
+}
+
+pub async fn create_file_request(State(state): State<AppState>,
+                                 jar: CookieJar,
+                                 payload: Json<CreateFileRequestForm>,
+) -> Result<Json<FileRequestResponse>, ServerError> {
+
+    println!("CreateFileRequest ran");
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let folder_id = Uuid::parse_str(&payload.folder_id)
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+    let title = payload.title.trim();
+    if title.is_empty() {
+        return Err(ServerError::InternalError("Invalid title".to_string()));
+    }
+    if let Some(deadline) = payload.deadline && deadline <= Utc::now() {
+        return Err(ServerError::InternalError("Deadline is in the past".to_string()));
+    }
+    if let Some(max) = payload.max_file_size && max <= 0 {
+        return Err(ServerError::InternalError("Invalid size limit".to_string()));
+    }
+
+    let folder: Option<(FileType,)> = sqlx::query_as(r#"SELECT file_type FROM files
+                                                        WHERE file_id = ($1) AND owner_id = ($2);"#)
+        .bind(folder_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    match folder {
+        Some((FileType::Folder,)) => {},
+        Some(_) => return Err(ServerError::InternalError("Target is not a folder".to_string())),
+        None => return Err(ServerError::NotFound("Folder not found".to_string())),
+    }
+
+    let allowed_types: Vec<String> = payload.allowed_types.clone().unwrap_or_default()
+        .into_iter()
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let request = sqlx::query_as::<_, FileRequestResponse>(r#"INSERT INTO file_requests
+                       (request_id, token, owner_id, folder_id, title, max_file_size,
+                       allowed_types, deadline)
+                       VALUES ($1,$2,$3,$4,$5,$6,$7,$8)
+                       RETURNING request_id, token, folder_id, title, max_file_size,
+                       allowed_types, deadline, revoked, upload_count, created_at;"#)
+        .bind(Uuid::new_v4())
+        .bind(generate_token())
+        .bind(owner_id)
+        .bind(folder_id)
+        .bind(title)
+        .bind(payload.max_file_size)
+        .bind(&allowed_types)
+        .bind(payload.deadline)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| {  eprintln!("Error {:?}", e);
+                        ServerError::DatabaseError(e.to_string())})?;
+
+    Ok(Json(request))
+}
+
+pub async fn get_file_requests(State(state): State<AppState>,
+                               jar: CookieJar,
+) -> Result<Json<Vec<FileRequestResponse>>, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let requests = sqlx::query_as::<_, FileRequestResponse>(r#"SELECT request_id, token,
+                       folder_id, title, max_file_size, allowed_types, deadline, revoked,
+                       upload_count, created_at
+                       FROM file_requests WHERE owner_id = ($1)
+                       ORDER BY created_at DESC;"#)
+        .bind(owner_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    Ok(Json(requests))
+}
+
+pub async fn revoke_file_request(State(state): State<AppState>,
+                                 jar: CookieJar,
+                                 payload: Json<RevokeFileRequestForm>,
+) -> Result<Json<String>, ServerError> {
+
+    println!("RevokeFileRequest ran");
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let request_id = Uuid::parse_str(&payload.request_id)
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+
+    let result = sqlx::query(r#"UPDATE file_requests SET revoked = TRUE
+                                WHERE request_id = ($1) AND owner_id = ($2);"#)
+        .bind(request_id)
+        .bind(owner_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("File request not found".to_string()));
+    }
+
+    Ok(Json("File Request Revoked".to_string()))
+}
+
+// unauthenticated, shows the restrictions but nothing from the folder
+pub async fn get_public_file_request(State(state): State<AppState>,
+                                     extract::Path(token): extract::Path<String>,
+) -> Result<Json<PublicFileRequest>, ServerError> {
+
+    let request = sqlx::query_as::<_, PublicFileRequest>(r#"SELECT title, max_file_size,
+                       allowed_types, deadline FROM file_requests
+                       WHERE token = ($1) AND NOT revoked
+                       AND (deadline IS NULL OR deadline > NOW());"#)
+        .bind(&token)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    match request {
+        Some(r) => Ok(Json(r)),
+        None => Err(ServerError::NotFound("File request not found".to_string())),
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct OpenRequest {
+    request_id: Uuid,
+    owner_id: Uuid,
+    folder_id: Uuid,
+    title: String,
+    max_file_size: Option<i64>,
+    allowed_types: Vec<String>,
+}
+
+// unauthenticated upload, charged to the folder owner
+pub async fn upload_to_file_request(State(state): State<AppState>,
+                                    extract::Path(token): extract::Path<String>,
+                                    headers: HeaderMap,
+                                    mut payload: Multipart,
+) -> Result<StatusCode, ServerError> {
+
+    println!("UploadToFileRequest ran");
+    let request: Option<OpenRequest> =
+        sqlx::query_as(r#"SELECT request_id, owner_id, folder_id, title, max_file_size,
+                          allowed_types FROM file_requests
+                          WHERE token = ($1) AND NOT revoked
+                          AND (deadline IS NULL OR deadline > NOW());"#)
+        .bind(&token)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let OpenRequest { request_id, owner_id, folder_id, title, max_file_size, allowed_types } = match request {
+        Some(r) => r,
+        None => return Err(ServerError::NotFound("File request not found".to_string())),
+    };
+
+    // known before any of the body is read, so an oversized file is cut off
+    // early rather than buffered. with DEDUP_SAVES_QUOTA a duplicate can be
+    // free, so only the total is certain there
+    let storage_used: i64 = sqlx::query_scalar(r#"SELECT storage_used FROM users WHERE user_id = ($1);"#)
+        .bind(owner_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let quota_left = match state.dedup.saves_quota {
+        true => STORAGE_LIMIT,
+        false => STORAGE_LIMIT - storage_used,
+    };
+    let limit = max_file_size.map_or(quota_left, |max| max.min(quota_left));
+
+    let expected = ExpectedChecksums::from_headers(&headers)?;
+    let mut upload: Option<(Bytes, Checksums)> = None;
+    let mut filename = String::new();
+    let mut content_type = String::new();
+    let mut uploader_name: Option<String> = None;
+    let mut uploader_email: Option<String> = None;
+
+    while let Some(field) = payload.next_field().await? {
+        match field.name() {
+        Some("file") => {
+            filename = field.file_name().unwrap_or("unknown").to_string();
+            content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
+            upload = match read_file_field_limited(field, limit).await? {
+                Some(u) => Some(u),
+                None if max_file_size.is_some_and(|max| max <= quota_left) => {
+                    return Err(ServerError::Forbidden("File is too large".to_string()));
+                },
+                None => return Err(ServerError::QuotaExceeded),
+            };
+        },
+        Some("name") => {
+            uploader_name = Some(field.text().await?.trim().to_string())
+                .filter(|n| !n.is_empty());
+        },
+        Some("email") => {
+            uploader_email = Some(field.text().await?.trim().to_string())
+                .filter(|e| !e.is_empty());
+        },
+        _ => {}
+        }
+    };
+
+    let (data, checksums) = match upload {
+        Some(u) => u,
+        None => return Err(ServerError::BadRequest("No file provided".to_string())),
+    };
+    expected.verify(&checksums)?;
+    // checked against what the file really is, the header is just the browser's guess
+    let extension = Path::new(&filename).extension().and_then(|s| s.to_str()).unwrap_or("");
+    if !type_allowed(&allowed_types, &filename, &detect_mime(&data, extension)) {
+        return Err(ServerError::Forbidden("File type not allowed".to_string()));
+    }
+
+    let uploaded = store_file(&state, NewFile {
+        owner_id,
+        parent_id: Some(folder_id),
+        filename: &filename,
+        content_type: &content_type,
+        data,
+        checksums: &checksums,
+        strip_gps: state.metadata.strip_gps,
+    }, None).await?;
+
+    sqlx::query(r#"INSERT INTO file_request_uploads (upload_id, request_id, file_id,
+                   uploader_name, uploader_email)
+                   VALUES ($1,$2,$3,$4,$5);"#)
+        .bind(Uuid::new_v4())
+        .bind(request_id)
+        .bind(uploaded.file_id)
+        .bind(&uploader_name)
+        .bind(&uploader_email)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    sqlx::query(r#"UPDATE file_requests SET upload_count = upload_count + 1
+                   WHERE request_id = ($1);"#)
+        .bind(request_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let from = match (&uploader_name, &uploader_email) {
+        (Some(n), Some(e)) => format!("{} <{}>", n, e),
+        (Some(n), None) => n.clone(),
+        (None, Some(e)) => e.clone(),
+        (None, None) => "Someone".to_string(),
+    };
+    let message = format!("{} uploaded {} to \"{}\"", from, filename, title);
+    // upload already went through, a failed notification shouldnt undo it
+    if let Err(e) = notify_user(&state.pool, &owner_id, "file_request_upload",
+                                &message, Some(uploaded.file_id)).await {
+        eprintln!("Error {:?}", e);
+    }
+    announce(&state, ChangeEvent::new(ChangeKind::Created, None, uploaded.file_id, owner_id,
+                                     Some(folder_id), Some(filename.clone()))).await;
+
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn get_notifications(State(state): State<AppState>,
+                               jar: CookieJar,
+) -> Result<Json<Vec<NotificationResponse>>, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let notifications = sqlx::query_as::<_, NotificationResponse>(r#"SELECT notification_id,
+                       kind, message, file_id, read, created_at
+                       FROM notifications WHERE user_id = ($1)
+                       ORDER BY created_at DESC LIMIT 100;"#)
+        .bind(user_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    Ok(Json(notifications))
+}
+
+pub async fn read_notifications(State(state): State<AppState>,
+                                jar: CookieJar,
+) -> Result<StatusCode, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    sqlx::query(r#"UPDATE notifications SET read = TRUE
+                   WHERE user_id = ($1) AND NOT read;"#)
+        .bind(user_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,211 @@
+use axum::http::HeaderMap;
+use axum_extra::extract::multipart::Field;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use md5::Md5;
+use md5::Digest as _;
+use sha2::{Sha256, Digest};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, StorageLayout};
+use crate::dedup::DedupConfig;
+use crate::msc_actions::notify_user;
+use crate::encryption::{Encryption, decrypting};
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksums {
+    pub sha256: String,
+    pub md5: String,
+}
+
+// what the client says the upload hashes to, both as lowercase hex
+#[derive(Debug, Default)]
+pub struct ExpectedChecksums {
+    pub sha256: Option<String>,
+    pub md5: Option<String>,
+}
+
+impl ExpectedChecksums {
+    // Content-MD5 is base64 (rfc 1864), X-Content-SHA256 is hex and
+    // x-amz-checksum-sha256 base64 like s3 clients send it
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ServerError> {
+        let header = |name: &str| headers.get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let from_base64 = |v: String| STANDARD.decode(v)
+            .map(|b| to_hex(&b))
+            .map_err(|_| ServerError::BadRequest("Invalid checksum header".to_string()));
+
+        let md5 = header("content-md5").map(from_base64).transpose()?;
+        let sha256 = match header("x-content-sha256") {
+            Some(v) => Some(v.to_lowercase()),
+            None => header("x-amz-checksum-sha256").map(from_base64).transpose()?,
+        };
+        Ok(ExpectedChecksums { sha256, md5 })
+    }
+
+    pub fn verify(&self, actual: &Checksums) -> Result<(), ServerError> {
+        if let Some(sha256) = &self.sha256 && *sha256 != actual.sha256 {
+            return Err(ServerError::BadRequest("SHA-256 checksum mismatch".to_string()));
+        }
+        if let Some(md5) = &self.md5 && *md5 != actual.md5 {
+            return Err(ServerError::BadRequest("MD5 checksum mismatch".to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct UploadHasher {
+    sha256: Sha256,
+    md5: Md5,
+}
+
+impl UploadHasher {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.sha256.update(chunk);
+        self.md5.update(chunk);
+    }
+
+    pub fn finish(self) -> Checksums {
+        Checksums {
+            sha256: to_hex(&self.sha256.finalize()),
+            md5: to_hex(&self.md5.finalize()),
+        }
+    }
+}
+
+// reads a multipart file field chunk by chunk, hashing as it goes
+pub async fn read_file_field(mut field: Field) -> Result<(Bytes, Checksums), ServerError> {
+    let mut hasher = UploadHasher::default();
+    let mut data = BytesMut::new();
+    while let Some(chunk) = field.chunk().await? {
+        hasher.update(&chunk);
+        data.extend_from_slice(&chunk);
+    }
+    Ok((data.freeze(), hasher.finish()))
+}
+
+// the same, but gives up with None as soon as the field passes `limit` bytes
+// instead of holding whatever an anonymous sender pushes
+pub async fn read_file_field_limited(mut field: Field, limit: i64) -> Result<Option<(Bytes, Checksums)>, ServerError> {
+    let mut hasher = UploadHasher::default();
+    let mut data = BytesMut::new();
+    while let Some(chunk) = field.chunk().await? {
+        if (data.len() + chunk.len()) as i64 > limit {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some((data.freeze(), hasher.finish())))
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub corrupted: usize,
+    pub missing: usize,
+}
+
+#[derive(sqlx::FromRow)]
+struct ScrubbedFile {
+    file_id: Uuid,
+    owner_id: Uuid,
+    file_name: String,
+    extension: Option<String>,
+    blob_hash: Option<String>,
+    key_id: Option<Uuid>,
+    checksum_sha256: String,
+    corrupted: bool,
+}
+
+// re-reads up to `limit` objects, least recently verified first, and flags any
+// whose content no longer matches the digest on the row
+pub async fn scrub(pool: &PgPool,
+                   store: &Arc<dyn ObjectStore>,
+                   layout: &StorageLayout,
+                   dedup: &DedupConfig,
+                   encryption: Option<&Encryption>,
+                   limit: i64,
+) -> Result<ScrubReport, ServerError> {
+    let files: Vec<ScrubbedFile> =
+        sqlx::query_as(r#"SELECT file_id, owner_id, file_name, extension, blob_hash, key_id,
+                          checksum_sha256, corrupted
+                          FROM files
+                          WHERE checksum_sha256 IS NOT NULL AND file_type != 'folder'
+                          ORDER BY verified_at ASC NULLS FIRST
+                          LIMIT ($1);"#)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut report = ScrubReport::default();
+    for ScrubbedFile { file_id, owner_id, file_name, extension, blob_hash, key_id, checksum_sha256: expected,
+                       corrupted: was_corrupted } in files {
+        let (bucket, key) = match &blob_hash {
+            Some(hash) => dedup.blob_location(hash),
+            None => layout.location(&owner_id.to_string(), &file_id.to_string(), &extension),
+        };
+        report.checked += 1;
+        let object = match store.get_stream(&bucket, &key).await {
+            Ok(o) => decrypting(pool, encryption, key_id, o).await,
+            Err(e) => Err(e),
+        };
+        let actual = match object {
+            Ok(mut object) => {
+                let mut hasher = Sha256::new();
+                let mut read_failed = false;
+                while let Some(chunk) = object.stream.next().await {
+                    match chunk {
+                        Ok(c) => hasher.update(&c),
+                        Err(e) => {
+                            eprintln!("Error {:?}", e);
+                            read_failed = true;
+                            break;
+                        },
+                    }
+                }
+                if read_failed { None } else { Some(to_hex(&hasher.finalize())) }
+            },
+            Err(ServerError::NotFound(_)) => {
+                report.missing += 1;
+                None
+            },
+            Err(e) => return Err(e),
+        };
+        let corrupted = actual.as_deref() != Some(expected.as_str());
+
+        sqlx::query(r#"UPDATE files SET verified_at = NOW(), corrupted = ($1)
+                       WHERE file_id = ($2);"#)
+            .bind(corrupted)
+            .bind(file_id)
+            .execute(pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+        if corrupted {
+            report.corrupted += 1;
+            // only tell the owner the first time
+            if !was_corrupted {
+                let message = format!("{} failed an integrity check and may be damaged", file_name);
+                if let Err(e) = notify_user(pool, &owner_id, "integrity_failure",
+                                            &message, Some(file_id)).await {
+                    eprintln!("Error {:?}", e);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
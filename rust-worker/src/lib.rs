@@ -3,3 +3,35 @@ pub mod methods;
 pub mod auth_methods;
 pub mod setup;
 pub mod msc_actions;
+pub mod crypto;
+pub mod share_methods;
+pub mod file_request_methods;
+pub mod storage;
+pub mod dedup;
+pub mod integrity;
+pub mod encryption;
+pub mod vault;
+pub mod thumbnails;
+pub mod mime;
+pub mod scanner;
+pub mod media_metadata;
+pub mod search;
+pub mod search_methods;
+pub mod tag_methods;
+pub mod comment_methods;
+pub mod admin_methods;
+pub mod audit;
+pub mod audit_methods;
+pub mod events;
+pub mod event_methods;
+pub mod changes;
+pub mod change_methods;
+pub mod webhooks;
+pub mod webhook_methods;
+pub mod webdav;
+pub mod webdav_methods;
+pub mod s3_gateway;
+pub mod s3_gateway_methods;
+pub mod sftp;
+pub mod sftp_methods;
+pub mod openapi;
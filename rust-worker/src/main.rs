@@ -2,6 +2,7 @@ use rust_worker::setup::{setup};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::env;
+use std::net::SocketAddr;
 use dotenv;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -20,12 +21,20 @@ async fn main() -> Result<(), aws_sdk_s3::Error> {
     .connect(&database_url)
     .await
     .expect("Failed to create pool");
-    let app = setup(pool).await.unwrap();
+    let app = match setup(pool).await {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        },
+    };
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
         .await
         .unwrap();
     
-    if let Err(e) = axum::serve(listener, app).await {
+    // peer addresses end up in the audit log when there is no proxy header
+    if let Err(e) = axum::serve(listener,
+                                app.into_make_service_with_connect_info::<SocketAddr>()).await {
         eprintln!("Error: {:?}", e);
     }
     Ok(())
@@ -0,0 +1,285 @@
+use bytes::Bytes;
+use exif::{In, Tag, Value};
+use lofty::prelude::*;
+use lofty::probe::Probe;
+use serde_json::{Map, Value as Json};
+use std::env;
+use std::io::Cursor;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::time::Duration;
+
+// what photo, music and video libraries need beyond name and size, stored as
+// media_metadata jsonb on the files row. everything is optional, missing or
+// unparsable fields are just left out
+//   images: width, height, orientation, taken_at, camera_make, camera_model, gps {lat, lon}
+//   audio:  duration_secs, bitrate, sample_rate, title, artist, album, genre
+//   video:  duration_secs, width, height, video_codec, audio_codec
+#[derive(Debug, Clone)]
+pub struct MetadataConfig {
+    // STRIP_GPS=true removes gps from stored jpegs unless the upload says otherwise
+    pub strip_gps: bool,
+}
+
+impl MetadataConfig {
+    pub fn from_env() -> Self {
+        MetadataConfig { strip_gps: env::var("STRIP_GPS").map(|v| v == "true").unwrap_or(false) }
+    }
+}
+
+fn exif_ascii(exif: &exif::Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first()
+            .map(|v| String::from_utf8_lossy(v).trim().trim_end_matches('\0').to_string())
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    }
+}
+
+// degrees/minutes/seconds with an N/S/E/W reference
+fn exif_coordinate(exif: &exif::Exif, tag: Tag, reference: Tag) -> Option<f64> {
+    let parts = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() >= 3 => [v[0].to_f64(), v[1].to_f64(), v[2].to_f64()],
+        _ => return None,
+    };
+    let value = parts[0] + parts[1] / 60.0 + parts[2] / 3600.0;
+    if !value.is_finite() {
+        return None;
+    }
+    match exif_ascii(exif, reference).as_deref() {
+        Some("S") | Some("W") => Some(-value),
+        _ => Some(value),
+    }
+}
+
+fn image_metadata(data: &[u8]) -> Map<String, Json> {
+    let mut meta = Map::new();
+    if let Ok(reader) = image::ImageReader::new(Cursor::new(data)).with_guessed_format()
+        && let Ok((width, height)) = reader.into_dimensions() {
+        meta.insert("width".to_string(), width.into());
+        meta.insert("height".to_string(), height.into());
+    }
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(e) => e,
+        Err(_) => return meta,
+    };
+    if let Some(orientation) = exif.get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0)) {
+        meta.insert("orientation".to_string(), orientation.into());
+    }
+    // exif dates have no zone, kept as local time in iso form so they sort
+    if let Some(taken) = exif_ascii(&exif, Tag::DateTimeOriginal)
+        .or_else(|| exif_ascii(&exif, Tag::DateTime))
+        .and_then(|d| chrono::NaiveDateTime::parse_from_str(&d, "%Y:%m:%d %H:%M:%S").ok()) {
+        meta.insert("taken_at".to_string(), taken.format("%Y-%m-%dT%H:%M:%S").to_string().into());
+    }
+    if let Some(make) = exif_ascii(&exif, Tag::Make) {
+        meta.insert("camera_make".to_string(), make.into());
+    }
+    if let Some(model) = exif_ascii(&exif, Tag::Model) {
+        meta.insert("camera_model".to_string(), model.into());
+    }
+    if let (Some(lat), Some(lon)) = (exif_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef),
+                                     exif_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef)) {
+        meta.insert("gps".to_string(), serde_json::json!({"lat": lat, "lon": lon}));
+    }
+    meta
+}
+
+fn audio_metadata(data: &[u8]) -> Map<String, Json> {
+    let mut meta = Map::new();
+    let tagged = match Probe::new(Cursor::new(data)).guess_file_type()
+        .ok()
+        .and_then(|p| p.read().ok()) {
+        Some(t) => t,
+        None => return meta,
+    };
+    let properties = tagged.properties();
+    meta.insert("duration_secs".to_string(), properties.duration().as_secs_f64().into());
+    if let Some(bitrate) = properties.audio_bitrate() {
+        meta.insert("bitrate".to_string(), bitrate.into());
+    }
+    if let Some(rate) = properties.sample_rate() {
+        meta.insert("sample_rate".to_string(), rate.into());
+    }
+    if let Some(tag) = tagged.primary_tag().or_else(|| tagged.first_tag()) {
+        let fields = [("title", tag.title()), ("artist", tag.artist()),
+                      ("album", tag.album()), ("genre", tag.genre())];
+        for (name, value) in fields {
+            if let Some(v) = value {
+                meta.insert(name.to_string(), v.to_string().into());
+            }
+        }
+    }
+    meta
+}
+
+// mp4 and quicktime, other containers only get their kind
+fn video_metadata(data: &[u8]) -> Map<String, Json> {
+    let mut meta = Map::new();
+    let reader = match mp4::Mp4Reader::read_header(Cursor::new(data), data.len() as u64) {
+        Ok(r) => r,
+        Err(_) => return meta,
+    };
+    meta.insert("duration_secs".to_string(), reader.duration().as_secs_f64().into());
+    for track in reader.tracks().values() {
+        match track.track_type() {
+            Ok(mp4::TrackType::Video) if !meta.contains_key("video_codec") => {
+                meta.insert("width".to_string(), track.width().into());
+                meta.insert("height".to_string(), track.height().into());
+                if let Ok(codec) = track.media_type() {
+                    meta.insert("video_codec".to_string(), codec.to_string().into());
+                }
+            },
+            Ok(mp4::TrackType::Audio) if !meta.contains_key("audio_codec") => {
+                if let Ok(codec) = track.media_type() {
+                    meta.insert("audio_codec".to_string(), codec.to_string().into());
+                }
+            },
+            _ => {},
+        }
+    }
+    meta
+}
+
+// None for anything that isnt media
+pub fn extract_metadata(data: &[u8], mime_type: &str) -> Option<Json> {
+    let (kind, mut meta) = match mime_type {
+        "image/svg+xml" => return None,
+        m if m.starts_with("image/") => ("image", image_metadata(data)),
+        m if m.starts_with("audio/") => ("audio", audio_metadata(data)),
+        m if m.starts_with("video/") => ("video", video_metadata(data)),
+        _ => return None,
+    };
+    meta.insert("kind".to_string(), kind.into());
+    Some(Json::Object(meta))
+}
+
+// the parsers run on untrusted uploads, so off the runtime, with panics caught
+// and a time limit. a file they choke on just gets no metadata
+pub async fn extract_metadata_blocking(data: Bytes, mime_type: String) -> Option<Json> {
+    let parsed = tokio::task::spawn_blocking(move || {
+        catch_unwind(AssertUnwindSafe(|| extract_metadata(&data, &mime_type)))
+    });
+    match tokio::time::timeout(Duration::from_secs(10), parsed).await {
+        Ok(Ok(Ok(meta))) => meta,
+        Ok(Ok(Err(_))) | Ok(Err(_)) => {
+            eprintln!("Metadata parser panicked");
+            None
+        },
+        Err(_) => {
+            eprintln!("Metadata parser timed out");
+            None
+        },
+    }
+}
+
+// for ILIKE '%...%' filters, so % and _ in the search match themselves
+pub fn contains_pattern(input: &str) -> String {
+    let mut pattern = String::with_capacity(input.len() + 2);
+    pattern.push('%');
+    for c in input.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+fn read_u16(data: &[u8], at: usize, le: bool) -> Option<u16> {
+    let b = data.get(at..at + 2)?;
+    Some(if le { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) })
+}
+
+fn read_u32(data: &[u8], at: usize, le: bool) -> Option<u32> {
+    let b = data.get(at..at + 4)?;
+    let b = [b[0], b[1], b[2], b[3]];
+    Some(if le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+}
+
+// bytes per component for each tiff type
+fn type_size(kind: u16) -> usize {
+    match kind {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+// empties the gps directory of a tiff block in place: values stored outside
+// the directory are zeroed and the entry count set to 0, so the offsets the
+// rest of the exif data relies on stay valid
+fn strip_tiff_gps(tiff: &mut [u8]) -> Option<bool> {
+    let le = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let ifd0 = read_u32(tiff, 4, le)? as usize;
+    let count = read_u16(tiff, ifd0, le)? as usize;
+    let mut gps_ifd = None;
+    for i in 0..count {
+        let entry = ifd0 + 2 + i * 12;
+        // GPSInfo
+        if read_u16(tiff, entry, le)? == 0x8825 {
+            gps_ifd = Some(read_u32(tiff, entry + 8, le)? as usize);
+        }
+    }
+    let gps_ifd = match gps_ifd {
+        Some(offset) => offset,
+        None => return Some(false),
+    };
+    // the pointer stays, an ifd emptied before has nothing left to strip
+    let gps_count = read_u16(tiff, gps_ifd, le)? as usize;
+    if gps_count == 0 {
+        return Some(false);
+    }
+    for i in 0..gps_count {
+        let entry = gps_ifd + 2 + i * 12;
+        let kind = read_u16(tiff, entry + 2, le)?;
+        let components = read_u32(tiff, entry + 4, le)? as usize;
+        let size = type_size(kind).saturating_mul(components);
+        if size > 4 {
+            let offset = read_u32(tiff, entry + 8, le)? as usize;
+            if let Some(value) = tiff.get_mut(offset..offset.saturating_add(size)) {
+                value.fill(0);
+            }
+        }
+        tiff.get_mut(entry..entry + 12)?.fill(0);
+    }
+    tiff.get_mut(gps_ifd..gps_ifd + 2)?.fill(0);
+    Some(true)
+}
+
+// jpeg only, other formats are stored as uploaded. returns None when there
+// was nothing to strip
+pub fn strip_gps(data: &Bytes) -> Option<Bytes> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut out = data.to_vec();
+    let mut pos = 2;
+    let mut stripped = false;
+    // walk the segments up to the image data
+    while pos + 4 <= out.len() && out[pos] == 0xff {
+        let marker = out[pos + 1];
+        if marker == 0xda || marker == 0xd9 {
+            break;
+        }
+        let len = u16::from_be_bytes([out[pos + 2], out[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > out.len() {
+            break;
+        }
+        // APP1 holding exif
+        if marker == 0xe1 && out[pos + 4..end].starts_with(b"Exif\0\0")
+            && let Some(true) = strip_tiff_gps(&mut out[pos + 10..end]) {
+            stripped = true;
+        }
+        pos = end;
+    }
+    if stripped { Some(Bytes::from(out)) } else { None }
+}
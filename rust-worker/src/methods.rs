@@ -1,8 +1,7 @@
-use axum::{extract, extract::State, Json, http::StatusCode};
+use axum::{extract, extract::State, Json, http::StatusCode, http::header, http::HeaderMap};
+use axum::body::Body;
+use axum::response::Response;
 use axum_extra::extract::Multipart;
-use aws_sdk_s3 as s3;
-use aws_sdk_s3::error::ProvideErrorMetadata;
-use aws_sdk_s3::presigning::PresigningConfig;
 use sqlx::Acquire;
 use axum_extra::extract::cookie::{Cookie, CookieJar};
 use jsonwebtoken::{encode, decode, Header, Algorithm, EncodingKey,
@@ -13,9 +12,8 @@ use chrono::{DateTime, Utc};
 use bytes::Bytes;
 use std::time::Duration;
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use failure;
 use serde_json::Value;
 
 use crate::models::{DatabaseFile,
@@ -23,49 +21,123 @@ use crate::models::{DatabaseFile,
                     OwnerId, 
                     CreateFolderForm, 
                     FileType, 
+                    ScanStatus,
+                    ColourLabel,
                     DeleteFileForm,
                     RenameFileForm,
+                    MoveFileForm,
                     DownloadFileForm,
+                    SignedObjectQuery,
+                    ThumbnailQuery,
+                    MediaFilterForm,
+                    SetVaultKeysForm,
+                    VaultKeysResponse,
+                    GetPublicKeyForm,
+                    PublicKeyResponse,
+                    CreateVaultFolderForm,
+                    UpdateVaultMetadataForm,
+                    ShareVaultItemForm,
+                    UnshareVaultItemForm,
+                    VaultItemsForm,
+                    DownloadVaultFileForm,
+                    VaultItemResponse,
                     AppState,
                     ServerError};
 use crate::auth_methods::get_current_user;
+use crate::msc_actions::{create_bucket_func, notify_user, file_audience};
+use crate::dedup::{blob_hash, charged_size, reference_blob, release_blob};
+use crate::integrity::{Checksums, ExpectedChecksums, UploadHasher, read_file_field};
+use crate::storage::{ObjectBody, bytes_stream, object_signature, verify_object_signature};
+use crate::encryption::{active_data_key, data_key as data_key_for, encrypt_stream, decrypting};
+use crate::thumbnails::{ThumbnailSize, ThumbnailFormat, thumbnail_kind, queue_thumbnail,
+                        delete_thumbnails};
+use crate::mime::{detect_mime, file_type_for};
+use crate::scanner::{check_download, queue_scan};
+use crate::search::{text_kind, queue_indexing};
+use crate::audit::{RequestInfo, AuditEvent, record};
+use crate::events::{ChangeEvent, ChangeKind, announce, emit};
+use crate::changes::record_change;
+use crate::media_metadata::{extract_metadata_blocking, contains_pattern, strip_gps as strip_jpeg_gps};
+use crate::vault::{VaultItem, PUBLIC_KEY_SIZE, PARENT_WRAPPED_KEY_SIZE, USER_WRAPPED_KEY_SIZE,
+                   decode_field, encode_field, decode_wrapped_key, decode_metadata,
+                   parent_kind, is_vault_folder, can_read_vault_item};
 
-async fn check_bucket(client: &s3::Client, bucket_name: &str)->Result<bool, s3::Error>{
-    match client.head_bucket().bucket(bucket_name).send().await {
-        Ok(_) => Ok(true),
-        Err(e) => {
-            if let Some(code) = e.code() {
-                if code == "NotFound" || code == "NoSuchBucket" {
-                    return Ok(false);
-                }
-            }
-            eprintln!("Error {:?}", e);
-            Err(e.into())
-        }
+// presigned straight to storage, unless objects are encrypted and have to go
+// through /content to be decrypted on the way out
+async fn file_url(state: &AppState,
+                  owner_id: &str,
+                  file_id: &str,
+                  extension: &Option<String>,
+                  blob_hash: &Option<String>,
+)->Result<String, ServerError> {
+    let expires_in = Duration::from_secs(604800);  //7days
+    if state.encryption.is_some() {
+        let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+        let signature = object_signature(&state.key, "content", file_id, expires);
+        return Ok(format!("{}/content/{}?expires={}&signature={}",
+                          state.public_url.trim_end_matches('/'), file_id, expires, signature));
     }
+    let (bucket, key) = file_location(state, owner_id, file_id, extension, blob_hash);
+    state.store.presign(&bucket, &key, expires_in).await
 }
 
-async fn get_presigned_url(client: &s3::Client, bucket_name: &str, object_key: &str)->Result<String, failure::Error> {
-    let expires_in = Duration::from_secs(604800);  //7days
-    match client.get_object()
-                            .bucket(bucket_name)
-                            .key(object_key)
-                            .presigned(PresigningConfig::expires_in(expires_in)?)
-                            .await {
-                                    Ok(link) => Ok(link.uri().to_string()),
-                                    Err(e) => {
-                                                eprintln!("Error {:?}", e);
-                                                return Err(e.into())
-                                              }
-                                  }
-}
-
-fn s3_key(file_id: String, file_ext: &Option<String>)->String{
+// what open_file needs, for the jobs that only have a file id
+#[derive(sqlx::FromRow)]
+pub(crate) struct StoredContent {
+    pub owner_id: Uuid,
+    pub extension: Option<String>,
+    pub blob_hash: Option<String>,
+    pub key_id: Option<Uuid>,
+}
+
+// plaintext stream of a stored file whatever backend, layout or encryption it went through
+pub(crate) async fn open_file(state: &AppState,
+                              owner_id: &str,
+                              file_id: &str,
+                              extension: &Option<String>,
+                              blob_hash: &Option<String>,
+                              key_id: Option<Uuid>,
+)->Result<ObjectBody, ServerError> {
+    let (bucket, key) = file_location(state, owner_id, file_id, extension, blob_hash);
+    let body = state.store.get_stream(&bucket, &key).await?;
+    decrypting(&state.pool, state.encryption.as_deref(), key_id, body).await
+}
+
+// changes the owner's cached map in place, if there is one. the entry is never
+// just dropped, get_current_user takes a missing one for a lost session
+pub(crate) async fn update_cached_files<F>(state: &AppState, owner_id: &Uuid, update: F)
+where F: FnOnce(&mut HashMap<Uuid, FileResponse>) {
+    if let Some(c) = state.cache.get(owner_id).await {
+        let mut e = (*c).clone();
+        update(&mut e);
+        state.cache.insert(*owner_id, Arc::new(e)).await;
+    }
+}
+
+// blob backed files live wherever the dedup config says, the rest where the layout does
+pub(crate) fn file_location(state: &AppState,
+                            owner_id: &str,
+                            file_id: &str,
+                            extension: &Option<String>,
+                            blob_hash: &Option<String>,
+) -> (String, String) {
+    match blob_hash {
+        Some(hash) => state.dedup.blob_location(hash),
+        None => state.layout.location(owner_id, file_id, extension),
+    }
+}
+
+pub(crate) fn s3_key(file_id: String, file_ext: &Option<String>)->String{
     if let Some(e) = file_ext && e != "" {
         return file_id + "." + e;
     }
     return file_id;
 }
+// where an overwrite waits for its row to commit, next to the user's objects
+// under a name s3_key never makes
+pub(crate) fn staging_key(file_id: &Uuid) -> String {
+    format!("staging/{}.{}", file_id, Uuid::new_v4())
+}
 fn update_url(//file: &FileResponse, 
               file_url: &Option<String>,
               file_modified: &Option<DateTime<Utc>>,
@@ -81,16 +153,8 @@ fn update_url(//file: &FileResponse,
 pub async fn create_bucket(State(state): State<AppState>,
                            payload: extract::Json<OwnerId>
 ) -> Result<Json<String>, ServerError> {
-    match state.client.create_bucket()
-        .bucket(&payload.owner_id)
-        .send()
-        .await{   
-            Ok(_) => Ok(Json("Success".to_string())),
-            Err(e) => {
-                        eprintln!("Error {:?}", e);    
-                        return Err(ServerError::S3Error(e.into()))
-            }
-    }
+    create_bucket_func(&state.store, &state.layout.bucket(&payload.owner_id)).await?;
+    Ok(Json("Success".to_string()))
 }
 // same thing but above serves as endpoint currently
 pub async fn get_files(State(state): State<AppState>,
@@ -106,7 +170,7 @@ pub async fn get_files(State(state): State<AppState>,
     };
     let owner_id = Uuid::parse_str(&user_id)
             .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?;
-    let client = &state.client;
+    let store = &state.store;
     let pool = &state.pool;
  
     let cur_date = Utc::now();
@@ -115,7 +179,8 @@ pub async fn get_files(State(state): State<AppState>,
         println!("Cache hit, {} items", c.len()); 
         let to_update: Vec<Uuid> = c
             .iter()
-            .filter(|(_,file)| update_url(&file.url, &file.last_modified, cur_date))
+            .filter(|(_,file)| check_download(&state.scanning, &file.scan_status).is_ok()
+                    && update_url(&file.url, &file.last_modified, cur_date))
             .map(|(id,_)| *id ).collect();
         if to_update.is_empty() {
             return Ok(Json((*c).clone()));
@@ -123,8 +188,9 @@ pub async fn get_files(State(state): State<AppState>,
         let mut e: HashMap<Uuid, FileResponse> = (*c).clone();
         let mut updated_urls: Vec<String> = Vec::new();
         for file_id in &to_update {
-            let key = s3_key(file_id.to_string(), &(e[&file_id].extension));
-            let file_url = get_presigned_url(client, &user_id, &key).await?;
+            let file_url = file_url(&state, &user_id, &file_id.to_string(),
+                                    &(e[file_id].extension),
+                                    &(e[file_id].blob_hash)).await?;
             e.entry(*file_id).and_modify(|f| { 
                                 f.url = Some(file_url.clone());                            
                                 f.last_modified = Some(cur_date);
@@ -149,12 +215,17 @@ pub async fn get_files(State(state): State<AppState>,
         return Ok(Json(e));
     }
 
-    if !((check_bucket(&client, &user_id)).await?) {
+    if !(store.bucket_exists(&state.layout.bucket(&user_id)).await?) {
         println!("User bucket not found!");
         return Err(ServerError::NotFound("User bucket not found".to_string()));
     }
  
-    let files = sqlx::query_as::<_,DatabaseFile>("SELECT * FROM files where owner_id = ($1);")
+    let files = sqlx::query_as::<_,DatabaseFile>(r#"SELECT f.*,
+                                                    ARRAY(SELECT t.name FROM file_tags ft
+                                                          JOIN tags t ON t.tag_id = ft.tag_id
+                                                          WHERE ft.file_id = f.file_id
+                                                          ORDER BY t.name) AS tags
+                                                    FROM files f WHERE f.owner_id = ($1);"#)
         .bind(&owner_id)
         .fetch_all(pool)
         .await
@@ -168,9 +239,12 @@ pub async fn get_files(State(state): State<AppState>,
     let mut to_update_ids: Vec<Uuid> = Vec::new();
     let mut to_update_urls: Vec<String> = Vec::new();
     for mut file in files {
-        if update_url(&file.url, &file.last_modified, cur_date) {
-            let key = s3_key(file.file_id.to_string(), &file.extension);
-            let file_url = get_presigned_url(client, &user_id, &key).await?;
+        // no links to quarantined or not yet scanned files
+        if check_download(&state.scanning, &file.scan_status).is_err() {
+            file.url = None;
+        } else if update_url(&file.url, &file.last_modified, cur_date) {
+            let file_url = file_url(&state, &user_id, &file.file_id.to_string(),
+                                    &file.extension, &file.blob_hash).await?;
             file.url = Some(file_url.clone());
             to_update_ids.push(file.file_id);            
             to_update_urls.push(file_url);
@@ -188,6 +262,18 @@ pub async fn get_files(State(state): State<AppState>,
             last_modified: Some(cur_date),
             shared_with: file.shared_with,
             url: file.url,
+            blob_hash: file.blob_hash,
+            checksum_sha256: file.checksum_sha256,
+            checksum_md5: file.checksum_md5,
+            vault: file.vault,
+            wrapped_key: file.wrapped_key.as_deref().map(encode_field),
+            encrypted_metadata: file.encrypted_metadata.as_deref().map(encode_field),
+            mime_type: file.mime_type,
+            scan_status: file.scan_status,
+            media_metadata: file.media_metadata,
+            colour_label: file.colour_label,
+            starred: file.starred,
+            tags: file.tags,
         });
     }
     sqlx::query(r#"UPDATE files SET last_modified = ($1),
@@ -208,36 +294,28 @@ pub async fn get_files(State(state): State<AppState>,
 }
 
 
-pub async fn create_folder(State(state): State<AppState>,
-                           jar: CookieJar,
-                           payload: Json<CreateFolderForm>
-)->Result<StatusCode, ServerError> {
-
-    println!("CreateFolder ran");
-    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await 
-    && id != "NOT VALID" {
-        Uuid::parse_str(&id)
-            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
-    } else {
-        return Err(ServerError::Unauthorized("No session token found".to_string()));
-    };
+// row and cache for a new plain folder, shared with the webdav interface
+pub(crate) async fn make_folder(state: &AppState,
+                                owner_id: Uuid,
+                                parent_id: Option<Uuid>,
+                                folder_name: &str,
+) -> Result<Uuid, ServerError> {
     let folder_id = Uuid::new_v4();
-    
-    let parent_id = match payload.parent_id.is_empty() {
-       true => None,
-       false => Some(Uuid::parse_str(&payload.parent_id) 
-                   .map_err(|e| ServerError::InternalError(format!("Failed to parse parent id. Error: {}", e)))?),
-    };
-
-    let folder_name =  payload.folder_name.trim();
+    let folder_name = folder_name.trim();
     if folder_name.is_empty() {
         println!("Name empty");
         return Err(ServerError::InternalError("Invalid name".to_string()));
     };
 
+    // plaintext names dont belong in a vault, see create_vault_folder
+    if is_vault_folder(&state.pool, &parent_id).await? {
+        return Err(ServerError::BadRequest("Folders in a vault must be created encrypted".to_string()));
+    }
+
     let created_at = Some(Utc::now());
     let shared_with: Vec<Uuid> = Vec::new();
     
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
     match sqlx::query(r#"INSERT into files (file_id, owner_id, parent_id, file_name,
                        size, file_type, created_at, last_modified, shared_with) 
                        VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9);"#)
@@ -250,13 +328,15 @@ pub async fn create_folder(State(state): State<AppState>,
         .bind(&created_at)
         .bind(&created_at)
         .bind(&shared_with)
-        .execute(&state.pool).await {
+        .execute(&mut *tx).await {
             Ok(_) => {},
             Err(e) => {
                         eprintln!("Error {:?}", e);
                         return Err(ServerError::DatabaseError(e.to_string()))
             },
     }
+    record_change(&mut tx, &folder_id, ChangeKind::Created, &[]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
     let new_folder = FileResponse {
         file_id: folder_id,
         owner_id: owner_id,
@@ -269,6 +349,18 @@ pub async fn create_folder(State(state): State<AppState>,
         last_modified: created_at,
         shared_with: shared_with,
         url: None,
+        blob_hash: None,
+        checksum_sha256: None,
+        checksum_md5: None,
+        vault: false,
+        wrapped_key: None,
+        encrypted_metadata: None,
+        mime_type: None,
+        scan_status: ScanStatus::Unscanned,
+        media_metadata: None,
+        colour_label: None,
+        starred: false,
+        tags: Vec::new(),
     };
 
     let files = if let Some(c) = state.cache.get(&owner_id).await {
@@ -281,13 +373,51 @@ pub async fn create_folder(State(state): State<AppState>,
             new_files
     };
     state.cache.insert(owner_id, Arc::new(files)).await;
+    Ok(folder_id)
+}
+
+pub async fn create_folder(State(state): State<AppState>,
+                           jar: CookieJar,
+                           info: RequestInfo,
+                           payload: Json<CreateFolderForm>
+)->Result<StatusCode, ServerError> {
+
+    println!("CreateFolder ran");
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await 
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let parent_id = match payload.parent_id.is_empty() {
+       true => None,
+       false => Some(Uuid::parse_str(&payload.parent_id) 
+                   .map_err(|e| ServerError::InternalError(format!("Failed to parse parent id. Error: {}", e)))?),
+    };
+    let folder_name = payload.folder_name.trim();
+    let folder_id = make_folder(&state, owner_id, parent_id, folder_name).await?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "folder_created",
+        file_id: Some(folder_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"name": folder_name, "parent_id": parent_id})),
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Created, Some(owner_id), folder_id, owner_id,
+                                     parent_id, Some(folder_name.to_string()))).await;
 
     Ok(StatusCode::CREATED)
 }
 
+// per user, in bytes
+pub const STORAGE_LIMIT: i64 = 1048576 * 2;
+
 //2mb limit 
 pub async fn upload_file(State(state): State<AppState>,
                          jar: CookieJar,
+                         info: RequestInfo,
+                         headers: HeaderMap,
                          mut payload: Multipart,
 )->Result<Json<String>, ServerError> {
 
@@ -299,16 +429,12 @@ pub async fn upload_file(State(state): State<AppState>,
   } else {
         return Err(ServerError::Unauthorized("No session token found".to_string()));
   };
-  if check_bucket(&state.client, &user_id).await? {
-      println!("Bucket does exit");
-  } else {
-    println!("User bucket not found");
-    return Err(ServerError::NotFound("User bucket not found".to_string()));
-  };
-  let mut data = Bytes::new();
+  let expected = ExpectedChecksums::from_headers(&headers)?;
+  let mut upload: Option<(Bytes, Checksums)> = None;
   let mut filename = String::new(); 
   let mut content_type = String::new();
   let mut payload_parent_id = String::new();
+  let mut strip_gps: Option<bool> = None;
 
   while let Some(field) = payload.next_field().await? {
       match field.name() {
@@ -316,7 +442,7 @@ pub async fn upload_file(State(state): State<AppState>,
         filename = field.file_name().unwrap_or("unknown").to_string();
         // app/octet - unknown generic type
         content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
-        data = field.bytes().await?;
+        upload = Some(read_file_field(field).await?);
       },
       // see about this one since move getting id frm cookies
       Some("user_id") => {
@@ -325,14 +451,147 @@ pub async fn upload_file(State(state): State<AppState>,
       Some("parent_id") => {
         payload_parent_id = field.text().await?;
       },
+      // overrides STRIP_GPS for this upload
+      Some("strip_gps") => {
+        strip_gps = Some(field.text().await?.trim() == "true");
+      },
       _ => {}
       }
   };
   
-
-  let file_size = data.len() as i64; 
   let owner_id = Uuid::parse_str(&user_id)
       .map_err(|e| ServerError::InternalError(e.to_string()))?;
+  let parent_id = match payload_parent_id.is_empty() {
+        true => None,
+        false => Some(Uuid::parse_str(&payload_parent_id)
+            .map_err(|e| ServerError::InternalError(e.to_string()))?),
+  };
+
+  let (data, checksums) = upload
+      .ok_or(ServerError::BadRequest("No file provided".to_string()))?;
+  expected.verify(&checksums)?;
+
+  let uploaded = store_file(&state, NewFile {
+      owner_id,
+      parent_id,
+      filename: &filename,
+      content_type: &content_type,
+      data,
+      checksums: &checksums,
+      strip_gps: strip_gps.unwrap_or(state.metadata.strip_gps),
+  }, None).await?;
+  record(&state.pool, &info, AuditEvent {
+      actor_id: Some(owner_id),
+      action: "file_uploaded",
+      file_id: Some(uploaded.file_id),
+      owner_id: Some(owner_id),
+      details: Some(serde_json::json!({"name": filename, "size": uploaded.size,
+                                       "parent_id": parent_id})),
+  }).await;
+  announce(&state, ChangeEvent::new(ChangeKind::Created, Some(owner_id), uploaded.file_id,
+                                   owner_id, parent_id, Some(filename.clone()))).await;
+  Ok(Json("File Uploaded".to_string()))
+}
+
+// an upload as every upload path hands it over.
+// `strip_gps` removes location data from jpegs before anything is hashed or stored
+pub(crate) struct NewFile<'a> {
+  pub owner_id: Uuid,
+  pub parent_id: Option<Uuid>,
+  pub filename: &'a str,
+  pub content_type: &'a str,
+  pub data: Bytes,
+  pub checksums: &'a Checksums,
+  pub strip_gps: bool,
+}
+
+// shared by every upload path: quota check, files row, folder sizes, object, cache.
+// `vault` is set for client encrypted uploads, whose data is already ciphertext
+pub(crate) async fn store_file(state: &AppState,
+                               file: NewFile<'_>,
+                               vault: Option<&VaultItem>,
+)->Result<FileResponse, ServerError> {
+  write_file(state, file, vault, None).await
+}
+
+// new content for a file that is already there. it keeps its id, and with it
+// its tags, comments, shares and history; name and folder stay as they are
+pub(crate) async fn replace_file(state: &AppState,
+                                 file_id: Uuid,
+                                 file: NewFile<'_>,
+)->Result<FileResponse, ServerError> {
+  write_file(state, file, None, Some(file_id)).await
+}
+
+// what an overwritten file had, its charge is given back the way remove_file would
+#[derive(sqlx::FromRow)]
+struct OldContent {
+  size: i64,
+  #[sqlx(skip)]
+  refund: i64,
+  blob_hash: Option<String>,
+  created_at: Option<DateTime<Utc>>,
+  shared_with: Vec<Uuid>,
+  colour_label: Option<ColourLabel>,
+  starred: bool,
+  tags: Vec<String>,
+}
+
+// locks the row and lets go of its blob. the row points at nothing until the
+// new content is written, so charged_size sees neither copy
+async fn take_content(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+                      state: &AppState,
+                      owner_id: &Uuid,
+                      file_id: &Uuid,
+)->Result<OldContent, ServerError> {
+  let mut old: OldContent =
+      sqlx::query_as(r#"SELECT f.size, f.blob_hash, f.created_at, f.shared_with, f.colour_label, f.starred,
+                        ARRAY(SELECT t.name FROM file_tags ft
+                              JOIN tags t ON t.tag_id = ft.tag_id
+                              WHERE ft.file_id = f.file_id
+                              ORDER BY t.name) AS tags
+                        FROM files f
+                        WHERE f.file_id = ($1) AND f.owner_id = ($2)
+                        AND f.file_type <> 'folder' AND NOT f.vault
+                        FOR UPDATE;"#)
+      .bind(file_id)
+      .bind(owner_id)
+      .fetch_optional(&mut **tx)
+      .await
+      .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+      .ok_or(ServerError::NotFound("File not found".to_string()))?;
+  old.refund = match &old.blob_hash {
+      Some(hash) => {
+          sqlx::query(r#"UPDATE files SET blob_hash = NULL WHERE file_id = ($1);"#)
+              .bind(file_id)
+              .execute(&mut **tx)
+              .await
+              .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+          let refund = charged_size(tx, &state.dedup, owner_id, hash, old.size).await?;
+          release_blob(tx, hash).await?;
+          refund
+      },
+      None => old.size,
+  };
+  Ok(old)
+}
+
+// store_file and replace_file, `existing` is the file being overwritten
+async fn write_file(state: &AppState,
+                    file: NewFile<'_>,
+                    vault: Option<&VaultItem>,
+                    existing: Option<Uuid>,
+)->Result<FileResponse, ServerError> {
+  let NewFile { owner_id, parent_id, filename, content_type, data, checksums, strip_gps } = file;
+
+  let user_id = owner_id.to_string();
+  if state.store.bucket_exists(&state.layout.bucket(&user_id)).await? {
+      println!("Bucket does exit");
+  } else {
+    println!("User bucket not found");
+    return Err(ServerError::NotFound("User bucket not found".to_string()));
+  };
+
 
   let storage_used: i64 = sqlx::query_scalar(r#"SELECT storage_used
                                                 FROM users
@@ -342,43 +601,123 @@ pub async fn upload_file(State(state): State<AppState>,
       .await
       .map_err(|e| ServerError::DatabaseError(format!("Failed to get storage. Error: {}", e)))?;
 
-  if (file_size + storage_used) > 1048576 * 2 {
-        return Err(ServerError::InternalError("Not enough storage".to_string())); 
+  if is_vault_folder(&state.pool, &parent_id).await? != vault.is_some() {
+      return Err(ServerError::BadRequest(match vault {
+          Some(_) => "Encrypted uploads have to go into a vault folder",
+          None => "Uploads into a vault have to be encrypted",
+      }.to_string()));
   }
 
-  let file_id = Uuid::new_v4();  
-  let parent_id = match payload_parent_id.is_empty() {
-        true => None,
-        false => Some(Uuid::parse_str(&payload_parent_id)
-            .map_err(|e| ServerError::InternalError(e.to_string()))?),
-  };
+  let file_id = existing.unwrap_or_else(Uuid::new_v4);
 
-  let name = Path::new(&filename).file_stem()
-      .and_then(|s| s.to_str()).unwrap_or("unknown");
-  let extension = Path::new(&filename)
-      .extension()
-      .and_then(|s| s.to_str())
-      .unwrap_or("");
+  // the real name is inside the vault metadata
+  let (name, extension) = match vault {
+      Some(_) => ("", ""),
+      None => (Path::new(filename).file_stem()
+                   .and_then(|s| s.to_str()).unwrap_or("unknown"),
+               Path::new(filename)
+                   .extension()
+                   .and_then(|s| s.to_str())
+                   .unwrap_or("")),
+  };
   
   let created_at = Some(Utc::now());
   let shared_with: Vec<Uuid> = Vec::new();
-  let file_type = match content_type.as_str() {
-      ctype if ctype.starts_with("image/") => FileType::Media,
-      ctype if ctype.starts_with("video/") => FileType::Media,
-      ctype if ctype.starts_with("audio/") => FileType::Media,
-      ctype if ctype.starts_with("text/") => FileType::Document,
-      "application/pdf" => FileType::Document,
-      _ => FileType::Other,
-
+  // the browser only guesses from the extension, so go by the bytes. vault
+  // uploads are ciphertext and have nothing to sniff
+  let mime_type = match vault {
+      Some(_) => None,
+      None => {
+          let detected = detect_mime(&data, extension);
+          state.mime_policy.check(&detected, content_type, extension)?;
+          Some(detected)
+      },
   };
+  let file_type = match &mime_type {
+      Some(m) => file_type_for(m),
+      None => FileType::Other,
+  };
+  let content_type = mime_type.as_deref().unwrap_or(content_type);
+  let mut media_metadata = match &mime_type {
+      Some(m) => extract_metadata_blocking(data.clone(), m.clone()).await,
+      None => None,
+  };
+  // the stored copy is what gets hashed, so the checksums follow the stripped bytes
+  let (data, checksums) = match strip_gps && content_type == "image/jpeg" {
+      true => match strip_jpeg_gps(&data) {
+          Some(stripped) => {
+              let mut hasher = UploadHasher::default();
+              hasher.update(&stripped);
+              if let Some(Value::Object(meta)) = &mut media_metadata {
+                  meta.remove("gps");
+              }
+              (stripped, hasher.finish())
+          },
+          None => (data, checksums.clone()),
+      },
+      false => (data, checksums.clone()),
+  };
+  let file_size = data.len() as i64;
+  // ciphertext would scan clean whatever is inside, so vault files stay unscanned
+  let scan_status = match (&state.scanning.scanner, vault) {
+      (Some(_), None) => ScanStatus::Pending,
+      _ => ScanStatus::Unscanned,
+  };
+ let blob_hash = state.dedup.enabled
+     .then(|| blob_hash(&checksums.sha256, &owner_id, state.encryption.is_some()));
  let mut conn = state.pool.acquire().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
  let mut tx = conn.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+ let old = match existing {
+     Some(id) => Some(take_content(&mut tx, state, &owner_id, &id).await?),
+     None => None,
+ };
+ let refund = old.as_ref().map(|o| o.refund).unwrap_or(0);
+ // duplicates of a blob the user already has can be free, see DedupConfig
+ let charged = match &blob_hash {
+     Some(hash) => charged_size(&mut tx, &state.dedup, &owner_id, hash, file_size).await?,
+     None => file_size,
+ };
+ if (charged - refund + storage_used) > STORAGE_LIMIT {
+       return Err(ServerError::QuotaExceeded);
+ }
+ // blob row has to exist before the files row points at it
+ let new_blob = match &blob_hash {
+     Some(hash) => reference_blob(&mut tx, hash, file_size).await?,
+     None => false,
+ };
+ // data key for the object, an existing blob keeps whatever key it was written with.
+ // with encryption on that is always one of this owner's keys, see blob_hash
+ let (key_id, data_key) = match (&state.encryption, &blob_hash) {
+     (_, Some(hash)) if !new_blob => {
+         let key_id: Option<Uuid> = sqlx::query_scalar(r#"SELECT key_id FROM blobs
+                                                          WHERE hash = ($1);"#)
+             .bind(hash)
+             .fetch_one(&mut *tx)
+             .await
+             .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+         (key_id, None)
+     },
+     (Some(encryption), _) => {
+         let (key_id, dek) = active_data_key(&state.pool, encryption, &owner_id).await?;
+         if let Some(hash) = &blob_hash {
+             sqlx::query(r#"UPDATE blobs SET key_id = ($1) WHERE hash = ($2);"#)
+                 .bind(key_id)
+                 .bind(hash)
+                 .execute(&mut *tx)
+                 .await
+                 .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+         }
+         (Some(key_id), Some(dek))
+     },
+     (None, _) => (None, None),
+ };
  
  // user table update
  match sqlx::query(r#"UPDATE users
               SET storage_used = storage_used + ($1)
               WHERE user_id = ($2);"#)
-              .bind(file_size)
+              .bind(charged - refund)
               .bind(&owner_id)
               .execute(&mut *tx)
               .await {
@@ -388,28 +727,78 @@ pub async fn upload_file(State(state): State<AppState>,
                               return Err(ServerError::DatabaseError(e.to_string()))
                    }
               }
- // file table update
- match sqlx::query(r#"INSERT INTO files (file_id, owner_id, parent_id, file_name,
-              size, extension, file_type, created_at, last_modified, shared_with)
-              VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10);"#)
-      .bind(&file_id)
-      .bind(&owner_id)
-      .bind(&parent_id)
-      .bind(name)
-      .bind(file_size)
-      .bind(&extension)
-      .bind(&file_type)
-      .bind(&created_at)
-      .bind(&created_at)
-      .bind(&shared_with)
-      .execute(&mut *tx)
-      .await {
-                   Ok(_) => println!("File Table Update"),
-                   Err(e) => {
-                              eprintln!("Error {:?}", e);
-                              return Err(ServerError::DatabaseError(e.to_string()))
-                   }
-      }
+ // file table update, the same row again for an overwrite
+ let mut objects = Vec::new();
+ if let Some(old) = &old {
+     sqlx::query(r#"UPDATE files SET size = ($2), file_type = ($3), last_modified = ($4), url = NULL,
+                    blob_hash = ($5), checksum_sha256 = ($6), checksum_md5 = ($7), key_id = ($8),
+                    mime_type = ($9), scan_status = ($10), media_metadata = ($11)
+                    WHERE file_id = ($1);"#)
+         .bind(file_id)
+         .bind(file_size)
+         .bind(&file_type)
+         .bind(created_at)
+         .bind(&blob_hash)
+         .bind(&checksums.sha256)
+         .bind(&checksums.md5)
+         .bind(key_id)
+         .bind(&mime_type)
+         .bind(&scan_status)
+         .bind(&media_metadata)
+         .execute(&mut *tx)
+         .await
+         .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+     // thumbnails of the old content, they are made again below
+     objects = delete_thumbnails(&mut tx, &owner_id, &file_id).await?;
+     // a plain object is overwritten where it is, one left behind by a switch to blobs is not
+     if old.blob_hash.is_none() && blob_hash.is_some() {
+         objects.push(state.layout.location(&user_id, &file_id.to_string(), &Some(extension.to_string())));
+     }
+ } else {
+     match sqlx::query(r#"INSERT INTO files (file_id, owner_id, parent_id, file_name,
+                  size, extension, file_type, created_at, last_modified, shared_with, blob_hash,
+                  checksum_sha256, checksum_md5, key_id, vault, wrapped_key, encrypted_metadata,
+                  mime_type, scan_status, media_metadata)
+                  VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16,$17,$18,$19,$20);"#)
+          .bind(file_id)
+          .bind(owner_id)
+          .bind(parent_id)
+          .bind(name)
+          .bind(file_size)
+          .bind(extension)
+          .bind(&file_type)
+          .bind(created_at)
+          .bind(created_at)
+          .bind(&shared_with)
+          .bind(&blob_hash)
+          .bind(&checksums.sha256)
+          .bind(&checksums.md5)
+          .bind(key_id)
+          .bind(vault.is_some())
+          .bind(vault.map(|v| &v.wrapped_key))
+          .bind(vault.map(|v| &v.metadata))
+          .bind(&mime_type)
+          .bind(&scan_status)
+          .bind(&media_metadata)
+          .execute(&mut *tx)
+          .await {
+                       Ok(_) => println!("File Table Update"),
+                       Err(e) => {
+                                  eprintln!("Error {:?}", e);
+                                  return Err(ServerError::DatabaseError(e.to_string()))
+                       }
+          }
+ }
+  // by the sniffed type, a client claiming image/png doesnt get a pdf decoded
+  if state.thumbnails.enabled && let Some(kind) = mime_type.as_deref().and_then(thumbnail_kind) {
+      queue_thumbnail(&mut tx, &file_id, kind).await?;
+  }
+  if scan_status == ScanStatus::Pending {
+      queue_scan(&mut tx, &file_id).await?;
+  }
+  if state.search.index_content && vault.is_none() && let Some(kind) = text_kind(content_type) {
+      queue_indexing(&mut tx, &file_id, kind).await?;
+  }
   if let Some(parent_id) = parent_id {
         match sqlx::query(r#"WITH RECURSIVE ancestors AS (
                                                     SELECT file_id, parent_id
@@ -425,7 +814,7 @@ pub async fn upload_file(State(state): State<AppState>,
                      SET size = size + ($2)
                      WHERE file_id IN (SELECT file_id FROM ancestors);"#)
             .bind(&parent_id)
-            .bind(&file_size)
+            .bind(file_size - old.as_ref().map(|o| o.size).unwrap_or(0))
             .execute(&mut *tx)
             .await {
                    Ok(_) => println!("Parent Update"),
@@ -435,25 +824,73 @@ pub async fn upload_file(State(state): State<AppState>,
                             }
             }
   } 
-  let s3_name = s3_key(file_id.to_string(),&Some(extension.to_string())); 
-  match state.client.put_object().bucket(&user_id).key(&s3_name).body(data.into())
-          .content_type(&content_type)
-          .send()
-          .await { 
-                   Ok(_) => {},
-                   Err(e) => {
-                              eprintln!("Error {:?}", e);
-                              return Err(ServerError::S3Error(e.into()))
-                    },
+  // (bucket, staged key, key) of an overwrite waiting for the commit
+  let mut staged = None;
+  match &blob_hash {
+      Some(hash) => {
+          let (bucket, key) = state.dedup.blob_location(hash);
+          if new_blob || state.store.head(&bucket, &key).await?.is_none() {
+              // rewriting a lost blob needs the key it was first written with
+              let dek = match (data_key, key_id, &state.encryption) {
+                  (Some(dek), _, _) => Some(dek),
+                  (None, Some(id), Some(encryption)) => Some(data_key_for(&state.pool, encryption, &id).await?),
+                  _ => None,
+              };
+              match dek {
+                  Some(dek) => state.store.put_stream(&bucket, &key,
+                                                      encrypt_stream(&dek, bytes_stream(data)),
+                                                      content_type).await?,
+                  None => state.store.put(&bucket, &key, data, content_type).await?,
+              }
+          } else {
+              println!("Blob already stored");
+          }
+      },
+      None => {
+          let (bucket, s3_name) = state.layout.location(&user_id, &file_id.to_string(),
+                                                        &Some(extension.to_string()));
+          // an overwrite is written beside the old object and only moved over
+          // it once the row is committed, a failed commit leaves both as they were
+          let put_key = match &old {
+              Some(_) => {
+                  let key = state.layout.key(&user_id, &staging_key(&file_id));
+                  staged = Some((bucket.clone(), key.clone(), s3_name.clone()));
+                  key
+              },
+              None => s3_name,
+          };
+          match data_key {
+              Some(dek) => state.store.put_stream(&bucket, &put_key,
+                                                  encrypt_stream(&dek, bytes_stream(data)),
+                                                  content_type).await?,
+              None => state.store.put(&bucket, &put_key, data, content_type).await?,
+          }
+      },
   }
+  let kind = if old.is_some() { ChangeKind::Modified } else { ChangeKind::Created };
+  record_change(&mut tx, &file_id, kind, &[]).await?;
   match tx.commit()
       .await {
             Ok(_) => {},
             Err(e) => {
                         eprintln!("Error {:?}", e);
+                        if let Some((bucket, key, _)) = &staged {
+                            let _ = state.store.delete(bucket, key).await;
+                        }
                         return Err(ServerError::DatabaseError(e.to_string()))
                       }
   }
+  if let Some((bucket, key, target)) = staged {
+      state.store.copy(&bucket, &key, &bucket, &target).await?;
+      if let Err(e) = state.store.delete(&bucket, &key).await {
+          eprintln!("Error {:?}", e);
+      }
+  }
+  for (bucket, key) in objects {
+      if let Err(e) = state.store.delete(&bucket, &key).await {
+          eprintln!("Error {:?}", e);
+      }
+  }
   let uploaded_file = FileResponse {
     file_id: file_id,
     owner_id: owner_id,
@@ -461,28 +898,41 @@ pub async fn upload_file(State(state): State<AppState>,
     file_name: name.to_string(),
     extension: Some(extension.to_string()),
     size: file_size,
-    file_type:FileType::Media,
-    created_at: created_at,
+    file_type,
+    created_at: old.as_ref().map_or(created_at, |o| o.created_at),
     last_modified: created_at,
-    shared_with: shared_with.clone(),
+    shared_with: old.as_ref().map_or(shared_with.clone(), |o| o.shared_with.clone()),
     url: None,
+    blob_hash,
+    checksum_sha256: Some(checksums.sha256.clone()),
+    checksum_md5: Some(checksums.md5.clone()),
+    vault: vault.is_some(),
+    wrapped_key: vault.map(|v| encode_field(&v.wrapped_key)),
+    encrypted_metadata: vault.map(|v| encode_field(&v.metadata)),
+    mime_type,
+    scan_status,
+    media_metadata,
+    colour_label: old.as_ref().and_then(|o| o.colour_label),
+    starred: old.as_ref().is_some_and(|o| o.starred),
+    tags: old.map(|o| o.tags).unwrap_or_default(),
   };
  
   let cached_files: HashMap<Uuid, FileResponse> = if let Some(c) = state.cache
   .get(&owner_id).await {
           let mut e = (*c).clone();
-          e.insert(file_id, uploaded_file);
+          e.insert(file_id, uploaded_file.clone());
           e
   } else { 
-      HashMap::from([(file_id, uploaded_file)],) 
+      HashMap::from([(file_id, uploaded_file.clone())],) 
   };
   state.cache.insert(owner_id, Arc::new(cached_files)).await;
-  Ok(Json("File Uploaded".to_string()))
+  Ok(uploaded_file)
 }
 
 
 pub async fn delete_file(State(state): State<AppState>,
                          jar: CookieJar,
+                         info: RequestInfo,
                          payload: extract::Json<DeleteFileForm>
 )->Result<Json<String>, ServerError> {
 
@@ -495,7 +945,7 @@ pub async fn delete_file(State(state): State<AppState>,
         return Err(ServerError::Unauthorized("No session token found".to_string()));
     };
 
-    if !((check_bucket(&state.client, &payload.owner_id)).await?) {
+    if !(state.store.bucket_exists(&state.layout.bucket(&payload.owner_id)).await?) {
         println!("User bucket not found!");
         return Err(ServerError::NotFound("User bucket not found".to_string()));
     };
@@ -505,23 +955,54 @@ pub async fn delete_file(State(state): State<AppState>,
 
     let file_id = Uuid::parse_str(&payload.file_id) 
         .map_err(|e| ServerError::InternalError(e.to_string()))?; 
+    // who could see it has to be known before it is gone
+    let audience = file_audience(&state.pool, &file_id).await?;
+    let (file_name, parent_id) = remove_file(&state, owner_id, file_id).await?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "file_deleted",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"name": file_name})),
+    }).await;
+    let mut deleted = ChangeEvent::new(ChangeKind::Deleted, Some(owner_id), file_id, owner_id,
+                                       parent_id, Some(file_name));
+    deleted.audience = audience;
+    emit(&state, deleted).await;
+    Ok(Json("File Deleted".to_string()))
+}
+
+// row, quota, folder sizes, derived objects and the object itself. also used
+// by admins clearing out quarantine. returns the name and parent the file had
+pub(crate) async fn remove_file(state: &AppState,
+                                owner_id: Uuid,
+                                file_id: Uuid,
+)->Result<(String, Option<Uuid>), ServerError> {
     let mut conn = state.pool.acquire().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
     let mut tx = conn.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
     
+    let mut objects = delete_thumbnails(&mut tx, &owner_id, &file_id).await?;
+    // while the row is still there to work out who could see it
+    record_change(&mut tx, &file_id, ChangeKind::Deleted, &[]).await?;
     // delete file from db
-    let (extension, size, parent_id): (Option<String>, i64, Option<Uuid>) = sqlx::query_as(r#"DELETE FROM files
+    let (extension, size, parent_id, blob_hash, file_name): (Option<String>, i64, Option<Uuid>, Option<String>, String) = sqlx::query_as(r#"DELETE FROM files
                                      WHERE file_id = ($1) AND owner_id = ($2)
-                                     RETURNING extension, size, parent_id;"#)
+                                     RETURNING extension, size, parent_id, blob_hash, file_name;"#)
         .bind(&file_id)
         .bind(&owner_id)
         .fetch_one(&mut *tx)
         .await
         .map_err(|e| ServerError::DatabaseError(format!("Database delete failed. Error: {}", e)))?;
+    // mirror of the upload charge, runs after the row is gone
+    let refund = match &blob_hash {
+        Some(hash) => charged_size(&mut tx, &state.dedup, &owner_id, hash, size).await?,
+        None => size,
+    };
     // update user storage
     sqlx::query(r#"UPDATE users
                  SET storage_used = storage_used - ($1)
                  WHERE user_id = ($2);"#)
-        .bind(size)
+        .bind(refund)
         .bind(&owner_id)
         .execute(&mut *tx)
         .await
@@ -548,15 +1029,9 @@ pub async fn delete_file(State(state): State<AppState>,
     }
 
     let ext = extension.clone().unwrap_or("".to_string());
-    let key = s3_key(payload.file_id.to_string(), &Some(ext));//payload.file_id.to_string() + "." + &ext;
-
-    match state.client.delete_object().bucket(&payload.owner_id).key(key)
-        .send().await {
-                Ok(_) => {},
-                Err(e) => {
-                    eprintln!("Error {:?}", e);
-                    return Err(ServerError::S3Error(e.into()))
-                },
+    match &blob_hash {
+        Some(hash) => release_blob(&mut tx, hash).await?,
+        None => objects.push(state.layout.location(&owner_id.to_string(), &file_id.to_string(), &Some(ext))),
     }
     match tx.commit().await {
                 Ok(_) => {},
@@ -565,18 +1040,66 @@ pub async fn delete_file(State(state): State<AppState>,
                             return Err(ServerError::DatabaseError(e.to_string()))
                 },
     }
+    // only once the rows are gone for good, an orphaned object beats a dangling row
+    for (bucket, key) in objects {
+        if let Err(e) = state.store.delete(&bucket, &key).await {
+            eprintln!("Error {:?}", e);
+        }
+    }
     if let Some(c) = state.cache.get(&owner_id).await {
         let mut e = (*c).clone();
         e.remove(&file_id);
         state.cache.remove(&owner_id).await;
         state.cache.insert(owner_id, Arc::new(e)).await;
     }
-                                            
-    Ok(Json("File Deleted".to_string()))
+    Ok((file_name, parent_id))
+}
+
+// the name without the extension, which stays as it is. returns the old name
+// and the parent, for the audit log and events
+pub(crate) async fn rename_row(state: &AppState,
+                               owner_id: Uuid,
+                               file_id: Uuid,
+                               name: &str,
+) -> Result<(String, Option<Uuid>), ServerError> {
+    // vault items are renamed through update_vault_metadata
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let (old_name, parent_id) = match sqlx::query_as::<_, (String, Option<Uuid>)>(r#"UPDATE files f
+                         SET file_name = ($1)
+                         FROM (SELECT file_id, file_name FROM files
+                               WHERE file_id = ($2) AND owner_id = ($3) AND NOT vault
+                               FOR UPDATE) old
+                         WHERE f.file_id = old.file_id
+                         RETURNING old.file_name, f.parent_id;"#)
+        .bind(name)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&mut *tx)
+        .await {
+            Ok(None) => {
+                return Err(ServerError::NotFound("File not found or in a vault".to_string()));
+            },
+            Ok(Some(renamed)) => renamed,
+            Err(e) => {
+                        eprintln!("Error {:?}", e);
+                        return Err(ServerError::DatabaseError(e.to_string()))
+            },
+        };
+    record_change(&mut tx, &file_id, ChangeKind::Renamed, &[]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if let Some(c) = state.cache.get(&owner_id).await {
+            let mut e = (*c).clone();
+            e.entry(file_id)
+                .and_modify(|f| f.file_name = name.to_string());
+            state.cache.remove(&owner_id).await;
+            state.cache.insert(owner_id, Arc::new(e)).await;
+    }
+    Ok((old_name, parent_id))
 }
 
 pub async fn rename_file(State(state): State<AppState>, 
                          jar: CookieJar,                
+                         info: RequestInfo,
                          payload: Json<RenameFileForm>,
 )->Result<Json<String>, ServerError> {
 
@@ -598,32 +1121,188 @@ pub async fn rename_file(State(state): State<AppState>,
     };
     let file_id = Uuid::parse_str(&payload.file_id)
         .map_err(|e| ServerError::InternalError(e.to_string()))?;    
-    match sqlx::query(r#"UPDATE files
-                         SET file_name = ($1)
-                         WHERE file_id = ($2) AND owner_id = ($3);"#)
-        .bind(&name)
+    let (old_name, parent_id) = rename_row(&state, owner_id, file_id, name).await?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "file_renamed",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"before": old_name, "after": name})),
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Renamed, Some(owner_id), file_id, owner_id,
+                                     parent_id, Some(name.to_string()))).await;
+   
+    Ok(Json("File Renamed".to_string()))
+}
+
+// adds delta to a folder and every folder above it
+async fn adjust_folder_sizes(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+                             folder_id: &Uuid,
+                             delta: i64,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"WITH RECURSIVE ancestors AS (
+                       SELECT file_id, parent_id FROM files
+                       WHERE file_id = ($1)
+                       UNION ALL
+
+                       SELECT f.file_id, f.parent_id FROM files f
+                       JOIN ancestors a ON f.file_id = a.parent_id
+                   )
+                   UPDATE files SET size = size + ($2)
+                   WHERE file_id IN (SELECT file_id FROM ancestors);"#)
+        .bind(folder_id)
+        .bind(delta)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// moves the row and carries its size from the old folders to the new ones.
+// returns the parent it had, nothing is touched when that is the new one
+pub(crate) async fn relocate_file(state: &AppState,
+                                  owner_id: Uuid,
+                                  file_id: Uuid,
+                                  new_parent: Option<Uuid>,
+) -> Result<Option<Uuid>, ServerError> {
+    if let Some(parent_id) = &new_parent {
+        match parent_kind(&state.pool, &owner_id, parent_id).await? {
+            Some((false, true)) => {},
+            Some((true, _)) => return Err(ServerError::BadRequest("Cannot move into a vault".to_string())),
+            Some(_) => return Err(ServerError::BadRequest("Parent is not a folder".to_string())),
+            None => return Err(ServerError::NotFound("Parent folder not found".to_string())),
+        }
+    }
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let moving: Option<(Option<Uuid>, i64, bool)> = sqlx::query_as(r#"SELECT parent_id, size, vault
+                                                                     FROM files
+                                                                     WHERE file_id = ($1) AND owner_id = ($2)
+                                                                     FOR UPDATE;"#)
         .bind(&file_id)
         .bind(&owner_id)
-        .execute(&state.pool)
-        .await {
-            Ok(_) => {},
-            Err(e) => {
-                        eprintln!("Error {:?}", e);
-                        return Err(ServerError::DatabaseError(e.to_string()))
-            },
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let (old_parent, size, vault) = moving.ok_or(ServerError::NotFound("File not found".to_string()))?;
+    if vault {
+        return Err(ServerError::BadRequest("Vault items cannot be moved".to_string()));
+    }
+    if old_parent == new_parent {
+        return Ok(old_parent);
+    }
+    // who could see it before, anyone left out afterwards gets a delete
+    let old_audience = file_audience(&mut *tx, &file_id).await?;
+    // a folder cant end up inside itself
+    if let Some(parent_id) = &new_parent {
+        let cycle: bool = sqlx::query_scalar(r#"WITH RECURSIVE ancestors AS (
+                                                   SELECT file_id, parent_id FROM files
+                                                   WHERE file_id = ($1)
+                                                   UNION ALL
+
+                                                   SELECT f.file_id, f.parent_id FROM files f
+                                                   JOIN ancestors a ON f.file_id = a.parent_id
+                                                )
+                                                SELECT EXISTS(SELECT 1 FROM ancestors WHERE file_id = ($2));"#)
+            .bind(parent_id)
+            .bind(file_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        if cycle {
+            return Err(ServerError::BadRequest("Cannot move a folder into itself".to_string()));
         }
-    if let Some(c) = state.cache.get(&owner_id).await {
-            let mut e = (*c).clone();
-            e.entry(file_id)
-                .and_modify(|f| f.file_name = name.to_string());
-            state.cache.remove(&owner_id).await;
-            state.cache.insert(owner_id, Arc::new(e)).await;
     }
-   
-    Ok(Json("File Renamed".to_string()))
+    sqlx::query("UPDATE files SET parent_id = ($1) WHERE file_id = ($2);")
+        .bind(new_parent)
+        .bind(file_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if let Some(parent_id) = &old_parent {
+        adjust_folder_sizes(&mut tx, parent_id, -size).await?;
+    }
+    if let Some(parent_id) = &new_parent {
+        adjust_folder_sizes(&mut tx, parent_id, size).await?;
+    }
+    record_change(&mut tx, &file_id, ChangeKind::Moved, &old_audience).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    update_cached_files(state, &owner_id, |files| {
+        if let Some(parent_id) = &old_parent {
+            let ancestors = ancestor_ids(files, parent_id);
+            for id in ancestors {
+                files.entry(id).and_modify(|f| f.size -= size);
+            }
+        }
+        if let Some(parent_id) = &new_parent {
+            let ancestors = ancestor_ids(files, parent_id);
+            for id in ancestors {
+                files.entry(id).and_modify(|f| f.size += size);
+            }
+        }
+        files.entry(file_id).and_modify(|f| f.parent_id = new_parent);
+    }).await;
+    Ok(old_parent)
+}
+
+// into another folder of the owner, or to the top with an empty parent_id.
+// vault items stay where they are, their keys are wrapped by their parent
+pub async fn move_file(State(state): State<AppState>,
+                       jar: CookieJar,
+                       info: RequestInfo,
+                       payload: Json<MoveFileForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let new_parent = match payload.parent_id.is_empty() {
+        true => None,
+        false => Some(Uuid::parse_str(&payload.parent_id)
+            .map_err(|e| ServerError::BadRequest(e.to_string()))?),
+    };
+    // who could see it before, anyone left out afterwards hears it is gone
+    let old_audience = file_audience(&state.pool, &file_id).await?;
+    let old_parent = relocate_file(&state, owner_id, file_id, new_parent).await?;
+    if old_parent == new_parent {
+        return Ok(StatusCode::OK);
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "file_moved",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"before": old_parent, "after": new_parent})),
+    }).await;
+    let mut moved = ChangeEvent::new(ChangeKind::Moved, Some(owner_id), file_id, owner_id,
+                                     new_parent, None);
+    moved.audience = old_audience;
+    announce(&state, moved).await;
+    Ok(StatusCode::OK)
+}
+
+// a folder and the folders above it, as far as the map knows them
+fn ancestor_ids(files: &HashMap<Uuid, FileResponse>, folder_id: &Uuid) -> Vec<Uuid> {
+    let mut ids = Vec::new();
+    let mut next = Some(*folder_id);
+    while let Some(id) = next {
+        if ids.contains(&id) {
+            break;
+        }
+        ids.push(id);
+        next = files.get(&id).and_then(|f| f.parent_id);
+    }
+    ids
 }
 pub async fn download_file(State(state): State<AppState>,
                            jar: CookieJar,
+                           info: RequestInfo,
                            payload: extract::Json<DownloadFileForm>
 ) -> Result<Json<serde_json::Value>, ServerError> {
     
@@ -640,6 +1319,19 @@ pub async fn download_file(State(state): State<AppState>,
     let file_id = Uuid::parse_str(&payload.file_id)
         .map_err(|e| ServerError::InternalError(e.to_string()))?;
 
+    // checked every time, cached urls would outlive a quarantine
+    let scan_status: Option<ScanStatus> = sqlx::query_scalar(r#"SELECT scan_status FROM files
+                                                                WHERE file_id = ($1) AND owner_id = ($2);"#)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    match scan_status {
+        Some(status) => check_download(&state.scanning, &status)?,
+        None => return Err(ServerError::NotFound("File not found".to_string())),
+    }
+
     let cur_date = Utc::now();
     let mut file_name = payload.file_id.clone();
     let mut url: Option<String> = None;
@@ -659,8 +1351,9 @@ pub async fn download_file(State(state): State<AppState>,
         let row = sqlx::query_as::
         <_,(Option<String>,
             String,
-            Option<DateTime<Utc>>)>
-            (r#"SELECT url, file_name, last_modified FROM files
+            Option<DateTime<Utc>>,
+            Option<String>)>
+            (r#"SELECT url, file_name, last_modified, blob_hash FROM files
                                       WHERE file_id = ($1) 
                                       AND owner_id = ($2);"#) 
                 .bind(&file_id)
@@ -668,8 +1361,9 @@ pub async fn download_file(State(state): State<AppState>,
                 .fetch_optional(&state.pool)
                 .await
                 .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let blob_hash = row.as_ref().and_then(|r| r.3.clone());
         match row {
-            Some((Some(database_url),fetched_file_name, Some(date)))
+            Some((Some(database_url),fetched_file_name, Some(date), _))
                 if date + chrono::Duration::days(6) >= cur_date => {
                 // updating cache 
                 if let Some(c) = state.cache.get(&owner_id).await {
@@ -686,9 +1380,8 @@ pub async fn download_file(State(state): State<AppState>,
             },
             _ => {  
                     let extension =  payload.file_extension.clone().unwrap_or("".to_string());
-                    let key = s3_key(payload.file_id.to_string(), &Some(extension));
-                    let new_url = get_presigned_url(&state.client,
-                                    &payload.owner_id, &key).await?;
+                    let new_url = file_url(&state, &payload.owner_id,
+                                    &payload.file_id, &Some(extension), &blob_hash).await?;
 
                     let fetched_file_name = sqlx::query_as::<_,(String,)>(r#"UPDATE files
                                    SET last_modified = ($1),
@@ -710,6 +1403,770 @@ pub async fn download_file(State(state): State<AppState>,
         .ok_or(ServerError::InternalError("Failed to get URL"
         .to_string()))?;
     println!("{}", url);
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "file_downloaded",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: None,
+    }).await;
     let v: Value = serde_json::json!({"url":url, "file_name":file_name});
     Ok(Json(v))
 }
+
+// links from presign() on the local and memory stores end up here
+pub async fn serve_signed_object(State(state): State<AppState>,
+                                 extract::Path((bucket, key)): extract::Path<(String, String)>,
+                                 extract::Query(query): extract::Query<SignedObjectQuery>,
+) -> Result<Response, ServerError> {
+
+    if query.expires < Utc::now().timestamp() {
+        return Err(ServerError::Unauthorized("Link expired".to_string()));
+    }
+    if !verify_object_signature(&state.key, &bucket, &key, query.expires, &query.signature) {
+        return Err(ServerError::Unauthorized("Invalid signature".to_string()));
+    }
+    let object = state.store.get_stream(&bucket, &key).await?;
+    let content_type = object.meta.content_type
+        .unwrap_or("application/octet-stream".to_string());
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, object.meta.size)
+        .body(Body::from_stream(object.stream))
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+// download links handed out while encryption is on, see file_url
+#[derive(sqlx::FromRow)]
+struct SignedContent {
+    owner_id: Uuid,
+    extension: Option<String>,
+    blob_hash: Option<String>,
+    key_id: Option<Uuid>,
+    size: i64,
+    scan_status: ScanStatus,
+}
+
+pub async fn serve_file_content(State(state): State<AppState>,
+                                extract::Path(file_id): extract::Path<String>,
+                                extract::Query(query): extract::Query<SignedObjectQuery>,
+) -> Result<Response, ServerError> {
+
+    if query.expires < Utc::now().timestamp() {
+        return Err(ServerError::Unauthorized("Link expired".to_string()));
+    }
+    if !verify_object_signature(&state.key, "content", &file_id, query.expires, &query.signature) {
+        return Err(ServerError::Unauthorized("Invalid signature".to_string()));
+    }
+    let parsed_id = Uuid::parse_str(&file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let row: Option<SignedContent> =
+        sqlx::query_as(r#"SELECT owner_id, extension, blob_hash, key_id, size, scan_status
+                          FROM files WHERE file_id = ($1);"#)
+        .bind(parsed_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let SignedContent { owner_id, extension, blob_hash, key_id, size, scan_status } = match row {
+        Some(r) => r,
+        None => return Err(ServerError::NotFound("File not found".to_string())),
+    };
+    check_download(&state.scanning, &scan_status)?;
+    let object = open_file(&state, &owner_id.to_string(), &file_id, &extension,
+                           &blob_hash, key_id).await?;
+    let content_type = object.meta.content_type
+        .unwrap_or("application/octet-stream".to_string());
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, size)
+        .body(Body::from_stream(object.stream))
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+// vaults, see vault.rs for what the client does with all of this
+pub async fn set_vault_keys(State(state): State<AppState>,
+                            jar: CookieJar,
+                            info: RequestInfo,
+                            payload: Json<SetVaultKeysForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let public_key = decode_field(&payload.public_key, "public key")?;
+    if public_key.len() != PUBLIC_KEY_SIZE {
+        return Err(ServerError::BadRequest("Public key has the wrong length".to_string()));
+    }
+    let private_key = decode_field(&payload.encrypted_private_key, "private key")?;
+    if private_key.is_empty() {
+        return Err(ServerError::BadRequest("Invalid private key".to_string()));
+    }
+    // replacing the keypair leaves existing vault roots unreadable unless the
+    // client re-wraps them first, that is on the client
+    sqlx::query(r#"UPDATE users SET vault_public_key = ($1), vault_private_key = ($2)
+                   WHERE user_id = ($3);"#)
+        .bind(&public_key)
+        .bind(&private_key)
+        .bind(owner_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_keys_set",
+        file_id: None,
+        owner_id: Some(owner_id),
+        details: None,
+    }).await;
+    Ok(StatusCode::OK)
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredVaultKeys {
+    vault_public_key: Option<Vec<u8>>,
+    vault_private_key: Option<Vec<u8>>,
+}
+
+pub async fn get_vault_keys(State(state): State<AppState>,
+                            jar: CookieJar,
+) -> Result<Json<VaultKeysResponse>, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let keys: Option<StoredVaultKeys> =
+        sqlx::query_as(r#"SELECT vault_public_key, vault_private_key FROM users
+                          WHERE user_id = ($1);"#)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    match keys {
+        Some(StoredVaultKeys { vault_public_key: Some(public_key), vault_private_key: Some(private_key) }) => {
+            Ok(Json(VaultKeysResponse {
+                public_key: encode_field(&public_key),
+                encrypted_private_key: encode_field(&private_key),
+            }))
+        },
+        _ => Err(ServerError::NotFound("No vault keys set".to_string())),
+    }
+}
+
+// what a client needs to wrap an item key for someone else
+pub async fn get_public_key(State(state): State<AppState>,
+                            jar: CookieJar,
+                            payload: Json<GetPublicKeyForm>,
+) -> Result<Json<PublicKeyResponse>, ServerError> {
+
+    if !(get_current_user(jar, &state.key, &state.cache).await
+         .is_ok_and(|id| id != "NOT VALID")) {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    }
+    let row: Option<(Uuid, Vec<u8>)> = sqlx::query_as(r#"SELECT user_id, vault_public_key
+                                                         FROM users
+                                                         WHERE email = ($1) AND active
+                                                         AND vault_public_key IS NOT NULL;"#)
+        .bind(payload.email.trim())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let (user_id, public_key) = row
+        .ok_or(ServerError::NotFound("User has no vault key".to_string()))?;
+    Ok(Json(PublicKeyResponse { user_id, public_key: encode_field(&public_key) }))
+}
+
+// a vault root when the parent is a plain folder (or none), otherwise a subfolder
+pub async fn create_vault_folder(State(state): State<AppState>,
+                                 jar: CookieJar,
+                                 info: RequestInfo,
+                                 payload: Json<CreateVaultFolderForm>,
+) -> Result<Json<FileResponse>, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let parent_id = match payload.parent_id.is_empty() {
+        true => None,
+        false => Some(Uuid::parse_str(&payload.parent_id)
+            .map_err(|e| ServerError::BadRequest(e.to_string()))?),
+    };
+    let in_vault = match &parent_id {
+        Some(id) => match parent_kind(&state.pool, &owner_id, id).await? {
+            Some((vault, true)) => vault,
+            Some((_, false)) => return Err(ServerError::BadRequest("Parent is not a folder".to_string())),
+            None => return Err(ServerError::NotFound("Parent folder not found".to_string())),
+        },
+        None => false,
+    };
+    let wrapped_key = decode_wrapped_key(&payload.wrapped_key,
+        if in_vault { PARENT_WRAPPED_KEY_SIZE } else { USER_WRAPPED_KEY_SIZE })?;
+    let metadata = decode_metadata(&payload.metadata)?;
+
+    let folder_id = Uuid::new_v4();
+    let created_at = Some(Utc::now());
+    let shared_with: Vec<Uuid> = Vec::new();
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    sqlx::query(r#"INSERT into files (file_id, owner_id, parent_id, file_name,
+                   size, file_type, created_at, last_modified, shared_with,
+                   vault, wrapped_key, encrypted_metadata)
+                   VALUES ($1,$2,$3,'',0,$4,$5,$5,$6,TRUE,$7,$8);"#)
+        .bind(folder_id)
+        .bind(owner_id)
+        .bind(parent_id)
+        .bind(FileType::Folder)
+        .bind(created_at)
+        .bind(&shared_with)
+        .bind(&wrapped_key)
+        .bind(&metadata)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {  eprintln!("Error {:?}", e);
+                        ServerError::DatabaseError(e.to_string())})?;
+    record_change(&mut tx, &folder_id, ChangeKind::Created, &[]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let new_folder = FileResponse {
+        file_id: folder_id,
+        owner_id,
+        parent_id,
+        file_name: String::new(),
+        extension: None,
+        size: 0,
+        file_type: FileType::Folder,
+        created_at,
+        last_modified: created_at,
+        shared_with,
+        url: None,
+        blob_hash: None,
+        checksum_sha256: None,
+        checksum_md5: None,
+        vault: true,
+        wrapped_key: Some(encode_field(&wrapped_key)),
+        encrypted_metadata: Some(encode_field(&metadata)),
+        mime_type: None,
+        scan_status: ScanStatus::Unscanned,
+        media_metadata: None,
+        colour_label: None,
+        starred: false,
+        tags: Vec::new(),
+    };
+    if let Some(c) = state.cache.get(&owner_id).await {
+        let mut e = (*c).clone();
+        e.insert(folder_id, new_folder.clone());
+        state.cache.insert(owner_id, Arc::new(e)).await;
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_folder_created",
+        file_id: Some(folder_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"parent_id": parent_id})),
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Created, Some(owner_id), folder_id, owner_id,
+                                     parent_id, None)).await;
+    Ok(Json(new_folder))
+}
+
+// the file field is ciphertext, stored and served back as is
+pub async fn upload_vault_file(State(state): State<AppState>,
+                               jar: CookieJar,
+                               info: RequestInfo,
+                               headers: HeaderMap,
+                               mut payload: Multipart,
+) -> Result<Json<FileResponse>, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let expected = ExpectedChecksums::from_headers(&headers)?;
+    let mut upload: Option<(Bytes, Checksums)> = None;
+    let mut parent_id = String::new();
+    let mut wrapped_key = String::new();
+    let mut metadata = String::new();
+    while let Some(field) = payload.next_field().await? {
+        match field.name() {
+            Some("file") => upload = Some(read_file_field(field).await?),
+            Some("parent_id") => parent_id = field.text().await?,
+            Some("wrapped_key") => wrapped_key = field.text().await?,
+            Some("metadata") => metadata = field.text().await?,
+            _ => {},
+        }
+    }
+    let parent_id = Uuid::parse_str(&parent_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    match parent_kind(&state.pool, &owner_id, &parent_id).await? {
+        Some((true, true)) => {},
+        Some(_) => return Err(ServerError::BadRequest("Parent is not a vault folder".to_string())),
+        None => return Err(ServerError::NotFound("Parent folder not found".to_string())),
+    }
+    let vault = VaultItem {
+        wrapped_key: decode_wrapped_key(&wrapped_key, PARENT_WRAPPED_KEY_SIZE)?,
+        metadata: decode_metadata(&metadata)?,
+    };
+    let (data, checksums) = upload
+        .ok_or(ServerError::BadRequest("No file provided".to_string()))?;
+    expected.verify(&checksums)?;
+
+    let file = store_file(&state, NewFile {
+        owner_id,
+        parent_id: Some(parent_id),
+        filename: "",
+        content_type: "application/octet-stream",
+        data,
+        checksums: &checksums,
+        strip_gps: false,
+    }, Some(&vault)).await?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_file_uploaded",
+        file_id: Some(file.file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"size": file.size, "parent_id": parent_id})),
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Created, Some(owner_id), file.file_id, owner_id,
+                                     Some(parent_id), None)).await;
+    Ok(Json(file))
+}
+
+// renaming a vault item is re-encrypting its metadata
+pub async fn update_vault_metadata(State(state): State<AppState>,
+                                   jar: CookieJar,
+                                   info: RequestInfo,
+                                   payload: Json<UpdateVaultMetadataForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let metadata = decode_metadata(&payload.metadata)?;
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id: Option<Option<Uuid>> = sqlx::query_scalar(r#"UPDATE files SET encrypted_metadata = ($1)
+                                WHERE file_id = ($2) AND owner_id = ($3) AND vault
+                                RETURNING parent_id;"#)
+        .bind(&metadata)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id = parent_id.ok_or(ServerError::NotFound("Vault item not found".to_string()))?;
+    record_change(&mut tx, &file_id, ChangeKind::Renamed, &[]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if let Some(c) = state.cache.get(&owner_id).await {
+        let mut e = (*c).clone();
+        e.entry(file_id)
+            .and_modify(|f| f.encrypted_metadata = Some(encode_field(&metadata)));
+        state.cache.insert(owner_id, Arc::new(e)).await;
+    }
+    // the name is encrypted, there is no before/after to keep
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_metadata_updated",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: None,
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Renamed, Some(owner_id), file_id, owner_id,
+                                     parent_id, None)).await;
+    Ok(StatusCode::OK)
+}
+
+pub async fn share_vault_item(State(state): State<AppState>,
+                              jar: CookieJar,
+                              info: RequestInfo,
+                              payload: Json<ShareVaultItemForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let recipient_id = Uuid::parse_str(&payload.recipient_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    if recipient_id == owner_id {
+        return Err(ServerError::BadRequest("Cannot share with yourself".to_string()));
+    }
+    let wrapped_key = decode_wrapped_key(&payload.wrapped_key, USER_WRAPPED_KEY_SIZE)?;
+
+    let recipient_ready: bool = sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM users
+                                                      WHERE user_id = ($1) AND active
+                                                      AND vault_public_key IS NOT NULL);"#)
+        .bind(recipient_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if !recipient_ready {
+        return Err(ServerError::NotFound("User has no vault key".to_string()));
+    }
+
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id: Option<Option<Uuid>> = sqlx::query_scalar(r#"UPDATE files
+                                SET shared_with = array_append(array_remove(shared_with, $1), $1)
+                                WHERE file_id = ($2) AND owner_id = ($3) AND vault
+                                RETURNING parent_id;"#)
+        .bind(recipient_id)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id = parent_id.ok_or(ServerError::NotFound("Vault item not found".to_string()))?;
+    sqlx::query(r#"INSERT INTO vault_item_keys (file_id, user_id, wrapped_key, granted_by)
+                   VALUES ($1,$2,$3,$4)
+                   ON CONFLICT (file_id, user_id) DO UPDATE
+                   SET wrapped_key = EXCLUDED.wrapped_key, created_at = NOW();"#)
+        .bind(file_id)
+        .bind(recipient_id)
+        .bind(&wrapped_key)
+        .bind(owner_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    record_change(&mut tx, &file_id, ChangeKind::Shared, &[]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    if let Err(e) = notify_user(&state.pool, &recipient_id, "vault_share",
+                                "An encrypted item was shared with you", Some(file_id)).await {
+        eprintln!("Error {:?}", e);
+    }
+    if let Some(c) = state.cache.get(&owner_id).await {
+        let mut e = (*c).clone();
+        e.entry(file_id).and_modify(|f| {
+            f.shared_with.retain(|id| *id != recipient_id);
+            f.shared_with.push(recipient_id);
+        });
+        state.cache.insert(owner_id, Arc::new(e)).await;
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_item_shared",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"recipient_id": recipient_id})),
+    }).await;
+    announce(&state, ChangeEvent::new(ChangeKind::Shared, Some(owner_id), file_id, owner_id,
+                                     parent_id, None)).await;
+    Ok(StatusCode::OK)
+}
+
+// drops the recipient's wrapped key. anything they already downloaded and
+// decrypted stays readable to them, rotate by moving the files to a new vault
+pub async fn unshare_vault_item(State(state): State<AppState>,
+                                jar: CookieJar,
+                                info: RequestInfo,
+                                payload: Json<UnshareVaultItemForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let recipient_id = Uuid::parse_str(&payload.recipient_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id: Option<Option<Uuid>> = sqlx::query_scalar(r#"UPDATE files SET shared_with = array_remove(shared_with, $1)
+                                WHERE file_id = ($2) AND owner_id = ($3) AND vault
+                                RETURNING parent_id;"#)
+        .bind(recipient_id)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let parent_id = parent_id.ok_or(ServerError::NotFound("Vault item not found".to_string()))?;
+    sqlx::query(r#"DELETE FROM vault_item_keys WHERE file_id = ($1) AND user_id = ($2);"#)
+        .bind(file_id)
+        .bind(recipient_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // unless a folder above is still shared with them, the item is gone for the recipient
+    record_change(&mut tx, &file_id, ChangeKind::Unshared, &[recipient_id]).await?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    if let Some(c) = state.cache.get(&owner_id).await {
+        let mut e = (*c).clone();
+        e.entry(file_id).and_modify(|f| f.shared_with.retain(|id| *id != recipient_id));
+        state.cache.insert(owner_id, Arc::new(e)).await;
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(owner_id),
+        action: "vault_item_unshared",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: Some(serde_json::json!({"recipient_id": recipient_id})),
+    }).await;
+    let mut unshared = ChangeEvent::new(ChangeKind::Unshared, Some(owner_id), file_id, owner_id,
+                                         parent_id, None);
+    // the recipient is no longer in the audience but should hear about it
+    unshared.audience.push(recipient_id);
+    announce(&state, unshared).await;
+    Ok(StatusCode::OK)
+}
+
+// items shared with the caller, or the contents of a vault folder they can read.
+// owners also get their vault items from get_files
+#[derive(sqlx::FromRow)]
+struct VaultItemRow {
+    file_id: Uuid,
+    owner_id: Uuid,
+    parent_id: Option<Uuid>,
+    file_type: FileType,
+    size: i64,
+    created_at: Option<DateTime<Utc>>,
+    wrapped_key: Option<Vec<u8>>,
+    encrypted_metadata: Option<Vec<u8>>,
+}
+
+pub async fn get_vault_items(State(state): State<AppState>,
+                             jar: CookieJar,
+                             payload: Json<VaultItemsForm>,
+) -> Result<Json<Vec<VaultItemResponse>>, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let rows: Vec<VaultItemRow> = match &payload.folder_id {
+        None => sqlx::query_as(r#"SELECT f.file_id, f.owner_id, f.parent_id, f.file_type,
+                                  f.size, f.created_at, k.wrapped_key, f.encrypted_metadata
+                                  FROM vault_item_keys k
+                                  JOIN files f ON f.file_id = k.file_id
+                                  WHERE k.user_id = ($1)
+                                  ORDER BY k.created_at DESC;"#)
+            .bind(user_id)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?,
+        Some(folder_id) => {
+            let folder_id = Uuid::parse_str(folder_id)
+                .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+            if !can_read_vault_item(&state.pool, &user_id, &folder_id).await? {
+                return Err(ServerError::Forbidden("No access to this vault folder".to_string()));
+            }
+            sqlx::query_as(r#"SELECT file_id, owner_id, parent_id, file_type,
+                              size, created_at, wrapped_key, encrypted_metadata
+                              FROM files
+                              WHERE parent_id = ($1) AND vault;"#)
+                .bind(folder_id)
+                .fetch_all(&state.pool)
+                .await
+                .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+        },
+    };
+    let items = rows.into_iter()
+        .map(|row| VaultItemResponse {
+            file_id: row.file_id,
+            owner_id: row.owner_id,
+            parent_id: row.parent_id,
+            file_type: row.file_type,
+            size: row.size,
+            created_at: row.created_at,
+            wrapped_key: row.wrapped_key.as_deref().map(encode_field),
+            encrypted_metadata: row.encrypted_metadata.as_deref().map(encode_field),
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+// link to the ciphertext for the owner or anyone it was shared with
+pub async fn download_vault_file(State(state): State<AppState>,
+                                 jar: CookieJar,
+                                 info: RequestInfo,
+                                 payload: Json<DownloadVaultFileForm>,
+) -> Result<Json<serde_json::Value>, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    if !can_read_vault_item(&state.pool, &user_id, &file_id).await? {
+        return Err(ServerError::Forbidden("No access to this vault item".to_string()));
+    }
+    let (owner_id, extension, blob_hash, file_type): (Uuid, Option<String>, Option<String>, FileType) =
+        sqlx::query_as(r#"SELECT owner_id, extension, blob_hash, file_type
+                          FROM files WHERE file_id = ($1);"#)
+        .bind(file_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if file_type == FileType::Folder {
+        return Err(ServerError::BadRequest("Folders have no content".to_string()));
+    }
+    let url = file_url(&state, &owner_id.to_string(), &payload.file_id,
+                       &extension, &blob_hash).await?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "vault_file_downloaded",
+        file_id: Some(file_id),
+        owner_id: Some(owner_id),
+        details: None,
+    }).await;
+    Ok(Json(serde_json::json!({"url": url})))
+}
+
+// the get_files listing narrowed down by media metadata
+pub async fn filter_media(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<MediaFilterForm>,
+)->Result<Json<HashMap<Uuid, FileResponse>>, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar.clone(), &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        id
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let owner_id = Uuid::parse_str(&user_id)
+        .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?;
+
+    let mut query = sqlx::QueryBuilder::new(r#"SELECT file_id FROM files
+                                               WHERE media_metadata IS NOT NULL AND owner_id = "#);
+    query.push_bind(owner_id);
+    if let Some(kind) = &payload.kind {
+        query.push(" AND media_metadata->>'kind' = ").push_bind(kind.clone());
+    }
+    // taken_at is iso, so a plain date works as a bound too
+    if let Some(after) = &payload.taken_after {
+        query.push(" AND media_metadata->>'taken_at' >= ").push_bind(after.clone());
+    }
+    if let Some(before) = &payload.taken_before {
+        query.push(" AND media_metadata->>'taken_at' <= ").push_bind(before.clone());
+    }
+    if let Some(camera) = &payload.camera {
+        query.push(r#" AND CONCAT_WS(' ', media_metadata->>'camera_make',
+                       media_metadata->>'camera_model') ILIKE "#)
+            .push_bind(contains_pattern(camera));
+    }
+    match payload.has_gps {
+        Some(true) => { query.push(" AND media_metadata ? 'gps'"); },
+        Some(false) => { query.push(" AND NOT media_metadata ? 'gps'"); },
+        None => {},
+    }
+    if let Some(width) = payload.min_width {
+        query.push(" AND (media_metadata->>'width')::int >= ").push_bind(width);
+    }
+    if let Some(height) = payload.min_height {
+        query.push(" AND (media_metadata->>'height')::int >= ").push_bind(height);
+    }
+    if let Some(duration) = payload.min_duration {
+        query.push(" AND (media_metadata->>'duration_secs')::float8 >= ").push_bind(duration);
+    }
+    if let Some(duration) = payload.max_duration {
+        query.push(" AND (media_metadata->>'duration_secs')::float8 <= ").push_bind(duration);
+    }
+    if let Some(artist) = &payload.artist {
+        query.push(" AND media_metadata->>'artist' ILIKE ").push_bind(contains_pattern(artist));
+    }
+    if let Some(album) = &payload.album {
+        query.push(" AND media_metadata->>'album' ILIKE ").push_bind(contains_pattern(album));
+    }
+    let matching: Vec<Uuid> = query.build_query_scalar()
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    // same entries get_files hands out, urls and cache included
+    let Json(mut files) = get_files(State(state), jar).await?;
+    let matching: HashSet<Uuid> = matching.into_iter().collect();
+    files.retain(|id, _| matching.contains(id));
+    Ok(Json(files))
+}
+
+// previews rendered by the thumbnail job, 404 until it has got to the file
+pub async fn get_thumbnail(State(state): State<AppState>,
+                           jar: CookieJar,
+                           extract::Path(file_id): extract::Path<String>,
+                           extract::Query(query): extract::Query<ThumbnailQuery>,
+) -> Result<Response, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&file_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let size = match &query.size {
+        Some(s) => ThumbnailSize::parse(s)
+            .ok_or(ServerError::BadRequest("Unknown thumbnail size".to_string()))?,
+        None => ThumbnailSize::Small,
+    };
+    let format = match &query.format {
+        Some(f) => ThumbnailFormat::parse(f)
+            .ok_or(ServerError::BadRequest("Unknown thumbnail format".to_string()))?,
+        None => ThumbnailFormat::Jpeg,
+    };
+    let row: Option<(String, String, Option<Uuid>)> =
+        sqlx::query_as(r#"SELECT t.bucket, t.key, t.key_id
+                          FROM thumbnails t
+                          JOIN files f ON f.file_id = t.file_id
+                          WHERE t.file_id = ($1) AND f.owner_id = ($2)
+                          AND t.size = ($3) AND t.format = ($4);"#)
+        .bind(file_id)
+        .bind(owner_id)
+        .bind(size.name())
+        .bind(format.name())
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let (bucket, key, key_id) = row
+        .ok_or(ServerError::NotFound("No thumbnail for this file".to_string()))?;
+    let object = state.store.get_stream(&bucket, &key).await?;
+    let object = decrypting(&state.pool, state.encryption.as_deref(), key_id, object).await?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_LENGTH, object.meta.size)
+        .header(header::CACHE_CONTROL, "private, max-age=86400")
+        .body(Body::from_stream(object.stream))
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
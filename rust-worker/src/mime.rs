@@ -0,0 +1,298 @@
+use std::env;
+
+use crate::models::{FileType, ServerError};
+
+// content sniffing. the multipart content type is whatever the browser guessed
+// from the extension, so the stored mime_type and FileType come from the first
+// bytes instead, with the extension only used to tell apart formats that share
+// a container (zip based office files, ole, plain text)
+const SNIFF_LEN: usize = 8192;
+
+fn magic(data: &[u8]) -> Option<&'static str> {
+    let starts = |m: &[u8]| data.starts_with(m);
+    let at = |offset: usize, m: &[u8]| data.len() >= offset + m.len() && &data[offset..offset + m.len()] == m;
+    let mime = match () {
+        _ if starts(b"\x89PNG\r\n\x1a\n") => "image/png",
+        _ if starts(b"\xff\xd8\xff") => "image/jpeg",
+        _ if starts(b"GIF87a") || starts(b"GIF89a") => "image/gif",
+        _ if starts(b"RIFF") && at(8, b"WEBP") => "image/webp",
+        _ if starts(b"RIFF") && at(8, b"WAVE") => "audio/wav",
+        _ if starts(b"RIFF") && at(8, b"AVI ") => "video/x-msvideo",
+        _ if starts(b"BM") && data.len() > 14 && at(6, b"\0\0\0\0") => "image/bmp",
+        _ if starts(b"II*\0") || starts(b"MM\0*") => "image/tiff",
+        _ if starts(b"\0\0\x01\0") => "image/x-icon",
+        _ if at(4, b"ftypheic") || at(4, b"ftypheix") || at(4, b"ftypmif1") => "image/heic",
+        _ if at(4, b"ftypavif") => "image/avif",
+        _ if at(4, b"ftypqt") => "video/quicktime",
+        _ if at(4, b"ftypM4A") => "audio/mp4",
+        _ if at(4, b"ftyp") => "video/mp4",
+        _ if starts(b"\x1a\x45\xdf\xa3") => "video/webm",
+        _ if starts(b"OggS") => "audio/ogg",
+        _ if starts(b"fLaC") => "audio/flac",
+        _ if starts(b"ID3") || starts(b"\xff\xfb") || starts(b"\xff\xf3") => "audio/mpeg",
+        _ if starts(b"MThd") => "audio/midi",
+        _ if starts(b"%PDF-") => "application/pdf",
+        _ if starts(b"{\\rtf") => "application/rtf",
+        _ if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") => "application/zip",
+        _ if starts(b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1") => "application/x-ole-storage",
+        _ if starts(b"\x1f\x8b") => "application/gzip",
+        _ if starts(b"BZh") => "application/x-bzip2",
+        _ if starts(b"\xfd7zXZ\0") => "application/x-xz",
+        _ if starts(b"7z\xbc\xaf\x27\x1c") => "application/x-7z-compressed",
+        _ if starts(b"Rar!\x1a\x07") => "application/vnd.rar",
+        _ if starts(b"\x28\xb5\x2f\xfd") => "application/zstd",
+        _ if at(257, b"ustar") => "application/x-tar",
+        _ if starts(b"SQLite format 3\0") => "application/vnd.sqlite3",
+        _ if starts(b"\0asm") => "application/wasm",
+        // executables
+        _ if starts(b"\x7fELF") => "application/x-executable",
+        _ if starts(b"MZ") => "application/x-msdownload",
+        _ if starts(b"\xfe\xed\xfa\xce") || starts(b"\xfe\xed\xfa\xcf")
+            || starts(b"\xce\xfa\xed\xfe") || starts(b"\xcf\xfa\xed\xfe") => "application/x-mach-binary",
+        // java classes share this with fat mach-o binaries, both count as executable
+        _ if starts(b"\xca\xfe\xba\xbe") => "application/x-mach-binary",
+        _ if starts(b"#!") => "text/x-shellscript",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+pub fn mime_for_extension(extension: &str) -> Option<&'static str> {
+    let mime = match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "ico" => "image/x-icon",
+        "heic" | "heif" => "image/heic",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" | "mkv" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "ogg" | "oga" => "audio/ogg",
+        "flac" => "audio/flac",
+        "mid" | "midi" => "audio/midi",
+        "pdf" => "application/pdf",
+        "rtf" => "application/rtf",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "epub" => "application/epub+zip",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+        "csv" => "text/csv",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "odp" => "application/vnd.oasis.opendocument.presentation",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "7z" => "application/x-7z-compressed",
+        "rar" => "application/vnd.rar",
+        "zst" => "application/zstd",
+        "tar" => "application/x-tar",
+        "txt" | "log" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "js" | "mjs" => "text/javascript",
+        "ts" => "text/x-typescript",
+        "rs" => "text/x-rust",
+        "py" => "text/x-python",
+        "go" => "text/x-go",
+        "java" => "text/x-java",
+        "c" | "h" => "text/x-c",
+        "cpp" | "cc" | "hpp" => "text/x-c++",
+        "rb" => "text/x-ruby",
+        "sql" => "application/sql",
+        "sh" | "bash" => "text/x-shellscript",
+        "wasm" => "application/wasm",
+        "sqlite" | "db" => "application/vnd.sqlite3",
+        "exe" | "dll" | "com" | "scr" => "application/x-msdownload",
+        "msi" => "application/x-msi",
+        "jar" => "application/java-archive",
+        "class" => "application/java-vm",
+        "apk" => "application/vnd.android.package-archive",
+        "elf" | "bin" | "so" => "application/x-executable",
+        "dylib" => "application/x-mach-binary",
+        "bat" | "cmd" | "ps1" => "text/x-shellscript",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+fn looks_like_text(data: &[u8]) -> bool {
+    if data.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => true,
+        // cut off mid character at the end of the sniffed window
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+// what the content actually is, as a mime type
+pub fn detect_mime(data: &[u8], extension: &str) -> String {
+    let head = &data[..data.len().min(SNIFF_LEN)];
+    let by_extension = mime_for_extension(extension);
+    let detected = match magic(head) {
+        // zip and ole are containers, the extension says which format is inside
+        Some("application/zip") => match by_extension {
+            Some(m) if m.contains("openxmlformats") || m.contains("opendocument")
+                || m == "application/epub+zip" || m == "application/java-archive"
+                || m == "application/vnd.android.package-archive" => m,
+            _ => "application/zip",
+        },
+        Some("application/x-ole-storage") => by_extension
+            .filter(|m| matches!(*m, "application/msword" | "application/vnd.ms-excel"
+                                     | "application/vnd.ms-powerpoint" | "application/x-msi"))
+            .unwrap_or("application/x-ole-storage"),
+        Some("application/x-mach-binary") if by_extension == Some("application/java-vm") => {
+            "application/java-vm"
+        },
+        Some(m) => m,
+        None if looks_like_text(head) => {
+            let lower = String::from_utf8_lossy(head).to_ascii_lowercase();
+            let trimmed = lower.trim_start();
+            if trimmed.starts_with("<svg") || (trimmed.starts_with("<?xml") && lower.contains("<svg")) {
+                "image/svg+xml"
+            } else if trimmed.starts_with("<!doctype html") || trimmed.starts_with("<html") {
+                "text/html"
+            } else {
+                match by_extension {
+                    // text is text, but the extension can say what kind
+                    Some(m) if m.starts_with("text/") || m == "application/json"
+                        || m == "application/xml" || m == "application/yaml"
+                        || m == "application/toml" || m == "application/sql" => m,
+                    _ => "text/plain",
+                }
+            }
+        },
+        None => "application/octet-stream",
+    };
+    detected.to_string()
+}
+
+pub fn is_executable(mime: &str) -> bool {
+    matches!(mime, "application/x-executable"
+                 | "application/x-msdownload"
+                 | "application/x-mach-binary"
+                 | "application/x-msi"
+                 | "application/java-archive"
+                 | "application/java-vm"
+                 | "application/vnd.android.package-archive"
+                 | "text/x-shellscript")
+}
+
+// what a public link may show in the browser. anything that can carry script
+// (html, svg, xml) goes out as a download
+pub fn is_safe_inline(mime: &str) -> bool {
+    matches!(mime, "image/png"
+                 | "image/jpeg"
+                 | "image/gif"
+                 | "image/webp"
+                 | "image/avif"
+                 | "image/bmp"
+                 | "application/pdf"
+                 | "text/plain")
+    || mime.starts_with("audio/")
+    || mime.starts_with("video/")
+}
+
+pub fn file_type_for(mime: &str) -> FileType {
+    match mime {
+        m if is_executable(m) => FileType::Executable,
+        m if m.starts_with("image/") || m.starts_with("video/") || m.starts_with("audio/") => FileType::Media,
+        "text/csv"
+        | "application/vnd.ms-excel"
+        | "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        | "application/vnd.oasis.opendocument.spreadsheet" => FileType::Spreadsheet,
+        "application/vnd.ms-powerpoint"
+        | "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        | "application/vnd.oasis.opendocument.presentation" => FileType::Presentation,
+        "application/pdf"
+        | "application/rtf"
+        | "application/msword"
+        | "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        | "application/vnd.oasis.opendocument.text"
+        | "application/epub+zip"
+        | "text/plain"
+        | "text/markdown" => FileType::Document,
+        "application/zip"
+        | "application/gzip"
+        | "application/x-bzip2"
+        | "application/x-xz"
+        | "application/x-7z-compressed"
+        | "application/vnd.rar"
+        | "application/zstd"
+        | "application/x-tar" => FileType::Archive,
+        m if m.starts_with("text/") => FileType::Code,
+        "application/json" | "application/xml" | "application/yaml" | "application/toml"
+        | "application/sql" | "application/wasm" => FileType::Code,
+        _ => FileType::Other,
+    }
+}
+
+// what to do when the content doesnt match what the client said it was.
+// MIME_MISMATCH_POLICY = allow | block-executables (default) | strict
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MismatchPolicy {
+    // record the detected type, nothing else
+    Allow,
+    // refuse executables named or labelled as anything else
+    BlockExecutables,
+    // refuse anything whose detected kind differs from the claimed one
+    Strict,
+}
+
+impl MismatchPolicy {
+    pub fn from_env() -> Self {
+        match env::var("MIME_MISMATCH_POLICY").unwrap_or_default().as_str() {
+            "allow" => MismatchPolicy::Allow,
+            "strict" => MismatchPolicy::Strict,
+            _ => MismatchPolicy::BlockExecutables,
+        }
+    }
+
+    pub fn check(&self, detected: &str, content_type: &str, extension: &str) -> Result<(), ServerError> {
+        // octet-stream is the browser saying it doesnt know
+        let claims: Vec<&str> = [Some(content_type), mime_for_extension(extension)]
+            .into_iter()
+            .flatten()
+            .filter(|c| !c.is_empty() && *c != "application/octet-stream")
+            .collect();
+        if claims.is_empty() {
+            return Ok(());
+        }
+        match self {
+            MismatchPolicy::Allow => Ok(()),
+            MismatchPolicy::BlockExecutables => {
+                if is_executable(detected) && !claims.iter().any(|c| is_executable(c)) {
+                    return Err(ServerError::Forbidden(format!("File content is an executable ({})", detected)));
+                }
+                Ok(())
+            },
+            MismatchPolicy::Strict => {
+                let kind = file_type_for(detected);
+                if claims.iter().any(|c| file_type_for(c) != kind) {
+                    return Err(ServerError::Forbidden(format!("File content ({}) does not match its type", detected)));
+                }
+                Ok(())
+            },
+        }
+    }
+}
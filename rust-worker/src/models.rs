@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use aws_sdk_s3 as s3;
@@ -9,16 +10,33 @@ use failure;
 use moka::future::Cache;
 use std::sync::Arc;
 use std::collections::HashMap;
+use crate::storage::{ObjectStore, StorageLayout};
+use crate::dedup::DedupConfig;
+use crate::encryption::Encryption;
+use crate::thumbnails::ThumbnailConfig;
+use crate::mime::MismatchPolicy;
+use crate::media_metadata::MetadataConfig;
+use crate::scanner::ScanConfig;
+use crate::search::SearchConfig;
+use crate::events::EventBus;
+use crate::webhooks::WebhookConfig;
+use crate::webdav::DavConfig;
+use crate::s3_gateway::S3GatewayConfig;
+use crate::sftp::SftpConfig;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct OwnerId {
     pub owner_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type,PartialEq, Clone)]
-#[sqlx(type_name="FILETYPE", rename_all="lowercase")]
-#[serde(rename_all = "lowercase")] //for deserializing
-pub enum FileType { Media, Document, Other, Folder }
+// what clients send and get back lives in servr_models, so servr-client can
+// use it without building the server
+pub use servr_models::{FileType, ScanStatus, ColourLabel, FileResponse, CreateFolderForm,
+                       DeleteFileForm, RenameFileForm, MoveFileForm, DownloadFileForm, ShareMode,
+                       CreateShareLinkForm, RevokeShareLinkForm, ShareLinkResponse, SearchForm,
+                       SearchHit, SearchResponse, ChangesQuery, ChangeEntry, ChangesResponse,
+                       CreateApiKeyForm, ApiKeyIdForm, ApiKeySignInForm, ApiKeyResponse,
+                       SignInForm, SignUpForm};
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
 pub struct DatabaseFile {
@@ -33,60 +51,360 @@ pub struct DatabaseFile {
     pub last_modified: Option<DateTime<Utc>>,
     pub url: Option<String>,
     pub shared_with: Vec<Uuid>,
+    pub blob_hash: Option<String>,
+    pub checksum_sha256: Option<String>,
+    pub checksum_md5: Option<String>,
+    pub vault: bool,
+    pub wrapped_key: Option<Vec<u8>>,
+    pub encrypted_metadata: Option<Vec<u8>>,
+    pub mime_type: Option<String>,
+    pub scan_status: ScanStatus,
+    pub media_metadata: Option<serde_json::Value>,
+    pub colour_label: Option<ColourLabel>,
+    pub starred: bool,
+    // names from file_tags, not a files column
+    pub tags: Vec<String>,
 }
-
-#[derive(Debug, Serialize, Clone)]
-pub struct FileResponse {
+// query string on the public route, the password goes in the x-share-password
+// header so it stays out of logs and history
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct ShareAccessQuery {
+    pub file_id: Option<String>,
+}
+// file requests
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct CreateFileRequestForm {
+    pub folder_id: String,
+    pub title: String,
+    pub max_file_size: Option<i64>,
+    // extensions ("pdf") or mime prefixes ("image/")
+    pub allowed_types: Option<Vec<String>>,
+    pub deadline: Option<DateTime<Utc>>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct RevokeFileRequestForm {
+    pub request_id: String,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct FileRequestResponse {
+    pub request_id: Uuid,
+    pub token: String,
+    pub folder_id: Uuid,
+    pub title: String,
+    pub max_file_size: Option<i64>,
+    pub allowed_types: Vec<String>,
+    pub deadline: Option<DateTime<Utc>>,
+    pub revoked: bool,
+    pub upload_count: i32,
+    pub created_at: Option<DateTime<Utc>>,
+}
+// what anonymous visitors get to see, no folder contents
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct PublicFileRequest {
+    pub title: String,
+    pub max_file_size: Option<i64>,
+    pub allowed_types: Vec<String>,
+    pub deadline: Option<DateTime<Utc>>,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct NotificationResponse {
+    pub notification_id: Uuid,
+    pub kind: String,
+    pub message: String,
+    pub file_id: Option<Uuid>,
+    pub read: bool,
+    pub created_at: Option<DateTime<Utc>>,
+}
+// vaults, binary fields are base64
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct SetVaultKeysForm {
+    pub public_key: String,
+    pub encrypted_private_key: String,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VaultKeysResponse {
+    pub public_key: String,
+    pub encrypted_private_key: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct GetPublicKeyForm {
+    pub email: String,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PublicKeyResponse {
+    pub user_id: Uuid,
+    pub public_key: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct CreateVaultFolderForm {
+    // empty for a vault root at the top level
+    pub parent_id: String,
+    pub wrapped_key: String,
+    pub metadata: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct UpdateVaultMetadataForm {
+    pub file_id: String,
+    pub metadata: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct ShareVaultItemForm {
+    pub file_id: String,
+    pub recipient_id: String,
+    // item key wrapped for the recipient's public key
+    pub wrapped_key: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct UnshareVaultItemForm {
+    pub file_id: String,
+    pub recipient_id: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct VaultItemsForm {
+    // none lists what has been shared with the caller
+    pub folder_id: Option<String>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct DownloadVaultFileForm {
+    pub file_id: String,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VaultItemResponse {
     pub file_id: Uuid,
     pub owner_id: Uuid,
     pub parent_id: Option<Uuid>,
-    pub file_name: String,
-    pub extension: Option<String>,
-    pub size: i64,
     pub file_type: FileType,
+    pub size: i64,
     pub created_at: Option<DateTime<Utc>>,
-    pub last_modified: Option<DateTime<Utc>>,
-    pub shared_with: Vec<Uuid>,
-    pub url: Option<String>,
+    pub wrapped_key: Option<String>,
+    pub encrypted_metadata: Option<String>,
 }
-
-// uploading
-#[derive(Debug,Deserialize)]
-pub struct CreateFolderForm {
-    //pub owner_id: String,
-    pub folder_name: String,
-    pub parent_id: String,
+// tags
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct CreateTagForm {
+    pub name: String,
 }
-
-
-//deleting
-#[derive(Debug,Deserialize)]
-pub struct DeleteFileForm {
-    pub owner_id: String,
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct RenameTagForm {
+    pub tag_id: String,
+    pub name: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct DeleteTagForm {
+    pub tag_id: String,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct TagResponse {
+    pub tag_id: Uuid,
+    pub name: String,
+    pub file_count: i64,
+    pub created_at: Option<DateTime<Utc>>,
+}
+// tags that dont exist yet are created
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct TagFilesForm {
+    pub file_ids: Vec<String>,
+    pub tags: Vec<String>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct FilesByTagForm {
+    pub tag: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct SetColourLabelForm {
+    pub file_ids: Vec<String>,
+    // null clears it
+    pub colour_label: Option<ColourLabel>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct SetStarredForm {
+    pub file_ids: Vec<String>,
+    pub starred: bool,
+}
+// comments
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct AddCommentForm {
     pub file_id: String,
+    // @email mentions anyone who can see the file
+    pub body: String,
+    // replying to
+    pub parent_id: Option<String>,
 }
-//renaming
-#[derive(Debug,Deserialize)]
-pub struct RenameFileForm {
-    pub owner_id: String,
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct GetCommentsForm {
     pub file_id: String,
-    pub file_name: String,
 }
-#[derive(Debug,Deserialize)]
-pub struct DownloadFileForm {
-    pub owner_id: String,
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct EditCommentForm {
+    pub comment_id: String,
+    pub body: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct DeleteCommentForm {
+    pub comment_id: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct ResolveCommentForm {
+    pub comment_id: String,
+    pub resolved: bool,
+}
+// flat, oldest first, replies point at their parent
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct CommentResponse {
+    pub comment_id: Uuid,
+    pub file_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub author_id: Uuid,
+    pub author_email: String,
+    // empty once deleted, the comment stays so replies keep their place
+    pub body: String,
+    pub mentions: Vec<Uuid>,
+    pub resolved: bool,
+    pub resolved_by: Option<Uuid>,
+    pub deleted: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub edited_at: Option<DateTime<Utc>>,
+}
+// admin
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct QuarantineActionForm {
     pub file_id: String,
-    pub file_extension: Option<String>,
 }
-#[derive(Debug,Deserialize)]
-pub struct SignInForm {
-    pub email: String,
-    pub password: String,
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct QuarantinedFileResponse {
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub owner_email: String,
+    pub file_name: String,
+    pub extension: Option<String>,
+    pub size: i64,
+    pub mime_type: Option<String>,
+    pub scan_result: Option<String>,
+    pub scanned_at: Option<DateTime<Utc>>,
+    pub created_at: Option<DateTime<Utc>>,
 }
-#[derive(Debug,Deserialize)]
-pub struct SignUpForm {
-    pub email: String,
-    pub password: String,
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct ActivityForm {
+    // event_id of the last event already seen, newest first
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct AuditQueryForm {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // any of, e.g. ["file_deleted", "login_failed"]
+    pub actions: Option<Vec<String>>,
+    pub actor_id: Option<Uuid>,
+    pub file_id: Option<Uuid>,
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct AuditEventResponse {
+    pub event_id: i64,
+    pub actor_id: Option<Uuid>,
+    pub actor_email: Option<String>,
+    pub action: String,
+    pub file_id: Option<Uuid>,
+    pub owner_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct CreateWebhookForm {
+    pub url: String,
+    // any of webhooks::EVENT_TYPES, empty or missing for all
+    pub event_types: Option<Vec<String>>,
+    // only changes in this folder, at any depth
+    pub folder_id: Option<String>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct WebhookIdForm {
+    pub webhook_id: String,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct SetWebhookActiveForm {
+    pub webhook_id: String,
+    pub active: bool,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct WebhookDeliveriesForm {
+    pub webhook_id: String,
+    // created_at of the last delivery already seen, newest first
+    pub before: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct WebhookResponse {
+    pub webhook_id: Uuid,
+    pub url: String,
+    pub event_types: Vec<String>,
+    pub folder_id: Option<Uuid>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    // only when the webhook is created, it is not shown again
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+#[derive(Debug, Serialize, sqlx::FromRow, JsonSchema)]
+pub struct WebhookDeliveryResponse {
+    pub delivery_id: Uuid,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    // pending, delivered or failed
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CreateAccessKeyForm {
+    pub name: String,
+}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AccessKeyIdForm {
+    pub access_key_id: String,
+}
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, JsonSchema)]
+pub struct AccessKeyResponse {
+    pub access_key_id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    // only when the key is created, it is not shown again
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_access_key: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AddSshKeyForm {
+    pub name: String,
+    // one authorized_keys line
+    pub public_key: String,
+    pub read_only: Option<bool>,
+    // a folder the key is held to
+    pub root_id: Option<Uuid>,
+}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SshKeyIdForm {
+    pub key_id: Uuid,
+}
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, JsonSchema)]
+pub struct SshKeyResponse {
+    pub key_id: Uuid,
+    pub name: String,
+    pub fingerprint: String,
+    pub read_only: bool,
+    pub root_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
 }
 #[derive(Debug,Deserialize)]
 pub struct TestToken {
@@ -96,18 +414,63 @@ pub type FileCache = Cache<Uuid, Arc<HashMap<Uuid, FileResponse>>>;
 #[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
-    pub client: s3::Client,
+    pub store: Arc<dyn ObjectStore>,
+    pub layout: StorageLayout,
+    pub dedup: DedupConfig,
+    pub encryption: Option<Arc<Encryption>>,
+    pub thumbnails: ThumbnailConfig,
+    pub mime_policy: MismatchPolicy,
+    pub scanning: ScanConfig,
+    pub metadata: MetadataConfig,
+    pub search: SearchConfig,
+    pub events: EventBus,
+    pub webhooks: WebhookConfig,
+    pub webdav: DavConfig,
+    pub s3_gateway: S3GatewayConfig,
+    pub sftp: SftpConfig,
     pub cache: Cache<Uuid, Arc<HashMap<Uuid, FileResponse>>>,
     pub key: String,
+    // base for links the worker serves itself
+    pub public_url: String,
 }
 #[derive(Clone)]
 pub struct AuthState {
     pub pool: PgPool,
     pub key: String,
-    pub client: s3::Client,
+    pub store: Arc<dyn ObjectStore>,
     // probably users cache pub cache: 
     // Cache<Uuid, Arc<HashMap<Uuid, FileResponse>>>, 
 }
+// every field narrows the listing, dates compare against exif taken_at
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct MediaFilterForm {
+    // image | audio | video
+    pub kind: Option<String>,
+    pub taken_after: Option<String>,
+    pub taken_before: Option<String>,
+    // matched against make and model
+    pub camera: Option<String>,
+    pub has_gps: Option<bool>,
+    pub min_width: Option<i32>,
+    pub min_height: Option<i32>,
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct ThumbnailQuery {
+    // small (default) | medium
+    pub size: Option<String>,
+    // jpeg (default) | webp
+    pub format: Option<String>,
+}
+// query string on links handed out by the local/memory stores
+#[derive(Debug,Deserialize, JsonSchema)]
+pub struct SignedObjectQuery {
+    pub expires: i64,
+    pub signature: String,
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
@@ -116,17 +479,24 @@ pub struct Claims {
 // error return types
 #[derive(Debug)]
 pub enum ServerError {
-    S3Error(s3::Error),
+    // boxed, s3::Error alone would make every Result carry 168 bytes
+    S3Error(Box<s3::Error>),
     // maybe ref
     InternalError(String),
     NotFound(String),   
     DatabaseError(String),
     Unauthorized(String),    
+    Forbidden(String),
+    BadRequest(String),
+    TooManyRequests(String),
+    // the upload doesnt fit in what is left of STORAGE_LIMIT. webdav and the
+    // s3 gateway answer this their own way
+    QuotaExceeded,
 }
 
 impl From<s3::Error> for ServerError {
     fn from(e: s3::Error) -> Self {
-        ServerError::S3Error(e)
+        ServerError::S3Error(Box::new(e))
     }
 }
 impl From<failure::Error> for ServerError {
@@ -166,6 +536,23 @@ impl IntoResponse for ServerError {
                     StatusCode::UNAUTHORIZED,
                     msg,
                 ).into_response(),
+            ServerError::Forbidden(msg) => (
+                    StatusCode::FORBIDDEN,
+                    msg,
+                ).into_response(),
+            ServerError::BadRequest(msg) => (
+                    StatusCode::BAD_REQUEST,
+                    msg,
+                ).into_response(),
+            ServerError::TooManyRequests(msg) => (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    msg,
+                ).into_response(),
+            // a 500 like it has always been for the json routes
+            ServerError::QuotaExceeded => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Not enough storage".to_string(),
+                ).into_response(),
 
         }
     }
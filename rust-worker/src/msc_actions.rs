@@ -2,7 +2,8 @@ use crate::models::ServerError;
 use uuid::Uuid;
 use sha2::{Sha256, Digest};
 use sqlx::PgPool;
-use aws_sdk_s3 as s3;
+use std::sync::Arc;
+use crate::storage::ObjectStore;
 
 pub async fn get_user_id(
     email: &str,
@@ -39,19 +40,71 @@ password: &str,
     hash.iter().map(|a| format!("{:02x}", a)).collect()
 }
 
-pub async fn create_bucket_func(client: s3::Client,
+// two v4 uuids back to back, 244 random bits, for public links
+pub fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+pub async fn create_bucket_func(store: &Arc<dyn ObjectStore>,
                         owner_id: &str,
 ) -> Result<(), ServerError> {
-    match client.create_bucket()
-        .bucket(owner_id)
-        .send()
-        .await{   
-            Ok(_) => return Ok(()),
-            Err(e) => {
-                        eprintln!("Error {:?}", e);    
-                        return Err(ServerError::S3Error(e.into()))
-            }
+    // in the shared layout every user maps to the same bucket
+    if store.bucket_exists(owner_id).await? {
+        return Ok(());
     }
+    store.create_bucket(owner_id).await
+}
+
+// in-app notification, shown through /get-notifications
+pub async fn notify_user(pool: &PgPool,
+                         user_id: &Uuid,
+                         kind: &str,
+                         message: &str,
+                         file_id: Option<Uuid>,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"INSERT INTO notifications (notification_id, user_id, kind,
+                   message, file_id)
+                   VALUES ($1,$2,$3,$4,$5);"#)
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(kind)
+        .bind(message)
+        .bind(file_id)
+        .execute(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(format!("Failed to notify user. Error: {}", e)))?;
+    Ok(())
 }
 
+// everyone who can see a file: its owner, plus whoever it or a folder above it
+// is shared with. takes the pool or a transaction, journal writes need the
+// audience as their transaction sees it
+pub async fn file_audience<'e, E: sqlx::PgExecutor<'e>>(db: E,
+                                                        file_id: &Uuid,
+) -> Result<Vec<Uuid>, ServerError> {
+    let audience: Vec<Uuid> = sqlx::query_scalar(r#"WITH RECURSIVE ancestors AS (
+                                                       SELECT file_id, parent_id, owner_id, shared_with
+                                                       FROM files
+                                                       WHERE file_id = ($1)
+                                                       UNION ALL
 
+                                                       SELECT f.file_id, f.parent_id, f.owner_id, f.shared_with
+                                                       FROM files f
+                                                       JOIN ancestors a ON f.file_id = a.parent_id
+                                                    )
+                                                    SELECT owner_id FROM ancestors
+                                                    UNION
+                                                    SELECT UNNEST(shared_with) FROM ancestors;"#)
+        .bind(file_id)
+        .fetch_all(db)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(audience)
+}
+
+pub async fn can_access_file(pool: &PgPool,
+                             user_id: &Uuid,
+                             file_id: &Uuid,
+) -> Result<bool, ServerError> {
+    Ok(file_audience(pool, file_id).await?.contains(user_id))
+}
@@ -0,0 +1,830 @@
+use axum::{extract::DefaultBodyLimit, response::Html, routing::MethodRouter, Json, Router};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::models::{OwnerId, FileResponse, CreateFolderForm, DeleteFileForm, RenameFileForm, MoveFileForm,
+                    DownloadFileForm, CreateShareLinkForm, RevokeShareLinkForm, ShareAccessQuery,
+                    ShareLinkResponse, CreateFileRequestForm, RevokeFileRequestForm, FileRequestResponse,
+                    PublicFileRequest, NotificationResponse, SetVaultKeysForm, VaultKeysResponse,
+                    GetPublicKeyForm, PublicKeyResponse, CreateVaultFolderForm, UpdateVaultMetadataForm,
+                    ShareVaultItemForm, UnshareVaultItemForm, VaultItemsForm, DownloadVaultFileForm,
+                    VaultItemResponse, CreateTagForm, RenameTagForm, DeleteTagForm, TagResponse, TagFilesForm,
+                    FilesByTagForm, SetColourLabelForm, SetStarredForm, AddCommentForm, GetCommentsForm,
+                    EditCommentForm, DeleteCommentForm, ResolveCommentForm, CommentResponse,
+                    QuarantineActionForm, QuarantinedFileResponse, SearchForm, SearchResponse, ActivityForm,
+                    AuditQueryForm, AuditEventResponse, CreateWebhookForm, WebhookIdForm, SetWebhookActiveForm,
+                    WebhookDeliveriesForm, WebhookResponse, WebhookDeliveryResponse, ChangesQuery,
+                    ChangesResponse, CreateApiKeyForm, ApiKeyIdForm, ApiKeySignInForm, ApiKeyResponse,
+                    CreateAccessKeyForm, AccessKeyIdForm, AccessKeyResponse, AddSshKeyForm, SshKeyIdForm,
+                    SshKeyResponse, SignInForm, SignUpForm, MediaFilterForm, ThumbnailQuery, SignedObjectQuery,
+                    FileType, ShareMode};
+use crate::models::AppState;
+use crate::events::ChangeEvent;
+use crate::methods::{get_files, create_folder, upload_file, delete_file, rename_file, move_file, download_file,
+                     create_bucket, serve_signed_object, serve_file_content, get_thumbnail, filter_media,
+                     set_vault_keys, get_vault_keys, get_public_key, create_vault_folder, upload_vault_file,
+                     update_vault_metadata, share_vault_item, unshare_vault_item, get_vault_items,
+                     download_vault_file};
+use crate::search_methods::search_files;
+use crate::tag_methods::{create_tag, get_tags, rename_tag, delete_tag, tag_files, untag_files, set_colour_label,
+                         set_starred, get_files_by_tag, get_starred};
+use crate::comment_methods::{add_comment, get_comments, edit_comment, delete_comment, resolve_comment};
+use crate::admin_methods::{get_quarantine, release_quarantined, delete_quarantined, rescan_file, get_audit_events};
+use crate::audit_methods::get_activity;
+use crate::event_methods::subscribe_events;
+use crate::change_methods::get_changes;
+use crate::webhook_methods::{create_webhook, get_webhooks, set_webhook_active, delete_webhook,
+                             get_webhook_deliveries, test_webhook};
+use crate::webdav_methods::webdav;
+use crate::s3_gateway_methods::{s3_gateway, create_access_key, get_access_keys, revoke_access_key};
+use crate::sftp_methods::{add_ssh_key, get_ssh_keys, delete_ssh_key};
+use crate::share_methods::{create_share_link, get_share_links, revoke_share_link, access_share_link};
+use crate::file_request_methods::{create_file_request, get_file_requests, revoke_file_request,
+                                  get_public_file_request, upload_to_file_request, get_notifications,
+                                  read_notifications, FILE_REQUEST_BODY_LIMIT};
+use crate::auth_methods::{login_user, create_user, read_me, logout_user, login_with_api_key, create_api_key,
+                          get_api_keys, revoke_api_key};
+use crate::setup::hello_world;
+
+// the http api as an openapi 3.1 document, at /openapi.json with a page to
+// try it at /docs. schemas are derived from the models, the operations are
+// listed below and are also the route table setup.rs serves, so a new route
+// is added here and nowhere else
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    // routed with any(), documented as each of these
+    Any(&'static [&'static str]),
+}
+
+impl Method {
+    // the axum routing function
+    pub fn router_name(&self) -> &'static str {
+        match self {
+            Method::Get => "get",
+            Method::Post => "post",
+            Method::Any(_) => "any",
+        }
+    }
+
+    pub fn verbs(&self) -> &'static [&'static str] {
+        match self {
+            Method::Get => &["get"],
+            Method::Post => &["post"],
+            Method::Any(verbs) => verbs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Auth {
+    Public,
+    // the session cookie from /sign-in
+    Session,
+    // a session for a super_user
+    Admin,
+    // session, basic or bearer
+    Dav,
+    SigV4,
+}
+
+pub struct Field {
+    pub name: &'static str,
+    pub file: bool,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+pub enum Body {
+    Empty,
+    Json(SchemaFn),
+    Multipart(&'static [Field]),
+    // whatever the protocol carries
+    Raw,
+}
+
+pub enum Reply {
+    Json(SchemaFn),
+    // a status code and no body
+    Status,
+    // sets or clears the session cookie
+    Cookie,
+    Text(&'static str),
+    // the object itself, in its own content type
+    File,
+    // a folder listing as json, or one file from it
+    FileOrJson(SchemaFn),
+    Html,
+    EventStream(SchemaFn),
+    // webdav and s3, it depends on the verb
+    Protocol(&'static str),
+}
+
+pub struct Operation {
+    pub method: Method,
+    // axum's form, {*rest} for a catch all
+    pub path: &'static str,
+    // routed to the same handler but left out of the spec
+    pub also: &'static [&'static str],
+    // the function in the route, also the operationId
+    pub handler: &'static str,
+    pub route: fn() -> MethodRouter<AppState>,
+    // instead of axum's default
+    pub body_limit: Option<usize>,
+    pub tag: &'static str,
+    pub summary: &'static str,
+    pub auth: Auth,
+    pub query: Option<SchemaFn>,
+    // request headers besides auth
+    pub headers: &'static [Field],
+    pub body: Body,
+    pub reply: Reply,
+}
+
+const fn op(method: Method,
+            path: &'static str,
+            handler: &'static str,
+            route: fn() -> MethodRouter<AppState>,
+            tag: &'static str,
+            summary: &'static str,
+) -> Operation {
+    Operation { method, path, also: &[], handler, route, body_limit: None, tag, summary, auth: Auth::Session,
+                query: None, headers: &[], body: Body::Empty, reply: Reply::Status }
+}
+
+// the name in the spec and the function routed come from the one identifier
+macro_rules! get {
+    ($path:expr, $handler:ident, $tag:expr, $summary:expr) => {
+        op(Method::Get, $path, stringify!($handler), || axum::routing::get($handler), $tag, $summary)
+    };
+}
+
+macro_rules! post {
+    ($path:expr, $handler:ident, $tag:expr, $summary:expr) => {
+        op(Method::Post, $path, stringify!($handler), || axum::routing::post($handler), $tag, $summary)
+    };
+}
+
+macro_rules! any {
+    ($method:expr, $path:expr, $handler:ident, $tag:expr, $summary:expr) => {
+        op($method, $path, stringify!($handler), || axum::routing::any($handler), $tag, $summary)
+    };
+}
+
+impl Operation {
+    const fn also(mut self, paths: &'static [&'static str]) -> Self {
+        self.also = paths;
+        self
+    }
+
+    const fn body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = Some(limit);
+        self
+    }
+
+    const fn auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    const fn query(mut self, query: SchemaFn) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    const fn headers(mut self, headers: &'static [Field]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    const fn json(mut self, body: SchemaFn) -> Self {
+        self.body = Body::Json(body);
+        self
+    }
+
+    const fn multipart(mut self, fields: &'static [Field]) -> Self {
+        self.body = Body::Multipart(fields);
+        self
+    }
+
+    const fn raw(mut self) -> Self {
+        self.body = Body::Raw;
+        self
+    }
+
+    const fn returns(mut self, reply: Reply) -> Self {
+        self.reply = reply;
+        self
+    }
+}
+
+// a $ref for structs and enums, inline for the rest
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+// the struct's own properties, for query strings
+fn inline<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    T::json_schema(generator)
+}
+
+// handlers that answer with json! rather than a model
+fn download_link(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "object",
+        "properties": {
+            "url": {"type": "string", "description": "presigned, valid for about a week"},
+            "file_name": {"type": "string"},
+        },
+        "required": ["url", "file_name"],
+    })
+}
+
+fn vault_download_link(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "object",
+        "properties": {"url": {"type": "string", "description": "presigned, to the ciphertext"}},
+        "required": ["url"],
+    })
+}
+
+fn shared_folder(generator: &mut SchemaGenerator) -> Schema {
+    let file_type = generator.subschema_for::<FileType>();
+    let mode = generator.subschema_for::<ShareMode>();
+    json_schema!({
+        "type": "object",
+        "properties": {
+            "file_name": {"type": "string"},
+            "mode": mode,
+            "files": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "file_id": {"type": "string", "format": "uuid"},
+                        "file_name": {"type": "string"},
+                        "extension": {"type": ["string", "null"]},
+                        "size": {"type": "integer"},
+                        "file_type": file_type,
+                    },
+                },
+            },
+        },
+        "required": ["file_name", "mode", "files"],
+    })
+}
+
+fn document(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({"type": "object", "description": "this document"})
+}
+
+const FILES: &str = "files";
+const TAGS: &str = "tags";
+const COMMENTS: &str = "comments";
+const VAULT: &str = "vault";
+const SHARING: &str = "sharing";
+const REQUESTS: &str = "file requests";
+const ADMIN: &str = "admin";
+const ACTIVITY: &str = "activity";
+const WEBHOOKS: &str = "webhooks";
+const WEBDAV: &str = "webdav";
+const S3: &str = "s3";
+const SSH: &str = "ssh keys";
+const AUTH: &str = "auth";
+const DOCS: &str = "docs";
+
+const UPLOAD_FIELDS: &[Field] = &[
+    Field { name: "file", file: true, required: true, description: "the file, its name comes from the part's filename" },
+    Field { name: "parent_id", file: false, required: false, description: "folder to upload into, empty for the top level" },
+    Field { name: "strip_gps", file: false, required: false, description: "\"true\" or \"false\", overrides STRIP_GPS" },
+];
+
+const VAULT_UPLOAD_FIELDS: &[Field] = &[
+    Field { name: "file", file: true, required: true, description: "ciphertext" },
+    Field { name: "parent_id", file: false, required: true, description: "a vault folder" },
+    Field { name: "wrapped_key", file: false, required: true, description: "the file key wrapped with the folder key, base64" },
+    Field { name: "metadata", file: false, required: true, description: "encrypted name and type, base64" },
+];
+
+const REQUEST_UPLOAD_FIELDS: &[Field] = &[
+    Field { name: "file", file: true, required: true, description: "the file, its name comes from the part's filename" },
+    Field { name: "name", file: false, required: false, description: "who is uploading" },
+    Field { name: "email", file: false, required: false, description: "how to reach them" },
+];
+
+const SHARE_HEADERS: &[Field] = &[
+    Field { name: "x-share-password", file: false, required: false, description: "for links with a password" },
+];
+
+pub const OPERATIONS: &[Operation] = &[
+    // files
+    post!("/get-files", get_files, FILES, "Every file and folder the user can see, by id")
+        .returns(Reply::Json(schema::<HashMap<Uuid, FileResponse>>)),
+    post!("/upload-file", upload_file, FILES, "Upload a file")
+        .multipart(UPLOAD_FIELDS)
+        .returns(Reply::Json(schema::<String>)),
+    post!("/delete-file", delete_file, FILES, "Delete a file, or a folder and everything in it")
+        .json(schema::<DeleteFileForm>)
+        .returns(Reply::Json(schema::<String>)),
+    post!("/create-bucket", create_bucket, FILES, "Create the object storage bucket for a user")
+        .auth(Auth::Public)
+        .json(schema::<OwnerId>)
+        .returns(Reply::Json(schema::<String>)),
+    post!("/rename-file", rename_file, FILES, "Rename a file or folder, the extension stays")
+        .json(schema::<RenameFileForm>)
+        .returns(Reply::Json(schema::<String>)),
+    post!("/move-file", move_file, FILES, "Move a file or folder to another folder")
+        .json(schema::<MoveFileForm>),
+    post!("/create-folder", create_folder, FILES, "Create a folder")
+        .json(schema::<CreateFolderForm>),
+    post!("/download-file", download_file, FILES, "A download link for a file")
+        .json(schema::<DownloadFileForm>)
+        .returns(Reply::Json(download_link)),
+    get!("/objects/{bucket}/{*key}", serve_signed_object, FILES, "An object behind a link from the local or memory store")
+        .auth(Auth::Public)
+        .query(inline::<SignedObjectQuery>)
+        .returns(Reply::File),
+    get!("/content/{file_id}", serve_file_content, FILES, "A file behind a download link, decrypted when encryption is on")
+        .auth(Auth::Public)
+        .query(inline::<SignedObjectQuery>)
+        .returns(Reply::File),
+    get!("/files/{file_id}/thumbnail", get_thumbnail, FILES, "A preview image, 404 until it has been rendered")
+        .query(inline::<ThumbnailQuery>)
+        .returns(Reply::File),
+    post!("/filter-media", filter_media, FILES, "Files narrowed down by their media metadata")
+        .json(schema::<MediaFilterForm>)
+        .returns(Reply::Json(schema::<HashMap<Uuid, FileResponse>>)),
+    post!("/search", search_files, FILES, "Full text search over names and content")
+        .json(schema::<SearchForm>)
+        .returns(Reply::Json(schema::<SearchResponse>)),
+    // tags, labels and stars
+    post!("/create-tag", create_tag, TAGS, "Create a tag")
+        .json(schema::<CreateTagForm>)
+        .returns(Reply::Json(schema::<TagResponse>)),
+    post!("/get-tags", get_tags, TAGS, "The user's tags with how many files have each")
+        .returns(Reply::Json(schema::<Vec<TagResponse>>)),
+    post!("/rename-tag", rename_tag, TAGS, "Rename a tag")
+        .json(schema::<RenameTagForm>),
+    post!("/delete-tag", delete_tag, TAGS, "Delete a tag, files keep everything else")
+        .json(schema::<DeleteTagForm>),
+    post!("/tag-files", tag_files, TAGS, "Add tags to files, returns how many files changed")
+        .json(schema::<TagFilesForm>)
+        .returns(Reply::Json(schema::<u64>)),
+    post!("/untag-files", untag_files, TAGS, "Take tags off files, returns how many files changed")
+        .json(schema::<TagFilesForm>)
+        .returns(Reply::Json(schema::<u64>)),
+    post!("/set-colour-label", set_colour_label, TAGS, "Set or clear the colour label on files")
+        .json(schema::<SetColourLabelForm>)
+        .returns(Reply::Json(schema::<u64>)),
+    post!("/set-starred", set_starred, TAGS, "Star or unstar files")
+        .json(schema::<SetStarredForm>)
+        .returns(Reply::Json(schema::<u64>)),
+    post!("/get-files-by-tag", get_files_by_tag, TAGS, "Files with a tag")
+        .json(schema::<FilesByTagForm>)
+        .returns(Reply::Json(schema::<HashMap<Uuid, FileResponse>>)),
+    post!("/get-starred", get_starred, TAGS, "Starred files")
+        .returns(Reply::Json(schema::<HashMap<Uuid, FileResponse>>)),
+    // comments
+    post!("/add-comment", add_comment, COMMENTS, "Comment on a file or reply to a comment")
+        .json(schema::<AddCommentForm>)
+        .returns(Reply::Json(schema::<CommentResponse>)),
+    post!("/get-comments", get_comments, COMMENTS, "Comments on a file, oldest first")
+        .json(schema::<GetCommentsForm>)
+        .returns(Reply::Json(schema::<Vec<CommentResponse>>)),
+    post!("/edit-comment", edit_comment, COMMENTS, "Edit your comment")
+        .json(schema::<EditCommentForm>),
+    post!("/delete-comment", delete_comment, COMMENTS, "Delete your comment, replies stay")
+        .json(schema::<DeleteCommentForm>),
+    post!("/resolve-comment", resolve_comment, COMMENTS, "Resolve or reopen a comment")
+        .json(schema::<ResolveCommentForm>),
+    // vault
+    post!("/set-vault-keys", set_vault_keys, VAULT, "Store the user's vault key pair")
+        .json(schema::<SetVaultKeysForm>),
+    post!("/get-vault-keys", get_vault_keys, VAULT, "The user's vault key pair")
+        .returns(Reply::Json(schema::<VaultKeysResponse>)),
+    post!("/get-public-key", get_public_key, VAULT, "Another user's vault public key, to share with them")
+        .json(schema::<GetPublicKeyForm>)
+        .returns(Reply::Json(schema::<PublicKeyResponse>)),
+    post!("/create-vault-folder", create_vault_folder, VAULT, "Create a vault folder")
+        .json(schema::<CreateVaultFolderForm>)
+        .returns(Reply::Json(schema::<FileResponse>)),
+    post!("/upload-vault-file", upload_vault_file, VAULT, "Upload an encrypted file into a vault folder")
+        .multipart(VAULT_UPLOAD_FIELDS)
+        .returns(Reply::Json(schema::<FileResponse>)),
+    post!("/update-vault-metadata", update_vault_metadata, VAULT, "Replace a vault item's encrypted metadata")
+        .json(schema::<UpdateVaultMetadataForm>),
+    post!("/share-vault-item", share_vault_item, VAULT, "Share a vault item with its key wrapped for the recipient")
+        .json(schema::<ShareVaultItemForm>),
+    post!("/unshare-vault-item", unshare_vault_item, VAULT, "Stop sharing a vault item")
+        .json(schema::<UnshareVaultItemForm>),
+    post!("/get-vault-items", get_vault_items, VAULT, "Items in a vault folder, or shared with the user")
+        .json(schema::<VaultItemsForm>)
+        .returns(Reply::Json(schema::<Vec<VaultItemResponse>>)),
+    post!("/download-vault-file", download_vault_file, VAULT, "A download link for a vault file's ciphertext")
+        .json(schema::<DownloadVaultFileForm>)
+        .returns(Reply::Json(vault_download_link)),
+    // share links
+    post!("/create-share-link", create_share_link, SHARING, "Create a public link to a file or folder")
+        .json(schema::<CreateShareLinkForm>)
+        .returns(Reply::Json(schema::<ShareLinkResponse>)),
+    post!("/get-share-links", get_share_links, SHARING, "The user's share links")
+        .returns(Reply::Json(schema::<Vec<ShareLinkResponse>>)),
+    post!("/revoke-share-link", revoke_share_link, SHARING, "Revoke a share link")
+        .json(schema::<RevokeShareLinkForm>)
+        .returns(Reply::Json(schema::<String>)),
+    get!("/s/{token}", access_share_link, SHARING, "Open a share link, a folder lists and a file downloads")
+        .auth(Auth::Public)
+        .query(inline::<ShareAccessQuery>)
+        .headers(SHARE_HEADERS)
+        .returns(Reply::FileOrJson(shared_folder)),
+    // file requests
+    post!("/create-file-request", create_file_request, REQUESTS, "Ask for uploads into a folder through a public link")
+        .json(schema::<CreateFileRequestForm>)
+        .returns(Reply::Json(schema::<FileRequestResponse>)),
+    post!("/get-file-requests", get_file_requests, REQUESTS, "The user's file requests")
+        .returns(Reply::Json(schema::<Vec<FileRequestResponse>>)),
+    post!("/revoke-file-request", revoke_file_request, REQUESTS, "Close a file request")
+        .json(schema::<RevokeFileRequestForm>)
+        .returns(Reply::Json(schema::<String>)),
+    get!("/r/{token}", get_public_file_request, REQUESTS, "What a file request asks for")
+        .auth(Auth::Public)
+        .returns(Reply::Json(schema::<PublicFileRequest>)),
+    post!("/r/{token}", upload_to_file_request, REQUESTS, "Upload to a file request")
+        .body_limit(FILE_REQUEST_BODY_LIMIT)
+        .auth(Auth::Public)
+        .multipart(REQUEST_UPLOAD_FIELDS),
+    post!("/get-notifications", get_notifications, REQUESTS, "Notifications, e.g. uploads to file requests")
+        .returns(Reply::Json(schema::<Vec<NotificationResponse>>)),
+    post!("/read-notifications", read_notifications, REQUESTS, "Mark every notification read"),
+    // admin
+    post!("/admin/quarantine", get_quarantine, ADMIN, "Files the scanner found infected")
+        .auth(Auth::Admin)
+        .returns(Reply::Json(schema::<Vec<QuarantinedFileResponse>>)),
+    post!("/admin/release-quarantined", release_quarantined, ADMIN, "Release a quarantined file")
+        .auth(Auth::Admin)
+        .json(schema::<QuarantineActionForm>),
+    post!("/admin/delete-quarantined", delete_quarantined, ADMIN, "Delete a quarantined file")
+        .auth(Auth::Admin)
+        .json(schema::<QuarantineActionForm>),
+    post!("/admin/rescan-file", rescan_file, ADMIN, "Queue a file for scanning again")
+        .auth(Auth::Admin)
+        .json(schema::<QuarantineActionForm>),
+    post!("/admin/audit-events", get_audit_events, ADMIN, "Search the audit log")
+        .auth(Auth::Admin)
+        .json(schema::<AuditQueryForm>)
+        .returns(Reply::Json(schema::<Vec<AuditEventResponse>>)),
+    // activity and changes
+    post!("/get-activity", get_activity, ACTIVITY, "The user's own audit events, newest first")
+        .json(schema::<ActivityForm>)
+        .returns(Reply::Json(schema::<Vec<AuditEventResponse>>)),
+    get!("/events", subscribe_events, ACTIVITY, "Server-sent events for changes the user can see")
+        .returns(Reply::EventStream(schema::<ChangeEvent>)),
+    get!("/changes", get_changes, ACTIVITY, "Changes since a cursor, for syncing clients")
+        .query(inline::<ChangesQuery>)
+        .returns(Reply::Json(schema::<ChangesResponse>)),
+    // webhooks
+    post!("/create-webhook", create_webhook, WEBHOOKS, "Register a webhook, the secret is only in this response")
+        .json(schema::<CreateWebhookForm>)
+        .returns(Reply::Json(schema::<WebhookResponse>)),
+    post!("/get-webhooks", get_webhooks, WEBHOOKS, "The user's webhooks")
+        .returns(Reply::Json(schema::<Vec<WebhookResponse>>)),
+    post!("/set-webhook-active", set_webhook_active, WEBHOOKS, "Pause or resume a webhook")
+        .json(schema::<SetWebhookActiveForm>),
+    post!("/delete-webhook", delete_webhook, WEBHOOKS, "Delete a webhook")
+        .json(schema::<WebhookIdForm>),
+    post!("/get-webhook-deliveries", get_webhook_deliveries, WEBHOOKS, "Recent deliveries for a webhook, newest first")
+        .json(schema::<WebhookDeliveriesForm>)
+        .returns(Reply::Json(schema::<Vec<WebhookDeliveryResponse>>)),
+    post!("/test-webhook", test_webhook, WEBHOOKS, "Queue a ping delivery")
+        .json(schema::<WebhookIdForm>)
+        .returns(Reply::Json(schema::<WebhookDeliveryResponse>)),
+    // webdav, /dav and /dav/ are the same handler
+    any!(Method::Any(&["get", "put", "delete", "options"]), "/dav/{*path}", webdav, WEBDAV,
+       "WebDAV over the folder tree, PROPFIND, MKCOL, MOVE, COPY, LOCK and UNLOCK as well")
+        .also(&["/dav", "/dav/"])
+        .auth(Auth::Dav)
+        .raw()
+        .returns(Reply::Protocol("as in rfc 4918, multistatus xml for PROPFIND")),
+    // s3 gateway, as is /s3 and /s3/
+    any!(Method::Any(&["get", "head", "put", "post", "delete"]), "/s3/{*path}", s3_gateway, S3,
+       "S3-compatible gateway, /s3/<bucket>/<key> with a top level folder as the bucket")
+        .also(&["/s3", "/s3/"])
+        .auth(Auth::SigV4)
+        .raw()
+        .returns(Reply::Protocol("as s3 answers, xml for listings and errors")),
+    post!("/create-access-key", create_access_key, S3, "Create an S3 access key, the secret is only in this response")
+        .json(schema::<CreateAccessKeyForm>)
+        .returns(Reply::Json(schema::<AccessKeyResponse>)),
+    post!("/get-access-keys", get_access_keys, S3, "The user's S3 access keys")
+        .returns(Reply::Json(schema::<Vec<AccessKeyResponse>>)),
+    post!("/revoke-access-key", revoke_access_key, S3, "Revoke an S3 access key")
+        .json(schema::<AccessKeyIdForm>),
+    // sftp keys
+    post!("/add-ssh-key", add_ssh_key, SSH, "Add a public key for the SFTP server")
+        .json(schema::<AddSshKeyForm>)
+        .returns(Reply::Json(schema::<SshKeyResponse>)),
+    post!("/get-ssh-keys", get_ssh_keys, SSH, "The user's SSH keys")
+        .returns(Reply::Json(schema::<Vec<SshKeyResponse>>)),
+    post!("/delete-ssh-key", delete_ssh_key, SSH, "Remove an SSH key")
+        .json(schema::<SshKeyIdForm>),
+    // auth
+    post!("/sign-in", login_user, AUTH, "Sign in with email and password")
+        .auth(Auth::Public)
+        .json(schema::<SignInForm>)
+        .returns(Reply::Cookie),
+    post!("/sign-up", create_user, AUTH, "Create an account")
+        .auth(Auth::Public)
+        .json(schema::<SignUpForm>),
+    post!("/sign-out", logout_user, AUTH, "End the session")
+        .returns(Reply::Cookie),
+    post!("/sign-in-key", login_with_api_key, AUTH, "Exchange an API key for a session")
+        .auth(Auth::Public)
+        .json(schema::<ApiKeySignInForm>)
+        .returns(Reply::Cookie),
+    post!("/create-api-key", create_api_key, AUTH, "Create an API key, the key is only in this response")
+        .json(schema::<CreateApiKeyForm>)
+        .returns(Reply::Json(schema::<ApiKeyResponse>)),
+    post!("/get-api-keys", get_api_keys, AUTH, "The user's API keys")
+        .returns(Reply::Json(schema::<Vec<ApiKeyResponse>>)),
+    post!("/revoke-api-key", revoke_api_key, AUTH, "Revoke an API key")
+        .json(schema::<ApiKeyIdForm>),
+    get!("/me", read_me, AUTH, "The signed in user's id")
+        .returns(Reply::Text("the user id")),
+    // this
+    get!("/openapi.json", openapi_json, DOCS, "This document")
+        .auth(Auth::Public)
+        .returns(Reply::Json(document)),
+    get!("/docs", openapi_docs, DOCS, "A page to browse and try the api")
+        .auth(Auth::Public)
+        .returns(Reply::Html),
+    get!("/", hello_world, DOCS, "Liveness check")
+        .auth(Auth::Public)
+        .returns(Reply::Text("Hello")),
+];
+
+// every operation routed, what setup.rs serves. routes on the same path are
+// merged into one by axum
+pub fn routes() -> Router<AppState> {
+    OPERATIONS.iter().fold(Router::new(), |router, op| {
+        let mut route = (op.route)();
+        if let Some(limit) = op.body_limit {
+            route = route.layer(DefaultBodyLimit::max(limit));
+        }
+        op.also.iter().chain([&op.path])
+            .fold(router, |router, path| router.route(path, route.clone()))
+    })
+}
+
+// /s3/{*path} is {path} to openapi
+pub fn openapi_path(path: &str) -> String {
+    path.replace("{*", "{")
+}
+
+fn path_params(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter_map(|part| part.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| name.trim_start_matches('*').to_string())
+        .collect()
+}
+
+fn parameters(op: &Operation, generator: &mut SchemaGenerator) -> Vec<Value> {
+    let mut parameters: Vec<Value> = path_params(op.path).into_iter()
+        .map(|name| json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}}))
+        .collect();
+    if let Some(query) = op.query {
+        let schema = query(generator).to_value();
+        let required: Vec<&str> = schema["required"].as_array()
+            .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default();
+        if let Some(properties) = schema["properties"].as_object() {
+            for (name, property) in properties {
+                parameters.push(json!({"name": name, "in": "query", "required": required.contains(&name.as_str()),
+                                       "schema": property}));
+            }
+        }
+    }
+    for header in op.headers {
+        parameters.push(json!({"name": header.name, "in": "header", "required": header.required,
+                               "description": header.description, "schema": {"type": "string"}}));
+    }
+    // what integrity.rs checks uploads against
+    if matches!(op.body, Body::Multipart(_)) {
+        for (name, description) in [("Content-MD5", "base64 md5 of the file"),
+                                    ("X-Content-SHA256", "hex sha-256 of the file"),
+                                    ("x-amz-checksum-sha256", "base64 sha-256 of the file")] {
+            parameters.push(json!({"name": name, "in": "header", "required": false,
+                                   "description": description, "schema": {"type": "string"}}));
+        }
+    }
+    parameters
+}
+
+fn request_body(body: &Body, generator: &mut SchemaGenerator) -> Option<Value> {
+    match body {
+        Body::Empty => None,
+        Body::Json(schema) => Some(json!({
+            "required": true,
+            "content": {"application/json": {"schema": schema(generator)}},
+        })),
+        Body::Multipart(fields) => {
+            let properties: Map<String, Value> = fields.iter()
+                .map(|f| (f.name.to_string(), match f.file {
+                    true => json!({"type": "string", "contentMediaType": "application/octet-stream",
+                                   "description": f.description}),
+                    false => json!({"type": "string", "description": f.description}),
+                }))
+                .collect();
+            let required: Vec<&str> = fields.iter().filter(|f| f.required).map(|f| f.name).collect();
+            Some(json!({
+                "required": true,
+                "content": {"multipart/form-data": {"schema": {
+                    "type": "object", "properties": properties, "required": required,
+                }}},
+            }))
+        },
+        Body::Raw => Some(json!({
+            "required": false,
+            "content": {"application/octet-stream": {"schema": {"type": "string", "contentMediaType": "application/octet-stream"}}},
+        })),
+    }
+}
+
+fn success(reply: &Reply, generator: &mut SchemaGenerator) -> Value {
+    let binary = json!({"schema": {"type": "string", "contentMediaType": "application/octet-stream"}});
+    match reply {
+        Reply::Json(schema) => json!({
+            "description": "OK",
+            "content": {"application/json": {"schema": schema(generator)}},
+        }),
+        Reply::Status => json!({"description": "OK, no body"}),
+        Reply::Cookie => json!({
+            "description": "OK, the session cookie is set or cleared",
+            "headers": {"Set-Cookie": {"schema": {"type": "string"}, "description": "session=<token>; HttpOnly; Path=/"}},
+        }),
+        Reply::Text(description) => json!({
+            "description": description,
+            "content": {"text/plain": {"schema": {"type": "string"}}},
+        }),
+        Reply::File => json!({
+            "description": "the file, with its own content type",
+            "content": {"*/*": binary},
+        }),
+        Reply::FileOrJson(schema) => json!({
+            "description": "a listing when the link or file_id is a folder, otherwise the file",
+            "content": {"application/json": {"schema": schema(generator)}, "*/*": binary},
+        }),
+        Reply::Html => json!({
+            "description": "OK",
+            "content": {"text/html": {"schema": {"type": "string"}}},
+        }),
+        // named after the kind, "resync" when some were missed
+        Reply::EventStream(schema) => json!({
+            "description": "one event per change, named after its kind with the change as data. \
+                            a resync event means some were missed and /get-files should be fetched again",
+            "content": {"text/event-stream": {"itemSchema": schema(generator)}},
+        }),
+        Reply::Protocol(description) => json!({
+            "description": description,
+            "content": {"*/*": binary},
+        }),
+    }
+}
+
+// errors are the message as plain text, see ServerError. the s3 gateway
+// answers in xml as s3 does
+fn responses(op: &Operation, generator: &mut SchemaGenerator) -> Value {
+    let mut responses = Map::new();
+    responses.insert("200".to_string(), success(&op.reply, generator));
+    let prefix = match op.auth {
+        Auth::SigV4 => "S3",
+        _ => "",
+    };
+    for (code, name) in [("400", "BadRequest"), ("401", "Unauthorized"), ("403", "Forbidden"),
+                         ("404", "NotFound"), ("500", "InternalError")] {
+        if code == "401" && matches!(op.auth, Auth::Public | Auth::SigV4) {
+            continue;
+        }
+        responses.insert(code.to_string(), json!({"$ref": format!("#/components/responses/{}{}", prefix, name)}));
+    }
+    Value::Object(responses)
+}
+
+fn security(auth: Auth) -> Value {
+    match auth {
+        Auth::Public => json!([]),
+        Auth::Session | Auth::Admin => json!([{"session": []}]),
+        Auth::Dav => json!([{"session": []}, {"basic": []}, {"bearer": []}]),
+        Auth::SigV4 => json!([{"sigv4": []}]),
+    }
+}
+
+fn error_responses() -> Value {
+    let mut responses = Map::new();
+    for (name, description, example) in [("BadRequest", "the request is malformed", "Invalid name"),
+                                         ("Unauthorized", "no session, or it expired", "No session token found"),
+                                         ("Forbidden", "not allowed, e.g. a quarantined file or an admin route",
+                                          "File is quarantined"),
+                                         ("NotFound", "nothing there, or nothing the user can see", "File not found"),
+                                         ("InternalError", "something went wrong on the server", "Not enough storage")] {
+        responses.insert(name.to_string(), json!({
+            "description": description,
+            "content": {"text/plain": {"schema": {"type": "string"}, "example": example}},
+        }));
+        let code = match name {
+            "BadRequest" => "InvalidArgument",
+            "Forbidden" => "AccessDenied",
+            "NotFound" => "NoSuchKey",
+            _ => "InternalError",
+        };
+        responses.insert(format!("S3{}", name), json!({
+            "description": description,
+            "content": {"application/xml": {"schema": {"type": "string"},
+                "example": format!("<Error><Code>{}</Code><Message>...</Message></Error>", code)}},
+        }));
+    }
+    Value::Object(responses)
+}
+
+pub fn spec() -> Value {
+    let mut settings = SchemaSettings::draft2020_12();
+    settings.definitions_path = "/components/schemas".into();
+    let mut generator = settings.into_generator();
+
+    let mut paths = Map::new();
+    for op in OPERATIONS {
+        let mut operation = json!({
+            "tags": [op.tag],
+            "summary": op.summary,
+            "security": security(op.auth),
+            "responses": responses(op, &mut generator),
+        });
+        let parameters = parameters(op, &mut generator);
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        if let Some(body) = request_body(&op.body, &mut generator) {
+            operation["requestBody"] = body;
+        }
+        if op.auth == Auth::Admin {
+            operation["description"] = json!("Only for users with super_user set.");
+        }
+        let item = paths.entry(openapi_path(op.path)).or_insert_with(|| json!({}));
+        for verb in op.method.verbs() {
+            let mut operation = operation.clone();
+            operation["operationId"] = match op.method {
+                Method::Any(_) => json!(format!("{}_{}", op.handler, verb)),
+                _ => json!(op.handler),
+            };
+            item[*verb] = operation;
+        }
+    }
+    let mut tags: Vec<&str> = Vec::new();
+    for op in OPERATIONS {
+        if !tags.contains(&op.tag) {
+            tags.push(op.tag);
+        }
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "servr",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "File storage api. Most routes take and return json and need the session \
+                            cookie from /sign-in or /sign-in-key. Errors are the message as plain text.",
+        },
+        "tags": tags.iter().map(|t| json!({"name": t})).collect::<Vec<Value>>(),
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "responses": error_responses(),
+            "securitySchemes": {
+                "session": {"type": "apiKey", "in": "cookie", "name": "session",
+                            "description": "set by /sign-in or /sign-in-key"},
+                "basic": {"type": "http", "scheme": "basic",
+                          "description": "email and password, or any user name with an api key as the password"},
+                "bearer": {"type": "http", "scheme": "bearer", "description": "an api key"},
+                "sigv4": {"type": "apiKey", "in": "header", "name": "Authorization",
+                          "description": "aws signature v4 with a key from /create-access-key, or a presigned url"},
+            },
+        },
+    })
+}
+
+// the same every time, built on first use
+static SPEC: LazyLock<Value> = LazyLock::new(spec);
+
+pub async fn openapi_json() -> Json<Value> {
+    Json(SPEC.clone())
+}
+
+pub async fn openapi_docs() -> Html<&'static str> {
+    Html(include_str!("openapi.html"))
+}
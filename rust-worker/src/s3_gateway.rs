@@ -0,0 +1,645 @@
+use axum::{http::{header, StatusCode},
+           response::{IntoResponse, Response}};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sha2::{Sha256, Digest};
+use sqlx::PgPool;
+use uuid::Uuid;
+use std::env;
+
+use crate::encryption::{Encryption, generate_data_key};
+use crate::models::{AppState, ServerError};
+use crate::crypto::{constant_time_eq, hmac_sha256};
+use crate::webdav::{DavEntry, percent_decode, xml_escape};
+
+// an s3 compatible endpoint over the folder tree, so aws cli, rclone and the
+// sdks can work with a user's files. path style only: /s3/<bucket>/<key>, or
+// /<bucket>/<key> on S3_GATEWAY_ADDR. a bucket is a top level folder and a key
+// the path below it. requests are signed with sigv4 by access keys from
+// /create-access-key. the handlers are in s3_gateway_methods.rs
+
+pub const S3_PREFIX: &str = "/s3";
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+pub const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// aws-chunked bodies, the first with a signature per chunk
+pub const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+pub const STREAMING_UNSIGNED_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
+pub const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+pub const EMPTY_MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+// how far x-amz-date can be from now, as s3 allows
+pub const MAX_SKEW_SECS: i64 = 15 * 60;
+// longest a presigned url can be valid for
+pub const MAX_PRESIGN_SECS: i64 = 7 * 24 * 60 * 60;
+pub const MAX_KEYS: usize = 1000;
+pub const MAX_PARTS: i32 = 10000;
+
+#[derive(Clone)]
+pub struct S3GatewayConfig {
+    pub enabled: bool,
+    // a listener with the gateway at its root, for clients that cant take a
+    // path in the endpoint url
+    pub addr: Option<String>,
+    // multipart uploads nobody completed are dropped after this
+    pub upload_ttl: chrono::Duration,
+}
+
+impl S3GatewayConfig {
+    pub fn from_env() -> Self {
+        S3GatewayConfig {
+            enabled: env::var("S3_GATEWAY").map(|v| v == "true").unwrap_or(false),
+            addr: env::var("S3_GATEWAY_ADDR").ok().filter(|a| !a.is_empty()),
+            upload_ttl: chrono::Duration::hours(env::var("S3_UPLOAD_TTL_HOURS").ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(24)),
+        }
+    }
+}
+
+// an error the way s3 clients expect it, as xml with a code they switch on
+#[derive(Debug)]
+pub struct S3Error {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl S3Error {
+    pub fn new(status: StatusCode, code: &'static str, message: &str) -> Self {
+        S3Error { status, code, message: message.to_string() }
+    }
+
+    pub fn access_denied() -> Self {
+        S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Access Denied")
+    }
+
+    pub fn signature_mismatch() -> Self {
+        S3Error::new(StatusCode::FORBIDDEN, "SignatureDoesNotMatch",
+                     "The request signature we calculated does not match the signature you provided")
+    }
+
+    pub fn no_such_bucket() -> Self {
+        S3Error::new(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist")
+    }
+
+    pub fn no_such_key() -> Self {
+        S3Error::new(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist")
+    }
+
+    pub fn no_such_upload() -> Self {
+        S3Error::new(StatusCode::NOT_FOUND, "NoSuchUpload", "The specified upload does not exist")
+    }
+
+    pub fn invalid_argument(message: &str) -> Self {
+        S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    pub fn quota_exceeded() -> Self {
+        S3Error::new(StatusCode::FORBIDDEN, "QuotaExceeded", "Not enough storage")
+    }
+}
+
+impl From<ServerError> for S3Error {
+    fn from(e: ServerError) -> Self {
+        match e {
+            ServerError::NotFound(_) => S3Error::no_such_key(),
+            ServerError::Unauthorized(_) | ServerError::Forbidden(_) => S3Error::access_denied(),
+            ServerError::BadRequest(msg) => S3Error::new(StatusCode::BAD_REQUEST, "InvalidRequest", &msg),
+            // quota, see store_file
+            ServerError::QuotaExceeded => S3Error::quota_exceeded(),
+            e => {
+                eprintln!("Error {:?}", e);
+                S3Error::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError",
+                             "We encountered an internal error. Please try again.")
+            },
+        }
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code>\
+                            <Message>{}</Message><RequestId>{}</RequestId></Error>",
+                           self.code, xml_escape(&self.message), Uuid::new_v4().simple());
+        (self.status, [(header::CONTENT_TYPE, "application/xml")], body).into_response()
+    }
+}
+
+pub fn xml_document(body: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body)
+}
+
+// 40 random hex characters, as long as aws's
+pub fn new_access_key_secret() -> String {
+    hex(&generate_data_key()[..20])
+}
+
+// what seals secrets when encryption is off, so they arent stored in the clear
+const SECRET_KEY_ID: &str = "secret-key";
+
+fn secret_key_sealer(key: &str) -> Encryption {
+    let derived: [u8; 32] = hmac_sha256(key.as_bytes(), b"s3-access-key-secrets");
+    Encryption::new(SECRET_KEY_ID.to_string(), derived, Vec::new())
+}
+
+// the secret has to come back for sigv4, so it is sealed rather than hashed.
+// under the master key when encryption is on, a key derived from SECRET_KEY
+// otherwise. returns it with the id of the master key that sealed it
+pub fn seal_secret(key: &str, encryption: Option<&Encryption>, secret: &str) -> Result<(Vec<u8>, String), ServerError> {
+    match encryption {
+        Some(encryption) => Ok((encryption.seal(secret.as_bytes())?, encryption.active_id().to_string())),
+        None => Ok((secret_key_sealer(key).seal(secret.as_bytes())?, SECRET_KEY_ID.to_string())),
+    }
+}
+
+pub fn open_secret(key: &str, encryption: Option<&Encryption>, sealed: &[u8], master_key_id: &str)
+    -> Result<String, ServerError> {
+    let plain = match (master_key_id, encryption) {
+        (SECRET_KEY_ID, _) => secret_key_sealer(key).open(SECRET_KEY_ID, sealed)?,
+        (_, Some(encryption)) => encryption.open(master_key_id, sealed)?,
+        (_, None) => return Err(ServerError::InternalError(
+            "Access key is sealed with a master key but none is configured".to_string())),
+    };
+    String::from_utf8(plain).map_err(|_| ServerError::InternalError("Invalid access key secret".to_string()))
+}
+
+// re-seals every secret not under the active master key, like rewrap_data_keys
+pub async fn reseal_secrets(pool: &PgPool, key: &str, encryption: &Encryption) -> Result<usize, ServerError> {
+    let sealed: Vec<(String, Vec<u8>, String)> =
+        sqlx::query_as(r#"SELECT access_key_id, secret, master_key_id FROM s3_access_keys
+                          WHERE master_key_id != ($1) AND revoked_at IS NULL;"#)
+        .bind(encryption.active_id())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let mut resealed = 0;
+    for (access_key_id, secret, master_key_id) in sealed {
+        let plain = open_secret(key, Some(encryption), &secret, &master_key_id)?;
+        let (secret, active_id) = seal_secret(key, Some(encryption), &plain)?;
+        sqlx::query(r#"UPDATE s3_access_keys SET secret = ($1), master_key_id = ($2)
+                       WHERE access_key_id = ($3) AND master_key_id = ($4);"#)
+            .bind(&secret)
+            .bind(&active_id)
+            .bind(&access_key_id)
+            .bind(&master_key_id)
+            .execute(pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        resealed += 1;
+    }
+    Ok(resealed)
+}
+
+// 20 characters like aws keys, SRV then 17 from a uuid
+pub fn new_access_key_id() -> String {
+    format!("SRV{}", &Uuid::new_v4().simple().to_string()[..17]).to_uppercase()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|a| format!("{:02x}", a)).collect()
+}
+
+// sigv4 escaping: unreserved characters stay, everything else is %XX. a
+// slash only stays in paths
+pub fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// decoded query pairs in the order they came. a + is a plus, clients encode
+// spaces as %20 for sigv4
+pub fn parse_query(query: Option<&str>) -> Vec<(String, String)> {
+    query.unwrap_or("").split('&')
+        .filter(|p| !p.is_empty())
+        .filter_map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            Some((percent_decode(k)?, percent_decode(v)?))
+        })
+        .collect()
+}
+
+pub fn query_value<'a>(query: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    query.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+}
+
+// each path segment decoded and escaped again the sigv4 way, s3 doesnt
+// normalize the path any further
+pub fn canonical_uri(path: &str) -> Option<String> {
+    let segments: Option<Vec<String>> = path.split('/')
+        .map(|s| percent_decode(s).map(|s| uri_encode(&s, true)))
+        .collect();
+    let uri = segments?.join("/");
+    Some(if uri.is_empty() { "/".to_string() } else { uri })
+}
+
+// sorted by name then value, without the signature of a presigned url
+pub fn canonical_query(query: &[(String, String)]) -> String {
+    let mut pairs: Vec<(String, String)> = query.iter()
+        .filter(|(k, _)| k != "X-Amz-Signature")
+        .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+        .collect();
+    pairs.sort();
+    pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&")
+}
+
+// headers are (lowercase name, value). repeated ones are joined with a comma,
+// values trimmed with inner runs of spaces collapsed
+pub fn canonical_request(method: &str,
+                         uri: &str,
+                         query: &str,
+                         headers: &[(String, String)],
+                         signed_headers: &[String],
+                         payload_hash: &str,
+) -> String {
+    let mut canonical_headers = String::new();
+    for name in signed_headers {
+        let values: Vec<String> = headers.iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, v)| v.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        canonical_headers.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+    format!("{}\n{}\n{}\n{}\n{}\n{}", method, uri, query, canonical_headers,
+            signed_headers.join(";"), payload_hash)
+}
+
+pub fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!("{}\n{}\n{}\n{}", ALGORITHM, amz_date, scope, sha256_hex(canonical_request.as_bytes()))
+}
+
+pub fn signing_key(secret: &str, date: &str, region: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, b"s3");
+    hmac_sha256(&k_service, b"aws4_request").to_vec()
+}
+
+pub fn signature(signing_key: &[u8], string_to_sign: &str) -> String {
+    hex(&hmac_sha256(signing_key, string_to_sign.as_bytes()))
+}
+
+pub fn parse_amz_date(amz_date: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ").ok().map(|d| d.and_utc())
+}
+
+// what a request was signed with, from the Authorization header or the query
+// of a presigned url
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigV4 {
+    pub access_key_id: String,
+    // yyyymmdd of the credential scope
+    pub date: String,
+    pub region: String,
+    pub signed_headers: Vec<String>,
+    pub signature: String,
+    // X-Amz-Expires of a presigned url
+    pub expires: Option<i64>,
+}
+
+impl SigV4 {
+    pub fn scope(&self) -> String {
+        format!("{}/{}/s3/aws4_request", self.date, self.region)
+    }
+
+    // AWS4-HMAC-SHA256 Credential=<id>/<date>/<region>/s3/aws4_request,
+    //   SignedHeaders=host;x-amz-date, Signature=<hex>
+    pub fn from_header(authorization: &str) -> Option<Self> {
+        let rest = authorization.strip_prefix(ALGORITHM)?.trim();
+        let mut credential = None;
+        let mut signed_headers = None;
+        let mut signature = None;
+        for part in rest.split(',') {
+            match part.trim().split_once('=') {
+                Some(("Credential", v)) => credential = Some(v),
+                Some(("SignedHeaders", v)) => signed_headers = Some(v),
+                Some(("Signature", v)) => signature = Some(v),
+                _ => {},
+            }
+        }
+        SigV4::from_parts(credential?, signed_headers?, signature?, None)
+    }
+
+    pub fn from_query(query: &[(String, String)]) -> Option<Self> {
+        if query_value(query, "X-Amz-Algorithm")? != ALGORITHM {
+            return None;
+        }
+        let expires = query_value(query, "X-Amz-Expires")?.parse::<i64>().ok()?;
+        SigV4::from_parts(query_value(query, "X-Amz-Credential")?,
+                          query_value(query, "X-Amz-SignedHeaders")?,
+                          query_value(query, "X-Amz-Signature")?,
+                          Some(expires))
+    }
+
+    fn from_parts(credential: &str, signed_headers: &str, signature: &str, expires: Option<i64>) -> Option<Self> {
+        let mut scope = credential.split('/');
+        let access_key_id = scope.next()?.to_string();
+        let date = scope.next()?.to_string();
+        let region = scope.next()?.to_string();
+        if scope.next()? != "s3" || scope.next()? != "aws4_request" || scope.next().is_some() {
+            return None;
+        }
+        let signed_headers: Vec<String> = signed_headers.split(';').map(|h| h.to_lowercase()).collect();
+        if !signed_headers.iter().any(|h| h == "host") {
+            return None;
+        }
+        Some(SigV4 { access_key_id, date, region, signed_headers, signature: signature.to_string(), expires })
+    }
+}
+
+// checks each chunk signature of an aws-chunked body as it goes
+#[derive(Clone)]
+pub struct ChunkSigner {
+    pub signing_key: Vec<u8>,
+    pub amz_date: String,
+    pub scope: String,
+    // the request's own signature, each chunk signs the one before it
+    pub seed: String,
+}
+
+// a chunk header is the size and a signature, anything longer isnt one
+const MAX_CHUNK_LINE: usize = 1024;
+
+// <hex size>[;chunk-signature=<sig>]\r\n<data>\r\n ... ending in a zero size
+// chunk, maybe followed by trailers which are ignored. fed the body as it
+// arrives, a chunk at most is held back until it is complete
+pub struct ChunkDecoder {
+    signer: Option<ChunkSigner>,
+    previous: String,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl ChunkDecoder {
+    pub fn new(signer: Option<ChunkSigner>) -> Self {
+        let previous = signer.as_ref().map(|s| s.seed.clone()).unwrap_or_default();
+        ChunkDecoder { signer, previous, buf: Vec::new(), finished: false }
+    }
+
+    // the data of the chunks completed by this piece. None when it doesnt
+    // parse or a signature is wrong
+    pub fn push(&mut self, piece: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        if self.finished {
+            return Some(out);
+        }
+        self.buf.extend_from_slice(piece);
+        let mut consumed = 0;
+        loop {
+            let rest = &self.buf[consumed..];
+            let Some(line_end) = rest.windows(2).position(|w| w == b"\r\n") else {
+                if rest.len() > MAX_CHUNK_LINE {
+                    return None;
+                }
+                break;
+            };
+            let line = std::str::from_utf8(&rest[..line_end]).ok()?;
+            let (size, extension) = line.split_once(';').unwrap_or((line, ""));
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let start = line_end + 2;
+            // the data and the crlf after it, the last chunk has neither
+            let end = match size {
+                0 => start,
+                _ => start.checked_add(size)?.checked_add(2)?,
+            };
+            if rest.len() < end {
+                break;
+            }
+            let data = &rest[start..start + size];
+            if let Some(signer) = &self.signer {
+                let chunk_signature = extension.trim().strip_prefix("chunk-signature=")?;
+                let to_sign = format!("AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}", signer.amz_date,
+                                      signer.scope, self.previous, EMPTY_SHA256, sha256_hex(data));
+                if !constant_time_eq(signature(&signer.signing_key, &to_sign).as_bytes(), chunk_signature.as_bytes()) {
+                    return None;
+                }
+                self.previous = chunk_signature.to_string();
+            }
+            if size == 0 {
+                self.finished = true;
+                self.buf = Vec::new();
+                return Some(out);
+            }
+            if &rest[start + size..end] != b"\r\n" {
+                return None;
+            }
+            out.extend_from_slice(data);
+            consumed += end;
+        }
+        self.buf.drain(..consumed);
+        Some(out)
+    }
+
+    // whether the zero size chunk has been seen, a body that stops short of
+    // it was cut off
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+// a whole aws-chunked body at once
+pub fn decode_chunked(body: &[u8], signer: Option<&ChunkSigner>) -> Option<Vec<u8>> {
+    let mut decoder = ChunkDecoder::new(signer.cloned());
+    let out = decoder.push(body)?;
+    decoder.finished().then_some(out)
+}
+
+// /<bucket>/<key> once the prefix is off, both decoded
+pub fn gateway_path(path: &str) -> Option<(Option<String>, String)> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return Some((None, String::new()));
+    }
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    Some((Some(percent_decode(bucket)?), percent_decode(key)?))
+}
+
+// a key as folder names, and whether it names a folder itself (a trailing
+// slash, how s3 tools make directories). keys with empty or dot segments
+// cant be put on the tree
+pub fn key_segments(key: &str) -> Option<(Vec<String>, bool)> {
+    let folder = key.ends_with('/');
+    let trimmed = key.strip_suffix('/').unwrap_or(key);
+    if trimmed.is_empty() {
+        return Some((Vec::new(), folder));
+    }
+    let mut segments = Vec::new();
+    for part in trimmed.split('/') {
+        if part.is_empty() || part == "." || part == ".." {
+            return None;
+        }
+        segments.push(part.to_string());
+    }
+    Some((segments, folder))
+}
+
+// bytes=a-b, bytes=a- or bytes=-n, as inclusive offsets. Some(None) when it
+// cant be satisfied, a range s3 doesnt understand is ignored
+pub fn parse_range(range: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return Some(None);
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        },
+        (start, "") => (start.parse::<u64>().ok()?, size.saturating_sub(1)),
+        (start, end) => (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?.min(size.saturating_sub(1))),
+    };
+    if start >= size || start > end {
+        return Some(None);
+    }
+    Some(Some((start, end)))
+}
+
+pub fn iso_date(date: &DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+pub fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// the inside of every <name>..</name>, for the flat bodies of DeleteObjects
+// and CompleteMultipartUpload
+pub fn elements<'a>(body: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        let inner = &rest[start + open.len()..];
+        let Some(end) = inner.find(&close) else { break };
+        found.push(&inner[..end]);
+        rest = &inner[end + close.len()..];
+    }
+    found
+}
+
+// s3 etags are the md5 of the content
+pub fn object_etag(entry: &DavEntry) -> String {
+    match &entry.checksum_md5 {
+        Some(md5) => format!("\"{}\"", md5),
+        None => entry.etag(),
+    }
+}
+
+// one key of a listing. folders only show up on their own, as "name/", when
+// there is nothing in them to imply them
+#[derive(Debug, Clone)]
+pub struct ObjectItem {
+    pub key: String,
+    pub size: i64,
+    pub etag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+pub struct ListPage<'a> {
+    pub contents: Vec<&'a ObjectItem>,
+    pub prefixes: Vec<String>,
+    pub truncated: bool,
+    // the last key or prefix of a truncated page, where the next one starts
+    pub next_marker: Option<String>,
+}
+
+fn common_prefix(key: &str, prefix: &str, delimiter: Option<&str>) -> Option<String> {
+    let delimiter = delimiter.filter(|d| !d.is_empty())?;
+    let rest = key.strip_prefix(prefix)?;
+    rest.find(delimiter).map(|i| key[..prefix.len() + i + delimiter.len()].to_string())
+}
+
+// one page of ListObjectsV2 over items sorted by key. a common prefix counts
+// as one key, and once handed out as a marker everything under it is skipped
+pub fn list_page<'a>(items: &'a [ObjectItem],
+                     prefix: &str,
+                     delimiter: Option<&str>,
+                     marker: Option<&str>,
+                     max_keys: usize,
+) -> ListPage<'a> {
+    let mut page = ListPage::default();
+    let mut count = 0;
+    let mut last = None;
+    for item in items.iter().filter(|i| i.key.starts_with(prefix)) {
+        let common = common_prefix(&item.key, prefix, delimiter);
+        if let Some(marker) = marker
+            && (item.key.as_str() <= marker || common.as_deref() == Some(marker)) {
+            continue;
+        }
+        if common.is_some() && page.prefixes.last() == common.as_ref() {
+            continue;
+        }
+        if count == max_keys {
+            page.truncated = true;
+            break;
+        }
+        count += 1;
+        match common {
+            Some(common) => {
+                last = Some(common.clone());
+                page.prefixes.push(common);
+            },
+            None => {
+                last = Some(item.key.clone());
+                page.contents.push(item);
+            },
+        }
+    }
+    if page.truncated {
+        page.next_marker = last;
+    }
+    page
+}
+
+// where the parts of a multipart upload wait, next to the user's files
+pub fn part_location(state: &AppState, owner_id: &Uuid, upload_id: &Uuid, part_number: i32) -> (String, String) {
+    let owner = owner_id.to_string();
+    (state.layout.bucket(&owner),
+     state.layout.key(&owner, &format!("multipart/{}/{}", upload_id, part_number)))
+}
+
+// parts and row of an upload that was completed, aborted or left behind
+pub async fn discard_upload(state: &AppState, owner_id: &Uuid, upload_id: &Uuid) -> Result<(), ServerError> {
+    let parts: Vec<i32> = sqlx::query_scalar("SELECT part_number FROM s3_upload_parts WHERE upload_id = ($1);")
+        .bind(upload_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    for part_number in parts {
+        let (bucket, key) = part_location(state, owner_id, upload_id, part_number);
+        state.store.delete(&bucket, &key).await?;
+    }
+    sqlx::query("DELETE FROM s3_uploads WHERE upload_id = ($1);")
+        .bind(upload_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// multipart uploads older than the ttl, returns how many went
+pub async fn expire_uploads(state: &AppState) -> Result<usize, ServerError> {
+    let cutoff = Utc::now() - state.s3_gateway.upload_ttl;
+    let stale: Vec<(Uuid, Uuid)> = sqlx::query_as(r#"SELECT upload_id, user_id FROM s3_uploads
+                                                     WHERE created_at < ($1);"#)
+        .bind(cutoff)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    for (upload_id, user_id) in &stale {
+        discard_upload(state, user_id, upload_id).await?;
+    }
+    Ok(stale.len())
+}
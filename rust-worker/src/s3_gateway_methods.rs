@@ -0,0 +1,1127 @@
+use axum::{body::{Body, BodyDataStream, Bytes},
+           extract::State,
+           http::{header, HeaderMap, Method, StatusCode, Uri},
+           response::{IntoResponse, Response},
+           Json};
+use axum_extra::extract::cookie::CookieJar;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use sha2::{Sha256, Digest};
+use uuid::Uuid;
+
+use crate::models::{AppState, ServerError, CreateAccessKeyForm, AccessKeyIdForm, AccessKeyResponse};
+use crate::auth_methods::current_user;
+use crate::msc_actions::file_audience;
+use crate::crypto::constant_time_eq;
+use crate::methods::{STORAGE_LIMIT, NewFile, make_folder, store_file, replace_file, remove_file, open_file};
+use crate::integrity::{Checksums, ExpectedChecksums, UploadHasher};
+use crate::scanner::check_download;
+use crate::audit::{RequestInfo, AuditEvent, record};
+use crate::events::{ChangeEvent, ChangeKind, announce, emit};
+use crate::encryption::{active_data_key, encrypt_stream, decrypting};
+use crate::storage::{ObjectBody, ObjectStream};
+use crate::webdav::{DavEntry, DavTree, load_tree, element_inner, http_date, percent_decode, xml_escape};
+use crate::s3_gateway::{S3Error, SigV4, ChunkSigner, ObjectItem, S3_PREFIX, XMLNS, UNSIGNED_PAYLOAD,
+                        STREAMING_PAYLOAD, STREAMING_UNSIGNED_TRAILER, EMPTY_MD5, MAX_SKEW_SECS, ChunkDecoder,
+                        MAX_PRESIGN_SECS, MAX_KEYS, MAX_PARTS, new_access_key_id, new_access_key_secret,
+                        seal_secret, open_secret,
+                        uri_encode, parse_query, query_value, canonical_uri, canonical_query,
+                        canonical_request, string_to_sign, signing_key, signature, parse_amz_date,
+                        gateway_path, key_segments, parse_range, iso_date, xml_unescape,
+                        elements, object_etag, list_page, part_location, discard_upload, xml_document};
+
+// subresources s3 has that the tree has no notion of
+const UNSUPPORTED: [&str; 14] = ["acl", "policy", "versioning", "tagging", "lifecycle", "cors", "encryption",
+                                 "website", "logging", "notification", "replication", "object-lock",
+                                 "retention", "legal-hold"];
+
+// the xml documents some requests carry, a DeleteObjects of a thousand long keys fits
+const XML_BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+type S3Result = Result<Response, S3Error>;
+
+fn xml(code: StatusCode, body: String) -> Response {
+    (code, [(header::CONTENT_TYPE, "application/xml")], xml_document(&body)).into_response()
+}
+
+fn not_implemented() -> S3Error {
+    S3Error::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented",
+                 "A header or query you provided implies functionality that is not implemented")
+}
+
+fn checksums_of(data: &[u8]) -> Checksums {
+    let mut hasher = UploadHasher::default();
+    hasher.update(data);
+    hasher.finish()
+}
+
+// the request body as it arrives, with any aws-chunked framing taken off and
+// a signed payload hash checked once the last of it has been read
+struct Payload {
+    stream: BodyDataStream,
+    decoder: Option<ChunkDecoder>,
+    signed_chunks: bool,
+    sha256: Option<(String, Sha256)>,
+}
+
+impl Payload {
+    fn new(body: Body, decoder: Option<ChunkDecoder>, signed_chunks: bool, sha256: Option<String>) -> Self {
+        Payload { stream: body.into_data_stream(), decoder, signed_chunks,
+                  sha256: sha256.map(|hash| (hash, Sha256::new())) }
+    }
+
+    fn undecodable(&self) -> S3Error {
+        match self.signed_chunks {
+            true => S3Error::signature_mismatch(),
+            false => S3Error::new(StatusCode::BAD_REQUEST, "IncompleteBody", "The request body could not be decoded"),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<Bytes>, S3Error> {
+        loop {
+            let piece = self.stream.try_next().await
+                .map_err(|e| S3Error::new(StatusCode::BAD_REQUEST, "IncompleteBody", &e.to_string()))?;
+            let Some(piece) = piece else {
+                if self.decoder.as_ref().is_some_and(|d| !d.finished()) {
+                    return Err(self.undecodable());
+                }
+                if let Some((expected, hasher)) = self.sha256.take() {
+                    let computed: String = hasher.finalize().iter().map(|a| format!("{:02x}", a)).collect();
+                    if computed != expected.to_lowercase() {
+                        return Err(S3Error::new(StatusCode::BAD_REQUEST, "XAmzContentSHA256Mismatch",
+                                                "The provided 'x-amz-content-sha256' header does not match what was computed"));
+                    }
+                }
+                return Ok(None);
+            };
+            let data = match &mut self.decoder {
+                Some(decoder) => match decoder.push(&piece) {
+                    Some(data) => Bytes::from(data),
+                    None => return Err(self.undecodable()),
+                },
+                None => piece,
+            };
+            if let Some((_, hasher)) = &mut self.sha256 {
+                hasher.update(&data);
+            }
+            if !data.is_empty() {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    // all of it, or the error once it runs past limit
+    async fn read_to_end(&mut self, limit: i64, too_large: fn() -> S3Error) -> Result<Bytes, S3Error> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.next().await? {
+            if (data.len() + chunk.len()) as i64 > limit {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+fn xml_too_large() -> S3Error {
+    S3Error::new(StatusCode::BAD_REQUEST, "MaxMessageLengthExceeded", "Your request was too big")
+}
+
+async fn read_all(object: ObjectBody) -> Result<Vec<u8>, ServerError> {
+    object.stream.map_ok(|chunk| chunk.to_vec()).try_concat().await
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+// under /s3
+pub async fn s3_gateway(State(state): State<AppState>,
+                        method: Method,
+                        uri: Uri,
+                        headers: HeaderMap,
+                        info: RequestInfo,
+                        body: Body,
+) -> Response {
+    handle(&state, S3_PREFIX, method, uri, headers, info, body).await
+}
+
+// everything on S3_GATEWAY_ADDR
+pub async fn s3_gateway_root(State(state): State<AppState>,
+                             method: Method,
+                             uri: Uri,
+                             headers: HeaderMap,
+                             info: RequestInfo,
+                             body: Body,
+) -> Response {
+    handle(&state, "", method, uri, headers, info, body).await
+}
+
+async fn handle(state: &AppState,
+                prefix: &str,
+                method: Method,
+                uri: Uri,
+                headers: HeaderMap,
+                info: RequestInfo,
+                body: Body,
+) -> Response {
+    if !state.s3_gateway.enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let query = parse_query(uri.query());
+    let result: S3Result = async {
+        let (user_id, body) = authenticate(state, &method, &uri, &headers, &query, &info, body).await?;
+        let path = uri.path().strip_prefix(prefix).unwrap_or(uri.path());
+        let (bucket, key) = gateway_path(path).ok_or(S3Error::invalid_argument("Invalid path"))?;
+        let tree = load_tree(state, &user_id).await?;
+        let gateway = Gateway { state, user_id, headers: &headers, info: &info, query: &query, tree };
+        gateway.route(&method, bucket, key, body).await
+    }.await;
+    result.unwrap_or_else(|e| e.into_response())
+}
+
+async fn failed_login(state: &AppState, info: &RequestInfo, access_key_id: &str, reason: &str) {
+    record(&state.pool, info, AuditEvent {
+        action: "login_failed",
+        details: Some(serde_json::json!({"access_key_id": access_key_id, "s3": true, "reason": reason})),
+        ..Default::default()
+    }).await;
+}
+
+// sigv4 from the Authorization header or a presigned query, returns the user
+// and the body to be read as it arrives. the body is a raw one rather than
+// Bytes, so axum's default limit doesnt turn parts into 413s
+async fn authenticate(state: &AppState,
+                      method: &Method,
+                      uri: &Uri,
+                      headers: &HeaderMap,
+                      query: &[(String, String)],
+                      info: &RequestInfo,
+                      body: Body,
+) -> Result<(Uuid, Payload), S3Error> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (auth, amz_date, payload_hash) = match header("authorization") {
+        Some(authorization) => {
+            let auth = SigV4::from_header(authorization).ok_or(S3Error::access_denied())?;
+            let amz_date = header("x-amz-date").ok_or(S3Error::access_denied())?;
+            let payload_hash = header("x-amz-content-sha256")
+                .ok_or(S3Error::invalid_argument("Missing required header x-amz-content-sha256"))?;
+            (auth, amz_date.to_string(), payload_hash.to_string())
+        },
+        None => {
+            let auth = SigV4::from_query(query).ok_or(S3Error::access_denied())?;
+            let amz_date = query_value(query, "X-Amz-Date").ok_or(S3Error::access_denied())?;
+            (auth, amz_date.to_string(), UNSIGNED_PAYLOAD.to_string())
+        },
+    };
+    let signed_at = parse_amz_date(&amz_date)
+        .filter(|_| amz_date.starts_with(&auth.date))
+        .ok_or(S3Error::access_denied())?;
+    let now = Utc::now();
+    match auth.expires {
+        Some(expires) => {
+            if !(1..=MAX_PRESIGN_SECS).contains(&expires)
+                || now > signed_at + chrono::Duration::seconds(expires)
+                || (signed_at - now).num_seconds() > MAX_SKEW_SECS {
+                return Err(S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", "Request has expired"));
+            }
+        },
+        None => if (now - signed_at).num_seconds().abs() > MAX_SKEW_SECS {
+            return Err(S3Error::new(StatusCode::FORBIDDEN, "RequestTimeTooSkewed",
+                                    "The difference between the request time and the current time is too large"));
+        },
+    }
+
+    let found: Option<(Uuid, Vec<u8>, String)> = sqlx::query_as(r#"SELECT k.user_id, k.secret, k.master_key_id
+                                                                  FROM s3_access_keys k
+                                                                  JOIN users u ON u.user_id = k.user_id
+                                                                  WHERE k.access_key_id = ($1)
+                                                                  AND k.revoked_at IS NULL AND u.active;"#)
+        .bind(&auth.access_key_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let Some((user_id, sealed, master_key_id)) = found else {
+        failed_login(state, info, &auth.access_key_id, "unknown key").await;
+        return Err(S3Error::new(StatusCode::FORBIDDEN, "InvalidAccessKeyId",
+                                "The AWS Access Key Id you provided does not exist in our records"));
+    };
+
+    let mut signed: Vec<(String, String)> = headers.iter()
+        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    // http/2 has no Host header, the authority stands in for it
+    if !headers.contains_key(header::HOST) && let Some(authority) = uri.authority() {
+        signed.push(("host".to_string(), authority.to_string()));
+    }
+    let path = canonical_uri(uri.path()).ok_or(S3Error::invalid_argument("Invalid path"))?;
+    let canonical = canonical_request(method.as_str(), &path, &canonical_query(query), &signed,
+                                      &auth.signed_headers, &payload_hash);
+    let scope = auth.scope();
+    let secret = open_secret(&state.key, state.encryption.as_deref(), &sealed, &master_key_id)?;
+    let key = signing_key(&secret, &auth.date, &auth.region);
+    let expected = signature(&key, &string_to_sign(&amz_date, &scope, &canonical));
+    if !constant_time_eq(expected.as_bytes(), auth.signature.as_bytes()) {
+        failed_login(state, info, &auth.access_key_id, "signature").await;
+        return Err(S3Error::signature_mismatch());
+    }
+    sqlx::query("UPDATE s3_access_keys SET last_used_at = NOW() WHERE access_key_id = ($1);")
+        .bind(&auth.access_key_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let body = match payload_hash.as_str() {
+        UNSIGNED_PAYLOAD => Payload::new(body, None, false, None),
+        STREAMING_PAYLOAD => {
+            let signer = ChunkSigner { signing_key: key, amz_date, scope, seed: auth.signature };
+            Payload::new(body, Some(ChunkDecoder::new(Some(signer))), true, None)
+        },
+        STREAMING_UNSIGNED_TRAILER => Payload::new(body, Some(ChunkDecoder::new(None)), false, None),
+        hash if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) =>
+            Payload::new(body, None, false, Some(hash.to_string())),
+        _ => return Err(not_implemented()),
+    };
+    Ok((user_id, body))
+}
+
+#[derive(sqlx::FromRow)]
+struct Upload {
+    upload_id: Uuid,
+    content_type: String,
+}
+
+// one request, like Dav in webdav_methods.rs
+struct Gateway<'a> {
+    state: &'a AppState,
+    user_id: Uuid,
+    headers: &'a HeaderMap,
+    info: &'a RequestInfo,
+    query: &'a [(String, String)],
+    tree: DavTree,
+}
+
+impl Gateway<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        query_value(self.query, name)
+    }
+
+    // the size of the object a request carries, aws-chunked framing aside
+    fn decoded_length(&self) -> Option<i64> {
+        self.header("x-amz-decoded-content-length")
+            .or(self.header("content-length"))
+            .and_then(|l| l.parse().ok())
+    }
+
+    async fn route(&self, method: &Method, bucket: Option<String>, key: String, body: Payload) -> S3Result {
+        if self.query.iter().any(|(k, _)| UNSUPPORTED.contains(&k.as_str())) {
+            return Err(not_implemented());
+        }
+        let Some(bucket) = bucket else {
+            return match *method == Method::GET {
+                true => self.list_buckets(),
+                false => Err(not_implemented()),
+            };
+        };
+        if key.is_empty() {
+            return match method.as_str() {
+                "GET" if self.param("location").is_some() => self.bucket_location(&bucket),
+                "GET" if self.param("uploads").is_some() => self.list_uploads(&bucket).await,
+                "GET" => self.list_objects(&bucket),
+                "HEAD" => self.bucket_folder(&bucket).map(|_| StatusCode::OK.into_response()),
+                "PUT" => self.create_bucket(&bucket).await,
+                "DELETE" => self.delete_bucket(&bucket).await,
+                "POST" if self.param("delete").is_some() => self.delete_objects(&bucket, body).await,
+                _ => Err(not_implemented()),
+            };
+        }
+        match (method.as_str(), self.param("uploadId")) {
+            ("GET", Some(upload_id)) => self.list_parts(&bucket, &key, upload_id).await,
+            ("GET", None) => self.get_object(&bucket, &key, true).await,
+            ("HEAD", None) => self.get_object(&bucket, &key, false).await,
+            ("PUT", Some(upload_id)) => self.upload_part(&bucket, &key, upload_id, body).await,
+            ("PUT", None) if self.header("x-amz-copy-source").is_some() => self.copy_object(&bucket, &key).await,
+            ("PUT", None) => self.put_object(&bucket, &key, body).await,
+            ("POST", None) if self.param("uploads").is_some() => self.create_upload(&bucket, &key).await,
+            ("POST", Some(upload_id)) => self.complete_upload(&bucket, &key, upload_id, body).await,
+            ("DELETE", Some(upload_id)) => self.abort_upload(&bucket, &key, upload_id).await,
+            ("DELETE", None) => self.delete_object(&bucket, &key).await,
+            _ => Err(not_implemented()),
+        }
+    }
+
+    fn bucket_folder(&self, bucket: &str) -> Result<&DavEntry, S3Error> {
+        self.tree.child(None, bucket)
+            .filter(|e| e.is_folder())
+            .ok_or(S3Error::no_such_bucket())
+    }
+
+    fn segments(key: &str) -> Result<(Vec<String>, bool), S3Error> {
+        key_segments(key).ok_or(S3Error::invalid_argument("Keys cannot have empty, . or .. segments"))
+    }
+
+    // what a key names under a bucket, a folder only when asked for with a
+    // trailing slash
+    fn lookup<'t>(&'t self, bucket: &'t DavEntry, key: &str) -> Result<Option<&'t DavEntry>, S3Error> {
+        let (segments, folder) = Gateway::segments(key)?;
+        if segments.is_empty() {
+            return Ok(None);
+        }
+        let mut current = bucket;
+        for part in &segments {
+            if !current.is_folder() {
+                return Ok(None);
+            }
+            match self.tree.child(Some(current.file_id), part) {
+                Some(child) => current = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(current).filter(|e| e.is_folder() == folder))
+    }
+
+    async fn storage_used(&self) -> Result<i64, S3Error> {
+        sqlx::query_scalar("SELECT storage_used FROM users WHERE user_id = ($1);")
+            .bind(self.user_id)
+            .fetch_one(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()).into())
+    }
+
+    async fn audit(&self, action: &str, file_id: Uuid, details: serde_json::Value) {
+        record(&self.state.pool, self.info, AuditEvent {
+            actor_id: Some(self.user_id),
+            action,
+            file_id: Some(file_id),
+            owner_id: Some(self.user_id),
+            details: Some(details),
+        }).await;
+    }
+
+    async fn created(&self, file_id: Uuid, parent_id: Option<Uuid>, name: &str) {
+        announce(self.state, ChangeEvent::new(ChangeKind::Created, Some(self.user_id), file_id,
+                                              self.user_id, parent_id, Some(name.to_string()))).await;
+    }
+
+    async fn remove(&self, entry: &DavEntry) -> Result<(), S3Error> {
+        let audience = file_audience(&self.state.pool, &entry.file_id).await?;
+        let (file_name, parent_id) = remove_file(self.state, self.user_id, entry.file_id).await?;
+        self.audit("file_deleted", entry.file_id, serde_json::json!({"name": file_name, "via": "s3"})).await;
+        let mut deleted = ChangeEvent::new(ChangeKind::Deleted, Some(self.user_id), entry.file_id,
+                                           self.user_id, parent_id, Some(file_name));
+        deleted.audience = audience;
+        emit(self.state, deleted).await;
+        Ok(())
+    }
+
+    // s3 has no directories, so the folders on the way to a key are made as
+    // needed. returns the innermost
+    async fn ensure_folders(&self, bucket: &DavEntry, folders: &[String]) -> Result<Uuid, S3Error> {
+        let mut current = bucket.file_id;
+        let mut fresh = false;
+        for name in folders {
+            if !fresh {
+                match self.tree.child(Some(current), name) {
+                    Some(e) if e.is_folder() => {
+                        current = e.file_id;
+                        continue;
+                    },
+                    Some(_) => return Err(S3Error::new(StatusCode::CONFLICT, "InvalidRequest",
+                                                       "A file is in the way of that key")),
+                    None => fresh = true,
+                }
+            }
+            let folder_id = make_folder(self.state, self.user_id, Some(current), name).await?;
+            self.audit("folder_created", folder_id, serde_json::json!({
+                "name": name, "parent_id": current, "via": "s3",
+            })).await;
+            self.created(folder_id, Some(current), name).await;
+            current = folder_id;
+        }
+        Ok(current)
+    }
+
+    // what the file at a key takes now, it comes back when the key is overwritten
+    fn replaced_size(&self, bucket: &DavEntry, key: &str) -> Result<i64, S3Error> {
+        Ok(self.lookup(bucket, key)?
+            .filter(|e| !e.is_folder())
+            .map_or(0, |e| e.size))
+    }
+
+    // a file at a key, through store_file like any upload. a file already
+    // there gets the new content in place and keeps its id, and with it its
+    // tags, comments and shares. returns the etag
+    async fn store_object(&self,
+                          bucket: &DavEntry,
+                          key: &str,
+                          data: Bytes,
+                          checksums: &Checksums,
+                          content_type: &str,
+    ) -> Result<String, S3Error> {
+        let (segments, folder) = Gateway::segments(key)?;
+        let (name, folders) = match segments.split_last() {
+            Some(split) if !folder => split,
+            _ => return Err(S3Error::invalid_argument("A key ending in / can only be an empty folder")),
+        };
+        let replaced = self.replaced_size(bucket, key)?;
+        if self.storage_used().await? - replaced + data.len() as i64 > STORAGE_LIMIT {
+            return Err(S3Error::quota_exceeded());
+        }
+        let parent_id = self.ensure_folders(bucket, folders).await?;
+        let existing = self.tree.child(Some(parent_id), name);
+        if existing.is_some_and(|e| e.is_folder()) {
+            return Err(S3Error::new(StatusCode::CONFLICT, "InvalidRequest", "A folder already has that name"));
+        }
+        let file = NewFile {
+            owner_id: self.user_id,
+            parent_id: Some(parent_id),
+            filename: name,
+            content_type,
+            data,
+            checksums,
+            strip_gps: self.state.metadata.strip_gps,
+        };
+        let stored = match existing {
+            Some(old) => replace_file(self.state, old.file_id, file).await?,
+            None => store_file(self.state, file, None).await?,
+        };
+        self.audit("file_uploaded", stored.file_id, serde_json::json!({
+            "name": name, "size": stored.size, "parent_id": parent_id, "via": "s3",
+            "overwritten": existing.is_some(),
+        })).await;
+        match existing {
+            Some(_) => announce(self.state, ChangeEvent::new(ChangeKind::Modified, Some(self.user_id),
+                                                             stored.file_id, self.user_id, Some(parent_id),
+                                                             Some(name.to_string()))).await,
+            None => self.created(stored.file_id, Some(parent_id), name).await,
+        }
+        Ok(format!("\"{}\"", checksums.md5))
+    }
+
+    fn list_buckets(&self) -> S3Result {
+        let buckets: String = self.tree.children(None).iter()
+            .filter(|e| e.is_folder())
+            .map(|e| format!("<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                             xml_escape(&e.name()),
+                             iso_date(&e.created_at.unwrap_or_default())))
+            .collect();
+        Ok(xml(StatusCode::OK, format!("<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>{}</ID>\
+                                        <DisplayName>{}</DisplayName></Owner><Buckets>{}</Buckets>\
+                                        </ListAllMyBucketsResult>",
+                                       XMLNS, self.user_id, self.user_id, buckets)))
+    }
+
+    // there are no regions, an empty constraint is us-east-1 to clients
+    fn bucket_location(&self, bucket: &str) -> S3Result {
+        self.bucket_folder(bucket)?;
+        Ok(xml(StatusCode::OK, format!("<LocationConstraint xmlns=\"{}\"></LocationConstraint>", XMLNS)))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> S3Result {
+        match self.tree.child(None, bucket) {
+            Some(e) if e.is_folder() => return Err(S3Error::new(StatusCode::CONFLICT, "BucketAlreadyOwnedByYou",
+                "Your previous request to create the named bucket succeeded and you already own it")),
+            Some(_) => return Err(S3Error::new(StatusCode::CONFLICT, "BucketAlreadyExists",
+                                               "A file already has that name")),
+            None => {},
+        }
+        if bucket.contains('/') || bucket == "." || bucket == ".." {
+            return Err(S3Error::new(StatusCode::BAD_REQUEST, "InvalidBucketName", "The specified bucket is not valid"));
+        }
+        let folder_id = make_folder(self.state, self.user_id, None, bucket).await?;
+        self.audit("folder_created", folder_id, serde_json::json!({"name": bucket, "parent_id": null, "via": "s3"})).await;
+        self.created(folder_id, None, bucket).await;
+        Ok((StatusCode::OK, [(header::LOCATION, format!("/{}", uri_encode(bucket, true)))]).into_response())
+    }
+
+    async fn delete_bucket(&self, bucket: &str) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        if !self.tree.children(Some(folder.file_id)).is_empty() {
+            return Err(S3Error::new(StatusCode::CONFLICT, "BucketNotEmpty",
+                                    "The bucket you tried to delete is not empty"));
+        }
+        self.remove(folder).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    // every key under a folder. folders with something in them are implied by
+    // it, empty ones are listed as "name/"
+    fn collect(&self, folder_id: Uuid, prefix: &str, out: &mut Vec<ObjectItem>) {
+        for child in self.tree.children(Some(folder_id)) {
+            let key = format!("{}{}", prefix, child.name());
+            if !child.is_folder() {
+                out.push(ObjectItem { key, size: child.size, etag: object_etag(child),
+                                      last_modified: child.last_modified });
+            } else if self.tree.children(Some(child.file_id)).is_empty() {
+                out.push(ObjectItem { key: format!("{}/", key), size: 0, etag: format!("\"{}\"", EMPTY_MD5),
+                                      last_modified: child.last_modified.or(child.created_at) });
+            } else {
+                self.collect(child.file_id, &format!("{}/", key), out);
+            }
+        }
+    }
+
+    // ListObjectsV2 with list-type=2, the older ListObjects otherwise. both
+    // page on the last key, v2 hides it in an opaque token
+    fn list_objects(&self, bucket: &str) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        let v2 = self.param("list-type") == Some("2");
+        let prefix = self.param("prefix").unwrap_or("");
+        let delimiter = self.param("delimiter").filter(|d| !d.is_empty());
+        let max_keys = match self.param("max-keys") {
+            Some(v) => v.parse::<usize>().map_err(|_| S3Error::invalid_argument("Invalid max-keys"))?.min(MAX_KEYS),
+            None => MAX_KEYS,
+        };
+        let token_engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let marker = match (v2, self.param("continuation-token")) {
+            (true, Some(token)) => Some(token_engine.decode(token).ok()
+                .and_then(|t| String::from_utf8(t).ok())
+                .ok_or(S3Error::invalid_argument("The continuation token provided is incorrect"))?),
+            (true, None) => self.param("start-after").map(|s| s.to_string()),
+            (false, _) => self.param("marker").map(|s| s.to_string()),
+        };
+        let url_encoded = self.param("encoding-type") == Some("url");
+        let encode = |s: &str| if url_encoded { uri_encode(s, false) } else { xml_escape(s) };
+
+        let mut items = Vec::new();
+        self.collect(folder.file_id, "", &mut items);
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+        let page = list_page(&items, prefix, delimiter, marker.as_deref(), max_keys);
+
+        let mut body = format!("<ListBucketResult xmlns=\"{}\"><Name>{}</Name><Prefix>{}</Prefix>\
+                                <MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+                               XMLNS, xml_escape(bucket), encode(prefix), max_keys, page.truncated);
+        if let Some(delimiter) = delimiter {
+            body.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+        }
+        if url_encoded {
+            body.push_str("<EncodingType>url</EncodingType>");
+        }
+        if v2 {
+            body.push_str(&format!("<KeyCount>{}</KeyCount>", page.contents.len() + page.prefixes.len()));
+            if let Some(token) = self.param("continuation-token") {
+                body.push_str(&format!("<ContinuationToken>{}</ContinuationToken>", xml_escape(token)));
+            }
+            if let Some(start_after) = self.param("start-after") {
+                body.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+            }
+            if let Some(next) = &page.next_marker {
+                body.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>",
+                                       token_engine.encode(next)));
+            }
+        } else {
+            body.push_str(&format!("<Marker>{}</Marker>", encode(marker.as_deref().unwrap_or(""))));
+            if let Some(next) = &page.next_marker {
+                body.push_str(&format!("<NextMarker>{}</NextMarker>", encode(next)));
+            }
+        }
+        for item in &page.contents {
+            body.push_str(&format!("<Contents><Key>{}</Key><LastModified>{}</LastModified>\
+                                    <ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                                   encode(&item.key), iso_date(&item.last_modified.unwrap_or_default()),
+                                   xml_escape(&item.etag), item.size));
+        }
+        for common in &page.prefixes {
+            body.push_str(&format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", encode(common)));
+        }
+        body.push_str("</ListBucketResult>");
+        Ok(xml(StatusCode::OK, body))
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str, with_body: bool) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        let entry = self.lookup(folder, key)?.ok_or(S3Error::no_such_key())?;
+        let mut response = Response::builder()
+            .header(header::ACCEPT_RANGES, "bytes");
+        if let Some(modified) = entry.last_modified.or(entry.created_at) {
+            response = response.header(header::LAST_MODIFIED, http_date(&modified));
+        }
+        // the marker of an empty folder
+        if entry.is_folder() {
+            return response.status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/x-directory")
+                .header(header::CONTENT_LENGTH, 0)
+                .header(header::ETAG, format!("\"{}\"", EMPTY_MD5))
+                .body(Body::empty())
+                .map_err(|e| ServerError::InternalError(e.to_string()).into());
+        }
+        check_download(&self.state.scanning, &entry.scan_status)?;
+        response = response
+            .header(header::CONTENT_TYPE, entry.mime_type.as_deref().unwrap_or("application/octet-stream"))
+            .header(header::ETAG, object_etag(entry));
+        let size = entry.size.max(0) as u64;
+        let range = match self.header("range").map(|r| parse_range(r, size)) {
+            Some(Some(None)) => return Err(S3Error::new(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange",
+                                                        "The requested range is not satisfiable")),
+            Some(Some(range)) => range,
+            _ => None,
+        };
+        if !with_body {
+            return response.status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, size)
+                .body(Body::empty())
+                .map_err(|e| ServerError::InternalError(e.to_string()).into());
+        }
+        let object = open_file(self.state, &self.user_id.to_string(), &entry.file_id.to_string(),
+                               &entry.extension, &entry.blob_hash, entry.key_id).await?;
+        self.audit("file_downloaded", entry.file_id, serde_json::json!({"via": "s3"})).await;
+        let response = match range {
+            None => response.status(StatusCode::OK)
+                .header(header::CONTENT_LENGTH, size)
+                .body(Body::from_stream(object.stream)),
+            // cut from the whole object, the quota keeps files small
+            Some((start, end)) => {
+                let data = read_all(object).await?;
+                let part = data.get(start as usize..=end as usize)
+                    .ok_or(ServerError::InternalError("Object shorter than its size".to_string()))?
+                    .to_vec();
+                response.status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+                    .header(header::CONTENT_LENGTH, part.len())
+                    .body(Body::from(part))
+            },
+        };
+        response.map_err(|e| ServerError::InternalError(e.to_string()).into())
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, mut body: Payload) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        let (segments, is_folder) = Gateway::segments(key)?;
+        // store_file takes the file whole, the quota is what bounds it. an
+        // overwritten file gives back what it took
+        let left = STORAGE_LIMIT - self.storage_used().await? + self.replaced_size(folder, key)?;
+        if self.decoded_length().is_some_and(|l| l > left) {
+            return Err(S3Error::quota_exceeded());
+        }
+        let data = body.read_to_end(left, S3Error::quota_exceeded).await?;
+        if is_folder {
+            if !data.is_empty() {
+                return Err(S3Error::invalid_argument("A key ending in / can only be an empty folder"));
+            }
+            self.ensure_folders(folder, &segments).await?;
+            return Ok((StatusCode::OK, [(header::ETAG, format!("\"{}\"", EMPTY_MD5))]).into_response());
+        }
+        let checksums = checksums_of(&data);
+        ExpectedChecksums::from_headers(self.headers)?.verify(&checksums)?;
+        let content_type = self.header("content-type").unwrap_or("application/octet-stream").to_string();
+        let etag = self.store_object(folder, key, data, &checksums, &content_type).await?;
+        Ok((StatusCode::OK, [(header::ETAG, etag)]).into_response())
+    }
+
+    // within the user's own files. a copy is a new upload, charged and
+    // scanned like one
+    async fn copy_object(&self, bucket: &str, key: &str) -> S3Result {
+        let source = self.header("x-amz-copy-source").unwrap_or("");
+        let source = source.split('?').next().and_then(percent_decode)
+            .ok_or(S3Error::invalid_argument("Invalid copy source"))?;
+        let (source_bucket, source_key) = source.trim_start_matches('/').split_once('/')
+            .ok_or(S3Error::invalid_argument("Invalid copy source"))?;
+        let source_folder = self.bucket_folder(source_bucket)?;
+        let entry = self.lookup(source_folder, source_key)?
+            .filter(|e| !e.is_folder())
+            .ok_or(S3Error::no_such_key())?;
+        check_download(&self.state.scanning, &entry.scan_status)?;
+        let object = open_file(self.state, &self.user_id.to_string(), &entry.file_id.to_string(),
+                               &entry.extension, &entry.blob_hash, entry.key_id).await?;
+        let data = read_all(object).await?;
+        let content_type = match self.header("x-amz-metadata-directive") {
+            Some("REPLACE") => self.header("content-type").map(|c| c.to_string()),
+            _ => entry.mime_type.clone(),
+        }.unwrap_or("application/octet-stream".to_string());
+        let checksums = checksums_of(&data);
+        let folder = self.bucket_folder(bucket)?;
+        let etag = self.store_object(folder, key, Bytes::from(data), &checksums, &content_type).await?;
+        Ok(xml(StatusCode::OK, format!("<CopyObjectResult xmlns=\"{}\"><LastModified>{}</LastModified>\
+                                        <ETag>{}</ETag></CopyObjectResult>",
+                                       XMLNS, iso_date(&Utc::now()), xml_escape(&etag))))
+    }
+
+    // deleting what isnt there succeeds, as on s3. a folder only goes once
+    // the rest of the request has emptied it, until then its contents imply it
+    async fn remove_key(&self, bucket: &DavEntry, key: &str, removed: &mut Vec<Uuid>) -> Result<(), S3Error> {
+        let Some(entry) = self.lookup(bucket, key)? else {
+            return Ok(());
+        };
+        if removed.contains(&entry.file_id) {
+            return Ok(());
+        }
+        if entry.is_folder() && !self.tree.children(Some(entry.file_id)).iter().all(|c| removed.contains(&c.file_id)) {
+            return Ok(());
+        }
+        self.remove(entry).await?;
+        removed.push(entry.file_id);
+        Ok(())
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        self.remove_key(folder, key, &mut Vec::new()).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    // DeleteObjects, deepest keys first so folders are empty by the time
+    // their own key comes up
+    async fn delete_objects(&self, bucket: &str, mut body: Payload) -> S3Result {
+        let folder = self.bucket_folder(bucket)?;
+        let body = body.read_to_end(XML_BODY_LIMIT as i64, xml_too_large).await?;
+        let body = String::from_utf8_lossy(&body);
+        let quiet = element_inner(&body, "Quiet").is_some_and(|q| q.trim() == "true");
+        let mut keys: Vec<String> = elements(&body, "Object").iter()
+            .filter_map(|o| element_inner(o, "Key"))
+            .map(xml_unescape)
+            .collect();
+        if keys.is_empty() || keys.len() > MAX_KEYS {
+            return Err(S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML",
+                                    "The XML you provided was not well-formed or did not validate"));
+        }
+        keys.sort_by(|a, b| b.cmp(a));
+        keys.dedup();
+        let mut removed = Vec::new();
+        let mut results = String::new();
+        for key in &keys {
+            match self.remove_key(folder, key, &mut removed).await {
+                Ok(()) if quiet => {},
+                Ok(()) => results.push_str(&format!("<Deleted><Key>{}</Key></Deleted>", xml_escape(key))),
+                Err(e) => results.push_str(&format!("<Error><Key>{}</Key><Code>{}</Code><Message>{}</Message></Error>",
+                                                    xml_escape(key), e.code, xml_escape(&e.message))),
+            }
+        }
+        Ok(xml(StatusCode::OK, format!("<DeleteResult xmlns=\"{}\">{}</DeleteResult>", XMLNS, results)))
+    }
+
+    async fn upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<Upload, S3Error> {
+        let upload_id = Uuid::parse_str(upload_id).map_err(|_| S3Error::no_such_upload())?;
+        sqlx::query_as::<_, Upload>(r#"SELECT upload_id, content_type FROM s3_uploads
+                                       WHERE upload_id = ($1) AND user_id = ($2)
+                                       AND bucket = ($3) AND object_key = ($4);"#)
+            .bind(upload_id)
+            .bind(self.user_id)
+            .bind(bucket)
+            .bind(key)
+            .fetch_optional(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+            .ok_or(S3Error::no_such_upload())
+    }
+
+    async fn create_upload(&self, bucket: &str, key: &str) -> S3Result {
+        self.bucket_folder(bucket)?;
+        let (segments, folder) = Gateway::segments(key)?;
+        if segments.is_empty() || folder {
+            return Err(S3Error::invalid_argument("A key ending in / can only be an empty folder"));
+        }
+        let upload_id = Uuid::new_v4();
+        sqlx::query(r#"INSERT INTO s3_uploads (upload_id, user_id, bucket, object_key, content_type)
+                       VALUES ($1,$2,$3,$4,$5);"#)
+            .bind(upload_id)
+            .bind(self.user_id)
+            .bind(bucket)
+            .bind(key)
+            .bind(self.header("content-type").unwrap_or("application/octet-stream"))
+            .execute(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        Ok(xml(StatusCode::OK, format!("<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket>\
+                                        <Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                                       XMLNS, xml_escape(bucket), xml_escape(key), upload_id)))
+    }
+
+    // parts wait in the user's bucket, encrypted like files when that is on
+    async fn upload_part(&self, bucket: &str, key: &str, upload_id: &str, mut body: Payload) -> S3Result {
+        let upload = self.upload(bucket, key, upload_id).await?;
+        let part_number = self.param("partNumber")
+            .and_then(|n| n.parse::<i32>().ok())
+            .filter(|n| (1..=MAX_PARTS).contains(n))
+            .ok_or(S3Error::invalid_argument("Part number must be an integer between 1 and 10000"))?;
+        // parts count against the quota before the file exists, so an upload
+        // that could never fit fails on the part that overflows it
+        let pending: i64 = sqlx::query_scalar(r#"SELECT COALESCE(SUM(p.size), 0)::BIGINT
+                                                FROM s3_upload_parts p
+                                                JOIN s3_uploads u ON u.upload_id = p.upload_id
+                                                WHERE u.user_id = ($1)
+                                                AND NOT (p.upload_id = ($2) AND p.part_number = ($3));"#)
+            .bind(self.user_id)
+            .bind(upload.upload_id)
+            .bind(part_number)
+            .fetch_one(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let left = STORAGE_LIMIT - self.storage_used().await? - pending;
+        if self.decoded_length().is_some_and(|l| l > left) {
+            return Err(S3Error::quota_exceeded());
+        }
+        let (key_id, dek) = match &self.state.encryption {
+            Some(encryption) => {
+                let (key_id, dek) = active_data_key(&self.state.pool, encryption, &self.user_id).await?;
+                (Some(key_id), Some(dek))
+            },
+            None => (None, None),
+        };
+        // the part goes to the store as it is read, hashed on the way
+        let (mut sender, receiver) = futures::channel::mpsc::channel::<Result<Bytes, std::io::Error>>(4);
+        let stream: ObjectStream = match dek {
+            Some(dek) => encrypt_stream(&dek, receiver.boxed()),
+            None => receiver.boxed(),
+        };
+        let (part_bucket, part_key) = part_location(self.state, &self.user_id, &upload.upload_id, part_number);
+        let read = async {
+            let mut hasher = UploadHasher::default();
+            let mut size = 0i64;
+            let result = async {
+                while let Some(chunk) = body.next().await? {
+                    size += chunk.len() as i64;
+                    if size > left {
+                        return Err(S3Error::quota_exceeded());
+                    }
+                    hasher.update(&chunk);
+                    // the store stopped reading, its own error says why
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            }.await;
+            // a stream that ends in an error, so the store doesnt keep a partial part
+            if result.is_err() {
+                let _ = sender.send(Err(std::io::Error::other("upload interrupted"))).await;
+            }
+            drop(sender);
+            result.map(|_| (hasher.finish(), size))
+        };
+        let write = self.state.store.put_stream(&part_bucket, &part_key, stream, "application/octet-stream");
+        let (read, written) = tokio::join!(read, write);
+        let verified = read.and_then(|(checksums, size)| {
+            written?;
+            ExpectedChecksums::from_headers(self.headers)?.verify(&checksums)?;
+            Ok((checksums, size))
+        });
+        let (checksums, size) = match verified {
+            Ok(read) => read,
+            // a part that failed has replaced whatever was under its number
+            Err(e) => {
+                let _ = self.state.store.delete(&part_bucket, &part_key).await;
+                sqlx::query("DELETE FROM s3_upload_parts WHERE upload_id = ($1) AND part_number = ($2);")
+                    .bind(upload.upload_id)
+                    .bind(part_number)
+                    .execute(&self.state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                return Err(e);
+            },
+        };
+        sqlx::query(r#"INSERT INTO s3_upload_parts (upload_id, part_number, size, etag, key_id)
+                       VALUES ($1,$2,$3,$4,$5)
+                       ON CONFLICT (upload_id, part_number)
+                       DO UPDATE SET size = EXCLUDED.size, etag = EXCLUDED.etag,
+                       key_id = EXCLUDED.key_id, uploaded_at = NOW();"#)
+            .bind(upload.upload_id)
+            .bind(part_number)
+            .bind(size)
+            .bind(&checksums.md5)
+            .bind(key_id)
+            .execute(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        Ok((StatusCode::OK, [(header::ETAG, format!("\"{}\"", checksums.md5))]).into_response())
+    }
+
+    // the parts named in the body, in order, become one file. the etag is the
+    // md5 of the whole rather than s3's md5 of md5s, so it matches what GET says
+    async fn complete_upload(&self, bucket: &str, key: &str, upload_id: &str, mut body: Payload) -> S3Result {
+        let upload = self.upload(bucket, key, upload_id).await?;
+        let body = body.read_to_end(XML_BODY_LIMIT as i64, xml_too_large).await?;
+        let body = String::from_utf8_lossy(&body);
+        let requested: Vec<(i32, String)> = elements(&body, "Part").iter()
+            .map(|part| {
+                let number = element_inner(part, "PartNumber").and_then(|n| n.trim().parse::<i32>().ok());
+                let etag = element_inner(part, "ETag")
+                    .map(|e| xml_unescape(e).trim().trim_matches('"').to_string());
+                number.zip(etag)
+            })
+            .collect::<Option<_>>()
+            .filter(|parts: &Vec<(i32, String)>| !parts.is_empty())
+            .ok_or(S3Error::new(StatusCode::BAD_REQUEST, "MalformedXML",
+                                "The XML you provided was not well-formed or did not validate"))?;
+        if !requested.windows(2).all(|w| w[0].0 < w[1].0) {
+            return Err(S3Error::new(StatusCode::BAD_REQUEST, "InvalidPartOrder",
+                                    "The list of parts was not in ascending order"));
+        }
+        let stored: Vec<(i32, String, i64, Option<Uuid>)> = sqlx::query_as(r#"SELECT part_number, etag, size, key_id
+                                                                             FROM s3_upload_parts
+                                                                             WHERE upload_id = ($1);"#)
+            .bind(upload.upload_id)
+            .fetch_all(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let parts = requested.iter()
+            .map(|(number, etag)| stored.iter().find(|(n, e, _, _)| n == number && e == etag))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(S3Error::new(StatusCode::BAD_REQUEST, "InvalidPart",
+                                "One or more of the specified parts could not be found"))?;
+        // the parts are already charged as pending, so this only fails when
+        // files were stored alongside the upload
+        let total: i64 = parts.iter().map(|(_, _, size, _)| size).sum();
+        let replaced = self.replaced_size(self.bucket_folder(bucket)?, key)?;
+        if self.storage_used().await? - replaced + total > STORAGE_LIMIT {
+            return Err(S3Error::quota_exceeded());
+        }
+        // one part open at a time, hashed as it is read. store_file sniffs and
+        // strips the file whole, so the concatenation is the one copy held
+        let mut concatenated = futures::stream::iter(parts)
+            .then(|(number, _, _, key_id)| async move {
+                let (part_bucket, part_key) = part_location(self.state, &self.user_id, &upload.upload_id, *number);
+                let object = self.state.store.get_stream(&part_bucket, &part_key).await?;
+                let object = decrypting(&self.state.pool, self.state.encryption.as_deref(), *key_id, object).await?;
+                Ok::<_, ServerError>(object.stream.map_err(|e| ServerError::InternalError(e.to_string())))
+            })
+            .try_flatten()
+            .boxed();
+        let mut hasher = UploadHasher::default();
+        let mut data = Vec::with_capacity(total as usize);
+        while let Some(chunk) = concatenated.try_next().await? {
+            hasher.update(&chunk);
+            data.extend_from_slice(&chunk);
+        }
+        let folder = self.bucket_folder(bucket)?;
+        let checksums = hasher.finish();
+        let etag = self.store_object(folder, key, Bytes::from(data), &checksums, &upload.content_type).await?;
+        discard_upload(self.state, &self.user_id, &upload.upload_id).await?;
+        Ok(xml(StatusCode::OK, format!("<CompleteMultipartUploadResult xmlns=\"{}\"><Location>/{}/{}</Location>\
+                                        <Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag>\
+                                        </CompleteMultipartUploadResult>",
+                                       XMLNS, xml_escape(&uri_encode(bucket, true)), xml_escape(&uri_encode(key, false)),
+                                       xml_escape(bucket), xml_escape(key), xml_escape(&etag))))
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> S3Result {
+        let upload = self.upload(bucket, key, upload_id).await?;
+        discard_upload(self.state, &self.user_id, &upload.upload_id).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    async fn list_uploads(&self, bucket: &str) -> S3Result {
+        self.bucket_folder(bucket)?;
+        let uploads: Vec<(Uuid, String, DateTime<Utc>)> = sqlx::query_as(r#"SELECT upload_id, object_key, created_at
+                                                                           FROM s3_uploads
+                                                                           WHERE user_id = ($1) AND bucket = ($2)
+                                                                           ORDER BY object_key, created_at;"#)
+            .bind(self.user_id)
+            .bind(bucket)
+            .fetch_all(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let entries: String = uploads.iter()
+            .map(|(upload_id, key, created_at)| format!("<Upload><Key>{}</Key><UploadId>{}</UploadId>\
+                                                         <Initiated>{}</Initiated></Upload>",
+                                                        xml_escape(key), upload_id, iso_date(created_at)))
+            .collect();
+        Ok(xml(StatusCode::OK, format!("<ListMultipartUploadsResult xmlns=\"{}\"><Bucket>{}</Bucket>\
+                                        <KeyMarker></KeyMarker><UploadIdMarker></UploadIdMarker>\
+                                        <MaxUploads>{}</MaxUploads><IsTruncated>false</IsTruncated>{}\
+                                        </ListMultipartUploadsResult>",
+                                       XMLNS, xml_escape(bucket), MAX_KEYS, entries)))
+    }
+
+    async fn list_parts(&self, bucket: &str, key: &str, upload_id: &str) -> S3Result {
+        let upload = self.upload(bucket, key, upload_id).await?;
+        let parts: Vec<(i32, String, i64, DateTime<Utc>)> = sqlx::query_as(r#"SELECT part_number, etag, size, uploaded_at
+                                                                             FROM s3_upload_parts
+                                                                             WHERE upload_id = ($1)
+                                                                             ORDER BY part_number;"#)
+            .bind(upload.upload_id)
+            .fetch_all(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let entries: String = parts.iter()
+            .map(|(number, etag, size, uploaded_at)| format!("<Part><PartNumber>{}</PartNumber>\
+                                                              <LastModified>{}</LastModified>\
+                                                              <ETag>&quot;{}&quot;</ETag><Size>{}</Size></Part>",
+                                                             number, iso_date(uploaded_at), etag, size))
+            .collect();
+        Ok(xml(StatusCode::OK, format!("<ListPartsResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key>\
+                                        <UploadId>{}</UploadId><IsTruncated>false</IsTruncated>{}\
+                                        </ListPartsResult>",
+                                       XMLNS, xml_escape(bucket), xml_escape(key), upload.upload_id, entries)))
+    }
+}
+
+// the secret is only in this response
+pub async fn create_access_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<CreateAccessKeyForm>,
+) -> Result<Json<AccessKeyResponse>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(ServerError::BadRequest("Key names are 1 to 100 characters".to_string()));
+    }
+    let secret = new_access_key_secret();
+    let (sealed, master_key_id) = seal_secret(&state.key, state.encryption.as_deref(), &secret)?;
+    let mut key = sqlx::query_as::<_, AccessKeyResponse>(r#"INSERT INTO s3_access_keys (access_key_id, user_id, name,
+                                                           secret, master_key_id)
+                                                           VALUES ($1,$2,$3,$4,$5)
+                                                           RETURNING access_key_id, name, created_at,
+                                                           last_used_at;"#)
+        .bind(new_access_key_id())
+        .bind(user_id)
+        .bind(name)
+        .bind(&sealed)
+        .bind(&master_key_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "access_key_created",
+        details: Some(serde_json::json!({"access_key_id": key.access_key_id, "name": name})),
+        ..Default::default()
+    }).await;
+    key.secret_access_key = Some(secret);
+    Ok(Json(key))
+}
+
+pub async fn get_access_keys(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Vec<AccessKeyResponse>>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let keys = sqlx::query_as::<_, AccessKeyResponse>(r#"SELECT access_key_id, name, created_at, last_used_at
+                                                         FROM s3_access_keys
+                                                         WHERE user_id = ($1) AND revoked_at IS NULL
+                                                         ORDER BY created_at;"#)
+        .bind(user_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(keys))
+}
+
+pub async fn revoke_access_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<AccessKeyIdForm>,
+) -> Result<StatusCode, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let result = sqlx::query(r#"UPDATE s3_access_keys SET revoked_at = NOW()
+                                WHERE access_key_id = ($1) AND user_id = ($2) AND revoked_at IS NULL;"#)
+        .bind(&payload.access_key_id)
+        .bind(user_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("Access key not found".to_string()));
+    }
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "access_key_revoked",
+        details: Some(serde_json::json!({"access_key_id": payload.access_key_id})),
+        ..Default::default()
+    }).await;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,91 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::models::ServerError;
+use crate::scanner::{Scanner, ScanVerdict};
+use crate::storage::ObjectStream;
+
+// clamd's INSTREAM command: length prefixed chunks, a zero length chunk to
+// finish, then one reply line like "stream: OK" or "stream: Eicar-Signature FOUND"
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+enum Address {
+    Tcp(String),
+    Unix(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    address: Address,
+    timeout: Duration,
+}
+
+impl ClamdScanner {
+    // tcp://host:3310 or unix:///var/run/clamav/clamd.ctl
+    pub fn new(address: &str) -> Self {
+        let address = match address.strip_prefix("unix://") {
+            Some(path) => Address::Unix(path.to_string()),
+            None => Address::Tcp(address.trim_start_matches("tcp://").to_string()),
+        };
+        ClamdScanner { address, timeout: Duration::from_secs(60) }
+    }
+}
+
+// forwards the object as it is read, a chunk at a time
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, mut data: ObjectStream)
+    -> std::io::Result<String> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    while let Some(bytes) = data.next().await {
+        // chunks() skips empty input, a zero length chunk would end the stream early
+        for chunk in bytes?.chunks(CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(chunk).await?;
+        }
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+    // clamd closes the connection after replying
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply).trim_end_matches(['\0', '\n']).to_string())
+}
+
+pub fn parse_reply(reply: &str) -> Result<ScanVerdict, ServerError> {
+    let reply = reply.trim_start_matches("stream:").trim();
+    if reply == "OK" {
+        return Ok(ScanVerdict::Clean);
+    }
+    if let Some(signature) = reply.strip_suffix(" FOUND") {
+        return Ok(ScanVerdict::Infected(signature.trim().to_string()));
+    }
+    // size limit exceeded and friends
+    Err(ServerError::InternalError(format!("clamd: {}", reply)))
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    async fn scan(&self, data: ObjectStream) -> Result<ScanVerdict, ServerError> {
+        let reply = tokio::time::timeout(self.timeout, async {
+            match &self.address {
+                Address::Tcp(addr) => {
+                    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+                    instream(&mut stream, data).await
+                },
+                #[cfg(unix)]
+                Address::Unix(path) => {
+                    let mut stream = tokio::net::UnixStream::connect(path).await?;
+                    instream(&mut stream, data).await
+                },
+                #[cfg(not(unix))]
+                Address::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                                                            "unix sockets not supported")),
+            }
+        }).await
+            .map_err(|_| ServerError::InternalError("clamd timed out".to_string()))?
+            .map_err(|e| ServerError::InternalError(format!("clamd: {}", e)))?;
+        parse_reply(&reply)
+    }
+}
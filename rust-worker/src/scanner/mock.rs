@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+
+use crate::models::ServerError;
+use crate::scanner::{Scanner, ScanVerdict};
+use crate::storage::ObjectStream;
+
+// the standard antivirus test file, every real scanner flags it
+pub const EICAR: &[u8] = br"X5O!P%@AP[4\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+// for tests and local setups without clamd, flags anything containing one of
+// its signatures (EICAR by default)
+#[derive(Debug, Clone)]
+pub struct MockScanner {
+    signatures: Vec<(String, Vec<u8>)>,
+}
+
+impl MockScanner {
+    pub fn new() -> Self {
+        MockScanner { signatures: vec![("Eicar-Test-Signature".to_string(), EICAR.to_vec())] }
+    }
+
+    pub fn with_signature(mut self, name: &str, pattern: &[u8]) -> Self {
+        self.signatures.push((name.to_string(), pattern.to_vec()));
+        self
+    }
+}
+
+impl Default for MockScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Scanner for MockScanner {
+    async fn scan(&self, mut data: ObjectStream) -> Result<ScanVerdict, ServerError> {
+        // the tail of the previous chunk is kept so a signature split across
+        // two chunks is still found
+        let keep = self.signatures.iter().map(|(_, p)| p.len()).max().unwrap_or(1).saturating_sub(1);
+        let mut window: Vec<u8> = Vec::new();
+        while let Some(chunk) = data.next().await {
+            window.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?);
+            for (name, pattern) in &self.signatures {
+                if !pattern.is_empty() && window.windows(pattern.len()).any(|w| w == pattern.as_slice()) {
+                    return Ok(ScanVerdict::Infected(name.clone()));
+                }
+            }
+            let drop = window.len().saturating_sub(keep);
+            window.drain(..drop);
+        }
+        Ok(ScanVerdict::Clean)
+    }
+}
@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use sqlx::{Postgres, Transaction};
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::models::{AppState, ScanStatus, ServerError};
+use crate::methods::{open_file, update_cached_files, StoredContent};
+use crate::msc_actions::notify_user;
+use crate::storage::ObjectStream;
+
+pub mod clamd;
+pub mod mock;
+
+pub use self::clamd::ClamdScanner;
+pub use self::mock::MockScanner;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanVerdict {
+    Clean,
+    // signature name
+    Infected(String),
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    // takes the object as it comes out of the store, files can be bigger than
+    // the worker wants to hold
+    async fn scan(&self, data: ObjectStream) -> Result<ScanVerdict, ServerError>;
+}
+
+// uploads get scan_status pending and a row in scan_jobs, a background task
+// scans them and quarantines what it flags. quarantine is the infected status:
+// the object stays where it is but nobody can download it until an admin
+// releases or deletes it
+#[derive(Clone)]
+pub struct ScanConfig {
+    pub scanner: Option<Arc<dyn Scanner>>,
+    // refuse downloads until a file has been scanned clean
+    pub block_pending: bool,
+    pub interval: u64,
+}
+
+impl ScanConfig {
+    // SCANNER = clamd | mock, unset turns scanning off
+    pub fn from_env() -> Self {
+        let scanner: Option<Arc<dyn Scanner>> = match env::var("SCANNER").unwrap_or_default().as_str() {
+            "clamd" => Some(Arc::new(ClamdScanner::new(&env::var("CLAMD_ADDRESS")
+                .unwrap_or("tcp://127.0.0.1:3310".to_string())))),
+            "mock" => Some(Arc::new(MockScanner::new())),
+            _ => None,
+        };
+        ScanConfig {
+            scanner,
+            block_pending: env::var("SCAN_BLOCK_PENDING").map(|v| v != "false").unwrap_or(true),
+            interval: env::var("SCAN_INTERVAL_SECS").ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(10),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        ScanConfig { scanner: None, block_pending: true, interval: 10 }
+    }
+}
+
+// whether a file with this status may be handed out
+pub fn check_download(config: &ScanConfig, status: &ScanStatus) -> Result<(), ServerError> {
+    match status {
+        ScanStatus::Infected => Err(ServerError::Forbidden("File is quarantined".to_string())),
+        ScanStatus::Pending | ScanStatus::Failed if config.block_pending => {
+            Err(ServerError::Forbidden("File has not been scanned yet".to_string()))
+        },
+        _ => Ok(()),
+    }
+}
+
+pub async fn queue_scan(tx: &mut Transaction<'_, Postgres>,
+                        file_id: &Uuid,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"INSERT INTO scan_jobs (file_id) VALUES ($1)
+                   ON CONFLICT (file_id) DO UPDATE SET attempts = 0, available_at = NOW();"#)
+        .bind(file_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct ScannedFile {
+    file_name: String,
+    #[sqlx(flatten)]
+    content: StoredContent,
+}
+
+async fn scan_file(state: &AppState,
+                   scanner: &Arc<dyn Scanner>,
+                   file_id: &Uuid,
+) -> Result<(), ServerError> {
+    let row: Option<ScannedFile> =
+        sqlx::query_as(r#"SELECT owner_id, file_name, extension, blob_hash, key_id
+                          FROM files WHERE file_id = ($1);"#)
+        .bind(file_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // deleted since it was queued
+    let ScannedFile { file_name, content: StoredContent { owner_id, extension, blob_hash, key_id } } = match row {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let object = open_file(state, &owner_id.to_string(), &file_id.to_string(),
+                           &extension, &blob_hash, key_id).await?;
+
+    let (status, result) = match scanner.scan(object.stream).await? {
+        ScanVerdict::Clean => (ScanStatus::Clean, None),
+        ScanVerdict::Infected(signature) => (ScanStatus::Infected, Some(signature)),
+    };
+    sqlx::query(r#"UPDATE files SET scan_status = ($1), scan_result = ($2), scanned_at = NOW()
+                   WHERE file_id = ($3);"#)
+        .bind(&status)
+        .bind(&result)
+        .bind(file_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // cached entries still say pending
+    update_cached_files(state, &owner_id, |files| {
+        files.entry(*file_id).and_modify(|f| {
+            f.scan_status = status.clone();
+            if status == ScanStatus::Infected {
+                f.url = None;
+            }
+        });
+    }).await;
+
+    if let Some(signature) = result {
+        println!("Quarantined {} ({})", file_id, signature);
+        let message = format!("{} was quarantined, the malware scan found {}", file_name, signature);
+        if let Err(e) = notify_user(&state.pool, &owner_id, "quarantined",
+                                    &message, Some(*file_id)).await {
+            eprintln!("Error {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+// claims up to `limit` jobs. a file the scanner keeps erroring on ends up
+// failed, which counts as unscanned for downloads
+pub async fn run_scan_jobs(state: &AppState, limit: i64) -> Result<usize, ServerError> {
+    let scanner = match &state.scanning.scanner {
+        Some(s) => s,
+        None => return Ok(0),
+    };
+    let jobs: Vec<(Uuid, i32)> = sqlx::query_as(r#"UPDATE scan_jobs
+                                                   SET attempts = attempts + 1,
+                                                   available_at = NOW() + INTERVAL '5 minutes'
+                                                   WHERE file_id IN (
+                                                       SELECT file_id FROM scan_jobs
+                                                       WHERE attempts < 3 AND available_at <= NOW()
+                                                       ORDER BY created_at
+                                                       LIMIT ($1)
+                                                       FOR UPDATE SKIP LOCKED)
+                                                   RETURNING file_id, attempts;"#)
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut done = 0;
+    for (file_id, attempts) in jobs {
+        match scan_file(state, scanner, &file_id).await {
+            Ok(()) => {
+                sqlx::query("DELETE FROM scan_jobs WHERE file_id = ($1);")
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                done += 1;
+            },
+            Err(e) => {
+                eprintln!("Error {:?}", e);
+                sqlx::query("UPDATE scan_jobs SET last_error = ($1) WHERE file_id = ($2);")
+                    .bind(format!("{:?}", e))
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                if attempts >= 3 {
+                    let owner_id: Option<Uuid> = sqlx::query_scalar(r#"UPDATE files SET scan_status = ($1)
+                                                                       WHERE file_id = ($2)
+                                                                       RETURNING owner_id;"#)
+                        .bind(ScanStatus::Failed)
+                        .bind(file_id)
+                        .fetch_optional(&state.pool)
+                        .await
+                        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                    // same as clean and quarantined, cached entries still say pending
+                    if let Some(owner_id) = owner_id {
+                        update_cached_files(state, &owner_id, |files| {
+                            files.entry(file_id).and_modify(|f| f.scan_status = ScanStatus::Failed);
+                        }).await;
+                    }
+                }
+            },
+        }
+    }
+    Ok(done)
+}
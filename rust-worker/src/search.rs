@@ -0,0 +1,293 @@
+use bytes::BytesMut;
+use futures::StreamExt;
+use sqlx::{Postgres, Transaction};
+use std::env;
+use std::io::{Cursor, Read};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::models::{AppState, ServerError};
+use crate::methods::{open_file, StoredContent};
+
+// names are searched straight off files (expression index), document text is
+// pulled out by a background job into file_contents, whose search_vector is a
+// generated column. vault items never get here, their content is ciphertext
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    // INDEX_CONTENT=false leaves search to names only
+    pub index_content: bool,
+    // poppler's pdftotext, pdf jobs fail without it
+    pub pdftotext: String,
+    pub interval: u64,
+}
+
+impl SearchConfig {
+    pub fn from_env() -> Self {
+        SearchConfig {
+            index_content: env::var("INDEX_CONTENT").map(|v| v != "false").unwrap_or(true),
+            pdftotext: env::var("PDFTOTEXT_PATH").unwrap_or("pdftotext".to_string()),
+            interval: env::var("INDEX_INTERVAL_SECS").ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(30),
+        }
+    }
+}
+
+// tsvectors top out at 1MB, this leaves room for the positions
+pub const MAX_CONTENT_SIZE: usize = 512 * 1024;
+// per zip entry, office files are zips and zips can be bombs
+const MAX_ENTRY_SIZE: u64 = 8 * 1024 * 1024;
+
+// markers ts_headline puts around matches, swapped for <mark> once the text is escaped
+pub const MATCH_START: &str = "\u{1}";
+pub const MATCH_END: &str = "\u{2}";
+
+// which extractor a file needs, None when there is no text to index
+pub fn text_kind(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "text/plain" | "text/markdown" | "text/csv" => Some("text"),
+        "application/pdf" => Some("pdf"),
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        | "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        | "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        | "application/vnd.oasis.opendocument.text"
+        | "application/vnd.oasis.opendocument.spreadsheet"
+        | "application/vnd.oasis.opendocument.presentation" => Some("office"),
+        _ => None,
+    }
+}
+
+pub async fn queue_indexing(tx: &mut Transaction<'_, Postgres>,
+                            file_id: &Uuid,
+                            kind: &str,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"INSERT INTO index_jobs (file_id, kind) VALUES ($1, $2)
+                   ON CONFLICT (file_id) DO NOTHING;"#)
+        .bind(file_id)
+        .bind(kind)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// cuts on a char boundary
+pub fn truncate_content(mut text: String) -> String {
+    if text.len() > MAX_CONTENT_SIZE {
+        let mut end = MAX_CONTENT_SIZE;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    // postgres text cant hold NUL
+    text.replace('\0', "")
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        e if e.starts_with("#x") => u32::from_str_radix(&e[2..], 16).ok().and_then(char::from_u32),
+        e if e.starts_with('#') => e[1..].parse::<u32>().ok().and_then(char::from_u32),
+        _ => None,
+    }
+}
+
+// text nodes of an office xml part. elements that end a paragraph, cell or
+// line become a space, runs inside a word are joined as they are
+pub fn xml_text(xml: &str) -> String {
+    let mut out = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(['<', '&']) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(e) => e,
+                None => break,
+            };
+            let tag = rest[1..end].trim_start_matches('/').trim_end_matches('/');
+            let name = tag.split_whitespace().next().unwrap_or("");
+            let local = name.rsplit(':').next().unwrap_or(name);
+            if matches!(local, "p" | "tab" | "br" | "si" | "c" | "h" | "s" | "table-cell"
+                               | "line-break" | "row") && !out.ends_with(' ') {
+                out.push(' ');
+            }
+            rest = &rest[end + 1..];
+        } else {
+            match rest.find(';').filter(|e| *e <= 10).and_then(|e| decode_entity(&rest[1..e]).map(|c| (e, c))) {
+                Some((end, c)) => {
+                    out.push(c);
+                    rest = &rest[end + 1..];
+                },
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                },
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// the parts of docx/xlsx/pptx and odf files that hold the visible text
+fn is_text_part(name: &str) -> bool {
+    name == "content.xml"
+        || name == "word/document.xml"
+        || name.starts_with("word/header") || name.starts_with("word/footer")
+        || name == "xl/sharedStrings.xml"
+        || (name.starts_with("xl/worksheets/") && name.ends_with(".xml"))
+        || (name.starts_with("ppt/slides/slide") && name.ends_with(".xml"))
+}
+
+pub fn extract_office_text(data: &[u8]) -> Result<String, ServerError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))
+        .map_err(|e| ServerError::BadRequest(format!("Not an office file. Error: {}", e)))?;
+    let mut names: Vec<String> = archive.file_names()
+        .filter(|n| is_text_part(n))
+        .map(|n| n.to_string())
+        .collect();
+    // slide10 after slide9
+    names.sort_by_key(|n| (n.len(), n.clone()));
+    let mut text = String::new();
+    for name in names {
+        let entry = archive.by_name(&name)
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        let mut xml = String::new();
+        entry.take(MAX_ENTRY_SIZE).read_to_string(&mut xml)
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        text.push_str(&xml_text(&xml));
+        text.push('\n');
+        if text.len() > MAX_CONTENT_SIZE {
+            break;
+        }
+    }
+    Ok(truncate_content(text))
+}
+
+async fn extract_pdf_text(pdftotext: &str, data: &[u8]) -> Result<String, ServerError> {
+    let dir = env::temp_dir().join(format!("servr-index-{}", Uuid::new_v4()));
+    tokio::fs::create_dir_all(&dir).await
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+    let input = dir.join("input.pdf");
+    let result = async {
+        tokio::fs::write(&input, data).await
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        let child = tokio::process::Command::new(pdftotext)
+            .args(["-enc", "UTF-8", "-q"])
+            .arg(&input)
+            .arg("-")
+            .kill_on_drop(true)
+            .output();
+        let finished = tokio::time::timeout(Duration::from_secs(30), child).await
+            .map_err(|_| ServerError::InternalError("pdftotext timed out".to_string()))?
+            .map_err(|e| ServerError::InternalError(format!("Failed to run pdftotext. Error: {}", e)))?;
+        if !finished.status.success() {
+            return Err(ServerError::InternalError(format!("pdftotext failed: {}",
+                String::from_utf8_lossy(&finished.stderr).trim())));
+        }
+        Ok(truncate_content(String::from_utf8_lossy(&finished.stdout).to_string()))
+    }.await;
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        eprintln!("Error {:?}", e);
+    }
+    result
+}
+
+// ts_headline output is the file's own text, so it is escaped before the
+// markers become tags
+pub fn render_highlight(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+async fn index(state: &AppState, file_id: &Uuid, kind: &str) -> Result<(), ServerError> {
+    let row: Option<StoredContent> =
+        sqlx::query_as(r#"SELECT owner_id, extension, blob_hash, key_id
+                          FROM files WHERE file_id = ($1) AND NOT vault;"#)
+        .bind(file_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // deleted since it was queued
+    let StoredContent { owner_id, extension, blob_hash, key_id } = match row {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let mut object = open_file(state, &owner_id.to_string(), &file_id.to_string(), &extension,
+                               &blob_hash, key_id).await?;
+    let mut data = BytesMut::new();
+    while let Some(chunk) = object.stream.next().await {
+        data.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?);
+    }
+    let data = data.freeze();
+
+    let content = match kind {
+        "pdf" => extract_pdf_text(&state.search.pdftotext, &data).await?,
+        "office" => tokio::task::spawn_blocking(move || extract_office_text(&data)).await
+            .map_err(|e| ServerError::InternalError(e.to_string()))??,
+        _ => truncate_content(String::from_utf8_lossy(&data).to_string()),
+    };
+    // the insert fails on the foreign key if the file went away meanwhile
+    sqlx::query(r#"INSERT INTO file_contents (file_id, content) VALUES ($1, $2)
+                   ON CONFLICT (file_id) DO UPDATE
+                   SET content = EXCLUDED.content, indexed_at = NOW();"#)
+        .bind(file_id)
+        .bind(&content)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// claims up to `limit` jobs, failures are retried a couple of times then left
+// in the table with their error
+pub async fn run_index_jobs(state: &AppState, limit: i64) -> Result<usize, ServerError> {
+    let jobs: Vec<(Uuid, String)> = sqlx::query_as(r#"UPDATE index_jobs
+                                                      SET attempts = attempts + 1,
+                                                      available_at = NOW() + INTERVAL '10 minutes'
+                                                      WHERE file_id IN (
+                                                          SELECT file_id FROM index_jobs
+                                                          WHERE attempts < 3 AND available_at <= NOW()
+                                                          ORDER BY created_at
+                                                          LIMIT ($1)
+                                                          FOR UPDATE SKIP LOCKED)
+                                                      RETURNING file_id, kind;"#)
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut done = 0;
+    for (file_id, kind) in jobs {
+        match index(state, &file_id, &kind).await {
+            Ok(()) => {
+                sqlx::query("DELETE FROM index_jobs WHERE file_id = ($1);")
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                done += 1;
+            },
+            Err(e) => {
+                eprintln!("Error {:?}", e);
+                sqlx::query("UPDATE index_jobs SET last_error = ($1) WHERE file_id = ($2);")
+                    .bind(format!("{:?}", e))
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+            },
+        }
+    }
+    Ok(done)
+}
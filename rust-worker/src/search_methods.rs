@@ -0,0 +1,90 @@
+use axum::{extract::State, Json};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+
+use crate::models::{AppState,
+                    SearchForm,
+                    SearchHit,
+                    SearchResponse,
+                    ServerError};
+use crate::auth_methods::get_current_user;
+use crate::search::{MATCH_START, MATCH_END, render_highlight};
+
+// names, tags and indexed document text of everything the user owns or has been
+// shared, directly or through a folder above it. best match first
+pub async fn search_files(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<SearchForm>,
+) -> Result<Json<SearchResponse>, ServerError> {
+
+    let user_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let query = payload.query.trim();
+    if query.is_empty() {
+        return Err(ServerError::BadRequest("Empty search".to_string()));
+    }
+    let page = payload.page.unwrap_or(1).max(1);
+    let per_page = payload.per_page.unwrap_or(20).clamp(1, 100);
+    let name_options = format!("StartSel={}, StopSel={}, HighlightAll=true", MATCH_START, MATCH_END);
+    let snippet_options = format!("StartSel={}, StopSel={}, MaxFragments=2, MaxWords=25, MinWords=8",
+                                  MATCH_START, MATCH_END);
+
+    let mut hits = sqlx::query_as::<_, SearchHit>(r#"WITH RECURSIVE visible AS (
+                                                         SELECT file_id FROM files
+                                                         WHERE owner_id = ($2) OR ($2) = ANY(shared_with)
+                                                         UNION
+
+                                                         SELECT f.file_id FROM files f
+                                                         JOIN visible v ON f.parent_id = v.file_id
+                                                      ),
+                                                      q AS (SELECT websearch_to_tsquery('english', $1) AS query)
+                                                      SELECT f.file_id, f.owner_id, f.parent_id, f.file_name,
+                                                      f.extension, f.size, f.file_type, f.last_modified,
+                                                      ts_rank_cd(setweight(to_tsvector('english', f.file_name), 'A')
+                                                                 || setweight(tg.tag_vector, 'B')
+                                                                 || setweight(COALESCE(c.search_vector, ''::tsvector), 'C'),
+                                                                 q.query) AS rank,
+                                                      ts_headline('english', f.file_name, q.query, $3) AS name_highlight,
+                                                      CASE WHEN c.search_vector @@ q.query
+                                                           THEN ts_headline('english', c.content, q.query, $4)
+                                                      END AS snippet,
+                                                      COUNT(*) OVER () AS total
+                                                      FROM files f
+                                                      CROSS JOIN q
+                                                      LEFT JOIN file_contents c ON c.file_id = f.file_id
+                                                      CROSS JOIN LATERAL (
+                                                          SELECT to_tsvector('english', COALESCE(string_agg(t.name, ' '), ''))
+                                                          AS tag_vector
+                                                          FROM file_tags ft JOIN tags t ON t.tag_id = ft.tag_id
+                                                          WHERE ft.file_id = f.file_id
+                                                      ) tg
+                                                      WHERE f.file_id IN (SELECT file_id FROM visible)
+                                                      AND NOT f.vault
+                                                      AND (to_tsvector('english', f.file_name) @@ q.query
+                                                           OR tg.tag_vector @@ q.query
+                                                           OR c.search_vector @@ q.query)
+                                                      ORDER BY rank DESC, f.last_modified DESC NULLS LAST
+                                                      LIMIT ($5) OFFSET ($6);"#)
+        .bind(query)
+        .bind(user_id)
+        .bind(&name_options)
+        .bind(&snippet_options)
+        .bind(per_page)
+        .bind((page - 1) * per_page)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let total = hits.first().map(|h| h.total).unwrap_or(0);
+    for hit in hits.iter_mut() {
+        hit.name_highlight = render_highlight(&hit.name_highlight);
+        hit.snippet = hit.snippet.as_deref().map(render_highlight);
+    }
+    Ok(Json(SearchResponse { total, page, per_page, results: hits }))
+}
@@ -1,62 +1,58 @@
-use axum::{routing::post,
-           routing::get, 
-           Router};
+use axum::Router;
 
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
-use aws_sdk_s3 as s3;
 use moka::future::Cache;
 
 use uuid::Uuid;
 use std::env;
 use std::sync::Arc;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 
-use crate::methods::{get_files, 
-                     create_folder, 
-                     upload_file, 
-                     delete_file, 
-                     rename_file,
-                     download_file,
-                     create_bucket,};
-use crate::auth_methods::{login_user, create_user, read_me, logout_user};
-use crate::models::{AppState, AuthState, FileResponse};
-
-async fn hello_world() -> &'static str {
+use crate::storage::{ObjectStore, StorageLayout, store_from_env};
+use crate::msc_actions::create_bucket_func;
+use crate::dedup::{DedupConfig, collect_garbage};
+use crate::encryption::Encryption;
+use crate::thumbnails::{ThumbnailConfig, run_thumbnail_jobs};
+use crate::mime::MismatchPolicy;
+use crate::scanner::{ScanConfig, run_scan_jobs};
+use crate::media_metadata::MetadataConfig;
+use crate::search::{SearchConfig, run_index_jobs};
+use crate::events::{EventBus, BUS_CAPACITY};
+use crate::webhooks::{WebhookConfig, run_dispatcher, run_deliveries};
+use crate::webdav::DavConfig;
+use crate::s3_gateway::{S3GatewayConfig, expire_uploads};
+use crate::s3_gateway_methods::s3_gateway_root;
+use crate::sftp::{SftpConfig, run_sftp};
+use crate::openapi::routes;
+use crate::models::{AppState, AuthState, FileResponse, ServerError};
+
+pub(crate) async fn hello_world() -> &'static str {
     println!("Hello");
     "Hello"
 }
 
-pub async fn setup(pool: PgPool) -> Result<Router, s3::Error> {
+pub async fn setup(pool: PgPool) -> Result<Router, ServerError> {
 
     println!("Listener On");
     
-    let minio_url = match env::var("MINIO_ENDPOINT") {
-        Ok(url) => { 
-            println!("Minio: {}",url);
-            url
-        },
-        Err(e) => {
-                   eprintln!("Error {:?}", e);
-                   "".to_string()
-        },
-    };
-    let config = aws_config::defaults(aws_config::BehaviorVersion::latest()) 
-        //was from_env(), but default naming in the env file
-        .endpoint_url(minio_url)
-        //.credentials_provider(r2_credentials)
-        .region(aws_config::meta
-            ::region::RegionProviderChain::default_provider()
-            .or_else("eu-west-2"))
-        .load()
-        .await;
+    let key = env::var("SECRET_KEY").unwrap_or_default();
+    let store = store_from_env(&key).await;
+    setup_with_store(pool, store, key).await
+}
 
-    let s3_config = s3::config::Builder::from(&config)
-        .force_path_style(true)
-        .build();
+// tests pass a MemoryStore here so they dont need minio
+pub async fn setup_with_store(pool: PgPool,
+                              store: Arc<dyn ObjectStore>,
+                              key: String,
+) -> Result<Router, ServerError> {
 
-    let client = s3::Client::from_conf(s3_config);
-    
+    // sessions, download links and s3 secrets are all signed with this, an
+    // empty key would let anyone mint them
+    if key.is_empty() {
+        return Err(ServerError::InternalError("SECRET_KEY is not set".to_string()));
+    }
 
     // Cache Setup
     const NUM_THREADS: u64 = 100;
@@ -64,31 +60,177 @@ pub async fn setup(pool: PgPool) -> Result<Router, s3::Error> {
     let cache: Cache<Uuid, Arc<HashMap<Uuid, FileResponse>>> = 
         Cache::new(NUM_THREADS);
    
-    let key: String = match std::env::var("SECRET_KEY") {
-    Ok(k) => k,
-    Err(e) => {
-        "".to_string()
+    let layout = StorageLayout::from_env();
+    if let StorageLayout::SharedBucket(bucket) = &layout {
+        println!("Storage layout: shared bucket {}", bucket);
+        if let Err(e) = create_bucket_func(&store, bucket).await {
+            eprintln!("Error {:?}", e);
+        }
+    }
+
+    let dedup = DedupConfig::from_env(&layout);
+    if dedup.enabled {
+        println!("Dedup on, blobs in {}", dedup.bucket);
+        if let Err(e) = create_bucket_func(&store, &dedup.bucket).await {
+            eprintln!("Error {:?}", e);
+        }
+        let gc_pool = pool.clone();
+        let gc_store = store.clone();
+        let gc_config = dedup.clone();
+        let interval = env::var("BLOB_GC_INTERVAL_SECS").ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(3600);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval));
+            loop {
+                ticker.tick().await;
+                match collect_garbage(&gc_pool, &gc_store, &gc_config,
+                                      chrono::Duration::hours(1)).await {
+                    Ok(n) if n > 0 => println!("Blob gc removed {}", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+    }
+
+    let encryption = match Encryption::from_env() {
+        Ok(Some(e)) => {
+            println!("Encryption on, master key {}", e.active_id());
+            Some(Arc::new(e))
+        },
+        Ok(None) => None,
+        Err(e) => {
+            // refusing to start beats writing plaintext when a key was intended
+            eprintln!("Invalid encryption config {:?}", e);
+            return Err(e);
         },
     };
+    let public_url = env::var("PUBLIC_URL")
+        .unwrap_or("http://localhost:3000".to_string());
 
-    let state = AppState {pool, client, cache, key};
+    let thumbnails = ThumbnailConfig::from_env();
+    let mime_policy = MismatchPolicy::from_env();
+    let scanning = ScanConfig::from_env();
+    let metadata = MetadataConfig::from_env();
+    let search = SearchConfig::from_env();
+    let events = EventBus::new(BUS_CAPACITY);
+    let webhooks = WebhookConfig::from_env();
+    let webdav_config = DavConfig::from_env();
+    let s3_gateway_config = S3GatewayConfig::from_env();
+    let sftp = SftpConfig::from_env();
+    let state = AppState {pool, store, layout, dedup, encryption, thumbnails, mime_policy,
+                          scanning, metadata, search, events, webhooks, webdav: webdav_config,
+                          s3_gateway: s3_gateway_config, sftp, cache, key, public_url};
+    if state.webhooks.enabled {
+        // changes become queued deliveries, which the second task sends
+        tokio::spawn(run_dispatcher(state.clone()));
+        let worker_state = state.clone();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(worker_state.webhooks.interval);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match run_deliveries(&worker_state, 20).await {
+                    Ok(n) if n > 0 => println!("Delivered {} webhooks", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+    }
+    if state.s3_gateway.enabled {
+        let worker_state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(3600));
+            loop {
+                ticker.tick().await;
+                match expire_uploads(&worker_state).await {
+                    Ok(n) if n > 0 => println!("Dropped {} abandoned multipart uploads", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+        // the same handler at the root of its own port
+        if let Some(addr) = state.s3_gateway.addr.clone() {
+            let gateway = Router::new()
+                .fallback(s3_gateway_root)
+                .with_state(state.clone());
+            tokio::spawn(async move {
+                match tokio::net::TcpListener::bind(&addr).await {
+                    Ok(listener) => {
+                        println!("S3 gateway on {}", addr);
+                        if let Err(e) = axum::serve(listener,
+                                                    gateway.into_make_service_with_connect_info::<SocketAddr>()).await {
+                            eprintln!("Error {:?}", e);
+                        }
+                    },
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            });
+        }
+    }
+    // ssh on its own port, next to the http listener
+    if let Some(addr) = state.sftp.addr.clone() {
+        let sftp_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_sftp(sftp_state, addr).await {
+                eprintln!("Error {:?}", e);
+            }
+        });
+    }
+    if state.scanning.scanner.is_some() {
+        println!("Malware scanning on");
+        let worker_state = state.clone();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(worker_state.scanning.interval);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match run_scan_jobs(&worker_state, 20).await {
+                    Ok(n) if n > 0 => println!("Scanned {} files", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+    }
+    if state.search.index_content {
+        let worker_state = state.clone();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(worker_state.search.interval);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match run_index_jobs(&worker_state, 20).await {
+                    Ok(n) if n > 0 => println!("Indexed {} files", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+    }
+    if state.thumbnails.enabled {
+        println!("Thumbnails on");
+        let worker_state = state.clone();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(worker_state.thumbnails.interval);
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match run_thumbnail_jobs(&worker_state, 20).await {
+                    Ok(n) if n > 0 => println!("Rendered thumbnails for {} files", n),
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Error {:?}", e),
+                }
+            }
+        });
+    }
     
 
     //Axum HTTP Server Setup
-    let app = Router::new()
-        .route("/get-files", post(get_files))
-        .route("/upload-file", post(upload_file))
-        .route("/delete-file", post(delete_file))
-        .route("/create-bucket", post(create_bucket))
-        .route("/rename-file", post(rename_file))
-        .route("/create-folder", post(create_folder))
-        .route("/download-file", post(download_file))
-        // auth
-        .route("/sign-in", post(login_user)) 
-        .route("/sign-up", post(create_user))
-        .route("/sign-out", post(logout_user)) 
-        .route("/me", get(read_me)) 
-        .route("/", get(hello_world))
+    let app = routes()
         .with_state(state);
  
     Ok(app)
@@ -0,0 +1,291 @@
+use async_trait::async_trait;
+use base64::Engine;
+use russh::server::{Auth, Handler, Msg, Server, Session};
+use russh::{Channel, ChannelId, MethodSet};
+use russh_keys::key::{KeyPair, PublicKey};
+use russh_keys::PublicKeyBase64;
+use sha2::{Sha256, Digest};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::{AppState, ServerError};
+use crate::auth_methods::{api_key_user, API_KEY_PREFIX};
+use crate::msc_actions::hash_algorithm;
+use crate::crypto::constant_time_eq;
+use crate::audit::{RequestInfo, AuditEvent, record};
+use crate::sftp_methods::SftpSession;
+
+// an sftp server for clients that only speak ssh. it shows the same folder
+// tree as webdav, logins are an ssh key from /add-ssh-key with the account
+// email as the user name, or email and password (or an api key) when password
+// auth is on. keys can be held to one folder and made read only. the sftp
+// operations are in sftp_methods.rs
+
+#[derive(Clone)]
+pub struct SftpConfig {
+    // off unless there is somewhere to listen
+    pub addr: Option<String>,
+    // made on first start when missing, so clients see the same host key after
+    pub host_key_path: String,
+    // when false only keys get in
+    pub password_auth: bool,
+}
+
+impl SftpConfig {
+    pub fn from_env() -> Self {
+        SftpConfig {
+            addr: env::var("SFTP_ADDR").ok().filter(|a| !a.is_empty()),
+            host_key_path: env::var("SFTP_HOST_KEY").unwrap_or("sftp_host_key".to_string()),
+            password_auth: env::var("SFTP_PASSWORD_AUTH").map(|v| v == "true").unwrap_or(false),
+        }
+    }
+}
+
+// who is logged in and what the key allows
+#[derive(Debug, Clone)]
+pub struct SftpLogin {
+    pub user_id: Uuid,
+    // None for password logins
+    pub key_id: Option<Uuid>,
+    pub read_only: bool,
+    // the folder that shows as /, None for the whole tree
+    pub root_id: Option<Uuid>,
+}
+
+// sftp paths are absolute or from /, there is no other working directory.
+// .. stops at the top, so nothing above the root can be named
+pub fn sftp_path(path: &str) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => { parts.pop(); },
+            part => parts.push(part.to_string()),
+        }
+    }
+    parts
+}
+
+pub fn display_path(path: &[String]) -> String {
+    format!("/{}", path.join("/"))
+}
+
+// mode bits with the file type, what ls -l on the client shows
+pub fn permissions(folder: bool, read_only: bool) -> u32 {
+    match (folder, read_only) {
+        (true, false) => 0o40755,
+        (true, true) => 0o40555,
+        (false, false) => 0o100644,
+        (false, true) => 0o100444,
+    }
+}
+
+// "ssh-ed25519 AAAA... comment" as in authorized_keys, without options. the
+// blob starts with its own algorithm name, which has to agree with the first
+// field
+pub fn parse_authorized_key(line: &str) -> Option<(String, Vec<u8>)> {
+    let mut parts = line.split_whitespace();
+    let algorithm = parts.next()?;
+    let blob = base64::engine::general_purpose::STANDARD.decode(parts.next()?).ok()?;
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    if blob.get(4..4 + len)? != algorithm.as_bytes() {
+        return None;
+    }
+    Some((algorithm.to_string(), blob))
+}
+
+// the same as ssh-keygen -l prints
+pub fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+fn host_key(path: &str) -> Result<KeyPair, ServerError> {
+    if Path::new(path).exists() {
+        return russh_keys::load_secret_key(path, None)
+            .map_err(|e| ServerError::InternalError(e.to_string()));
+    }
+    let key = KeyPair::generate_ed25519()
+        .ok_or(ServerError::InternalError("Could not generate a host key".to_string()))?;
+    let mut pem = Vec::new();
+    russh_keys::encode_pkcs8_pem(&key, &mut pem).map_err(|e| ServerError::InternalError(e.to_string()))?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&pem))
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+    println!("Generated SFTP host key at {}", path);
+    Ok(key)
+}
+
+// runs until the listener fails
+pub async fn run_sftp(state: AppState, addr: String) -> Result<(), ServerError> {
+    let key = host_key(&state.sftp.host_key_path)?;
+    let mut methods = MethodSet::PUBLICKEY;
+    if state.sftp.password_auth {
+        methods |= MethodSet::PASSWORD;
+    }
+    let config = Arc::new(russh::server::Config {
+        methods,
+        keys: vec![key],
+        auth_rejection_time: Duration::from_secs(3),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        inactivity_timeout: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    });
+    println!("SFTP on {}", addr);
+    let mut server = SftpServer { state };
+    server.run_on_address(config, addr.as_str()).await
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+struct SftpServer {
+    state: AppState,
+}
+
+impl Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> SshSession {
+        SshSession {
+            state: self.state.clone(),
+            info: RequestInfo { ip: peer.map(|p| p.ip().to_string()), user_agent: None },
+            login: None,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+// one ssh connection
+pub struct SshSession {
+    state: AppState,
+    info: RequestInfo,
+    login: Option<SftpLogin>,
+    // opened but not yet handed to sftp
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl SshSession {
+    async fn accept(&mut self, login: SftpLogin) -> Auth {
+        record(&self.state.pool, &self.info, AuditEvent {
+            actor_id: Some(login.user_id),
+            action: "login",
+            details: Some(serde_json::json!({"sftp": true, "key_id": login.key_id})),
+            ..Default::default()
+        }).await;
+        self.login = Some(login);
+        Auth::Accept
+    }
+
+    // the user name of either login, matched the way sign-in matches it
+    async fn account(&self, user: &str) -> Option<(Uuid, String)> {
+        sqlx::query_as(r#"SELECT user_id, hashed_password FROM users
+                          WHERE email = ($1) AND active;"#)
+            .bind(user)
+            .fetch_optional(&self.state.pool)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Error {:?}", e);
+                None
+            })
+    }
+
+    async fn reject(&self, user: &str, method: &str) -> Auth {
+        record(&self.state.pool, &self.info, AuditEvent {
+            action: "login_failed",
+            details: Some(serde_json::json!({"email": user, "sftp": true, "method": method})),
+            ..Default::default()
+        }).await;
+        Auth::Reject { proceed_with_methods: None }
+    }
+}
+
+#[async_trait]
+impl Handler for SshSession {
+    type Error = russh::Error;
+
+    // email and password, or an api key with any user name
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if !self.state.sftp.password_auth {
+            return Ok(Auth::Reject { proceed_with_methods: None });
+        }
+        let user_id = match password.starts_with(API_KEY_PREFIX) {
+            true => api_key_user(&self.state.pool, password).await.ok().flatten(),
+            false => self.account(user).await
+                .filter(|(_, hashed)| constant_time_eq(hash_algorithm(password).as_bytes(), hashed.as_bytes()))
+                .map(|(user_id, _)| user_id),
+        };
+        match user_id {
+            Some(user_id) => Ok(self.accept(SftpLogin { user_id, key_id: None, read_only: false, root_id: None }).await),
+            None => Ok(self.reject(user, "password").await),
+        }
+    }
+
+    // russh has checked the signature by now, what is left is whether the key
+    // belongs to the account named
+    async fn auth_publickey(&mut self, user: &str, public_key: &PublicKey) -> Result<Auth, Self::Error> {
+        let Some((user_id, _)) = self.account(user).await else {
+            return Ok(self.reject(user, "publickey").await);
+        };
+        let key: Option<(Uuid, bool, Option<Uuid>)> = sqlx::query_as(r#"UPDATE ssh_keys SET last_used_at = NOW()
+                                                                          WHERE user_id = ($1)
+                                                                          AND split_part(public_key, ' ', 2) = ($2)
+                                                                          RETURNING key_id, read_only, root_id;"#)
+            .bind(user_id)
+            .bind(public_key.public_key_base64())
+            .fetch_optional(&self.state.pool)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Error {:?}", e);
+                None
+            });
+        match key {
+            Some((key_id, read_only, root_id)) => {
+                Ok(self.accept(SftpLogin { user_id, key_id: Some(key_id), read_only, root_id }).await)
+            },
+            None => Ok(self.reject(user, "publickey").await),
+        }
+    }
+
+    async fn channel_open_session(&mut self,
+                                  channel: Channel<Msg>,
+                                  _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn channel_eof(&mut self, channel: ChannelId, session: &mut Session) -> Result<(), Self::Error> {
+        self.channels.remove(&channel);
+        let _ = session.close(channel);
+        Ok(())
+    }
+
+    // sftp only, there is no shell or exec
+    async fn subsystem_request(&mut self,
+                               channel_id: ChannelId,
+                               name: &str,
+                               session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let channel = self.channels.remove(&channel_id);
+        match (name, channel, self.login.clone()) {
+            ("sftp", Some(channel), Some(login)) => {
+                let _ = session.channel_success(channel_id);
+                let handler = SftpSession::new(self.state.clone(), login, self.info.clone());
+                tokio::spawn(russh_sftp::server::run(channel.into_stream(), handler));
+            },
+            _ => {
+                let _ = session.channel_failure(channel_id);
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+use axum::{extract::{State, Json},
+           http::StatusCode as HttpStatus};
+use axum_extra::extract::cookie::CookieJar;
+use base64::Engine;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use russh_sftp::protocol::{Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::env;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+
+use crate::models::{AppState, ServerError, AddSshKeyForm, SshKeyIdForm, SshKeyResponse};
+use crate::auth_methods::current_user;
+use crate::msc_actions::file_audience;
+use crate::methods::{STORAGE_LIMIT, NewFile, make_folder, store_file, replace_file, remove_file, relocate_file, open_file};
+use crate::storage::ObjectBody;
+use crate::integrity::UploadHasher;
+use crate::scanner::check_download;
+use crate::vault::parent_kind;
+use crate::audit::{RequestInfo, AuditEvent, record};
+use crate::events::{ChangeEvent, ChangeKind, announce, emit};
+use crate::webdav::{DavEntry, DavTree, load_tree, rename_entry};
+use crate::sftp::{SftpLogin, sftp_path, display_path, permissions, parse_authorized_key, fingerprint};
+
+// open handles a session can hold, each read handle has a file in memory
+const MAX_HANDLES: usize = 32;
+
+// what an open handle points at. files are read whole when opened, never
+// bigger than the quota. writes are staged on disk and stored on close
+enum OpenHandle {
+    Dir { folder_id: Option<Uuid>, done: bool },
+    Read { data: Bytes, attrs: FileAttributes },
+    Write { parent_id: Option<Uuid>, name: String, existing: Option<Uuid>, append: bool, staged: Staged },
+}
+
+// a temp file for a write handle, so writes at any offset land where they
+// belong without a buffer sized to match. gone with the handle
+struct Staged {
+    path: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+}
+
+impl Staged {
+    async fn new() -> std::io::Result<Self> {
+        let path = env::temp_dir().join(format!("servr-sftp-{}", Uuid::new_v4()));
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await?;
+        Ok(Staged { path, file, size: 0 })
+    }
+
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(data).await?;
+        self.size = self.size.max(offset + data.len() as u64);
+        Ok(())
+    }
+
+    async fn read_at(&mut self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let len = len.min(self.size.saturating_sub(offset) as usize);
+        let mut data = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn contents(&mut self) -> std::io::Result<Vec<u8>> {
+        self.file.flush().await?;
+        tokio::fs::read(&self.path).await
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn io_status(e: std::io::Error) -> StatusCode {
+    status_of(ServerError::InternalError(e.to_string()))
+}
+
+// the sftp side of one ssh channel
+pub struct SftpSession {
+    state: AppState,
+    login: SftpLogin,
+    info: RequestInfo,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+fn status_of(e: ServerError) -> StatusCode {
+    match e {
+        ServerError::NotFound(_) => StatusCode::NoSuchFile,
+        ServerError::Unauthorized(_) | ServerError::Forbidden(_) => StatusCode::PermissionDenied,
+        e => {
+            eprintln!("Error {:?}", e);
+            StatusCode::Failure
+        },
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+impl SftpSession {
+    pub fn new(state: AppState, login: SftpLogin, info: RequestInfo) -> Self {
+        SftpSession { state, login, info, handles: HashMap::new(), next_handle: 0 }
+    }
+
+    // reloaded for each operation, other clients may have changed things
+    async fn tree(&self) -> Result<DavTree, StatusCode> {
+        load_tree(&self.state, &self.login.user_id).await.map_err(status_of)
+    }
+
+    fn writable(&self) -> Result<(), StatusCode> {
+        match self.login.read_only {
+            true => Err(StatusCode::PermissionDenied),
+            false => Ok(()),
+        }
+    }
+
+    // Some(None) is / without a chroot
+    fn resolve<'t>(&self, tree: &'t DavTree, path: &[String]) -> Result<Option<&'t DavEntry>, StatusCode> {
+        tree.resolve_from(self.login.root_id, path).ok_or(StatusCode::NoSuchFile)
+    }
+
+    // an entry below /, the root itself cant be removed or renamed
+    fn resolve_entry<'t>(&self, tree: &'t DavTree, path: &[String]) -> Result<&'t DavEntry, StatusCode> {
+        match self.resolve(tree, path)? {
+            Some(entry) if Some(entry.file_id) != self.login.root_id => Ok(entry),
+            _ => Err(StatusCode::PermissionDenied),
+        }
+    }
+
+    // the folder and name a new item at path goes into
+    fn destination(&self, tree: &DavTree, path: &[String]) -> Result<(Option<Uuid>, String), StatusCode> {
+        let parent_id = tree.parent_from(self.login.root_id, path).ok_or(StatusCode::NoSuchFile)?;
+        let name = path.last().cloned().ok_or(StatusCode::Failure)?;
+        Ok((parent_id, name))
+    }
+
+    fn attrs(&self, entry: Option<&DavEntry>) -> FileAttributes {
+        let folder = entry.is_none_or(|e| e.is_folder());
+        FileAttributes {
+            size: Some(entry.map(|e| e.size.max(0) as u64).unwrap_or(0)),
+            permissions: Some(permissions(folder, self.login.read_only)),
+            mtime: entry.and_then(|e| e.last_modified).map(|d| d.timestamp() as u32),
+            atime: entry.and_then(|e| e.last_modified).map(|d| d.timestamp() as u32),
+            ..Default::default()
+        }
+    }
+
+    fn add_handle(&mut self, handle: OpenHandle) -> Result<String, StatusCode> {
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(StatusCode::Failure);
+        }
+        self.next_handle += 1;
+        let name = self.next_handle.to_string();
+        self.handles.insert(name.clone(), handle);
+        Ok(name)
+    }
+
+    async fn object(&self, entry: &DavEntry) -> Result<ObjectBody, StatusCode> {
+        check_download(&self.state.scanning, &entry.scan_status).map_err(status_of)?;
+        open_file(&self.state, &self.login.user_id.to_string(), &entry.file_id.to_string(),
+                  &entry.extension, &entry.blob_hash, entry.key_id).await.map_err(status_of)
+    }
+
+    async fn contents(&self, entry: &DavEntry) -> Result<Bytes, StatusCode> {
+        let object = self.object(entry).await?;
+        let data: Vec<u8> = object.stream.map_ok(|chunk| chunk.to_vec()).try_concat().await
+            .map_err(io_status)?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn audit(&self, action: &str, file_id: Uuid, mut details: serde_json::Value) {
+        details["via"] = serde_json::json!("sftp");
+        details["key_id"] = serde_json::json!(self.login.key_id);
+        record(&self.state.pool, &self.info, AuditEvent {
+            actor_id: Some(self.login.user_id),
+            action,
+            file_id: Some(file_id),
+            owner_id: Some(self.login.user_id),
+            details: Some(details),
+        }).await;
+    }
+
+    async fn created(&self, file_id: Uuid, parent_id: Option<Uuid>, name: &str) {
+        announce(&self.state, ChangeEvent::new(ChangeKind::Created, Some(self.login.user_id), file_id,
+                                               self.login.user_id, parent_id, Some(name.to_string()))).await;
+    }
+
+    // the same bookkeeping as /delete-file, quota, folder sizes, blobs
+    async fn delete(&self, file_id: Uuid) -> Result<(), ServerError> {
+        let audience = file_audience(&self.state.pool, &file_id).await?;
+        let (file_name, parent_id) = remove_file(&self.state, self.login.user_id, file_id).await?;
+        self.audit("file_deleted", file_id, serde_json::json!({"name": file_name})).await;
+        let mut deleted = ChangeEvent::new(ChangeKind::Deleted, Some(self.login.user_id), file_id,
+                                           self.login.user_id, parent_id, Some(file_name));
+        deleted.audience = audience;
+        emit(&self.state, deleted).await;
+        Ok(())
+    }
+
+    // through store_file like any upload. a file that was there gets the new
+    // content in place, keeping its id and so its tags, comments and shares,
+    // and the quota only sees the difference
+    async fn commit(&self,
+                    parent_id: Option<Uuid>,
+                    name: &str,
+                    existing: Option<Uuid>,
+                    data: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        let mut hasher = UploadHasher::default();
+        hasher.update(&data);
+        let checksums = hasher.finish();
+        // sftp has no content type, store_file works it out from the data and name
+        let file = NewFile {
+            owner_id: self.login.user_id,
+            parent_id,
+            filename: name,
+            content_type: "application/octet-stream",
+            data: Bytes::from(data),
+            checksums: &checksums,
+            strip_gps: self.state.metadata.strip_gps,
+        };
+        let stored = match existing {
+            Some(old) => replace_file(&self.state, old, file).await?,
+            None => store_file(&self.state, file, None).await?,
+        };
+        self.audit("file_uploaded", stored.file_id, serde_json::json!({
+            "name": name, "size": stored.size, "parent_id": parent_id, "overwritten": existing.is_some(),
+        })).await;
+        match existing {
+            Some(_) => announce(&self.state, ChangeEvent::new(ChangeKind::Modified, Some(self.login.user_id),
+                                                              stored.file_id, self.login.user_id, parent_id,
+                                                              Some(name.to_string()))).await,
+            None => self.created(stored.file_id, parent_id, name).await,
+        }
+        Ok(())
+    }
+}
+
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(&mut self,
+                  _version: u32,
+                  _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = display_path(&sftp_path(&path));
+        Ok(Name { id, files: vec![File::new(path, FileAttributes::default())] })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let tree = self.tree().await?;
+        let entry = self.resolve(&tree, &sftp_path(&path))?;
+        Ok(Attrs { id, attrs: self.attrs(entry) })
+    }
+
+    // there are no links, lstat is stat
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let attrs = match self.handles.get(&handle).ok_or(StatusCode::Failure)? {
+            OpenHandle::Dir { .. } => self.attrs(None),
+            OpenHandle::Read { attrs, .. } => attrs.clone(),
+            OpenHandle::Write { staged, .. } => FileAttributes {
+                size: Some(staged.size),
+                permissions: Some(permissions(false, false)),
+                ..Default::default()
+            },
+        };
+        Ok(Attrs { id, attrs })
+    }
+
+    // clients set times after an upload, there is nowhere to keep them
+    async fn setstat(&mut self, id: u32, _path: String, _attrs: FileAttributes) -> Result<Status, Self::Error> {
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(&mut self, id: u32, _handle: String, _attrs: FileAttributes) -> Result<Status, Self::Error> {
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let tree = self.tree().await?;
+        let folder_id = match self.resolve(&tree, &sftp_path(&path))? {
+            Some(entry) if !entry.is_folder() => return Err(StatusCode::Failure),
+            entry => entry.map(|e| e.file_id),
+        };
+        let handle = self.add_handle(OpenHandle::Dir { folder_id, done: false })?;
+        Ok(Handle { id, handle })
+    }
+
+    // everything in one batch, then eof
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let folder_id = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Dir { done: true, .. }) => return Err(StatusCode::Eof),
+            Some(OpenHandle::Dir { folder_id, done }) => {
+                *done = true;
+                *folder_id
+            },
+            _ => return Err(StatusCode::Failure),
+        };
+        let tree = self.tree().await?;
+        let files = tree.children(folder_id).into_iter()
+            .map(|child| File::new(child.name(), self.attrs(Some(child))))
+            .collect();
+        Ok(Name { id, files })
+    }
+
+    async fn open(&mut self,
+                  id: u32,
+                  filename: String,
+                  pflags: OpenFlags,
+                  _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let path = sftp_path(&filename);
+        let tree = self.tree().await?;
+        let writing = pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE);
+        if !writing {
+            let entry = match self.resolve(&tree, &path)? {
+                Some(entry) if !entry.is_folder() => entry,
+                _ => return Err(StatusCode::Failure),
+            };
+            let data = self.contents(entry).await?;
+            self.audit("file_downloaded", entry.file_id, serde_json::json!({})).await;
+            let attrs = self.attrs(Some(entry));
+            let handle = self.add_handle(OpenHandle::Read { data, attrs })?;
+            return Ok(Handle { id, handle });
+        }
+        self.writable()?;
+        let (parent_id, name) = self.destination(&tree, &path)?;
+        let existing = tree.child(parent_id, &name);
+        let keep = match existing {
+            Some(entry) if entry.is_folder() => return Err(StatusCode::Failure),
+            Some(_) if pflags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) => return Err(StatusCode::Failure),
+            Some(entry) => (!pflags.contains(OpenFlags::TRUNCATE)).then_some(entry),
+            None if pflags.contains(OpenFlags::CREATE) => None,
+            None => return Err(StatusCode::NoSuchFile),
+        };
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(StatusCode::Failure);
+        }
+        let mut staged = Staged::new().await.map_err(io_status)?;
+        if let Some(entry) = keep {
+            let mut object = self.object(entry).await?;
+            while let Some(chunk) = object.stream.try_next().await.map_err(io_status)? {
+                staged.write_at(staged.size, &chunk).await.map_err(io_status)?;
+            }
+        }
+        let handle = self.add_handle(OpenHandle::Write {
+            parent_id,
+            name,
+            existing: existing.map(|e| e.file_id),
+            append: pflags.contains(OpenFlags::APPEND),
+            staged,
+        })?;
+        Ok(Handle { id, handle })
+    }
+
+    async fn read(&mut self, id: u32, handle: String, offset: u64, len: u32) -> Result<Data, Self::Error> {
+        let data: &[u8] = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Read { data, .. }) => &data[..],
+            Some(OpenHandle::Write { staged, .. }) => {
+                if offset >= staged.size {
+                    return Err(StatusCode::Eof);
+                }
+                let data = staged.read_at(offset, len as usize).await.map_err(io_status)?;
+                return Ok(Data { id, data });
+            },
+            _ => return Err(StatusCode::Failure),
+        };
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        if start >= data.len() {
+            return Err(StatusCode::Eof);
+        }
+        let end = data.len().min(start.saturating_add(len as usize));
+        Ok(Data { id, data: data[start..end].to_vec() })
+    }
+
+    // into the staged file, nothing is stored until close. anything past
+    // the quota could never be kept so it fails here rather than at the end
+    async fn write(&mut self, id: u32, handle: String, offset: u64, data: Vec<u8>) -> Result<Status, Self::Error> {
+        let (staged, append) = match self.handles.get_mut(&handle) {
+            Some(OpenHandle::Write { staged, append, .. }) => (staged, *append),
+            _ => return Err(StatusCode::Failure),
+        };
+        let start = match append {
+            true => staged.size,
+            false => offset,
+        };
+        let end = start.checked_add(data.len() as u64).ok_or(StatusCode::Failure)?;
+        if end > STORAGE_LIMIT as u64 {
+            return Err(StatusCode::Failure);
+        }
+        staged.write_at(start, &data).await.map_err(io_status)?;
+        Ok(ok(id))
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        match self.handles.remove(&handle) {
+            Some(OpenHandle::Write { parent_id, name, existing, mut staged, .. }) => {
+                let data = staged.contents().await.map_err(io_status)?;
+                self.commit(parent_id, &name, existing, data).await.map_err(status_of)?;
+            },
+            Some(_) => {},
+            None => return Err(StatusCode::Failure),
+        }
+        Ok(ok(id))
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let tree = self.tree().await?;
+        let entry = self.resolve_entry(&tree, &sftp_path(&filename))?;
+        if entry.is_folder() {
+            return Err(StatusCode::Failure);
+        }
+        self.delete(entry.file_id).await.map_err(status_of)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(&mut self, id: u32, path: String, _attrs: FileAttributes) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let tree = self.tree().await?;
+        let (parent_id, name) = self.destination(&tree, &sftp_path(&path))?;
+        if tree.child(parent_id, &name).is_some() {
+            return Err(StatusCode::Failure);
+        }
+        let folder_id = make_folder(&self.state, self.login.user_id, parent_id, &name).await.map_err(status_of)?;
+        self.audit("folder_created", folder_id, serde_json::json!({"name": name, "parent_id": parent_id})).await;
+        self.created(folder_id, parent_id, &name).await;
+        Ok(ok(id))
+    }
+
+    // empty folders only, as rmdir does anywhere else
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let tree = self.tree().await?;
+        let entry = self.resolve_entry(&tree, &sftp_path(&path))?;
+        if !entry.is_folder() || !tree.children(Some(entry.file_id)).is_empty() {
+            return Err(StatusCode::Failure);
+        }
+        self.delete(entry.file_id).await.map_err(status_of)?;
+        Ok(ok(id))
+    }
+
+    // sftp v3 rename never overwrites
+    async fn rename(&mut self, id: u32, oldpath: String, newpath: String) -> Result<Status, Self::Error> {
+        self.writable()?;
+        let tree = self.tree().await?;
+        let (from, to) = (sftp_path(&oldpath), sftp_path(&newpath));
+        let entry = self.resolve_entry(&tree, &from)?;
+        if to.starts_with(&from) {
+            return Err(StatusCode::Failure);
+        }
+        let (parent_id, name) = self.destination(&tree, &to)?;
+        if tree.child(parent_id, &name).is_some() {
+            return Err(StatusCode::Failure);
+        }
+        let audience = file_audience(&self.state.pool, &entry.file_id).await.map_err(status_of)?;
+        let old_parent = relocate_file(&self.state, self.login.user_id, entry.file_id, parent_id).await
+            .map_err(status_of)?;
+        let renamed = name != entry.name();
+        if renamed {
+            rename_entry(&self.state, self.login.user_id, entry, &name).await.map_err(status_of)?;
+        }
+        let moved = old_parent != parent_id;
+        if moved {
+            self.audit("file_moved", entry.file_id, serde_json::json!({"before": old_parent, "after": parent_id})).await;
+        }
+        if renamed {
+            self.audit("file_renamed", entry.file_id, serde_json::json!({"before": entry.name(), "after": name})).await;
+        }
+        if moved || renamed {
+            let kind = if moved { ChangeKind::Moved } else { ChangeKind::Renamed };
+            let mut event = ChangeEvent::new(kind, Some(self.login.user_id), entry.file_id, self.login.user_id,
+                                             parent_id, Some(name));
+            event.audience = audience;
+            announce(&self.state, event).await;
+        }
+        Ok(ok(id))
+    }
+}
+
+pub async fn add_ssh_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<AddSshKeyForm>,
+) -> Result<Json<SshKeyResponse>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(ServerError::BadRequest("Key names are 1 to 100 characters".to_string()));
+    }
+    let (algorithm, blob) = parse_authorized_key(&payload.public_key)
+        .ok_or(ServerError::BadRequest("Not an OpenSSH public key".to_string()))?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&blob);
+    // only keys the server can check a signature from
+    russh_keys::parse_public_key_base64(&encoded)
+        .map_err(|_| ServerError::BadRequest("Unsupported key type".to_string()))?;
+    // vault folders have encrypted names, so they are not in the tree to chroot to
+    if let Some(root_id) = &payload.root_id {
+        match parent_kind(&state.pool, &user_id, root_id).await? {
+            Some((false, true)) => {},
+            Some(_) => return Err(ServerError::BadRequest("Root must be a folder outside the vault".to_string())),
+            None => return Err(ServerError::NotFound("Folder not found".to_string())),
+        }
+    }
+    let key = sqlx::query_as::<_, SshKeyResponse>(r#"INSERT INTO ssh_keys (key_id, user_id, name, public_key,
+                                                    fingerprint, read_only, root_id)
+                                                    VALUES ($1,$2,$3,$4,$5,$6,$7)
+                                                    RETURNING key_id, name, fingerprint, read_only, root_id,
+                                                    created_at, last_used_at;"#)
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(name)
+        .bind(format!("{} {}", algorithm, encoded))
+        .bind(fingerprint(&blob))
+        .bind(payload.read_only.unwrap_or(false))
+        .bind(payload.root_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref d) if d.is_unique_violation() =>
+                ServerError::BadRequest("Key already added".to_string()),
+            e => ServerError::DatabaseError(e.to_string()),
+        })?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "ssh_key_added",
+        details: Some(serde_json::json!({"key_id": key.key_id, "name": name, "fingerprint": key.fingerprint,
+                                         "read_only": key.read_only, "root_id": key.root_id})),
+        ..Default::default()
+    }).await;
+    Ok(Json(key))
+}
+
+pub async fn get_ssh_keys(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Vec<SshKeyResponse>>, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let keys = sqlx::query_as::<_, SshKeyResponse>(r#"SELECT key_id, name, fingerprint, read_only, root_id,
+                                                     created_at, last_used_at
+                                                     FROM ssh_keys
+                                                     WHERE user_id = ($1)
+                                                     ORDER BY created_at;"#)
+        .bind(user_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(keys))
+}
+
+// open sessions keep going, the key just wont log in again
+pub async fn delete_ssh_key(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    info: RequestInfo,
+    payload: Json<SshKeyIdForm>,
+) -> Result<HttpStatus, ServerError> {
+    let user_id = current_user(&state, jar).await?;
+    let fingerprint: Option<String> = sqlx::query_scalar(r#"DELETE FROM ssh_keys
+                                                           WHERE key_id = ($1) AND user_id = ($2)
+                                                           RETURNING fingerprint;"#)
+        .bind(payload.key_id)
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let fingerprint = fingerprint.ok_or(ServerError::NotFound("SSH key not found".to_string()))?;
+    record(&state.pool, &info, AuditEvent {
+        actor_id: Some(user_id),
+        action: "ssh_key_deleted",
+        details: Some(serde_json::json!({"key_id": payload.key_id, "fingerprint": fingerprint})),
+        ..Default::default()
+    }).await;
+    Ok(HttpStatus::OK)
+}
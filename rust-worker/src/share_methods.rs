@@ -0,0 +1,378 @@
+use axum::{extract, extract::State, Json, http::StatusCode, http::header, http::HeaderMap};
+use axum::body::Body;
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+use chrono::Utc;
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{PasswordHash, SaltString, rand_core::OsRng};
+
+use crate::models::{AppState,
+                    CreateShareLinkForm,
+                    RevokeShareLinkForm,
+                    ShareAccessQuery,
+                    ShareLinkResponse,
+                    ShareMode,
+                    FileType,
+                    ScanStatus,
+                    ServerError};
+use crate::auth_methods::get_current_user;
+use crate::methods::open_file;
+use crate::msc_actions::generate_token;
+use crate::scanner::check_download;
+use crate::mime::is_safe_inline;
+
+pub const SHARE_PASSWORD_HEADER: &str = "x-share-password";
+// wrong passwords in a row before a link locks, after that it takes one guess
+// per lockout until the right one comes in
+pub const SHARE_PASSWORD_ATTEMPTS: i32 = 5;
+pub const SHARE_LOCKOUT_SECS: i64 = 15 * 60;
+
+// argon2 with a salt per link, the phc string carries the salt and parameters
+pub fn hash_share_password(password: &str) -> Result<String, ServerError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
+
+// the comparison inside verify_password is constant time
+pub fn verify_share_password(password: &str, hashed: &str) -> bool {
+    PasswordHash::new(hashed)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false)
+}
+
+pub async fn create_share_link(State(state): State<AppState>,
+                               jar: CookieJar,
+                               payload: Json<CreateShareLinkForm>,
+) -> Result<Json<ShareLinkResponse>, ServerError> {
+
+    println!("CreateShareLink ran");
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let file_id = Uuid::parse_str(&payload.file_id)
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+
+    if let Some(expires_at) = payload.expires_at && expires_at <= Utc::now() {
+        return Err(ServerError::InternalError("Expiry date is in the past".to_string()));
+    }
+    if let Some(max) = payload.max_downloads && max <= 0 {
+        return Err(ServerError::InternalError("Invalid download limit".to_string()));
+    }
+
+    // only the owner can share
+    let vault: Option<bool> = sqlx::query_scalar(r#"SELECT vault FROM files
+                                                    WHERE file_id = ($1) AND owner_id = ($2);"#)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    match vault {
+        None => return Err(ServerError::NotFound("File not found".to_string())),
+        // a public link would only hand out ciphertext, see share_vault_item
+        Some(true) => return Err(ServerError::BadRequest("Vault items cannot be shared by link".to_string())),
+        Some(false) => {},
+    }
+
+    // argon2 is slow on purpose, keep it off the runtime threads
+    let hashed_password = match payload.password.clone().filter(|p| !p.is_empty()) {
+        Some(password) => Some(tokio::task::spawn_blocking(move || hash_share_password(&password))
+            .await
+            .map_err(|e| ServerError::InternalError(e.to_string()))??),
+        None => None,
+    };
+
+    let link = sqlx::query_as::<_, ShareLinkResponse>(r#"INSERT INTO share_links (link_id, token,
+                       file_id, owner_id, mode, expires_at, hashed_password, max_downloads)
+                       VALUES ($1,$2,$3,$4,$5,$6,$7,$8)
+                       RETURNING link_id, token, file_id, mode, expires_at,
+                       hashed_password IS NOT NULL AS has_password, max_downloads,
+                       download_count, view_count, revoked, created_at, last_accessed;"#)
+        .bind(Uuid::new_v4())
+        .bind(generate_token())
+        .bind(file_id)
+        .bind(owner_id)
+        .bind(&payload.mode)
+        .bind(payload.expires_at)
+        .bind(&hashed_password)
+        .bind(payload.max_downloads)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| {  eprintln!("Error {:?}", e);
+                        ServerError::DatabaseError(e.to_string())})?;
+
+    Ok(Json(link))
+}
+
+pub async fn get_share_links(State(state): State<AppState>,
+                             jar: CookieJar,
+) -> Result<Json<Vec<ShareLinkResponse>>, ServerError> {
+
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let links = sqlx::query_as::<_, ShareLinkResponse>(r#"SELECT link_id, token, file_id, mode,
+                       expires_at, hashed_password IS NOT NULL AS has_password, max_downloads,
+                       download_count, view_count, revoked, created_at, last_accessed
+                       FROM share_links WHERE owner_id = ($1)
+                       ORDER BY created_at DESC;"#)
+        .bind(owner_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    Ok(Json(links))
+}
+
+pub async fn revoke_share_link(State(state): State<AppState>,
+                               jar: CookieJar,
+                               payload: Json<RevokeShareLinkForm>,
+) -> Result<Json<String>, ServerError> {
+
+    println!("RevokeShareLink ran");
+    let owner_id = if let Ok(id) = get_current_user(jar, &state.key, &state.cache).await
+    && id != "NOT VALID" {
+        Uuid::parse_str(&id)
+            .map_err(|_| ServerError::InternalError("Failed to parse user id".to_string()))?
+    } else {
+        return Err(ServerError::Unauthorized("No session token found".to_string()));
+    };
+    let link_id = Uuid::parse_str(&payload.link_id)
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+
+    let result = sqlx::query(r#"UPDATE share_links SET revoked = TRUE
+                                WHERE link_id = ($1) AND owner_id = ($2);"#)
+        .bind(link_id)
+        .bind(owner_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("Share link not found".to_string()));
+    }
+
+    Ok(Json("Share Link Revoked".to_string()))
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredLink {
+    link_id: Uuid,
+    file_id: Uuid,
+    owner_id: Uuid,
+    mode: ShareMode,
+    expires_at: Option<chrono::DateTime<Utc>>,
+    hashed_password: Option<String>,
+    max_downloads: Option<i32>,
+    download_count: i32,
+    revoked: bool,
+}
+
+// the linked file, or one inside a linked folder
+#[derive(sqlx::FromRow)]
+struct SharedFile {
+    file_id: Uuid,
+    file_name: String,
+    extension: Option<String>,
+    size: i64,
+    file_type: FileType,
+    blob_hash: Option<String>,
+    key_id: Option<Uuid>,
+    scan_status: ScanStatus,
+}
+
+// unauthenticated, token is the only credential
+pub async fn access_share_link(State(state): State<AppState>,
+                               extract::Path(token): extract::Path<String>,
+                               extract::Query(query): extract::Query<ShareAccessQuery>,
+                               headers: HeaderMap,
+) -> Result<Response, ServerError> {
+
+    println!("AccessShareLink ran");
+    let link: Option<StoredLink> =
+        sqlx::query_as(r#"SELECT link_id, file_id, owner_id, mode, expires_at,
+                          hashed_password, max_downloads, download_count, revoked
+                          FROM share_links WHERE token = ($1);"#)
+        .bind(&token)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let StoredLink { link_id, file_id: root_id, owner_id, mode, expires_at, hashed_password, max_downloads,
+                     download_count, revoked } = match link {
+        Some(l) => l,
+        None => return Err(ServerError::NotFound("Link not found".to_string())),
+    };
+    // revoked and expired links look the same as missing ones
+    if revoked || expires_at.is_some_and(|date| date <= Utc::now()) {
+        return Err(ServerError::NotFound("Link not found".to_string()));
+    }
+    if let Some(hashed) = hashed_password {
+        // every guess is counted before argon2 runs, so however many arrive at
+        // once a link only ever verifies SHARE_PASSWORD_ATTEMPTS per lockout
+        let claimed = sqlx::query(r#"UPDATE share_links
+                                     SET password_failures = password_failures + 1,
+                                     locked_until = CASE WHEN password_failures + 1 >= ($2)
+                                         THEN NOW() + make_interval(secs => ($3))
+                                         ELSE locked_until END
+                                     WHERE link_id = ($1)
+                                     AND (locked_until IS NULL OR locked_until <= NOW());"#)
+            .bind(link_id)
+            .bind(SHARE_PASSWORD_ATTEMPTS)
+            .bind(SHARE_LOCKOUT_SECS as f64)
+            .execute(&state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+            .rows_affected();
+        if claimed == 0 {
+            return Err(ServerError::TooManyRequests("Too many password attempts".to_string()));
+        }
+        let given = headers.get(SHARE_PASSWORD_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let valid = tokio::task::spawn_blocking(move || verify_share_password(&given, &hashed))
+            .await
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        if !valid {
+            return Err(ServerError::Unauthorized("Invalid password".to_string()));
+        }
+        sqlx::query(r#"UPDATE share_links SET password_failures = 0, locked_until = NULL
+                       WHERE link_id = ($1);"#)
+            .bind(link_id)
+            .execute(&state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    }
+
+    // a folder link can serve anything below it
+    let target_id = match &query.file_id {
+        Some(id) if !id.is_empty() => Uuid::parse_str(id)
+            .map_err(|e| ServerError::InternalError(e.to_string()))?,
+        _ => root_id,
+    };
+    let target: Option<SharedFile> =
+        sqlx::query_as(r#"WITH RECURSIVE tree AS (
+                                SELECT file_id FROM files WHERE file_id = ($1)
+                                UNION ALL
+                                SELECT f.file_id FROM files f
+                                JOIN tree t ON f.parent_id = t.file_id
+                          )
+                          SELECT file_id, file_name, extension, size, file_type, blob_hash, key_id,
+                          scan_status
+                          FROM files
+                          WHERE file_id = ($2) AND file_id IN (SELECT file_id FROM tree);"#)
+        .bind(root_id)
+        .bind(target_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let SharedFile { file_id, file_name, extension, size, file_type, blob_hash, key_id, scan_status } = match target {
+        Some(t) => t,
+        None => return Err(ServerError::NotFound("File not found".to_string())),
+    };
+
+    if file_type == FileType::Folder {
+        let children: Vec<(Uuid, String, Option<String>, i64, FileType)> =
+            sqlx::query_as(r#"SELECT file_id, file_name, extension, size, file_type
+                              FROM files WHERE parent_id = ($1)
+                              ORDER BY file_name;"#)
+            .bind(file_id)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        sqlx::query(r#"UPDATE share_links SET view_count = view_count + 1,
+                       last_accessed = ($1) WHERE link_id = ($2);"#)
+            .bind(Utc::now())
+            .bind(link_id)
+            .execute(&state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        let listing: Vec<serde_json::Value> = children.into_iter()
+            .map(|(id, name, ext, size, ftype)| serde_json::json!({
+                "file_id": id, "file_name": name, "extension": ext,
+                "size": size, "file_type": ftype,
+            }))
+            .collect();
+        return Ok(Json(serde_json::json!({"file_name": file_name,
+                                           "mode": mode,
+                                           "files": listing})).into_response());
+    }
+
+    // before the counter so a refused download doesnt use one up
+    check_download(&state.scanning, &scan_status)?;
+
+    // opened first, a storage error shouldnt use up one of the downloads
+    let object = open_file(&state, &owner_id.to_string(), &file_id.to_string(),
+                           &extension, &blob_hash, key_id).await?;
+
+    // counter bump doubles as the limit check so concurrent requests cant overshoot
+    let counted = match mode {
+        ShareMode::Download => {
+            if let Some(max) = max_downloads && download_count >= max {
+                return Err(ServerError::Forbidden("Download limit reached".to_string()));
+            }
+            sqlx::query(r#"UPDATE share_links
+                           SET download_count = download_count + 1, last_accessed = ($1)
+                           WHERE link_id = ($2)
+                           AND (max_downloads IS NULL OR download_count < max_downloads);"#)
+                .bind(Utc::now())
+                .bind(link_id)
+                .execute(&state.pool)
+                .await
+                .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+                .rows_affected()
+        },
+        ShareMode::View => sqlx::query(r#"UPDATE share_links
+                           SET view_count = view_count + 1, last_accessed = ($1)
+                           WHERE link_id = ($2);"#)
+                .bind(Utc::now())
+                .bind(link_id)
+                .execute(&state.pool)
+                .await
+                .map_err(|e| ServerError::DatabaseError(e.to_string()))?
+                .rows_affected(),
+    };
+    if counted == 0 {
+        return Err(ServerError::Forbidden("Download limit reached".to_string()));
+    }
+
+    let stored_type = object.meta.content_type
+        .unwrap_or("application/octet-stream".to_string());
+    let full_name = match &extension {
+        Some(e) if !e.is_empty() => format!("{}.{}", file_name, e),
+        _ => file_name,
+    };
+    // served from our own origin, so only types that cant run script are shown
+    // in the browser, the rest is downloaded as opaque bytes
+    let inline = mode == ShareMode::View && is_safe_inline(&stored_type);
+    let (content_type, disposition) = if inline {
+        (stored_type, format!("inline; filename=\"{}\"", full_name.replace('"', "")))
+    } else {
+        ("application/octet-stream".to_string(),
+         format!("attachment; filename=\"{}\"", full_name.replace('"', "")))
+    };
+    let body = Body::from_stream(object.stream);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, size)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::CONTENT_SECURITY_POLICY, "sandbox")
+        .body(body)
+        .map_err(|e| ServerError::InternalError(e.to_string()))
+}
@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, ObjectMeta, ObjectBody, ObjectStream, signed_object_url};
+
+// single node installs, objects live at {root}/{bucket}/{key}
+// content types are kept next to them under {root}/.meta/{bucket}/{key}
+// and half written objects under {root}/.staging, outside every bucket
+#[derive(Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+    base_url: String,
+    secret: String,
+}
+
+fn io_error(e: std::io::Error) -> ServerError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        return ServerError::NotFound("Object not found".to_string());
+    }
+    eprintln!("Error {:?}", e);
+    ServerError::InternalError(e.to_string())
+}
+
+// keys come from us but bucket names and prefixes can come from requests
+fn safe_relative(path: &str) -> Result<&Path, ServerError> {
+    let p = Path::new(path);
+    if path.is_empty() || p.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(ServerError::InternalError("Invalid object path".to_string()));
+    }
+    Ok(p)
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>, base_url: String, secret: String) -> Self {
+        LocalStore { root: root.into(), base_url, secret }
+    }
+
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, ServerError> {
+        // .meta and .staging are ours
+        if bucket.starts_with('.') {
+            return Err(ServerError::InternalError("Invalid object path".to_string()));
+        }
+        Ok(self.root.join(safe_relative(bucket)?))
+    }
+
+    async fn staging_path(&self) -> Result<PathBuf, ServerError> {
+        let dir = self.root.join(".staging");
+        fs::create_dir_all(&dir).await.map_err(io_error)?;
+        Ok(dir.join(uuid::Uuid::new_v4().simple().to_string()))
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, ServerError> {
+        Ok(self.bucket_path(bucket)?.join(safe_relative(key)?))
+    }
+
+    fn meta_path(&self, bucket: &str, key: &str) -> Result<PathBuf, ServerError> {
+        Ok(self.root.join(".meta").join(safe_relative(bucket)?).join(safe_relative(key)?))
+    }
+
+    async fn object_meta(&self, bucket: &str, key: &str) -> Result<ObjectMeta, ServerError> {
+        let metadata = fs::metadata(self.object_path(bucket, key)?).await.map_err(io_error)?;
+        let content_type = fs::read_to_string(self.meta_path(bucket, key)?).await.ok();
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: metadata.len() as i64,
+            content_type,
+            last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        })
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn create_bucket(&self, bucket: &str) -> Result<(), ServerError> {
+        fs::create_dir_all(self.bucket_path(bucket)?).await.map_err(io_error)
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, ServerError> {
+        Ok(fs::metadata(self.bucket_path(bucket)?).await
+            .map(|m| m.is_dir())
+            .unwrap_or(false))
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Bytes, content_type: &str)
+        -> Result<(), ServerError> {
+        let path = self.object_path(bucket, key)?;
+        let meta = self.meta_path(bucket, key)?;
+        for p in [&path, &meta] {
+            if let Some(parent) = p.parent() {
+                fs::create_dir_all(parent).await.map_err(io_error)?;
+            }
+        }
+        // write then rename so readers never see half an object
+        let tmp = self.staging_path().await?;
+        fs::write(&tmp, &data).await.map_err(io_error)?;
+        fs::rename(&tmp, &path).await.map_err(io_error)?;
+        fs::write(&meta, content_type).await.map_err(io_error)?;
+        Ok(())
+    }
+
+    async fn put_stream(&self, bucket: &str, key: &str, mut stream: ObjectStream, content_type: &str)
+        -> Result<(), ServerError> {
+        let path = self.object_path(bucket, key)?;
+        let meta = self.meta_path(bucket, key)?;
+        for p in [&path, &meta] {
+            if let Some(parent) = p.parent() {
+                fs::create_dir_all(parent).await.map_err(io_error)?;
+            }
+        }
+        let tmp = self.staging_path().await?;
+        let mut file = fs::File::create(&tmp).await.map_err(io_error)?;
+        let written: Result<(), std::io::Error> = async {
+            while let Some(chunk) = stream.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await
+        }.await;
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(io_error(e));
+        }
+        fs::rename(&tmp, &path).await.map_err(io_error)?;
+        fs::write(&meta, content_type).await.map_err(io_error)?;
+        Ok(())
+    }
+
+    async fn get_stream(&self, bucket: &str, key: &str) -> Result<ObjectBody, ServerError> {
+        let meta = self.object_meta(bucket, key).await?;
+        let file = fs::File::open(self.object_path(bucket, key)?).await.map_err(io_error)?;
+        Ok(ObjectBody { meta, stream: ReaderStream::new(file).boxed() })
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectMeta>, ServerError> {
+        match self.object_meta(bucket, key).await {
+            Ok(m) => Ok(Some(m)),
+            Err(ServerError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), ServerError> {
+        // same as s3, deleting something missing is not an error
+        match fs::remove_file(self.object_path(bucket, key)?).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(io_error(e)),
+        }
+        let _ = fs::remove_file(self.meta_path(bucket, key)?).await;
+        Ok(())
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ServerError> {
+        let bucket_path = self.bucket_path(bucket)?;
+        let mut objects: Vec<ObjectMeta> = Vec::new();
+        let mut dirs: Vec<PathBuf> = vec![bucket_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(e) => e,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_error(e)),
+            };
+            while let Some(entry) = entries.next_entry().await.map_err(io_error)? {
+                let path = entry.path();
+                let file_type = entry.file_type().await.map_err(io_error)?;
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let key = match path.strip_prefix(&bucket_path).ok().and_then(|k| k.to_str()) {
+                    Some(k) => k.replace('\\', "/"),
+                    None => continue,
+                };
+                if !key.starts_with(prefix) {
+                    continue;
+                }
+                objects.push(self.object_meta(bucket, &key).await?);
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    async fn copy(&self, src_bucket: &str, src_key: &str, dst_bucket: &str, dst_key: &str)
+        -> Result<(), ServerError> {
+        let dst = self.object_path(dst_bucket, dst_key)?;
+        let dst_meta = self.meta_path(dst_bucket, dst_key)?;
+        for p in [&dst, &dst_meta] {
+            if let Some(parent) = p.parent() {
+                fs::create_dir_all(parent).await.map_err(io_error)?;
+            }
+        }
+        fs::copy(self.object_path(src_bucket, src_key)?, &dst).await.map_err(io_error)?;
+        if let Ok(content_type) = fs::read_to_string(self.meta_path(src_bucket, src_key)?).await {
+            fs::write(&dst_meta, content_type).await.map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    async fn presign(&self, bucket: &str, key: &str, expires_in: Duration)
+        -> Result<String, ServerError> {
+        Ok(signed_object_url(&self.base_url, &self.secret, bucket, key, expires_in))
+    }
+}
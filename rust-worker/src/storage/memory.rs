@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, ObjectMeta, ObjectBody, signed_object_url};
+
+#[derive(Clone)]
+struct MemoryObject {
+    data: Bytes,
+    content_type: String,
+    last_modified: DateTime<Utc>,
+}
+
+// for tests, nothing survives a restart
+#[derive(Clone)]
+pub struct MemoryStore {
+    buckets: Arc<RwLock<HashMap<String, HashMap<String, MemoryObject>>>>,
+    base_url: String,
+    secret: String,
+}
+
+fn lock_error() -> ServerError {
+    ServerError::InternalError("Memory store lock poisoned".to_string())
+}
+
+impl MemoryStore {
+    pub fn new(base_url: String, secret: String) -> Self {
+        MemoryStore { buckets: Arc::new(RwLock::new(HashMap::new())), base_url, secret }
+    }
+
+    fn meta(key: &str, object: &MemoryObject) -> ObjectMeta {
+        ObjectMeta {
+            key: key.to_string(),
+            size: object.data.len() as i64,
+            content_type: Some(object.content_type.clone()),
+            last_modified: Some(object.last_modified),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MemoryStore {
+    async fn create_bucket(&self, bucket: &str) -> Result<(), ServerError> {
+        let mut buckets = self.buckets.write().map_err(|_| lock_error())?;
+        buckets.entry(bucket.to_string()).or_default();
+        Ok(())
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, ServerError> {
+        let buckets = self.buckets.read().map_err(|_| lock_error())?;
+        Ok(buckets.contains_key(bucket))
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Bytes, content_type: &str)
+        -> Result<(), ServerError> {
+        let mut buckets = self.buckets.write().map_err(|_| lock_error())?;
+        let objects = buckets.get_mut(bucket)
+            .ok_or(ServerError::NotFound("Bucket not found".to_string()))?;
+        objects.insert(key.to_string(), MemoryObject {
+            data,
+            content_type: content_type.to_string(),
+            last_modified: Utc::now(),
+        });
+        Ok(())
+    }
+
+    async fn get_stream(&self, bucket: &str, key: &str) -> Result<ObjectBody, ServerError> {
+        let object = {
+            let buckets = self.buckets.read().map_err(|_| lock_error())?;
+            buckets.get(bucket).and_then(|o| o.get(key)).cloned()
+                .ok_or(ServerError::NotFound("Object not found".to_string()))?
+        };
+        let meta = MemoryStore::meta(key, &object);
+        let data = object.data;
+        let stream = futures::stream::once(async move { Ok(data) }).boxed();
+        Ok(ObjectBody { meta, stream })
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectMeta>, ServerError> {
+        let buckets = self.buckets.read().map_err(|_| lock_error())?;
+        Ok(buckets.get(bucket)
+            .and_then(|o| o.get(key))
+            .map(|object| MemoryStore::meta(key, object)))
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), ServerError> {
+        let mut buckets = self.buckets.write().map_err(|_| lock_error())?;
+        if let Some(objects) = buckets.get_mut(bucket) {
+            objects.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ServerError> {
+        let buckets = self.buckets.read().map_err(|_| lock_error())?;
+        let mut objects: Vec<ObjectMeta> = buckets.get(bucket)
+            .map(|o| o.iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, object)| MemoryStore::meta(key, object))
+                .collect())
+            .unwrap_or_default();
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    async fn copy(&self, src_bucket: &str, src_key: &str, dst_bucket: &str, dst_key: &str)
+        -> Result<(), ServerError> {
+        let mut buckets = self.buckets.write().map_err(|_| lock_error())?;
+        let object = buckets.get(src_bucket).and_then(|o| o.get(src_key)).cloned()
+            .ok_or(ServerError::NotFound("Object not found".to_string()))?;
+        let objects = buckets.get_mut(dst_bucket)
+            .ok_or(ServerError::NotFound("Bucket not found".to_string()))?;
+        objects.insert(dst_key.to_string(), MemoryObject { last_modified: Utc::now(), ..object });
+        Ok(())
+    }
+
+    async fn presign(&self, bucket: &str, key: &str, expires_in: Duration)
+        -> Result<String, ServerError> {
+        Ok(signed_object_url(&self.base_url, &self.secret, bucket, key, expires_in))
+    }
+}
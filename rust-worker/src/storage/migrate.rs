@@ -0,0 +1,63 @@
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, StorageLayout};
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub users: usize,
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: Vec<String>,
+}
+
+// copies every per-user bucket into the shared layout. objects already at the
+// destination with the same size are skipped so the tool can be re-run after a failure
+pub async fn migrate_to_shared(store: &dyn ObjectStore,
+                               user_ids: &[String],
+                               shared_bucket: &str,
+                               delete_source: bool,
+                               dry_run: bool,
+) -> Result<MigrationReport, ServerError> {
+    let layout = StorageLayout::SharedBucket(shared_bucket.to_string());
+    let mut report = MigrationReport::default();
+
+    if !store.bucket_exists(shared_bucket).await? && !dry_run {
+        store.create_bucket(shared_bucket).await?;
+    }
+
+    for user_id in user_ids {
+        if !store.bucket_exists(user_id).await? {
+            println!("No bucket for {}, skipping", user_id);
+            continue;
+        }
+        report.users += 1;
+        for object in store.list(user_id, "").await? {
+            let dst_key = layout.key(user_id, &object.key);
+            if let Some(existing) = store.head(shared_bucket, &dst_key).await?
+            && existing.size == object.size {
+                report.skipped += 1;
+                continue;
+            }
+            if dry_run {
+                println!("Would copy {}/{} -> {}/{}", user_id, object.key, shared_bucket, dst_key);
+                report.copied += 1;
+                continue;
+            }
+            if let Err(e) = store.copy(user_id, &object.key, shared_bucket, &dst_key).await {
+                eprintln!("Error {:?}", e);
+                report.failed.push(format!("{}/{}", user_id, object.key));
+                continue;
+            }
+            // only drop the source once the copy is confirmed
+            match store.head(shared_bucket, &dst_key).await? {
+                Some(copied) if copied.size == object.size => {
+                    report.copied += 1;
+                    if delete_source {
+                        store.delete(user_id, &object.key).await?;
+                    }
+                },
+                _ => report.failed.push(format!("{}/{}", user_id, object.key)),
+            }
+        }
+    }
+    Ok(report)
+}
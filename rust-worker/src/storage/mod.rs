@@ -0,0 +1,148 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::ServerError;
+use crate::crypto::{constant_time_eq, sign};
+use crate::methods::s3_key;
+
+pub mod s3;
+pub mod local;
+pub mod memory;
+pub mod migrate;
+
+pub use self::s3::S3Store;
+pub use self::local::LocalStore;
+pub use self::memory::MemoryStore;
+
+pub type ObjectStream = BoxStream<'static, Result<Bytes, std::io::Error>>;
+
+pub fn bytes_stream(data: Bytes) -> ObjectStream {
+    futures::stream::once(async move { Ok(data) }).boxed()
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: i64,
+    pub content_type: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+pub struct ObjectBody {
+    pub meta: ObjectMeta,
+    pub stream: ObjectStream,
+}
+
+// everything handlers need from object storage, AppState holds one of these
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn create_bucket(&self, bucket: &str) -> Result<(), ServerError>;
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, ServerError>;
+    async fn put(&self, bucket: &str, key: &str, data: Bytes, content_type: &str)
+        -> Result<(), ServerError>;
+    // for bodies that shouldnt be held whole. this collects them, backends
+    // that can write as the chunks arrive override it
+    async fn put_stream(&self, bucket: &str, key: &str, mut stream: ObjectStream, content_type: &str)
+        -> Result<(), ServerError> {
+        let mut data = BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?);
+        }
+        self.put(bucket, key, data.freeze(), content_type).await
+    }
+    async fn get_stream(&self, bucket: &str, key: &str) -> Result<ObjectBody, ServerError>;
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectMeta>, ServerError>;
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), ServerError>;
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ServerError>;
+    async fn copy(&self, src_bucket: &str, src_key: &str, dst_bucket: &str, dst_key: &str)
+        -> Result<(), ServerError>;
+    async fn presign(&self, bucket: &str, key: &str, expires_in: Duration)
+        -> Result<String, ServerError>;
+}
+
+// where a user's objects live. bucket per user is the original layout, shared
+// keeps everyone in one bucket under {user_id}/ so provider bucket limits dont apply
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageLayout {
+    BucketPerUser,
+    SharedBucket(String),
+}
+
+impl StorageLayout {
+    // STORAGE_LAYOUT = bucket-per-user (default) | shared, SHARED_BUCKET names the bucket
+    pub fn from_env() -> Self {
+        match env::var("STORAGE_LAYOUT").unwrap_or_default().as_str() {
+            "shared" => StorageLayout::SharedBucket(env::var("SHARED_BUCKET")
+                .unwrap_or("servr-storage".to_string())),
+            _ => StorageLayout::BucketPerUser,
+        }
+    }
+
+    pub fn bucket(&self, owner_id: &str) -> String {
+        match self {
+            StorageLayout::BucketPerUser => owner_id.to_string(),
+            StorageLayout::SharedBucket(bucket) => bucket.clone(),
+        }
+    }
+
+    pub fn key(&self, owner_id: &str, object_name: &str) -> String {
+        match self {
+            StorageLayout::BucketPerUser => object_name.to_string(),
+            StorageLayout::SharedBucket(_) => format!("{}/{}", owner_id, object_name),
+        }
+    }
+
+    // (bucket, key) for a file row
+    pub fn location(&self, owner_id: &str, file_id: &str, extension: &Option<String>)
+        -> (String, String) {
+        let object_name = s3_key(file_id.to_string(), extension);
+        (self.bucket(owner_id), self.key(owner_id, &object_name))
+    }
+}
+
+// local and memory backends have nothing to presign with, so they hand out
+// links to /objects which checks this signature before streaming
+pub fn object_signature(secret: &str, bucket: &str, key: &str, expires: i64) -> String {
+    sign(secret.as_bytes(), format!("{}:{}:{}", bucket, key, expires).as_bytes())
+}
+
+pub fn verify_object_signature(secret: &str, bucket: &str, key: &str, expires: i64, signature: &str) -> bool {
+    constant_time_eq(object_signature(secret, bucket, key, expires).as_bytes(), signature.as_bytes())
+}
+
+pub fn signed_object_url(base_url: &str,
+                         secret: &str,
+                         bucket: &str,
+                         key: &str,
+                         expires_in: Duration,
+) -> String {
+    let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+    let signature = object_signature(secret, bucket, key, expires);
+    format!("{}/objects/{}/{}?expires={}&signature={}",
+            base_url.trim_end_matches('/'), bucket, key, expires, signature)
+}
+
+// STORAGE_BACKEND = s3 (default) | local | memory
+pub async fn store_from_env(secret: &str) -> Arc<dyn ObjectStore> {
+    let base_url = env::var("PUBLIC_URL")
+        .unwrap_or("http://localhost:3000".to_string());
+    match env::var("STORAGE_BACKEND").unwrap_or_default().as_str() {
+        "local" => {
+            let root = env::var("LOCAL_STORAGE_PATH")
+                .unwrap_or("./storage".to_string());
+            println!("Storage: local ({})", root);
+            Arc::new(LocalStore::new(root, base_url, secret.to_string()))
+        },
+        "memory" => {
+            println!("Storage: memory");
+            Arc::new(MemoryStore::new(base_url, secret.to_string()))
+        },
+        _ => Arc::new(S3Store::from_env().await),
+    }
+}
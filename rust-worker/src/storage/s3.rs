@@ -0,0 +1,305 @@
+use async_trait::async_trait;
+use aws_sdk_s3 as s3;
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use std::env;
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+
+use crate::models::ServerError;
+use crate::storage::{ObjectStore, ObjectMeta, ObjectBody, ObjectStream};
+
+// s3 wants multipart parts of at least 5MiB, all but the last
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct S3Store {
+    pub client: s3::Client,
+}
+
+fn to_utc(date: Option<&s3::primitives::DateTime>) -> Option<DateTime<Utc>> {
+    date.and_then(|d| DateTime::from_timestamp(d.secs(), d.subsec_nanos()))
+}
+
+impl S3Store {
+    pub fn new(client: s3::Client) -> Self {
+        S3Store { client }
+    }
+
+    pub async fn from_env() -> Self {
+        let minio_url = match env::var("MINIO_ENDPOINT") {
+            Ok(url) => {
+                println!("Minio: {}",url);
+                url
+            },
+            Err(e) => {
+                       eprintln!("Error {:?}", e);
+                       "".to_string()
+            },
+        };
+        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            //was from_env(), but default naming in the env file
+            .endpoint_url(minio_url)
+            //.credentials_provider(r2_credentials)
+            .region(aws_config::meta
+                ::region::RegionProviderChain::default_provider()
+                .or_else("eu-west-2"))
+            .load()
+            .await;
+
+        let s3_config = s3::config::Builder::from(&config)
+            .force_path_style(true)
+            .build();
+
+        S3Store::new(s3::Client::from_conf(s3_config))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn create_bucket(&self, bucket: &str) -> Result<(), ServerError> {
+        match self.client.create_bucket()
+            .bucket(bucket)
+            .send()
+            .await{
+                Ok(_) => Ok(()),
+                Err(e) => {
+                            eprintln!("Error {:?}", e);
+                            Err(ServerError::S3Error(Box::new(e.into())))
+                }
+        }
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, ServerError> {
+        match self.client.head_bucket().bucket(bucket).send().await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                if let Some(code) = e.code()
+                    && (code == "NotFound" || code == "NoSuchBucket") {
+                    return Ok(false);
+                }
+                eprintln!("Error {:?}", e);
+                Err(ServerError::S3Error(Box::new(e.into())))
+            }
+        }
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Bytes, content_type: &str)
+        -> Result<(), ServerError> {
+        match self.client.put_object().bucket(bucket).key(key).body(data.into())
+                .content_type(content_type)
+                .send()
+                .await {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                               eprintln!("Error {:?}", e);
+                               Err(ServerError::S3Error(Box::new(e.into())))
+                    },
+        }
+    }
+
+    async fn put_stream(&self, bucket: &str, key: &str, mut stream: ObjectStream, content_type: &str)
+        -> Result<(), ServerError> {
+        let mut part = BytesMut::new();
+        let mut ended = false;
+        while part.len() < PART_SIZE {
+            match stream.next().await {
+                Some(chunk) => part.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?),
+                None => {
+                    ended = true;
+                    break;
+                },
+            }
+        }
+        // fits in one part, no need for a multipart upload
+        if ended {
+            return self.put(bucket, key, part.freeze(), content_type).await;
+        }
+        let upload = self.client.create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .content_type(content_type)
+            .send()
+            .await
+            .map_err(|e| {  eprintln!("Error {:?}", e);
+                            ServerError::S3Error(Box::new(e.into()))})?;
+        let upload_id = upload.upload_id().unwrap_or_default().to_string();
+        let uploaded: Result<Vec<CompletedPart>, ServerError> = async {
+            let mut parts: Vec<CompletedPart> = Vec::new();
+            loop {
+                while part.len() < PART_SIZE && !ended {
+                    match stream.next().await {
+                        Some(chunk) => part.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?),
+                        None => ended = true,
+                    }
+                }
+                if part.is_empty() {
+                    break;
+                }
+                let number = parts.len() as i32 + 1;
+                let body = part.split_to(part.len().min(PART_SIZE)).freeze();
+                let res = self.client.upload_part()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .part_number(number)
+                    .body(body.into())
+                    .send()
+                    .await
+                    .map_err(|e| {  eprintln!("Error {:?}", e);
+                                    ServerError::S3Error(Box::new(e.into()))})?;
+                parts.push(CompletedPart::builder()
+                    .part_number(number)
+                    .set_e_tag(res.e_tag().map(|t| t.to_string()))
+                    .build());
+                if ended && part.is_empty() {
+                    break;
+                }
+            }
+            Ok(parts)
+        }.await;
+        let parts = match uploaded {
+            Ok(parts) => parts,
+            Err(e) => {
+                let _ = self.client.abort_multipart_upload()
+                    .bucket(bucket).key(key).upload_id(&upload_id).send().await;
+                return Err(e);
+            },
+        };
+        match self.client.complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    eprintln!("Error {:?}", e);
+                    let _ = self.client.abort_multipart_upload()
+                        .bucket(bucket).key(key).upload_id(&upload_id).send().await;
+                    Err(ServerError::S3Error(Box::new(e.into())))
+                },
+        }
+    }
+
+    async fn get_stream(&self, bucket: &str, key: &str) -> Result<ObjectBody, ServerError> {
+        let object = match self.client.get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await {
+                Ok(o) => o,
+                Err(e) => {
+                    if let Some(code) = e.code() && code == "NoSuchKey" {
+                        return Err(ServerError::NotFound("Object not found".to_string()));
+                    }
+                    eprintln!("Error {:?}", e);
+                    return Err(ServerError::S3Error(Box::new(e.into())))
+                },
+        };
+        let meta = ObjectMeta {
+            key: key.to_string(),
+            size: object.content_length().unwrap_or(0),
+            content_type: object.content_type().map(|c| c.to_string()),
+            last_modified: to_utc(object.last_modified()),
+        };
+        let stream = ReaderStream::new(object.body.into_async_read()).boxed();
+        Ok(ObjectBody { meta, stream })
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<Option<ObjectMeta>, ServerError> {
+        match self.client.head_object().bucket(bucket).key(key).send().await {
+            Ok(o) => Ok(Some(ObjectMeta {
+                key: key.to_string(),
+                size: o.content_length().unwrap_or(0),
+                content_type: o.content_type().map(|c| c.to_string()),
+                last_modified: to_utc(o.last_modified()),
+            })),
+            Err(e) => {
+                if let Some(code) = e.code()
+                    && (code == "NotFound" || code == "NoSuchKey") {
+                    return Ok(None);
+                }
+                eprintln!("Error {:?}", e);
+                Err(ServerError::S3Error(Box::new(e.into())))
+            }
+        }
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), ServerError> {
+        match self.client.delete_object().bucket(bucket).key(key)
+            .send().await {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        eprintln!("Error {:?}", e);
+                        Err(ServerError::S3Error(Box::new(e.into())))
+                    },
+        }
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectMeta>, ServerError> {
+        let mut objects: Vec<ObjectMeta> = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let page = self.client.list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_continuation_token(token.clone())
+                .send()
+                .await
+                .map_err(|e| {  eprintln!("Error {:?}", e);
+                                ServerError::S3Error(Box::new(e.into()))})?;
+            for o in page.contents() {
+                objects.push(ObjectMeta {
+                    key: o.key().unwrap_or_default().to_string(),
+                    size: o.size().unwrap_or(0),
+                    content_type: None,
+                    last_modified: to_utc(o.last_modified()),
+                });
+            }
+            match page.next_continuation_token() {
+                Some(t) if page.is_truncated().unwrap_or(false) => token = Some(t.to_string()),
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn copy(&self, src_bucket: &str, src_key: &str, dst_bucket: &str, dst_key: &str)
+        -> Result<(), ServerError> {
+        match self.client.copy_object()
+            .copy_source(format!("{}/{}", src_bucket, src_key))
+            .bucket(dst_bucket)
+            .key(dst_key)
+            .send()
+            .await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    eprintln!("Error {:?}", e);
+                    Err(ServerError::S3Error(Box::new(e.into())))
+                },
+        }
+    }
+
+    async fn presign(&self, bucket: &str, key: &str, expires_in: Duration)
+        -> Result<String, ServerError> {
+        let config = PresigningConfig::expires_in(expires_in)
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        match self.client.get_object()
+                                .bucket(bucket)
+                                .key(key)
+                                .presigned(config)
+                                .await {
+                                        Ok(link) => Ok(link.uri().to_string()),
+                                        Err(e) => {
+                                                    eprintln!("Error {:?}", e);
+                                                    Err(ServerError::S3Error(Box::new(e.into())))
+                                                  }
+                                      }
+    }
+}
@@ -0,0 +1,294 @@
+use axum::{extract::State, Json, http::StatusCode};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+use std::collections::HashMap;
+
+use crate::models::{AppState,
+                    CreateTagForm,
+                    RenameTagForm,
+                    DeleteTagForm,
+                    TagResponse,
+                    TagFilesForm,
+                    FilesByTagForm,
+                    SetColourLabelForm,
+                    SetStarredForm,
+                    FileResponse,
+                    ServerError};
+use crate::auth_methods::current_user;
+use crate::methods::{get_files, update_cached_files};
+
+// tags, colour labels and stars all belong to the file's owner. every change
+// is mirrored into the owner's cached map so get_files carries it
+
+// enough for a whole selection, small enough for one statement
+const MAX_BULK_FILES: usize = 1000;
+
+fn tag_name(name: &str) -> Result<String, ServerError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err(ServerError::BadRequest("Tag names are 1 to 64 characters".to_string()));
+    }
+    Ok(name.to_string())
+}
+
+fn parse_file_ids(file_ids: &[String]) -> Result<Vec<Uuid>, ServerError> {
+    if file_ids.is_empty() || file_ids.len() > MAX_BULK_FILES {
+        return Err(ServerError::BadRequest(format!("Select between 1 and {} files", MAX_BULK_FILES)));
+    }
+    file_ids.iter()
+        .map(|id| Uuid::parse_str(id).map_err(|e| ServerError::BadRequest(e.to_string())))
+        .collect()
+}
+
+pub async fn create_tag(State(state): State<AppState>,
+                        jar: CookieJar,
+                        payload: Json<CreateTagForm>,
+) -> Result<Json<TagResponse>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let name = tag_name(&payload.name)?;
+    let tag = sqlx::query_as::<_, TagResponse>(r#"INSERT INTO tags (tag_id, owner_id, name)
+                                                 VALUES ($1, $2, $3)
+                                                 ON CONFLICT (owner_id, name) DO NOTHING
+                                                 RETURNING tag_id, name, 0::bigint AS file_count,
+                                                 created_at;"#)
+        .bind(Uuid::new_v4())
+        .bind(owner_id)
+        .bind(&name)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    match tag {
+        Some(t) => Ok(Json(t)),
+        None => Err(ServerError::BadRequest("Tag already exists".to_string())),
+    }
+}
+
+pub async fn get_tags(State(state): State<AppState>,
+                      jar: CookieJar,
+) -> Result<Json<Vec<TagResponse>>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let tags = sqlx::query_as::<_, TagResponse>(r#"SELECT t.tag_id, t.name, t.created_at,
+                                                  COUNT(ft.file_id) AS file_count
+                                                  FROM tags t
+                                                  LEFT JOIN file_tags ft ON ft.tag_id = t.tag_id
+                                                  WHERE t.owner_id = ($1)
+                                                  GROUP BY t.tag_id
+                                                  ORDER BY t.name;"#)
+        .bind(owner_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(tags))
+}
+
+pub async fn rename_tag(State(state): State<AppState>,
+                        jar: CookieJar,
+                        payload: Json<RenameTagForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let tag_id = Uuid::parse_str(&payload.tag_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let name = tag_name(&payload.name)?;
+    let old_name: Option<String> = sqlx::query_scalar(r#"UPDATE tags t SET name = ($1)
+                                                         FROM (SELECT tag_id, name FROM tags
+                                                               WHERE tag_id = ($2) AND owner_id = ($3)
+                                                               FOR UPDATE) old
+                                                         WHERE t.tag_id = old.tag_id
+                                                         RETURNING old.name;"#)
+        .bind(&name)
+        .bind(tag_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref d) if d.is_unique_violation() =>
+                ServerError::BadRequest("Tag already exists".to_string()),
+            e => ServerError::DatabaseError(e.to_string()),
+        })?;
+    let old_name = old_name.ok_or(ServerError::NotFound("Tag not found".to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for f in files.values_mut() {
+            if let Some(tag) = f.tags.iter_mut().find(|t| **t == old_name) {
+                *tag = name.clone();
+                f.tags.sort();
+            }
+        }
+    }).await;
+    Ok(StatusCode::OK)
+}
+
+// untags every file it was on
+pub async fn delete_tag(State(state): State<AppState>,
+                        jar: CookieJar,
+                        payload: Json<DeleteTagForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let tag_id = Uuid::parse_str(&payload.tag_id)
+        .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+    let name: Option<String> = sqlx::query_scalar(r#"DELETE FROM tags WHERE tag_id = ($1) AND owner_id = ($2)
+                                                     RETURNING name;"#)
+        .bind(tag_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let name = name.ok_or(ServerError::NotFound("Tag not found".to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for f in files.values_mut() {
+            f.tags.retain(|t| *t != name);
+        }
+    }).await;
+    Ok(StatusCode::OK)
+}
+
+// files the caller doesnt own are skipped, the count says how many got tagged
+pub async fn tag_files(State(state): State<AppState>,
+                       jar: CookieJar,
+                       payload: Json<TagFilesForm>,
+) -> Result<Json<u64>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let file_ids = parse_file_ids(&payload.file_ids)?;
+    let names = payload.tags.iter()
+        .map(|t| tag_name(t))
+        .collect::<Result<Vec<String>, ServerError>>()?;
+    if names.is_empty() {
+        return Err(ServerError::BadRequest("No tags given".to_string()));
+    }
+    let mut tx = state.pool.begin().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    for name in &names {
+        sqlx::query(r#"INSERT INTO tags (tag_id, owner_id, name) VALUES ($1, $2, $3)
+                       ON CONFLICT (owner_id, name) DO NOTHING;"#)
+            .bind(Uuid::new_v4())
+            .bind(owner_id)
+            .bind(name)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    }
+    let tagged = sqlx::query(r#"INSERT INTO file_tags (file_id, tag_id)
+                                SELECT f.file_id, t.tag_id
+                                FROM files f JOIN tags t ON t.owner_id = f.owner_id
+                                WHERE f.owner_id = ($1) AND f.file_id = ANY($2) AND t.name = ANY($3)
+                                ON CONFLICT DO NOTHING;"#)
+        .bind(owner_id)
+        .bind(&file_ids)
+        .bind(&names)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    tx.commit().await.map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for file_id in &file_ids {
+            files.entry(*file_id).and_modify(|f| {
+                for name in &names {
+                    if !f.tags.contains(name) {
+                        f.tags.push(name.clone());
+                    }
+                }
+                f.tags.sort();
+            });
+        }
+    }).await;
+    Ok(Json(tagged.rows_affected()))
+}
+
+// the tags themselves stay, delete-tag removes them
+pub async fn untag_files(State(state): State<AppState>,
+                         jar: CookieJar,
+                         payload: Json<TagFilesForm>,
+) -> Result<Json<u64>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let file_ids = parse_file_ids(&payload.file_ids)?;
+    let names: Vec<String> = payload.tags.iter().map(|t| t.trim().to_string()).collect();
+    let untagged = sqlx::query(r#"DELETE FROM file_tags ft
+                                  USING tags t
+                                  WHERE ft.tag_id = t.tag_id AND t.owner_id = ($1)
+                                  AND ft.file_id = ANY($2) AND t.name = ANY($3);"#)
+        .bind(owner_id)
+        .bind(&file_ids)
+        .bind(&names)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for file_id in &file_ids {
+            files.entry(*file_id).and_modify(|f| f.tags.retain(|t| !names.contains(t)));
+        }
+    }).await;
+    Ok(Json(untagged.rows_affected()))
+}
+
+pub async fn set_colour_label(State(state): State<AppState>,
+                              jar: CookieJar,
+                              payload: Json<SetColourLabelForm>,
+) -> Result<Json<u64>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let file_ids = parse_file_ids(&payload.file_ids)?;
+    let updated = sqlx::query(r#"UPDATE files SET colour_label = ($1)
+                                 WHERE owner_id = ($2) AND file_id = ANY($3);"#)
+        .bind(payload.colour_label)
+        .bind(owner_id)
+        .bind(&file_ids)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for file_id in &file_ids {
+            files.entry(*file_id).and_modify(|f| f.colour_label = payload.colour_label);
+        }
+    }).await;
+    Ok(Json(updated.rows_affected()))
+}
+
+pub async fn set_starred(State(state): State<AppState>,
+                         jar: CookieJar,
+                         payload: Json<SetStarredForm>,
+) -> Result<Json<u64>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let file_ids = parse_file_ids(&payload.file_ids)?;
+    let updated = sqlx::query(r#"UPDATE files SET starred = ($1)
+                                 WHERE owner_id = ($2) AND file_id = ANY($3);"#)
+        .bind(payload.starred)
+        .bind(owner_id)
+        .bind(&file_ids)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    update_cached_files(&state, &owner_id, |files| {
+        for file_id in &file_ids {
+            files.entry(*file_id).and_modify(|f| f.starred = payload.starred);
+        }
+    }).await;
+    Ok(Json(updated.rows_affected()))
+}
+
+// the get_files listing narrowed to one tag
+pub async fn get_files_by_tag(State(state): State<AppState>,
+                              jar: CookieJar,
+                              payload: Json<FilesByTagForm>,
+) -> Result<Json<HashMap<Uuid, FileResponse>>, ServerError> {
+
+    current_user(&state, jar.clone()).await?;
+    let tag = payload.tag.trim().to_string();
+    let Json(mut files) = get_files(State(state), jar).await?;
+    files.retain(|_, f| f.tags.contains(&tag));
+    Ok(Json(files))
+}
+
+pub async fn get_starred(State(state): State<AppState>,
+                         jar: CookieJar,
+) -> Result<Json<HashMap<Uuid, FileResponse>>, ServerError> {
+
+    let Json(mut files) = get_files(State(state), jar).await?;
+    files.retain(|_, f| f.starred);
+    Ok(Json(files))
+}
@@ -0,0 +1,346 @@
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use image::{DynamicImage, ExtendedColorType, ImageReader, Limits};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use sqlx::{Postgres, Transaction};
+use std::env;
+use std::io::Cursor;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::models::{AppState, ServerError};
+use crate::methods::{open_file, StoredContent};
+use crate::storage::{StorageLayout, bytes_stream};
+use crate::encryption::{data_key, encrypt_stream};
+
+// previews for images and the first page of pdfs. uploads queue a row in
+// thumbnail_jobs, a background task renders every size in both formats and
+// records the objects in thumbnails so they go away with the file
+#[derive(Debug, Clone)]
+pub struct ThumbnailConfig {
+    pub enabled: bool,
+    // poppler's pdftoppm renders pdf pages, without it pdf jobs just fail
+    pub pdftoppm: String,
+    pub interval: u64,
+}
+
+impl ThumbnailConfig {
+    pub fn from_env() -> Self {
+        ThumbnailConfig {
+            enabled: env::var("THUMBNAILS").map(|v| v == "true").unwrap_or(false),
+            pdftoppm: env::var("PDFTOPPM_PATH").unwrap_or("pdftoppm".to_string()),
+            interval: env::var("THUMBNAIL_INTERVAL_SECS").ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailSize { Small, Medium }
+
+impl ThumbnailSize {
+    pub const ALL: [ThumbnailSize; 2] = [ThumbnailSize::Small, ThumbnailSize::Medium];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "small" => Some(ThumbnailSize::Small),
+            "medium" => Some(ThumbnailSize::Medium),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ThumbnailSize::Small => "small",
+            ThumbnailSize::Medium => "medium",
+        }
+    }
+
+    // longest edge in pixels
+    pub fn edge(self) -> u32 {
+        match self {
+            ThumbnailSize::Small => 128,
+            ThumbnailSize::Medium => 512,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailFormat { Jpeg, Webp }
+
+impl ThumbnailFormat {
+    pub const ALL: [ThumbnailFormat; 2] = [ThumbnailFormat::Jpeg, ThumbnailFormat::Webp];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "jpeg" | "jpg" => Some(ThumbnailFormat::Jpeg),
+            "webp" => Some(ThumbnailFormat::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpeg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+        }
+    }
+}
+
+// which renderer a file needs, None when there is nothing to preview
+pub fn thumbnail_kind(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        // vector and raw camera formats are beyond the image crate
+        "image/svg+xml" => None,
+        ctype if ctype.starts_with("image/") => Some("image"),
+        "application/pdf" => Some("pdf"),
+        _ => None,
+    }
+}
+
+pub fn thumbnail_location(layout: &StorageLayout,
+                          owner_id: &str,
+                          file_id: &str,
+                          size: ThumbnailSize,
+                          format: ThumbnailFormat,
+) -> (String, String) {
+    let object_name = format!("thumbnails/{}/{}.{}", file_id, size.name(), format.name());
+    (layout.bucket(owner_id), layout.key(owner_id, &object_name))
+}
+
+pub async fn queue_thumbnail(tx: &mut Transaction<'_, Postgres>,
+                             file_id: &Uuid,
+                             kind: &str,
+) -> Result<(), ServerError> {
+    sqlx::query(r#"INSERT INTO thumbnail_jobs (file_id, kind) VALUES ($1, $2)
+                   ON CONFLICT (file_id) DO NOTHING;"#)
+        .bind(file_id)
+        .bind(kind)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// runs with the file delete so derived objects dont outlive their source. the
+// objects come back to the caller, who deletes them once that commits, so a
+// rollback cant leave rows pointing at thumbnails that are gone
+pub async fn delete_thumbnails(tx: &mut Transaction<'_, Postgres>,
+                               owner_id: &Uuid,
+                               file_id: &Uuid,
+) -> Result<Vec<(String, String)>, ServerError> {
+    let objects: Vec<(String, String)> = sqlx::query_as(r#"DELETE FROM thumbnails t
+                                                           USING files f
+                                                           WHERE t.file_id = f.file_id
+                                                           AND f.file_id = ($1) AND f.owner_id = ($2)
+                                                           RETURNING t.bucket, t.key;"#)
+        .bind(file_id)
+        .bind(owner_id)
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(objects)
+}
+
+// guards against decompression bombs, uploads are small but headers can lie
+pub fn decode_image(data: &[u8]) -> Result<DynamicImage, ServerError> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(12_000);
+    limits.max_image_height = Some(12_000);
+    limits.max_alloc = Some(256 * 1024 * 1024);
+    reader.limits(limits);
+    reader.decode().map_err(|e| ServerError::InternalError(format!("Failed to decode image. Error: {}", e)))
+}
+
+// one size in one format
+pub struct RenderedThumbnail {
+    pub size: ThumbnailSize,
+    pub format: ThumbnailFormat,
+    pub body: Bytes,
+    pub width: u32,
+    pub height: u32,
+}
+
+// every size in every format, never scaled up
+pub fn render_thumbnails(image: &DynamicImage) -> Result<Vec<RenderedThumbnail>, ServerError> {
+    let mut rendered = Vec::new();
+    for size in ThumbnailSize::ALL {
+        let edge = size.edge();
+        let scaled = if image.width() <= edge && image.height() <= edge {
+            image.clone()
+        } else {
+            image.thumbnail(edge, edge)
+        };
+        let (width, height) = (scaled.width(), scaled.height());
+        for format in ThumbnailFormat::ALL {
+            let mut out = Vec::new();
+            let encoded = match format {
+                // jpeg has no alpha
+                ThumbnailFormat::Jpeg => JpegEncoder::new_with_quality(&mut out, 80)
+                    .encode_image(&DynamicImage::ImageRgb8(scaled.to_rgb8())),
+                ThumbnailFormat::Webp => WebPEncoder::new_lossless(&mut out)
+                    .encode(scaled.to_rgba8().as_raw(), width, height, ExtendedColorType::Rgba8),
+            };
+            encoded.map_err(|e| ServerError::InternalError(e.to_string()))?;
+            rendered.push(RenderedThumbnail { size, format, body: Bytes::from(out), width, height });
+        }
+    }
+    Ok(rendered)
+}
+
+// first page through pdftoppm, scaled so the medium size still has detail
+async fn render_pdf_page(pdftoppm: &str, data: &[u8]) -> Result<DynamicImage, ServerError> {
+    let dir = env::temp_dir().join(format!("servr-thumb-{}", Uuid::new_v4()));
+    tokio::fs::create_dir_all(&dir).await
+        .map_err(|e| ServerError::InternalError(e.to_string()))?;
+    let input = dir.join("input.pdf");
+    let output = dir.join("page");
+    let result = async {
+        tokio::fs::write(&input, data).await
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        let child = tokio::process::Command::new(pdftoppm)
+            .args(["-f", "1", "-l", "1", "-singlefile", "-png", "-scale-to", "1024"])
+            .arg(&input)
+            .arg(&output)
+            .kill_on_drop(true)
+            .output();
+        let finished = tokio::time::timeout(Duration::from_secs(30), child).await
+            .map_err(|_| ServerError::InternalError("pdftoppm timed out".to_string()))?
+            .map_err(|e| ServerError::InternalError(format!("Failed to run pdftoppm. Error: {}", e)))?;
+        if !finished.status.success() {
+            return Err(ServerError::InternalError(format!("pdftoppm failed: {}",
+                String::from_utf8_lossy(&finished.stderr).trim())));
+        }
+        let png = tokio::fs::read(output.with_extension("png")).await
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        decode_image(&png)
+    }.await;
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        eprintln!("Error {:?}", e);
+    }
+    result
+}
+
+async fn generate(state: &AppState, file_id: &Uuid, kind: &str) -> Result<(), ServerError> {
+    let row: Option<StoredContent> =
+        sqlx::query_as(r#"SELECT owner_id, extension, blob_hash, key_id
+                          FROM files WHERE file_id = ($1) AND NOT vault;"#)
+        .bind(file_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    // deleted since it was queued
+    let StoredContent { owner_id, extension, blob_hash, key_id } = match row {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let owner = owner_id.to_string();
+    let mut object = open_file(state, &owner, &file_id.to_string(), &extension,
+                               &blob_hash, key_id).await?;
+    let mut data = BytesMut::new();
+    while let Some(chunk) = object.stream.next().await {
+        data.extend_from_slice(&chunk.map_err(|e| ServerError::InternalError(e.to_string()))?);
+    }
+    let data = data.freeze();
+
+    let image = match kind {
+        "pdf" => render_pdf_page(&state.thumbnails.pdftoppm, &data).await?,
+        _ => tokio::task::spawn_blocking(move || decode_image(&data)).await
+            .map_err(|e| ServerError::InternalError(e.to_string()))??,
+    };
+    let rendered = tokio::task::spawn_blocking(move || render_thumbnails(&image)).await
+        .map_err(|e| ServerError::InternalError(e.to_string()))??;
+
+    // previews of an encrypted file are as sensitive as the file
+    let dek = match (key_id, &state.encryption) {
+        (Some(id), Some(encryption)) => Some(data_key(&state.pool, encryption, &id).await?),
+        _ => None,
+    };
+    for RenderedThumbnail { size, format, body, width, height } in rendered {
+        let (bucket, key) = thumbnail_location(&state.layout, &owner, &file_id.to_string(),
+                                               size, format);
+        match &dek {
+            Some(dek) => state.store.put_stream(&bucket, &key, encrypt_stream(dek, bytes_stream(body)),
+                                                format.content_type()).await?,
+            None => state.store.put(&bucket, &key, body, format.content_type()).await?,
+        }
+        let recorded = sqlx::query(r#"INSERT INTO thumbnails (file_id, size, format, bucket, key,
+                                      width, height, key_id)
+                                      VALUES ($1,$2,$3,$4,$5,$6,$7,$8)
+                                      ON CONFLICT (file_id, size, format) DO UPDATE
+                                      SET bucket = EXCLUDED.bucket, key = EXCLUDED.key,
+                                      width = EXCLUDED.width, height = EXCLUDED.height,
+                                      key_id = EXCLUDED.key_id, created_at = NOW();"#)
+            .bind(file_id)
+            .bind(size.name())
+            .bind(format.name())
+            .bind(&bucket)
+            .bind(&key)
+            .bind(width as i32)
+            .bind(height as i32)
+            .bind(key_id)
+            .execute(&state.pool)
+            .await;
+        if let Err(e) = recorded {
+            // most likely the file went away mid render
+            state.store.delete(&bucket, &key).await?;
+            return Err(ServerError::DatabaseError(e.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// claims up to `limit` jobs, failures are retried a couple of times then left
+// in the table with their error
+pub async fn run_thumbnail_jobs(state: &AppState, limit: i64) -> Result<usize, ServerError> {
+    let jobs: Vec<(Uuid, String)> = sqlx::query_as(r#"UPDATE thumbnail_jobs
+                                                      SET attempts = attempts + 1,
+                                                      available_at = NOW() + INTERVAL '10 minutes'
+                                                      WHERE file_id IN (
+                                                          SELECT file_id FROM thumbnail_jobs
+                                                          WHERE attempts < 3 AND available_at <= NOW()
+                                                          ORDER BY created_at
+                                                          LIMIT ($1)
+                                                          FOR UPDATE SKIP LOCKED)
+                                                      RETURNING file_id, kind;"#)
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut done = 0;
+    for (file_id, kind) in jobs {
+        match generate(state, &file_id, &kind).await {
+            Ok(()) => {
+                sqlx::query("DELETE FROM thumbnail_jobs WHERE file_id = ($1);")
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+                done += 1;
+            },
+            Err(e) => {
+                eprintln!("Error {:?}", e);
+                sqlx::query("UPDATE thumbnail_jobs SET last_error = ($1) WHERE file_id = ($2);")
+                    .bind(format!("{:?}", e))
+                    .bind(file_id)
+                    .execute(&state.pool)
+                    .await
+                    .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+            },
+        }
+    }
+    Ok(done)
+}
@@ -0,0 +1,127 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::models::ServerError;
+
+// vault folders are end to end encrypted, the server only ever sees ciphertext.
+// everything below is done by the client, the server just checks sizes and who
+// may fetch what. all binary fields travel as base64 in json.
+//
+// user keys: an X25519 keypair per user. the public key is stored raw (32 bytes),
+// the private key is stored however the client sealed it (passphrase derived key),
+// the server never looks inside.
+//
+// item keys: every vault folder and file has its own random 32 byte key.
+//   - under a vault folder the item key is wrapped with the parent's key:
+//       nonce (12) | AES-256-GCM(parent key, item key) (48)
+//     = 60 bytes
+//   - a vault root is wrapped for the owner's public key, and a shared item for
+//     the recipient's:
+//       ephemeral X25519 public key (32) | nonce (12) | AES-256-GCM(k, item key) (48)
+//     = 92 bytes, k = HKDF-SHA256(shared secret, info "servr-vault-v1" | recipient public key)
+//   the owner's wrap lives on the files row, recipients' in vault_item_keys.
+//   holding the key of a folder unlocks everything under it.
+//
+// metadata: nonce (12) | AES-256-GCM(item key, json {"name","extension","content_type","size"})
+// content: the same chunked layout as server side encryption (see encryption.rs)
+// with magic "SVV1", sealed with the item key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const PARENT_WRAPPED_KEY_SIZE: usize = 12 + 32 + 16;
+pub const USER_WRAPPED_KEY_SIZE: usize = PUBLIC_KEY_SIZE + 12 + 32 + 16;
+// names and types only, anything bigger is a client bug
+const MAX_METADATA_SIZE: usize = 4096;
+
+pub fn decode_field(value: &str, what: &str) -> Result<Vec<u8>, ServerError> {
+    STANDARD.decode(value.trim())
+        .map_err(|_| ServerError::BadRequest(format!("Invalid {}", what)))
+}
+
+pub fn encode_field(value: &[u8]) -> String {
+    STANDARD.encode(value)
+}
+
+pub fn decode_wrapped_key(value: &str, expected: usize) -> Result<Vec<u8>, ServerError> {
+    let key = decode_field(value, "wrapped key")?;
+    if key.len() != expected {
+        return Err(ServerError::BadRequest("Wrapped key has the wrong length".to_string()));
+    }
+    Ok(key)
+}
+
+pub fn decode_metadata(value: &str) -> Result<Vec<u8>, ServerError> {
+    let metadata = decode_field(value, "metadata")?;
+    // a nonce and a tag at least
+    if metadata.len() < 12 + 16 || metadata.len() > MAX_METADATA_SIZE {
+        return Err(ServerError::BadRequest("Invalid metadata".to_string()));
+    }
+    Ok(metadata)
+}
+
+// wrapped key and encrypted metadata for a new vault item
+#[derive(Debug, Clone)]
+pub struct VaultItem {
+    pub wrapped_key: Vec<u8>,
+    pub metadata: Vec<u8>,
+}
+
+// (is_vault, file_type is folder) for a folder the caller owns, None if it isnt theirs
+pub async fn parent_kind(pool: &PgPool,
+                         owner_id: &Uuid,
+                         parent_id: &Uuid,
+) -> Result<Option<(bool, bool)>, ServerError> {
+    let row: Option<(bool, bool)> = sqlx::query_as(r#"SELECT vault, file_type = 'folder'
+                                                      FROM files
+                                                      WHERE file_id = ($1) AND owner_id = ($2);"#)
+        .bind(parent_id)
+        .bind(owner_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(row)
+}
+
+pub async fn is_vault_folder(pool: &PgPool,
+                             parent_id: &Option<Uuid>,
+) -> Result<bool, ServerError> {
+    let parent_id = match parent_id {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    let vault: Option<bool> = sqlx::query_scalar(r#"SELECT vault FROM files WHERE file_id = ($1);"#)
+        .bind(parent_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(vault.unwrap_or(false))
+}
+
+// the owner, or anyone holding a wrapped key for the item or a folder above it
+pub async fn can_read_vault_item(pool: &PgPool,
+                                 user_id: &Uuid,
+                                 file_id: &Uuid,
+) -> Result<bool, ServerError> {
+    let allowed: bool = sqlx::query_scalar(r#"WITH RECURSIVE ancestors AS (
+                                                  SELECT file_id, parent_id, owner_id
+                                                  FROM files
+                                                  WHERE file_id = ($1) AND vault
+                                                  UNION ALL
+
+                                                  SELECT f.file_id, f.parent_id, f.owner_id
+                                                  FROM files f
+                                                  JOIN ancestors a ON f.file_id = a.parent_id
+                                                  WHERE f.vault
+                                               )
+                                               SELECT EXISTS(SELECT 1 FROM ancestors
+                                                             WHERE owner_id = ($2))
+                                               OR EXISTS(SELECT 1 FROM vault_item_keys k
+                                                         JOIN ancestors a ON a.file_id = k.file_id
+                                                         WHERE k.user_id = ($2));"#)
+        .bind(file_id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(allowed)
+}
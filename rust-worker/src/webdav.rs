@@ -0,0 +1,438 @@
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::{AppState, FileType, ScanStatus, ServerError};
+use crate::methods::{rename_row, file_location, update_cached_files};
+
+// webdav under /dav, so storage can be mounted in a file manager. paths are
+// the user's own folder tree by name, vault items are left out since their
+// names are encrypted. the verbs themselves are in webdav_methods.rs
+
+pub const DAV_PREFIX: &str = "/dav";
+// longest a lock lives without being refreshed
+pub const MAX_LOCK_SECS: u64 = 3600;
+
+#[derive(Debug, Clone)]
+pub struct DavLock {
+    // opaquelocktoken:<uuid>
+    pub token: String,
+    pub user_id: Uuid,
+    pub path: Vec<String>,
+    // depth infinity, covers everything below a folder too
+    pub infinite: bool,
+    // the client's <owner> href or text, handed back in lock discovery
+    pub owner: Option<String>,
+    pub timeout: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl DavLock {
+    pub fn covers(&self, user_id: &Uuid, path: &[String]) -> bool {
+        self.user_id == *user_id
+            && self.expires_at > Utc::now()
+            && path.starts_with(&self.path)
+            && (path.len() == self.path.len() || self.infinite)
+    }
+}
+
+#[derive(Clone)]
+pub struct DavConfig {
+    pub enabled: bool,
+    // by token. in memory like the event bus, a restart drops them
+    pub locks: Cache<String, Arc<DavLock>>,
+}
+
+impl DavConfig {
+    pub fn from_env() -> Self {
+        DavConfig {
+            enabled: env::var("WEBDAV").map(|v| v == "true").unwrap_or(false),
+            locks: Cache::builder()
+                .time_to_live(Duration::from_secs(MAX_LOCK_SECS))
+                .build(),
+        }
+    }
+
+    // a lock on the path, above it, or with below set anywhere under it, that
+    // the request didnt send the token of in its If header
+    pub fn conflicting(&self,
+                       user_id: &Uuid,
+                       path: &[String],
+                       below: bool,
+                       if_header: Option<&str>,
+    ) -> Option<Arc<DavLock>> {
+        self.locks.iter()
+            .map(|(_, lock)| lock)
+            .filter(|lock| lock.covers(user_id, path)
+                || below && lock.user_id == *user_id && lock.expires_at > Utc::now()
+                   && lock.path.starts_with(path))
+            .find(|lock| !if_header.is_some_and(|h| h.contains(&lock.token)))
+    }
+
+    // once a path is deleted or moved away its locks mean nothing
+    pub async fn release_below(&self, user_id: &Uuid, path: &[String]) {
+        let tokens: Vec<String> = self.locks.iter()
+            .filter(|(_, lock)| lock.user_id == *user_id && lock.path.starts_with(path))
+            .map(|(token, _)| (*token).clone())
+            .collect();
+        for token in tokens {
+            self.locks.invalidate(&token).await;
+        }
+    }
+}
+
+// the columns webdav needs, for the user's plain files
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DavEntry {
+    pub file_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub file_name: String,
+    pub extension: Option<String>,
+    pub size: i64,
+    pub file_type: FileType,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub checksum_sha256: Option<String>,
+    pub checksum_md5: Option<String>,
+    pub mime_type: Option<String>,
+    pub blob_hash: Option<String>,
+    pub key_id: Option<Uuid>,
+    pub scan_status: ScanStatus,
+}
+
+impl DavEntry {
+    pub fn is_folder(&self) -> bool {
+        self.file_type == FileType::Folder
+    }
+
+    // files keep their extension in its own column
+    pub fn name(&self) -> String {
+        match self.extension.as_deref() {
+            Some(ext) if !ext.is_empty() => format!("{}.{}", self.file_name, ext),
+            _ => self.file_name.clone(),
+        }
+    }
+
+    pub fn etag(&self) -> String {
+        match &self.checksum_sha256 {
+            Some(sum) => format!("\"{}\"", sum),
+            None => format!("\"{}-{}\"", self.file_id.simple(),
+                            self.last_modified.map(|d| d.timestamp()).unwrap_or(0)),
+        }
+    }
+}
+
+pub struct DavTree {
+    entries: HashMap<Uuid, DavEntry>,
+}
+
+impl DavTree {
+    pub fn new(entries: Vec<DavEntry>) -> Self {
+        DavTree { entries: entries.into_iter().map(|e| (e.file_id, e)).collect() }
+    }
+
+    pub fn child(&self, parent_id: Option<Uuid>, name: &str) -> Option<&DavEntry> {
+        self.entries.values().find(|e| e.parent_id == parent_id && e.name() == name)
+    }
+
+    pub fn children(&self, parent_id: Option<Uuid>) -> Vec<&DavEntry> {
+        let mut children: Vec<&DavEntry> = self.entries.values()
+            .filter(|e| e.parent_id == parent_id)
+            .collect();
+        children.sort_by_key(|e| e.name());
+        children
+    }
+
+    pub fn get(&self, file_id: &Uuid) -> Option<&DavEntry> {
+        self.entries.get(file_id)
+    }
+
+    // Some(None) is the top level, None is nothing there
+    pub fn resolve(&self, path: &[String]) -> Option<Option<&DavEntry>> {
+        self.resolve_from(None, path)
+    }
+
+    // the same from a folder rather than the top level
+    pub fn resolve_from(&self, root: Option<Uuid>, path: &[String]) -> Option<Option<&DavEntry>> {
+        let mut current: Option<&DavEntry> = match root {
+            Some(id) => Some(self.get(&id)?),
+            None => None,
+        };
+        for part in path {
+            if current.is_some_and(|c| !c.is_folder()) {
+                return None;
+            }
+            current = Some(self.child(current.map(|c| c.file_id), part)?);
+        }
+        Some(current)
+    }
+
+    // the folder a new item at path goes into, None when there isnt one
+    pub fn parent_of(&self, path: &[String]) -> Option<Option<Uuid>> {
+        self.parent_from(None, path)
+    }
+
+    pub fn parent_from(&self, root: Option<Uuid>, path: &[String]) -> Option<Option<Uuid>> {
+        let (_, parent) = path.split_last()?;
+        match self.resolve_from(root, parent)? {
+            Some(e) if e.is_folder() => Some(Some(e.file_id)),
+            Some(_) => None,
+            None => Some(None),
+        }
+    }
+
+    // everything under a folder, deepest first so it can be removed in order
+    pub fn descendants(&self, folder_id: Uuid) -> Vec<&DavEntry> {
+        let mut out = Vec::new();
+        for child in self.children(Some(folder_id)) {
+            if child.is_folder() {
+                out.extend(self.descendants(child.file_id));
+            }
+            out.push(child);
+        }
+        out
+    }
+}
+
+// the whole tree, vault items left out
+pub(crate) async fn load_tree(state: &AppState, user_id: &Uuid) -> Result<DavTree, ServerError> {
+    let entries = sqlx::query_as::<_, DavEntry>(r#"SELECT file_id, parent_id, file_name, extension, size,
+                                                  file_type, created_at, last_modified, checksum_sha256,
+                                                  checksum_md5, mime_type, blob_hash, key_id, scan_status
+                                                  FROM files
+                                                  WHERE owner_id = ($1) AND NOT vault;"#)
+        .bind(user_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(DavTree::new(entries))
+}
+
+// folders take the whole name. files only keep a plain rename when the
+// extension stays, otherwise the object moves along with it
+pub(crate) async fn rename_entry(state: &AppState,
+                                 user_id: Uuid,
+                                 entry: &DavEntry,
+                                 name: &str,
+) -> Result<(), ServerError> {
+    if entry.is_folder() {
+        rename_row(state, user_id, entry.file_id, name).await?;
+        return Ok(());
+    }
+    let (stem, extension) = split_name(name);
+    if extension == entry.extension.clone().unwrap_or_default() {
+        rename_row(state, user_id, entry.file_id, &stem).await?;
+        return Ok(());
+    }
+    let owner = user_id.to_string();
+    let file_id = entry.file_id.to_string();
+    // blob backed objects are found by hash, the rest by a key with the extension in it
+    let keys = match &entry.blob_hash {
+        Some(_) => None,
+        None => {
+            let (bucket, old_key) = file_location(state, &owner, &file_id, &entry.extension, &None);
+            let (_, new_key) = file_location(state, &owner, &file_id, &Some(extension.clone()), &None);
+            state.store.copy(&bucket, &old_key, &bucket, &new_key).await?;
+            Some((bucket, old_key))
+        },
+    };
+    // no extension is "", the way uploads store it
+    sqlx::query(r#"UPDATE files SET file_name = ($1), extension = ($2), url = NULL
+                   WHERE file_id = ($3) AND owner_id = ($4);"#)
+        .bind(&stem)
+        .bind(&extension)
+        .bind(entry.file_id)
+        .bind(user_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if let Some((bucket, old_key)) = keys {
+        state.store.delete(&bucket, &old_key).await?;
+    }
+    update_cached_files(state, &user_id, |files| {
+        files.entry(entry.file_id).and_modify(|f| {
+            f.file_name = stem.clone();
+            f.extension = Some(extension.clone());
+            f.url = None;
+        });
+    }).await;
+    Ok(())
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// everything but unreserved characters (rfc 3986) is escaped
+pub fn encode_segment(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// request path to segments under /dav. dot segments are refused rather than
+// resolved, nothing legitimate sends them
+pub fn dav_path(path: &str) -> Option<Vec<String>> {
+    let rest = path.strip_prefix(DAV_PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let mut segments = Vec::new();
+    for part in rest.split('/').filter(|p| !p.is_empty()) {
+        let part = percent_decode(part)?;
+        if part == "." || part == ".." || part.contains('/') {
+            return None;
+        }
+        segments.push(part);
+    }
+    Some(segments)
+}
+
+// Destination is a full url or an absolute path
+pub fn destination_path(destination: &str) -> Option<Vec<String>> {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/').unwrap_or(rest.len())..],
+        None => destination,
+    };
+    dav_path(path.split(['?', '#']).next().unwrap_or(""))
+}
+
+pub fn href(path: &[String], folder: bool) -> String {
+    let mut href = DAV_PREFIX.to_string();
+    for part in path {
+        href.push('/');
+        href.push_str(&encode_segment(part));
+    }
+    if folder {
+        href.push('/');
+    }
+    href
+}
+
+// how store_file splits an uploaded name
+pub fn split_name(name: &str) -> (String, String) {
+    let path = std::path::Path::new(name);
+    (path.file_stem().and_then(|s| s.to_str()).unwrap_or(name).to_string(),
+     path.extension().and_then(|s| s.to_str()).unwrap_or("").to_string())
+}
+
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Timeout: Second-600 or Infinite, capped either way
+pub fn lock_timeout(header: Option<&str>) -> u64 {
+    header.and_then(|h| h.split(',')
+            .map(|t| t.trim())
+            .find_map(|t| match t {
+                "Infinite" => Some(MAX_LOCK_SECS),
+                _ => t.strip_prefix("Second-").and_then(|s| s.parse::<u64>().ok()),
+            }))
+        .unwrap_or(MAX_LOCK_SECS)
+        .clamp(1, MAX_LOCK_SECS)
+}
+
+// what is between <x:name ...> and </x:name>, whatever the namespace prefix.
+// enough for the little of lock bodies we read
+pub fn element_inner<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = 0;
+    while let Some(found) = body[search..].find('<') {
+        let start = search + found;
+        let tag_end = start + body[start..].find('>')?;
+        let tag = &body[start + 1..tag_end];
+        // <a:exclusive/> has no space before the slash
+        let tag_name = tag.split_whitespace().next().unwrap_or("").trim_end_matches('/');
+        let local = tag_name.rsplit(':').next().unwrap_or("");
+        if local == name && !tag_name.starts_with('/') {
+            if tag.ends_with('/') {
+                return Some("");
+            }
+            let close = format!("</{}>", tag_name);
+            let inner_end = tag_end + 1 + body[tag_end + 1..].find(&close)?;
+            return Some(&body[tag_end + 1..inner_end]);
+        }
+        search = tag_end + 1;
+    }
+    None
+}
+
+// one <D:response> of a PROPFIND, the top level has no entry
+pub fn prop_response(path: &[String], entry: Option<&DavEntry>, quota: Option<(i64, i64)>) -> String {
+    let folder = entry.is_none_or(|e| e.is_folder());
+    let mut props = String::new();
+    let name = entry.map(|e| e.name()).unwrap_or_default();
+    props.push_str(&format!("<D:displayname>{}</D:displayname>", xml_escape(&name)));
+    if folder {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        props.push_str("<D:resourcetype/>");
+    }
+    if let Some(e) = entry {
+        if let Some(created) = &e.created_at {
+            props.push_str(&format!("<D:creationdate>{}</D:creationdate>", created.to_rfc3339()));
+        }
+        if let Some(modified) = &e.last_modified {
+            props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(modified)));
+        }
+        if !folder {
+            props.push_str(&format!("<D:getcontentlength>{}</D:getcontentlength>", e.size));
+            props.push_str(&format!("<D:getcontenttype>{}</D:getcontenttype>",
+                xml_escape(e.mime_type.as_deref().unwrap_or("application/octet-stream"))));
+            props.push_str(&format!("<D:getetag>{}</D:getetag>", xml_escape(&e.etag())));
+        }
+    }
+    // rfc 4331
+    if let Some((used, available)) = quota && folder {
+        props.push_str(&format!("<D:quota-used-bytes>{}</D:quota-used-bytes>", used));
+        props.push_str(&format!("<D:quota-available-bytes>{}</D:quota-available-bytes>", available));
+    }
+    props.push_str("<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+                    <D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>");
+    format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+             <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+            xml_escape(&href(path, folder)), props)
+}
+
+pub fn multistatus(responses: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+            responses)
+}
+
+// body of a LOCK response
+pub fn lock_discovery(lock: &DavLock) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>\
+             <D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope>\
+             <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot>\
+             </D:activelock></D:lockdiscovery></D:prop>",
+            if lock.infinite { "infinity" } else { "0" },
+            lock.owner.as_ref()
+                .map(|o| format!("<D:owner><D:href>{}</D:href></D:owner>", xml_escape(o)))
+                .unwrap_or_default(),
+            lock.timeout,
+            lock.token,
+            xml_escape(&href(&lock.path, false)))
+}
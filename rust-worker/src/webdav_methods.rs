@@ -0,0 +1,560 @@
+use axum::{body::{Body, Bytes},
+           extract::State,
+           http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+           response::{IntoResponse, Response}};
+use axum_extra::extract::cookie::CookieJar;
+use base64::Engine;
+use futures::TryStreamExt;
+use chrono::Utc;
+use uuid::Uuid;
+use std::sync::Arc;
+
+use crate::models::{AppState, ServerError};
+use crate::auth_methods::{current_user, api_key_user, API_KEY_PREFIX};
+use crate::msc_actions::{get_user_id, file_audience};
+use crate::methods::{STORAGE_LIMIT, NewFile, make_folder, store_file, replace_file, remove_file, relocate_file, open_file};
+use crate::integrity::{ExpectedChecksums, UploadHasher};
+use crate::scanner::check_download;
+use crate::audit::{RequestInfo, AuditEvent, record};
+use crate::events::{ChangeEvent, ChangeKind, announce, emit};
+use crate::webdav::{DavEntry, DavLock, DavTree, load_tree, rename_entry, dav_path, destination_path, element_inner,
+                    http_date, lock_discovery, lock_timeout, multistatus, prop_response};
+
+const ALLOW: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, MKCOL, MOVE, COPY, DELETE, LOCK, UNLOCK";
+// lock and propfind documents, put bodies are streamed and only held to the quota
+const XML_BODY_LIMIT: usize = 1024 * 1024;
+
+fn status(code: StatusCode) -> Response {
+    code.into_response()
+}
+
+fn xml(code: StatusCode, body: String) -> Response {
+    (code, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response()
+}
+
+// basic auth as email and password, or an api key as the password (any user
+// name) or bearer token. a session cookie works too, for browsers
+async fn dav_user(state: &AppState,
+                  headers: &HeaderMap,
+                  jar: CookieJar,
+                  info: &RequestInfo,
+) -> Result<Uuid, ServerError> {
+    let authorization = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let (user, secret) = match authorization {
+        Some(auth) if auth.starts_with("Basic ") => {
+            let decoded = base64::engine::general_purpose::STANDARD.decode(auth[6..].trim())
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())
+                .ok_or(ServerError::Unauthorized("Invalid credentials".to_string()))?;
+            let (user, password) = decoded.split_once(':')
+                .ok_or(ServerError::Unauthorized("Invalid credentials".to_string()))?;
+            (user.to_string(), password.to_string())
+        },
+        Some(auth) if auth.starts_with("Bearer ") => (String::new(), auth[7..].trim().to_string()),
+        _ => return current_user(state, jar).await,
+    };
+    let user_id = match secret.starts_with(API_KEY_PREFIX) {
+        true => api_key_user(&state.pool, &secret).await?,
+        false => get_user_id(&user, &secret, &state.pool).await.ok(),
+    };
+    match user_id {
+        Some(id) => Ok(id),
+        None => {
+            record(&state.pool, info, AuditEvent {
+                action: "login_failed",
+                details: Some(serde_json::json!({"email": user, "webdav": true})),
+                ..Default::default()
+            }).await;
+            Err(ServerError::Unauthorized("Invalid credentials".to_string()))
+        },
+    }
+}
+
+// one request, with what every verb needs
+struct Dav<'a> {
+    state: &'a AppState,
+    user_id: Uuid,
+    headers: &'a HeaderMap,
+    info: &'a RequestInfo,
+    tree: DavTree,
+}
+
+pub async fn webdav(State(state): State<AppState>,
+                    method: Method,
+                    uri: Uri,
+                    headers: HeaderMap,
+                    jar: CookieJar,
+                    info: RequestInfo,
+                    body: Body,
+) -> Response {
+
+    if !state.webdav.enabled {
+        return status(StatusCode::NOT_FOUND);
+    }
+    // clients probe this before they authenticate
+    if method == Method::OPTIONS {
+        return (StatusCode::OK, [(header::ALLOW, ALLOW), (header::HeaderName::from_static("dav"), "1, 2"),
+                                 (header::HeaderName::from_static("ms-author-via"), "DAV")]).into_response();
+    }
+    let user_id = match dav_user(&state, &headers, jar, &info).await {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::UNAUTHORIZED,
+                          [(header::WWW_AUTHENTICATE, "Basic realm=\"servr\", charset=\"UTF-8\"")]).into_response(),
+    };
+    let path = match dav_path(uri.path()) {
+        Some(path) => path,
+        None => return (StatusCode::BAD_REQUEST, "Invalid path").into_response(),
+    };
+    let tree = match load_tree(&state, &user_id).await {
+        Ok(tree) => tree,
+        Err(e) => return e.into_response(),
+    };
+    // a raw body, so axum's default limit doesnt turn big puts into 413s
+    let (body, upload) = match method == Method::PUT {
+        true => (Bytes::new(), body),
+        false => match axum::body::to_bytes(body, XML_BODY_LIMIT).await {
+            Ok(bytes) => (bytes, Body::empty()),
+            Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        },
+    };
+    let dav = Dav { state: &state, user_id, headers: &headers, info: &info, tree };
+    let result = match method.as_str() {
+        "PROPFIND" => dav.propfind(&path).await,
+        "GET" => dav.get(&path, true).await,
+        "HEAD" => dav.get(&path, false).await,
+        "PUT" => dav.put(&path, upload).await,
+        "MKCOL" => dav.mkcol(&path, &body).await,
+        "DELETE" => dav.delete(&path).await,
+        "MOVE" => dav.transfer(&path, true).await,
+        "COPY" => dav.transfer(&path, false).await,
+        "LOCK" => dav.lock(&path, &body).await,
+        "UNLOCK" => dav.unlock().await,
+        _ => Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()),
+    };
+    result.unwrap_or_else(|e| match e {
+        ServerError::QuotaExceeded => status(StatusCode::INSUFFICIENT_STORAGE),
+        e => e.into_response(),
+    })
+}
+
+impl Dav<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    // 423 when someone else holds a lock over the path
+    fn locked(&self, path: &[String], below: bool) -> Option<Response> {
+        self.state.webdav.conflicting(&self.user_id, path, below, self.header("if"))
+            .map(|_| status(StatusCode::LOCKED))
+    }
+
+    async fn quota(&self) -> Result<(i64, i64), ServerError> {
+        let used: i64 = sqlx::query_scalar("SELECT storage_used FROM users WHERE user_id = ($1);")
+            .bind(self.user_id)
+            .fetch_one(&self.state.pool)
+            .await
+            .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+        Ok((used, (STORAGE_LIMIT - used).max(0)))
+    }
+
+    async fn audit(&self, action: &str, file_id: Uuid, details: serde_json::Value) {
+        record(&self.state.pool, self.info, AuditEvent {
+            actor_id: Some(self.user_id),
+            action,
+            file_id: Some(file_id),
+            owner_id: Some(self.user_id),
+            details: Some(details),
+        }).await;
+    }
+
+    async fn created(&self, file_id: Uuid, parent_id: Option<Uuid>, name: &str) {
+        announce(self.state, ChangeEvent::new(ChangeKind::Created, Some(self.user_id), file_id,
+                                              self.user_id, parent_id, Some(name.to_string()))).await;
+    }
+
+    // depth 0 and 1 only, a whole tree in one answer is refused as rfc 4918 allows
+    async fn propfind(&self, path: &[String]) -> Result<Response, ServerError> {
+        let depth = match self.header("depth") {
+            Some("0") => 0,
+            Some("infinity") => return Ok(xml(StatusCode::FORBIDDEN,
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                 <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>".to_string())),
+            _ => 1,
+        };
+        let entry = match self.tree.resolve(path) {
+            Some(entry) => entry,
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        let quota = match entry.is_none_or(|e| e.is_folder()) {
+            true => Some(self.quota().await?),
+            false => None,
+        };
+        let mut responses = prop_response(path, entry, quota);
+        if depth == 1 && entry.is_none_or(|e| e.is_folder()) {
+            for child in self.tree.children(entry.map(|e| e.file_id)) {
+                let mut child_path = path.to_vec();
+                child_path.push(child.name());
+                responses.push_str(&prop_response(&child_path, Some(child), quota));
+            }
+        }
+        Ok(xml(StatusCode::MULTI_STATUS, multistatus(&responses)))
+    }
+
+    async fn get(&self, path: &[String], with_body: bool) -> Result<Response, ServerError> {
+        let entry = match self.tree.resolve(path) {
+            Some(Some(entry)) if !entry.is_folder() => entry,
+            Some(_) => return Ok((StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()),
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        check_download(&self.state.scanning, &entry.scan_status)?;
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, entry.mime_type.as_deref().unwrap_or("application/octet-stream"))
+            .header(header::CONTENT_LENGTH, entry.size)
+            .header(header::ETAG, entry.etag());
+        if let Some(modified) = &entry.last_modified {
+            response = response.header(header::LAST_MODIFIED, http_date(modified));
+        }
+        if !with_body {
+            return response.body(Body::empty()).map_err(|e| ServerError::InternalError(e.to_string()));
+        }
+        let object = open_file(self.state, &self.user_id.to_string(), &entry.file_id.to_string(),
+                               &entry.extension, &entry.blob_hash, entry.key_id).await?;
+        self.audit("file_downloaded", entry.file_id, serde_json::json!({"via": "webdav"})).await;
+        response.body(Body::from_stream(object.stream))
+            .map_err(|e| ServerError::InternalError(e.to_string()))
+    }
+
+    // read as it arrives and held to the quota left, an overwrite keeps the
+    // file's id, so its tags, comments and shares stay with it
+    async fn put(&self, path: &[String], body: Body) -> Result<Response, ServerError> {
+        let name = match path.last() {
+            Some(name) => name.clone(),
+            None => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        };
+        if let Some(locked) = self.locked(path, false) {
+            return Ok(locked);
+        }
+        let parent_id = match self.tree.parent_of(path) {
+            Some(parent_id) => parent_id,
+            None => return Ok(status(StatusCode::CONFLICT)),
+        };
+        let existing = self.tree.child(parent_id, &name);
+        if existing.is_some_and(|e| e.is_folder()) {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        // what the old content takes comes back when it is overwritten
+        let (_, left) = self.quota().await?;
+        let left = left + existing.map(|e| e.size).unwrap_or(0);
+        if self.header("content-length").and_then(|l| l.parse::<i64>().ok()).is_some_and(|l| l > left) {
+            return Ok(status(StatusCode::INSUFFICIENT_STORAGE));
+        }
+        let expected = ExpectedChecksums::from_headers(self.headers)?;
+        let mut hasher = UploadHasher::default();
+        let mut data = Vec::new();
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.try_next().await.map_err(|e| ServerError::BadRequest(e.to_string()))? {
+            if (data.len() + chunk.len()) as i64 > left {
+                return Ok(status(StatusCode::INSUFFICIENT_STORAGE));
+            }
+            hasher.update(&chunk);
+            data.extend_from_slice(&chunk);
+        }
+        let checksums = hasher.finish();
+        expected.verify(&checksums)?;
+        let content_type = self.header("content-type").unwrap_or("application/octet-stream").to_string();
+
+        let file = NewFile {
+            owner_id: self.user_id,
+            parent_id,
+            filename: &name,
+            content_type: &content_type,
+            data: Bytes::from(data),
+            checksums: &checksums,
+            strip_gps: self.state.metadata.strip_gps,
+        };
+        let stored = match existing {
+            Some(old) => replace_file(self.state, old.file_id, file).await?,
+            None => store_file(self.state, file, None).await?,
+        };
+        self.audit("file_uploaded", stored.file_id, serde_json::json!({
+            "name": name, "size": stored.size, "parent_id": parent_id, "via": "webdav",
+            "overwritten": existing.is_some(),
+        })).await;
+        let etag = format!("\"{}\"", checksums.sha256);
+        match existing {
+            Some(_) => {
+                announce(self.state, ChangeEvent::new(ChangeKind::Modified, Some(self.user_id), stored.file_id,
+                                                      self.user_id, parent_id, Some(name.clone()))).await;
+                Ok((StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response())
+            },
+            None => {
+                self.created(stored.file_id, parent_id, &name).await;
+                Ok((StatusCode::CREATED, [(header::ETAG, etag)]).into_response())
+            },
+        }
+    }
+
+    async fn mkcol(&self, path: &[String], body: &Bytes) -> Result<Response, ServerError> {
+        if !body.is_empty() {
+            return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+        let name = match path.last() {
+            Some(name) => name.clone(),
+            None => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        };
+        let parent_id = match self.tree.parent_of(path) {
+            Some(parent_id) => parent_id,
+            None => return Ok(status(StatusCode::CONFLICT)),
+        };
+        if self.tree.child(parent_id, &name).is_some() {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        let folder_id = make_folder(self.state, self.user_id, parent_id, &name).await?;
+        self.audit("folder_created", folder_id, serde_json::json!({
+            "name": name, "parent_id": parent_id, "via": "webdav",
+        })).await;
+        self.created(folder_id, parent_id, &name).await;
+        Ok(status(StatusCode::CREATED))
+    }
+
+    // files one by one, deepest first, so quota and folder sizes come out right.
+    // one event for the top, a deleted folder stands for what was in it
+    async fn remove_tree(&self, entry: &DavEntry) -> Result<(), ServerError> {
+        let audience = file_audience(&self.state.pool, &entry.file_id).await?;
+        if entry.is_folder() {
+            for child in self.tree.descendants(entry.file_id) {
+                remove_file(self.state, self.user_id, child.file_id).await?;
+            }
+        }
+        let (file_name, parent_id) = remove_file(self.state, self.user_id, entry.file_id).await?;
+        self.audit("file_deleted", entry.file_id, serde_json::json!({
+            "name": file_name, "via": "webdav",
+        })).await;
+        let mut deleted = ChangeEvent::new(ChangeKind::Deleted, Some(self.user_id), entry.file_id,
+                                           self.user_id, parent_id, Some(file_name));
+        deleted.audience = audience;
+        emit(self.state, deleted).await;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &[String]) -> Result<Response, ServerError> {
+        let entry = match self.tree.resolve(path) {
+            Some(Some(entry)) => entry,
+            Some(None) => return Ok(status(StatusCode::FORBIDDEN)),
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        if let Some(locked) = self.locked(path, true) {
+            return Ok(locked);
+        }
+        self.remove_tree(entry).await?;
+        self.state.webdav.release_below(&self.user_id, path).await;
+        Ok(status(StatusCode::NO_CONTENT))
+    }
+
+    // MOVE and COPY share everything up to the actual work
+    async fn transfer(&self, path: &[String], moving: bool) -> Result<Response, ServerError> {
+        let source = match self.tree.resolve(path) {
+            Some(Some(entry)) => entry,
+            Some(None) => return Ok(status(StatusCode::FORBIDDEN)),
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        let dest = match self.header("destination") {
+            Some(destination) => match destination_path(destination) {
+                Some(dest) => dest,
+                // somewhere other than this server's /dav
+                None => return Ok(status(StatusCode::BAD_GATEWAY)),
+            },
+            None => return Ok((StatusCode::BAD_REQUEST, "Destination missing").into_response()),
+        };
+        if dest.is_empty() || dest == path {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+        if dest.starts_with(path) {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+        let dest_parent = match self.tree.parent_of(&dest) {
+            Some(parent_id) => parent_id,
+            None => return Ok(status(StatusCode::CONFLICT)),
+        };
+        if moving && let Some(locked) = self.locked(path, true) {
+            return Ok(locked);
+        }
+        if let Some(locked) = self.locked(&dest, true) {
+            return Ok(locked);
+        }
+        let dest_name = dest.last().cloned().unwrap_or_default();
+        let existing = self.tree.child(dest_parent, &dest_name);
+        if let Some(existing) = existing {
+            if self.header("overwrite") == Some("F") {
+                return Ok(status(StatusCode::PRECONDITION_FAILED));
+            }
+            self.remove_tree(existing).await?;
+            self.state.webdav.release_below(&self.user_id, &dest).await;
+        }
+        match moving {
+            true => {
+                self.move_entry(source, dest_parent, &dest_name).await?;
+                self.state.webdav.release_below(&self.user_id, path).await;
+            },
+            false => {
+                let shallow = self.header("depth") == Some("0");
+                let copy_id = self.copy_entry(source, dest_parent, &dest_name, shallow).await?;
+                self.audit("file_copied", copy_id, serde_json::json!({
+                    "from": source.file_id, "name": dest_name, "parent_id": dest_parent, "via": "webdav",
+                })).await;
+            },
+        }
+        Ok(status(if existing.is_some() { StatusCode::NO_CONTENT } else { StatusCode::CREATED }))
+    }
+
+    async fn move_entry(&self, entry: &DavEntry, parent_id: Option<Uuid>, name: &str) -> Result<(), ServerError> {
+        let audience = file_audience(&self.state.pool, &entry.file_id).await?;
+        let old_parent = relocate_file(self.state, self.user_id, entry.file_id, parent_id).await?;
+        let renamed = name != entry.name();
+        if renamed {
+            rename_entry(self.state, self.user_id, entry, name).await?;
+        }
+        let moved = old_parent != parent_id;
+        if moved {
+            self.audit("file_moved", entry.file_id, serde_json::json!({
+                "before": old_parent, "after": parent_id, "via": "webdav",
+            })).await;
+        }
+        if renamed {
+            self.audit("file_renamed", entry.file_id, serde_json::json!({
+                "before": entry.name(), "after": name, "via": "webdav",
+            })).await;
+        }
+        if moved || renamed {
+            let kind = if moved { ChangeKind::Moved } else { ChangeKind::Renamed };
+            let mut event = ChangeEvent::new(kind, Some(self.user_id), entry.file_id, self.user_id,
+                                             parent_id, Some(name.to_string()));
+            event.audience = audience;
+            announce(self.state, event).await;
+        }
+        Ok(())
+    }
+
+    // copies go through store_file like any upload, so they are charged, sized,
+    // scanned and deduplicated the same way
+    async fn copy_entry(&self,
+                        entry: &DavEntry,
+                        parent_id: Option<Uuid>,
+                        name: &str,
+                        shallow: bool,
+    ) -> Result<Uuid, ServerError> {
+        if entry.is_folder() {
+            let folder_id = make_folder(self.state, self.user_id, parent_id, name).await?;
+            self.created(folder_id, parent_id, name).await;
+            if !shallow {
+                for child in self.tree.children(Some(entry.file_id)) {
+                    Box::pin(self.copy_entry(child, Some(folder_id), &child.name(), false)).await?;
+                }
+            }
+            return Ok(folder_id);
+        }
+        check_download(&self.state.scanning, &entry.scan_status)?;
+        let object = open_file(self.state, &self.user_id.to_string(), &entry.file_id.to_string(),
+                               &entry.extension, &entry.blob_hash, entry.key_id).await?;
+        let data: Vec<u8> = object.stream.map_ok(|chunk| chunk.to_vec()).try_concat().await
+            .map_err(|e| ServerError::InternalError(e.to_string()))?;
+        let mut hasher = UploadHasher::default();
+        hasher.update(&data);
+        let checksums = hasher.finish();
+        let content_type = entry.mime_type.clone().unwrap_or("application/octet-stream".to_string());
+        let stored = store_file(self.state, NewFile {
+            owner_id: self.user_id,
+            parent_id,
+            filename: name,
+            content_type: &content_type,
+            data: Bytes::from(data),
+            checksums: &checksums,
+            strip_gps: false,
+        }, None).await?;
+        self.created(stored.file_id, parent_id, name).await;
+        Ok(stored.file_id)
+    }
+
+    // exclusive write locks only, a shared request gets an exclusive one. an
+    // empty body with an If header refreshes. locking a name that doesnt exist
+    // yet creates an empty file there, as rfc 4918 asks
+    async fn lock(&self, path: &[String], body: &Bytes) -> Result<Response, ServerError> {
+        let timeout = lock_timeout(self.header("timeout"));
+        let expires_at = Utc::now() + chrono::Duration::seconds(timeout as i64);
+        if body.is_empty() {
+            let if_header = self.header("if").unwrap_or("");
+            let held = self.state.webdav.locks.iter()
+                .map(|(_, lock)| lock)
+                .find(|lock| lock.user_id == self.user_id && if_header.contains(&lock.token));
+            return Ok(match held {
+                Some(lock) => {
+                    let refreshed = DavLock { timeout, expires_at, ..(*lock).clone() };
+                    let response = xml(StatusCode::OK, lock_discovery(&refreshed));
+                    self.state.webdav.locks.insert(refreshed.token.clone(), Arc::new(refreshed)).await;
+                    response
+                },
+                None => status(StatusCode::PRECONDITION_FAILED),
+            });
+        }
+        let infinite = self.header("depth") != Some("0");
+        if let Some(locked) = self.locked(path, infinite) {
+            return Ok(locked);
+        }
+        let body = String::from_utf8_lossy(body);
+        let owner = element_inner(&body, "owner")
+            .map(|o| element_inner(o, "href").unwrap_or(o).trim().to_string())
+            .filter(|o| !o.is_empty() && !o.contains('<'));
+
+        let code = match self.tree.resolve(path) {
+            Some(_) => StatusCode::OK,
+            None => {
+                let name = path.last().cloned().unwrap_or_default();
+                let parent_id = match self.tree.parent_of(path) {
+                    Some(parent_id) => parent_id,
+                    None => return Ok(status(StatusCode::CONFLICT)),
+                };
+                let checksums = UploadHasher::default().finish();
+                let stored = store_file(self.state, NewFile {
+                    owner_id: self.user_id,
+                    parent_id,
+                    filename: &name,
+                    content_type: "application/octet-stream",
+                    data: Bytes::new(),
+                    checksums: &checksums,
+                    strip_gps: false,
+                }, None).await?;
+                self.created(stored.file_id, parent_id, &name).await;
+                StatusCode::CREATED
+            },
+        };
+        let lock = DavLock {
+            token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+            user_id: self.user_id,
+            path: path.to_vec(),
+            infinite,
+            owner,
+            timeout,
+            expires_at,
+        };
+        let mut response = xml(code, lock_discovery(&lock));
+        if let Ok(value) = HeaderValue::from_str(&format!("<{}>", lock.token)) {
+            response.headers_mut().insert(header::HeaderName::from_static("lock-token"), value);
+        }
+        self.state.webdav.locks.insert(lock.token.clone(), Arc::new(lock)).await;
+        Ok(response)
+    }
+
+    async fn unlock(&self) -> Result<Response, ServerError> {
+        let token = self.header("lock-token")
+            .map(|t| t.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+            .unwrap_or_default();
+        match self.state.webdav.locks.get(&token).await {
+            Some(lock) if lock.user_id == self.user_id => {
+                self.state.webdav.locks.invalidate(&token).await;
+                Ok(status(StatusCode::NO_CONTENT))
+            },
+            _ => Ok(status(StatusCode::CONFLICT)),
+        }
+    }
+}
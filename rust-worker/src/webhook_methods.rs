@@ -0,0 +1,226 @@
+use axum::{extract::State, Json, http::StatusCode};
+use axum_extra::extract::cookie::CookieJar;
+
+use uuid::Uuid;
+
+use crate::models::{AppState,
+                    CreateWebhookForm,
+                    WebhookIdForm,
+                    SetWebhookActiveForm,
+                    WebhookDeliveriesForm,
+                    WebhookResponse,
+                    WebhookDeliveryResponse,
+                    FileType,
+                    ServerError};
+use crate::auth_methods::current_user;
+use crate::msc_actions::generate_token;
+use crate::webhooks::{EVENT_TYPES, valid_url, check_target, deliver, record_attempt};
+
+// a user has a handful of these, not hundreds
+const MAX_WEBHOOKS: i64 = 20;
+
+fn parse_webhook_id(id: &str) -> Result<Uuid, ServerError> {
+    Uuid::parse_str(id).map_err(|e| ServerError::BadRequest(e.to_string()))
+}
+
+async fn owned_webhook(state: &AppState,
+                       owner_id: &Uuid,
+                       webhook_id: &Uuid,
+) -> Result<(String, String), ServerError> {
+    let webhook: Option<(String, String)> = sqlx::query_as(r#"SELECT url, secret FROM webhooks
+                                                              WHERE webhook_id = ($1) AND owner_id = ($2);"#)
+        .bind(webhook_id)
+        .bind(owner_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    webhook.ok_or(ServerError::NotFound("Webhook not found".to_string()))
+}
+
+// the secret is only in this response, receivers verify X-Servr-Signature with it
+pub async fn create_webhook(State(state): State<AppState>,
+                            jar: CookieJar,
+                            payload: Json<CreateWebhookForm>,
+) -> Result<Json<WebhookResponse>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let url = payload.url.trim().to_string();
+    if !valid_url(&url) {
+        return Err(ServerError::BadRequest("Webhooks need an http or https url".to_string()));
+    }
+    check_target(&url, state.webhooks.allow_private).await
+        .map_err(ServerError::BadRequest)?;
+    let mut event_types = payload.event_types.clone().unwrap_or_default();
+    if let Some(unknown) = event_types.iter().find(|t| !EVENT_TYPES.contains(&t.as_str())) {
+        return Err(ServerError::BadRequest(format!("Unknown event type {}", unknown)));
+    }
+    event_types.sort();
+    event_types.dedup();
+    let folder_id = match payload.folder_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(id) => {
+            let folder_id = Uuid::parse_str(id)
+                .map_err(|e| ServerError::BadRequest(e.to_string()))?;
+            let file_type: Option<FileType> = sqlx::query_scalar(r#"SELECT file_type FROM files
+                                                                    WHERE file_id = ($1) AND owner_id = ($2);"#)
+                .bind(folder_id)
+                .bind(owner_id)
+                .fetch_optional(&state.pool)
+                .await
+                .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+            match file_type {
+                Some(FileType::Folder) => Some(folder_id),
+                Some(_) => return Err(ServerError::BadRequest("Not a folder".to_string())),
+                None => return Err(ServerError::NotFound("Folder not found".to_string())),
+            }
+        },
+        None => None,
+    };
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM webhooks WHERE owner_id = ($1);")
+        .bind(owner_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if count >= MAX_WEBHOOKS {
+        return Err(ServerError::Forbidden(format!("At most {} webhooks", MAX_WEBHOOKS)));
+    }
+
+    let secret = generate_token();
+    let mut webhook = sqlx::query_as::<_, WebhookResponse>(r#"INSERT INTO webhooks (webhook_id, owner_id,
+                                                              url, secret, event_types, folder_id)
+                                                              VALUES ($1,$2,$3,$4,$5,$6)
+                                                              RETURNING webhook_id, url, event_types,
+                                                              folder_id, active, created_at;"#)
+        .bind(Uuid::new_v4())
+        .bind(owner_id)
+        .bind(&url)
+        .bind(&secret)
+        .bind(&event_types)
+        .bind(folder_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    webhook.secret = Some(secret);
+    Ok(Json(webhook))
+}
+
+pub async fn get_webhooks(State(state): State<AppState>,
+                          jar: CookieJar,
+) -> Result<Json<Vec<WebhookResponse>>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let webhooks = sqlx::query_as::<_, WebhookResponse>(r#"SELECT webhook_id, url, event_types,
+                                                           folder_id, active, created_at
+                                                           FROM webhooks WHERE owner_id = ($1)
+                                                           ORDER BY created_at;"#)
+        .bind(owner_id)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(webhooks))
+}
+
+// paused webhooks queue nothing new, and their pending deliveries wait
+pub async fn set_webhook_active(State(state): State<AppState>,
+                                jar: CookieJar,
+                                payload: Json<SetWebhookActiveForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let webhook_id = parse_webhook_id(&payload.webhook_id)?;
+    let result = sqlx::query("UPDATE webhooks SET active = ($1) WHERE webhook_id = ($2) AND owner_id = ($3);")
+        .bind(payload.active)
+        .bind(webhook_id)
+        .bind(owner_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("Webhook not found".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+// takes its queue and delivery log with it
+pub async fn delete_webhook(State(state): State<AppState>,
+                            jar: CookieJar,
+                            payload: Json<WebhookIdForm>,
+) -> Result<StatusCode, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let webhook_id = parse_webhook_id(&payload.webhook_id)?;
+    let result = sqlx::query("DELETE FROM webhooks WHERE webhook_id = ($1) AND owner_id = ($2);")
+        .bind(webhook_id)
+        .bind(owner_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    if result.rows_affected() == 0 {
+        return Err(ServerError::NotFound("Webhook not found".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+pub async fn get_webhook_deliveries(State(state): State<AppState>,
+                                    jar: CookieJar,
+                                    payload: Json<WebhookDeliveriesForm>,
+) -> Result<Json<Vec<WebhookDeliveryResponse>>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let webhook_id = parse_webhook_id(&payload.webhook_id)?;
+    owned_webhook(&state, &owner_id, &webhook_id).await?;
+    let deliveries = sqlx::query_as::<_, WebhookDeliveryResponse>(r#"SELECT delivery_id, event_type,
+                                                                     payload, status, attempts,
+                                                                     last_status_code, last_error,
+                                                                     next_attempt_at, created_at,
+                                                                     delivered_at
+                                                                     FROM webhook_deliveries
+                                                                     WHERE webhook_id = ($1)
+                                                                     AND (($2)::timestamptz IS NULL
+                                                                          OR created_at < ($2))
+                                                                     ORDER BY created_at DESC
+                                                                     LIMIT ($3);"#)
+        .bind(webhook_id)
+        .bind(payload.before)
+        .bind(payload.limit.unwrap_or(50).clamp(1, 200))
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(deliveries))
+}
+
+// sends a ping right away and answers with how it went. a failed ping is
+// retried like any other delivery
+pub async fn test_webhook(State(state): State<AppState>,
+                          jar: CookieJar,
+                          payload: Json<WebhookIdForm>,
+) -> Result<Json<WebhookDeliveryResponse>, ServerError> {
+
+    let owner_id = current_user(&state, jar).await?;
+    let webhook_id = parse_webhook_id(&payload.webhook_id)?;
+    let (url, secret) = owned_webhook(&state, &owner_id, &webhook_id).await?;
+    let delivery_id = Uuid::new_v4();
+    let ping = serde_json::json!({"event": "ping", "data": {"webhook_id": webhook_id}});
+    sqlx::query(r#"INSERT INTO webhook_deliveries (delivery_id, webhook_id, event_type, payload,
+                   attempts, next_attempt_at)
+                   VALUES ($1,$2,'ping',$3,1,NOW() + INTERVAL '10 minutes');"#)
+        .bind(delivery_id)
+        .bind(webhook_id)
+        .bind(&ping)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    let result = deliver(&state.webhooks, &url, &secret, "ping", &delivery_id, &ping).await;
+    record_attempt(&state, &delivery_id, 1, &result).await?;
+    let delivery = sqlx::query_as::<_, WebhookDeliveryResponse>(r#"SELECT delivery_id, event_type,
+                                                                   payload, status, attempts,
+                                                                   last_status_code, last_error,
+                                                                   next_attempt_at, created_at,
+                                                                   delivered_at
+                                                                   FROM webhook_deliveries
+                                                                   WHERE delivery_id = ($1);"#)
+        .bind(delivery_id)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(Json(delivery))
+}
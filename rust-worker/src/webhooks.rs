@@ -0,0 +1,308 @@
+use chrono::Utc;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::{AppState, ServerError};
+use crate::events::ChangeEvent;
+use crate::crypto::sign;
+
+// outgoing webhooks. a task on the event bus turns every change into one
+// webhook_deliveries row per matching webhook, and a second task posts them,
+// retrying with exponential backoff. the rows are the queue and the log.
+//
+// a delivery is a POST of {"event": kind, "data": ...} with
+//   X-Servr-Event: created | renamed | ... | ping
+//   X-Servr-Delivery: delivery id, the same across retries
+//   X-Servr-Timestamp: unix seconds
+//   X-Servr-Signature: sha256=hex(hmac_sha256(secret, timestamp + "." + body))
+
+// what a webhook can subscribe to, nothing selected means all of them
+pub const EVENT_TYPES: [&str; 7] = ["created", "modified", "renamed", "moved", "deleted", "shared", "unshared"];
+// first retry after this, doubling up to MAX_BACKOFF_SECS
+pub const BASE_BACKOFF_SECS: i64 = 30;
+pub const MAX_BACKOFF_SECS: i64 = 6 * 60 * 60;
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+    pub enabled: bool,
+    pub client: reqwest::Client,
+    pub interval: u64,
+    pub max_attempts: i32,
+    // WEBHOOK_ALLOW_PRIVATE=true lets webhooks reach loopback and private
+    // addresses, for local setups. off, the worker cant be used to probe the
+    // network it sits in
+    pub allow_private: bool,
+}
+
+// drops private answers at connect time too, so a name that resolved to a
+// public address when the webhook was saved cant be pointed inward later
+struct PublicResolver {
+    allow_private: bool,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|a| allow_private || public_ip(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err("Webhook host does not resolve to a public address".into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let timeout = env::var("WEBHOOK_TIMEOUT_SECS").ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(10);
+        let allow_private = env::var("WEBHOOK_ALLOW_PRIVATE").map(|v| v == "true").unwrap_or(false);
+        WebhookConfig {
+            enabled: env::var("WEBHOOKS").map(|v| v != "false").unwrap_or(true),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(timeout))
+                .redirect(reqwest::redirect::Policy::none())
+                .dns_resolver(Arc::new(PublicResolver { allow_private }))
+                .build()
+                .unwrap_or_default(),
+            interval: env::var("WEBHOOK_INTERVAL_SECS").ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(5),
+            max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS").ok()
+                .and_then(|v| v.parse::<i32>().ok())
+                .unwrap_or(8),
+            allow_private,
+        }
+    }
+}
+
+// what a receiver compares X-Servr-Signature against
+pub fn signature_header(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    format!("sha256={}", sign(secret.as_bytes(), &message))
+}
+
+// seconds until the next try after `attempts` failed ones
+pub fn backoff_secs(attempts: i32) -> i64 {
+    let doublings = attempts.saturating_sub(1).clamp(0, 20) as u32;
+    BASE_BACKOFF_SECS.saturating_mul(2_i64.pow(doublings)).min(MAX_BACKOFF_SECS)
+}
+
+pub fn valid_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.host_str().is_some())
+}
+
+// false for loopback, private, link local (cloud metadata lives at
+// 169.254.169.254), carrier grade nat, multicast and the like
+pub fn public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified()
+              || v4.is_broadcast() || v4.is_multicast() || v4.is_documentation()
+              || a == 0 || (a == 100 && (b & 0xc0) == 64))
+        },
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => public_ip(IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                // unique local fc00::/7 and link local fe80::/10
+                !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast()
+                  || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+            },
+        },
+    }
+}
+
+// every address the url's host resolves to has to be public. checked when a
+// webhook is saved and again before each send, ip literals never reach the
+// client's resolver
+pub async fn check_target(url: &str, allow_private: bool) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    if allow_private {
+        return Ok(());
+    }
+    let host = parsed.host_str().ok_or("Webhook url has no host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, parsed.port_or_known_default().unwrap_or(80))).await
+            .map_err(|_| "Webhook host does not resolve".to_string())?
+            .map(|a| a.ip())
+            .collect(),
+    };
+    if addrs.is_empty() || !addrs.into_iter().all(public_ip) {
+        return Err("Webhook host is not a public address".to_string());
+    }
+    Ok(())
+}
+
+// one POST, the status code of whatever answered
+pub async fn send(client: &reqwest::Client,
+                  url: &str,
+                  secret: &str,
+                  event_type: &str,
+                  delivery_id: &Uuid,
+                  payload: &Value,
+) -> Result<u16, String> {
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let timestamp = Utc::now().timestamp();
+    let res = client.post(url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "servr-storage-webhooks")
+        .header("X-Servr-Event", event_type)
+        .header("X-Servr-Delivery", delivery_id.to_string())
+        .header("X-Servr-Timestamp", timestamp.to_string())
+        .header("X-Servr-Signature", signature_header(secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(res.status().as_u16())
+}
+
+// one row per active webhook of someone who can see the file, subscribed to
+// the kind, and either unscoped or scoped to the file or a folder above it
+pub async fn queue_deliveries(state: &AppState, event: &ChangeEvent) -> Result<u64, ServerError> {
+    let payload = serde_json::json!({"event": event.kind.as_str(), "data": event});
+    let queued = sqlx::query(r#"WITH RECURSIVE ancestors AS (
+                                    SELECT file_id, parent_id FROM files
+                                    WHERE file_id = ($4)
+                                    UNION ALL
+
+                                    SELECT f.file_id, f.parent_id FROM files f
+                                    JOIN ancestors a ON f.file_id = a.parent_id
+                                )
+                                INSERT INTO webhook_deliveries (delivery_id, webhook_id, event_type, payload)
+                                SELECT gen_random_uuid(), w.webhook_id, ($1), ($2)
+                                FROM webhooks w
+                                WHERE w.active AND w.owner_id = ANY($3)
+                                AND (cardinality(w.event_types) = 0 OR ($1) = ANY(w.event_types))
+                                AND (w.folder_id IS NULL OR w.folder_id = ($5)
+                                     OR w.folder_id IN (SELECT file_id FROM ancestors));"#)
+        .bind(event.kind.as_str())
+        .bind(&payload)
+        .bind(&event.audience)
+        .bind(event.parent_id)
+        .bind(event.file_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(queued.rows_affected())
+}
+
+// runs for the life of the server. changes published while it lags behind
+// the bus are lost to webhooks, that is logged
+pub async fn run_dispatcher(state: AppState) {
+    let mut receiver = state.events.subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if let Err(e) = queue_deliveries(&state, &event).await {
+                    eprintln!("Error {:?}", e);
+                }
+            },
+            Err(RecvError::Lagged(missed)) => eprintln!("Webhooks missed {} events", missed),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+// send() behind the address check, what the dispatcher and pings go through
+pub async fn deliver(config: &WebhookConfig,
+                     url: &str,
+                     secret: &str,
+                     event_type: &str,
+                     delivery_id: &Uuid,
+                     payload: &Value,
+) -> Result<u16, String> {
+    check_target(url, config.allow_private).await?;
+    send(&config.client, url, secret, event_type, delivery_id, payload).await
+}
+
+// stores how an attempt went: delivered on a 2xx, otherwise back in the queue
+// after the backoff, or failed for good once out of attempts. the log is shown
+// to the webhook's owner, so connection errors are kept out of it, they would
+// tell what answers where inside our network
+pub async fn record_attempt(state: &AppState,
+                            delivery_id: &Uuid,
+                            attempts: i32,
+                            result: &Result<u16, String>,
+) -> Result<(), ServerError> {
+    let (status_code, error) = match result {
+        Ok(code) if (200..300).contains(code) => (Some(*code as i32), None),
+        Ok(code) => (Some(*code as i32), Some(format!("Receiver answered {}", code))),
+        Err(e) => {
+            eprintln!("Webhook delivery {} failed. Error {}", delivery_id, e);
+            (None, Some("Could not deliver to the receiver".to_string()))
+        },
+    };
+    let status = match (&error, attempts >= state.webhooks.max_attempts) {
+        (None, _) => "delivered",
+        (Some(_), true) => "failed",
+        (Some(_), false) => "pending",
+    };
+    sqlx::query(r#"UPDATE webhook_deliveries
+                   SET status = ($1), last_status_code = ($2), last_error = ($3),
+                   next_attempt_at = NOW() + make_interval(secs => ($4)),
+                   delivered_at = CASE WHEN ($1) = 'delivered' THEN NOW() END
+                   WHERE delivery_id = ($5);"#)
+        .bind(status)
+        .bind(status_code)
+        .bind(&error)
+        .bind(backoff_secs(attempts) as f64)
+        .bind(delivery_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// claims due deliveries the same way the scan and index jobs do, so several
+// workers can share the queue
+pub async fn run_deliveries(state: &AppState, limit: i64) -> Result<usize, ServerError> {
+    let due: Vec<(Uuid, String, Value, i32, String, String)> =
+        sqlx::query_as(r#"WITH claimed AS (
+                              UPDATE webhook_deliveries
+                              SET attempts = attempts + 1,
+                              next_attempt_at = NOW() + INTERVAL '10 minutes'
+                              WHERE delivery_id IN (
+                                  SELECT d.delivery_id FROM webhook_deliveries d
+                                  JOIN webhooks w ON w.webhook_id = d.webhook_id
+                                  WHERE d.status = 'pending' AND d.next_attempt_at <= NOW()
+                                  AND w.active
+                                  ORDER BY d.next_attempt_at
+                                  LIMIT ($1)
+                                  FOR UPDATE OF d SKIP LOCKED)
+                              RETURNING delivery_id, webhook_id, event_type, payload, attempts
+                          )
+                          SELECT c.delivery_id, c.event_type, c.payload, c.attempts, w.url, w.secret
+                          FROM claimed c JOIN webhooks w ON w.webhook_id = c.webhook_id;"#)
+        .bind(limit)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| ServerError::DatabaseError(e.to_string()))?;
+
+    let mut delivered = 0;
+    for (delivery_id, event_type, payload, attempts, url, secret) in due {
+        let result = deliver(&state.webhooks, &url, &secret, &event_type,
+                             &delivery_id, &payload).await;
+        if matches!(result, Ok(code) if (200..300).contains(&code)) {
+            delivered += 1;
+        }
+        record_attempt(state, &delivery_id, attempts, &result).await?;
+    }
+    Ok(delivered)
+}
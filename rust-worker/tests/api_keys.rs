@@ -0,0 +1,43 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+
+#[tokio::test]
+async fn test_create_api_key_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/create-api-key", app.base_url))
+        .json(&serde_json::json!({"name": "laptop"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn test_sign_in_with_unknown_key() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/sign-in-key", app.base_url))
+        .json(&serde_json::json!({"api_key": "srv_notarealkey"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+    assert!(res.headers().get("set-cookie").is_none());
+}
+
+#[tokio::test]
+async fn test_move_file_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/move-file", app.base_url))
+        .json(&serde_json::json!({"file_id": uuid::Uuid::new_v4().to_string(), "parent_id": ""}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
@@ -0,0 +1,91 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_dav_app, signed_in_user};
+use serde_json::{json, Value};
+use rust_worker::audit::{page_size, client_ip, TrustedProxies, MAX_PAGE};
+use std::net::IpAddr;
+
+#[test]
+fn test_page_size() {
+    assert_eq!(page_size(None), 50);
+    assert_eq!(page_size(Some(0)), 1);
+    assert_eq!(page_size(Some(10_000)), MAX_PAGE);
+}
+
+#[test]
+fn test_client_ip() {
+    let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+    let trusted = TrustedProxies::parse("10.0.0.2, 172.16.0.0/12");
+    assert!(trusted.contains("172.20.1.1".parse().unwrap()));
+    assert!(trusted.contains("::ffff:10.0.0.2".parse().unwrap()));
+    assert!(!trusted.contains("10.0.0.3".parse().unwrap()));
+
+    // a client talking to us directly cant pick its address
+    assert_eq!(client_ip(ip("203.0.113.9"), Some("1.2.3.4"), Some("1.2.3.4"), &trusted), ip("203.0.113.9"));
+    // through the proxy the hop it appended counts, not what the client sent before it
+    assert_eq!(client_ip(ip("10.0.0.2"), Some("1.2.3.4, 203.0.113.9"), None, &trusted), ip("203.0.113.9"));
+    assert_eq!(client_ip(ip("10.0.0.2"), Some("203.0.113.9, 172.16.0.5"), None, &trusted), ip("203.0.113.9"));
+    assert_eq!(client_ip(ip("10.0.0.2"), None, Some("203.0.113.9"), &trusted), ip("203.0.113.9"));
+    assert_eq!(client_ip(ip("10.0.0.2"), Some("junk"), None, &trusted), ip("10.0.0.2"));
+    // nobody trusted by default
+    assert_eq!(client_ip(ip("10.0.0.2"), Some("1.2.3.4"), None, &TrustedProxies::default()), ip("10.0.0.2"));
+    assert_eq!(client_ip(None, Some("1.2.3.4"), None, &trusted), None);
+}
+
+#[tokio::test]
+async fn test_activity_is_recorded() {
+    let app = spawn_dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    // nobody signed in, so it's not in anyone's activity
+    let res = app.client
+        .post(format!("{}/sign-in", app.base_url))
+        .json(&json!({"email": user.email, "password": "wrong password"}))
+        .send()
+        .await
+        .unwrap();
+    assert!(!res.status().is_success());
+
+    let events: Vec<Value> = app.post_as(&user, "/get-activity", json!({})).await.json().await.unwrap();
+    let actions: Vec<&str> = events.iter().map(|e| e["action"].as_str().unwrap()).collect();
+    assert_eq!(actions, vec!["file_uploaded", "login", "user_created"]);
+    assert_eq!(events[0]["file_id"], user.file_id.to_string().as_str());
+    assert_eq!(events[0]["actor_email"], user.email.as_str());
+    assert_eq!(events[0]["details"]["via"], "webdav");
+
+    // newest first, a page at a time
+    let page: Vec<Value> = app.post_as(&user, "/get-activity", json!({"limit": 1})).await.json().await.unwrap();
+    assert_eq!(page.len(), 1);
+    let rest: Vec<Value> = app.post_as(&user, "/get-activity", json!({"before": page[0]["event_id"]})).await
+        .json().await.unwrap();
+    assert_eq!(rest.iter().map(|e| e["action"].as_str().unwrap()).collect::<Vec<_>>(), vec!["login", "user_created"]);
+
+    // the whole log is for admins
+    let res = app.post_as(&user, "/admin/audit-events", json!({})).await;
+    assert_eq!(res.status(), 403);
+    sqlx::query("UPDATE users SET super_user = TRUE WHERE user_id = $1;")
+        .bind(user.user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    let failed: Vec<Value> = app.post_as(&user, "/admin/audit-events", json!({"actions": ["login_failed"], "limit": 500})).await
+        .json().await.unwrap();
+    assert!(failed.iter().any(|e| e["details"]["email"] == user.email.as_str() && e["actor_id"].is_null()));
+    let on_file: Vec<Value> = app.post_as(&user, "/admin/audit-events", json!({"file_id": user.file_id})).await
+        .json().await.unwrap();
+    assert_eq!(on_file.len(), 1);
+    assert_eq!(on_file[0]["action"], "file_uploaded");
+}
+
+#[tokio::test]
+async fn test_get_activity_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/get-activity", app.base_url))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
@@ -0,0 +1,92 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_dav_app, signed_in_user, TestApp, User};
+use rust_worker::changes::journal_kind;
+use rust_worker::events::ChangeKind;
+use rust_worker::models::ChangesResponse;
+
+#[test]
+fn test_journal_kind() {
+    assert_eq!(journal_kind(ChangeKind::Created), "created");
+    assert_eq!(journal_kind(ChangeKind::Renamed), "updated");
+    assert_eq!(journal_kind(ChangeKind::Moved), "updated");
+    assert_eq!(journal_kind(ChangeKind::Shared), "updated");
+    assert_eq!(journal_kind(ChangeKind::Deleted), "deleted");
+}
+
+#[tokio::test]
+async fn test_changes_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .get(format!("{}/changes?cursor=0&wait=5", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+async fn changes(app: &TestApp, user: &User, query: &str) -> ChangesResponse {
+    let res = app.client
+        .get(format!("{}/changes?{}", app.base_url, query))
+        .header("cookie", &user.cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    res.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_change_cursor() {
+    let app = spawn_dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    let stranger = signed_in_user(&app, &pool).await;
+
+    // no cursor starts at the head, everything so far is in get_files
+    let head = changes(&app, &user, "").await;
+    assert!(head.changes.is_empty() && head.cursor > 0);
+
+    let notes = app.upload_as(&user, &pool, "notes.txt", "notes").await;
+    let page = changes(&app, &user, &format!("cursor={}", head.cursor)).await;
+    assert_eq!(page.changes.len(), 1);
+    assert_eq!((page.changes[0].file_id, page.changes[0].kind.as_str()), (notes, "created"));
+    assert_eq!(page.changes[0].file_name.as_deref(), Some("notes"));
+    assert!(!page.has_more && page.cursor > head.cursor);
+
+    // paging a page at a time picks up where the last one stopped
+    app.upload_as(&user, &pool, "notes.txt", "changed").await;
+    let todo = app.upload_as(&user, &pool, "todo.txt", "todo").await;
+    let first = changes(&app, &user, &format!("cursor={}&limit=1", page.cursor)).await;
+    assert!(first.has_more);
+    assert_eq!((first.changes[0].file_id, first.changes[0].kind.as_str()), (notes, "updated"));
+    let second = changes(&app, &user, &format!("cursor={}&limit=1", first.cursor)).await;
+    assert!(!second.has_more);
+    assert_eq!((second.changes[0].file_id, second.changes[0].kind.as_str()), (todo, "created"));
+
+    // a waiting request comes back as soon as something happens
+    let cursor = second.cursor;
+    let waiting = tokio::spawn({
+        let (client, base_url, cookie) = (app.client.clone(), app.base_url.clone(), user.cookie.clone());
+        async move {
+            client.get(format!("{}/changes?cursor={}&wait=30", base_url, cursor))
+                .header("cookie", cookie)
+                .send().await.unwrap()
+                .json::<ChangesResponse>().await.unwrap()
+        }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let res = app.post_as(&user, "/delete-file", serde_json::json!({"owner_id": user.user_id.to_string(),
+                                                                   "file_id": todo.to_string()})).await;
+    assert_eq!(res.status(), 200);
+    let woken = tokio::time::timeout(std::time::Duration::from_secs(10), waiting).await
+        .expect("long poll not woken").unwrap();
+    assert_eq!(woken.changes.len(), 1);
+    assert_eq!((woken.changes[0].file_id, woken.changes[0].kind.as_str()), (todo, "deleted"));
+    assert!(woken.changes[0].file_name.is_none());
+
+    // none of it shows up for someone else
+    let theirs = changes(&app, &stranger, "cursor=0").await;
+    assert!(theirs.changes.iter().all(|c| c.file_id != notes && c.file_id != todo));
+}
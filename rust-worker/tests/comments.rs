@@ -0,0 +1,74 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_dav_app, signed_in_user};
+use rust_worker::comment_methods::parse_mentions;
+use serde_json::{json, Value};
+
+#[test]
+fn test_parse_mentions() {
+    assert_eq!(parse_mentions("thanks @Bob@Example.com, and @alice@example.com!"),
+               vec!["alice@example.com".to_string(), "bob@example.com".to_string()]);
+    // handles without a domain and emails without the @ prefix arent mentions
+    assert!(parse_mentions("@bob see carol@example.com").is_empty());
+    assert_eq!(parse_mentions("@a@b.io @a@b.io").len(), 1);
+}
+
+#[tokio::test]
+async fn test_comment_threads() {
+    let app = spawn_dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+
+    let res = app.post_as(&user, "/add-comment", json!({"file_id": user.file_id, "body": "looks good"})).await;
+    assert_eq!(res.status(), 200);
+    let thread: Value = res.json().await.unwrap();
+    assert_eq!(thread["parent_id"], Value::Null);
+    assert_eq!(thread["author_email"], user.email.as_str());
+    let reply: Value = app.post_as(&user, "/add-comment", json!({"file_id": user.file_id, "body": "one more thing",
+                                                                 "parent_id": thread["comment_id"]})).await
+        .json().await.unwrap();
+    assert_eq!(reply["parent_id"], thread["comment_id"]);
+
+    // only threads resolve
+    let res = app.post_as(&user, "/resolve-comment", json!({"comment_id": reply["comment_id"], "resolved": true})).await;
+    assert_eq!(res.status(), 400);
+    let res = app.post_as(&user, "/resolve-comment", json!({"comment_id": thread["comment_id"], "resolved": true})).await;
+    assert_eq!(res.status(), 200);
+    let comments: Vec<Value> = app.post_as(&user, "/get-comments", json!({"file_id": user.file_id})).await
+        .json().await.unwrap();
+    assert_eq!(comments.len(), 2);
+    assert_eq!(comments[0]["comment_id"], thread["comment_id"]);
+    assert_eq!(comments[0]["resolved"], true);
+    assert_eq!(comments[0]["resolved_by"], user.user_id.to_string().as_str());
+    assert_eq!(comments[1]["resolved"], false);
+
+    // a deleted thread keeps its place for the replies
+    let res = app.post_as(&user, "/delete-comment", json!({"comment_id": thread["comment_id"]})).await;
+    assert_eq!(res.status(), 200);
+    let comments: Vec<Value> = app.post_as(&user, "/get-comments", json!({"file_id": user.file_id})).await
+        .json().await.unwrap();
+    assert_eq!(comments.len(), 2);
+    assert_eq!((comments[0]["deleted"].clone(), comments[0]["body"].clone()), (json!(true), json!("")));
+    assert_eq!(comments[1]["body"], "one more thing");
+
+    // a file the user can't see looks like one that doesn't exist
+    let stranger = signed_in_user(&app, &pool).await;
+    let res = app.post_as(&stranger, "/add-comment", json!({"file_id": user.file_id, "body": "hi"})).await;
+    assert_eq!(res.status(), 404);
+    let res = app.post_as(&stranger, "/resolve-comment", json!({"comment_id": reply["comment_id"], "resolved": true})).await;
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn test_add_comment_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/add-comment", app.base_url))
+        .json(&serde_json::json!({"file_id":"7c590022-c579-4e69-8eb4-92e67440f93f",
+                                  "body":"looks good"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
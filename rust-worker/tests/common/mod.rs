@@ -1,12 +1,40 @@
 use tokio::net::TcpListener;
-use rust_worker::setup::setup;
+use rust_worker::setup::setup_with_store;
+use rust_worker::storage::MemoryStore;
+use std::sync::Arc;
+use uuid::Uuid;
 
 pub struct TestApp {
     pub base_url: String,
     pub client: reqwest::Client,
 }
 
+// settings are read from the environment when an app is set up, so apps are
+// spawned one at a time and only the one that asks for a setting sees it
+static SETUP: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[allow(dead_code)]
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(&[]).await
+}
+
+pub async fn spawn_app_with(vars: &[(&str, String)]) -> TestApp {
+    let _setup = SETUP.lock().await;
+    unsafe {
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+    }
+    let app = start_app().await;
+    unsafe {
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+    }
+    app
+}
+
+async fn start_app() -> TestApp {
     let database_url = match std::env::var("DATABASE_URL") {
         Ok(url) => url,
         Err(e) => { eprintln!("Error: {}", e);
@@ -24,10 +52,16 @@ pub async fn spawn_app() -> TestApp {
         .await.unwrap();
     let port = listener.local_addr().unwrap().port();
 
-    let app = setup(pool).await.unwrap();
+    let secret = std::env::var("SECRET_KEY").ok()
+        .filter(|k| !k.is_empty())
+        .unwrap_or("test-secret".to_string());
+    let store = Arc::new(MemoryStore::new(format!("http://127.0.0.1:{}", port), secret.clone()));
+    let app = setup_with_store(pool, store, secret).await.unwrap();
 
     tokio::spawn(async move {
-        axum::serve(listener, app).await.unwrap();
+        axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+            .await
+            .unwrap();
     });
 
     TestApp {
@@ -36,4 +70,91 @@ pub async fn spawn_app() -> TestApp {
     }
 }
 
+// a signed up user with a working session and one file, welcome.txt. a session
+// only counts once the user's files are cached, and the upload fills that
+// cache, so it goes over webdav (the app needs WEBDAV=true)
+#[allow(dead_code)]
+pub struct User {
+    pub user_id: Uuid,
+    pub email: String,
+    pub cookie: String,
+    pub file_id: Uuid,
+}
 
+#[allow(dead_code)]
+pub async fn spawn_dav_app() -> TestApp {
+    spawn_app_with(&[("WEBDAV", "true".to_string())]).await
+}
+
+#[allow(dead_code)]
+pub async fn signed_in_user(app: &TestApp, pool: &sqlx::PgPool) -> User {
+    let email = format!("{}@mail.com", Uuid::new_v4());
+    let form = serde_json::json!({"email": email, "password": "12345678"});
+    let res = app.client
+        .post(format!("{}/sign-up", app.base_url))
+        .json(&form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let res = app.client
+        .post(format!("{}/sign-in", app.base_url))
+        .json(&form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let cookie = res.headers()["set-cookie"].to_str().unwrap()
+        .split(';').next().unwrap().to_string();
+
+    let res = app.client
+        .put(format!("{}/dav/welcome.txt", app.base_url))
+        .basic_auth(&email, Some("12345678"))
+        .body("welcome")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let (user_id, file_id): (Uuid, Uuid) = sqlx::query_as(r#"SELECT u.user_id, f.file_id FROM users u
+                                                            JOIN files f ON f.owner_id = u.user_id
+                                                            WHERE u.email = $1;"#)
+        .bind(&email)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    User { user_id, email, cookie, file_id }
+}
+
+impl TestApp {
+    // a json route as the user
+    #[allow(dead_code)]
+    pub async fn post_as(&self, user: &User, path: &str, body: serde_json::Value) -> reqwest::Response {
+        self.client
+            .post(format!("{}{}", self.base_url, path))
+            .header("cookie", &user.cookie)
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    // a second file over webdav, its id
+    #[allow(dead_code)]
+    pub async fn upload_as(&self, user: &User, pool: &sqlx::PgPool, name: &str, body: impl Into<reqwest::Body>) -> Uuid {
+        let res = self.client
+            .put(format!("{}/dav/{}", self.base_url, name))
+            .basic_auth(&user.email, Some("12345678"))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+        sqlx::query_scalar(r#"SELECT file_id FROM files WHERE owner_id = $1 AND file_name = $2;"#)
+            .bind(user.user_id)
+            .bind(stem)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+}
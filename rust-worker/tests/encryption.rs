@@ -0,0 +1,66 @@
+use futures::StreamExt;
+use rust_worker::encryption::{Encryption, CHUNK_SIZE, encrypt_stream, decrypt_stream,
+                              generate_data_key, encrypted_size, plaintext_size};
+
+async fn encrypt_all(dek: &[u8; 32], data: &[u8]) -> bytes::Bytes {
+    // the plaintext arrives in pieces that dont line up with chunks either
+    let pieces: Vec<Result<bytes::Bytes, std::io::Error>> = data
+        .chunks(5003)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut stream = encrypt_stream(dek, futures::stream::iter(pieces).boxed());
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        out.extend_from_slice(&chunk.unwrap());
+    }
+    out.into()
+}
+
+async fn decrypt_all(dek: &[u8; 32], encrypted: bytes::Bytes) -> Result<Vec<u8>, std::io::Error> {
+    // split into odd sized pieces to exercise the chunk buffering
+    let pieces: Vec<Result<bytes::Bytes, std::io::Error>> = encrypted
+        .chunks(7919)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut stream = decrypt_stream(dek, futures::stream::iter(pieces).boxed());
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        out.extend_from_slice(&chunk?);
+    }
+    Ok(out)
+}
+
+#[tokio::test]
+async fn test_encrypt_round_trip() {
+    let dek = generate_data_key();
+    for len in [0, 11, CHUNK_SIZE, CHUNK_SIZE + 1, CHUNK_SIZE * 2 + 5] {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt_all(&dek, &data).await;
+        assert_eq!(encrypted.len(), encrypted_size(len));
+        assert_eq!(plaintext_size(encrypted.len() as i64), len as i64);
+        assert_eq!(decrypt_all(&dek, encrypted).await.unwrap(), data);
+    }
+}
+
+#[tokio::test]
+async fn test_truncated_object_rejected() {
+    let dek = generate_data_key();
+    let data = vec![1u8; CHUNK_SIZE * 2];
+    let encrypted = encrypt_all(&dek, &data).await;
+    // dropping the last chunk leaves a valid looking but non-final chunk at the end
+    let truncated = encrypted.slice(..encrypted.len() - (CHUNK_SIZE + 16));
+    assert!(decrypt_all(&dek, truncated).await.is_err());
+}
+
+#[test]
+fn test_rewrap_keeps_data_key() {
+    let old = Encryption::new("1".to_string(), [1u8; 32], vec![]);
+    let dek = generate_data_key();
+    let wrapped = old.wrap(&dek).unwrap();
+
+    let new = Encryption::new("2".to_string(), [2u8; 32], vec![("1".to_string(), [1u8; 32])]);
+    let unwrapped = new.unwrap("1", &wrapped).unwrap();
+    let rewrapped = new.wrap(&unwrapped).unwrap();
+    assert_eq!(new.unwrap("2", &rewrapped).unwrap(), dek);
+    assert!(new.unwrap("1", &rewrapped).is_err());
+}
@@ -0,0 +1,80 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_dav_app, signed_in_user};
+use rust_worker::events::{EventBus, ChangeEvent, ChangeKind};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_bus_delivers_to_audience() {
+    let bus = EventBus::new(16);
+    let mut receiver = bus.subscribe();
+    let owner = Uuid::new_v4();
+    let teammate = Uuid::new_v4();
+
+    let mut event = ChangeEvent::new(ChangeKind::Created, Some(owner), Uuid::new_v4(), owner,
+                                     None, Some("report.pdf".to_string()));
+    event.audience = vec![owner, teammate];
+    bus.publish(event);
+
+    let received = receiver.recv().await.unwrap();
+    assert!(received.visible_to(&teammate));
+    assert!(!received.visible_to(&Uuid::new_v4()));
+    let json = serde_json::to_value(&*received).unwrap();
+    assert_eq!(json["kind"], "created");
+    // who else can see it isnt sent to subscribers
+    assert!(json.get("audience").is_none());
+}
+
+#[tokio::test]
+async fn test_publish_wo_subscribers() {
+    let bus = EventBus::new(16);
+    let owner = Uuid::new_v4();
+    bus.publish(ChangeEvent::new(ChangeKind::Deleted, Some(owner), Uuid::new_v4(), owner, None, None));
+}
+
+// the next event off the stream, skipping keep alives
+async fn next_event(res: &mut reqwest::Response) -> String {
+    let mut text = String::new();
+    loop {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), res.chunk()).await
+            .expect("no event in time").unwrap().unwrap();
+        text.push_str(&String::from_utf8_lossy(&chunk));
+        if let Some((event, _)) = text.split_once("\n\n") && event.contains("event:") {
+            return event.to_string();
+        }
+        if text.ends_with("\n\n") {
+            text.clear();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_events_stream() {
+    let app = spawn_dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    let stranger = signed_in_user(&app, &pool).await;
+
+    let res = app.client
+        .get(format!("{}/events", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+    let mut res = app.client
+        .get(format!("{}/events", app.base_url))
+        .header("cookie", &user.cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    // someone else's upload never shows, the user's own does
+    app.upload_as(&stranger, &pool, "secret.txt", "secret").await;
+    let file_id = app.upload_as(&user, &pool, "other.txt", "other").await;
+    let event = next_event(&mut res).await;
+    assert!(event.starts_with("event: created"), "{}", event);
+    let data: serde_json::Value = serde_json::from_str(event.split_once("data: ").unwrap().1).unwrap();
+    assert_eq!(data["file_id"], file_id.to_string().as_str());
+    assert_eq!(data["actor_id"], user.user_id.to_string().as_str());
+}
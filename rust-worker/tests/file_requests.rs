@@ -0,0 +1,35 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+
+#[tokio::test]
+async fn test_unknown_file_request_token() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .get(format!("{}/r/{}", app.base_url, "doesnotexist"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn test_upload_to_unknown_file_request() {
+    let app = spawn_app().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part("file", reqwest::multipart::Part::bytes(b"Hello World".to_vec())
+            .file_name("test.txt")
+            .mime_str("text/plain").unwrap())
+        .text("name", "Client")
+        .text("email", "client@mail.com");
+
+    let res = app.client
+        .post(format!("{}/r/{}", app.base_url, "doesnotexist"))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
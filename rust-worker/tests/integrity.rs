@@ -0,0 +1,33 @@
+use axum::http::{HeaderMap, HeaderValue};
+use rust_worker::integrity::{ExpectedChecksums, UploadHasher};
+
+// "Hello World"
+const SHA256: &str = "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e";
+const MD5_BASE64: &str = "sQqNsWTgdUEFt6mb5y4/5Q==";
+
+#[test]
+fn test_checksums_match_headers() {
+    let mut hasher = UploadHasher::default();
+    hasher.update(b"Hello ");
+    hasher.update(b"World");
+    let checksums = hasher.finish();
+    assert_eq!(checksums.sha256, SHA256);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-md5", HeaderValue::from_static(MD5_BASE64));
+    headers.insert("x-content-sha256", HeaderValue::from_static(SHA256));
+    let expected = ExpectedChecksums::from_headers(&headers).unwrap();
+    assert!(expected.verify(&checksums).is_ok());
+}
+
+#[test]
+fn test_checksum_mismatch_rejected() {
+    let mut hasher = UploadHasher::default();
+    hasher.update(b"Hello World!");
+    let checksums = hasher.finish();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-content-sha256", HeaderValue::from_static(SHA256));
+    let expected = ExpectedChecksums::from_headers(&headers).unwrap();
+    assert!(expected.verify(&checksums).is_err());
+}
@@ -0,0 +1,106 @@
+use bytes::Bytes;
+use rust_worker::media_metadata::{extract_metadata, extract_metadata_blocking, contains_pattern, strip_gps};
+
+fn entry(out: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32) {
+    out.extend_from_slice(&tag.to_be_bytes());
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&count.to_be_bytes());
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn rationals(out: &mut Vec<u8>, values: [u32; 3]) {
+    for v in values {
+        out.extend_from_slice(&v.to_be_bytes());
+        out.extend_from_slice(&1u32.to_be_bytes());
+    }
+}
+
+// big endian tiff: ifd0 with Make and a GPS pointer, gps at 51°30'N 0°7'30"W
+fn exif_block() -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&2u16.to_be_bytes());
+    entry(&mut tiff, 0x010f, 2, 6, 38);
+    entry(&mut tiff, 0x8825, 4, 1, 44);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff.extend_from_slice(b"Canon\0");
+    tiff.extend_from_slice(&4u16.to_be_bytes());
+    entry(&mut tiff, 0x0001, 2, 2, u32::from_be_bytes(*b"N\0\0\0"));
+    entry(&mut tiff, 0x0002, 5, 3, 98);
+    entry(&mut tiff, 0x0003, 2, 2, u32::from_be_bytes(*b"W\0\0\0"));
+    entry(&mut tiff, 0x0004, 5, 3, 122);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    rationals(&mut tiff, [51, 30, 0]);
+    rationals(&mut tiff, [0, 7, 30]);
+
+    let mut app1 = vec![0xff, 0xe1];
+    app1.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend_from_slice(&tiff);
+    app1
+}
+
+fn jpeg_with_exif() -> Bytes {
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+        .encode(&[128u8; 8 * 4 * 3], 8, 4, image::ExtendedColorType::Rgb8)
+        .unwrap();
+    // right after SOI
+    let mut out = jpeg[..2].to_vec();
+    out.extend_from_slice(&exif_block());
+    out.extend_from_slice(&jpeg[2..]);
+    Bytes::from(out)
+}
+
+#[test]
+fn test_image_metadata() {
+    let meta = extract_metadata(&jpeg_with_exif(), "image/jpeg").unwrap();
+    assert_eq!(meta["kind"], "image");
+    assert_eq!(meta["width"], 8);
+    assert_eq!(meta["height"], 4);
+    assert_eq!(meta["camera_make"], "Canon");
+    assert_eq!(meta["gps"]["lat"], 51.5);
+    assert_eq!(meta["gps"]["lon"], -0.125);
+}
+
+#[test]
+fn test_strip_gps() {
+    let original = jpeg_with_exif();
+    let stripped = strip_gps(&original).unwrap();
+    // done in place, everything else keeps its offset
+    assert_eq!(stripped.len(), original.len());
+    let meta = extract_metadata(&stripped, "image/jpeg").unwrap();
+    assert!(meta.get("gps").is_none());
+    assert_eq!(meta["camera_make"], "Canon");
+    assert_eq!(meta["width"], 8);
+    // nothing left to strip the second time
+    assert!(strip_gps(&stripped).is_none());
+}
+
+#[test]
+fn test_not_media() {
+    assert!(extract_metadata(b"%PDF-1.7\n", "application/pdf").is_none());
+    assert!(extract_metadata(b"<svg/>", "image/svg+xml").is_none());
+    assert!(strip_gps(&Bytes::from_static(b"\x89PNG\r\n\x1a\n")).is_none());
+    // garbage still gets a kind, just nothing else
+    let meta = extract_metadata(b"not a video", "video/mp4").unwrap();
+    assert_eq!(meta["kind"], "video");
+    assert!(meta.get("duration_secs").is_none());
+}
+
+#[tokio::test]
+async fn test_extract_off_runtime() {
+    let meta = extract_metadata_blocking(jpeg_with_exif(), "image/jpeg".to_string())
+        .await
+        .unwrap();
+    assert_eq!(meta["kind"], "image");
+    assert!(extract_metadata_blocking(Bytes::from_static(b"<svg/>"), "image/svg+xml".to_string())
+        .await
+        .is_none());
+}
+
+#[test]
+fn test_contains_pattern() {
+    assert_eq!(contains_pattern("Canon"), "%Canon%");
+    assert_eq!(contains_pattern("100%"), "%100\\%%");
+    assert_eq!(contains_pattern("a_b\\c"), "%a\\_b\\\\c%");
+}
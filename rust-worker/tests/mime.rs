@@ -0,0 +1,56 @@
+use rust_worker::mime::{detect_mime, file_type_for, is_safe_inline, MismatchPolicy};
+use rust_worker::models::FileType;
+
+const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+const ELF: &[u8] = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0";
+
+#[test]
+fn test_detect_by_content() {
+    assert_eq!(detect_mime(PNG, "jpg"), "image/png");
+    assert_eq!(detect_mime(ELF, "png"), "application/x-executable");
+    assert_eq!(detect_mime(b"%PDF-1.7\n", ""), "application/pdf");
+    assert_eq!(detect_mime(b"PK\x03\x04rest", "xlsx"),
+               "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet");
+    assert_eq!(detect_mime(b"PK\x03\x04rest", "png"), "application/zip");
+    assert_eq!(detect_mime(b"fn main() {}\n", "rs"), "text/x-rust");
+    assert_eq!(detect_mime(b"a,b\n1,2\n", "csv"), "text/csv");
+    assert_eq!(detect_mime(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>", "png"), "image/svg+xml");
+    assert_eq!(detect_mime(b"\0\x01\x02\x03", "txt"), "application/octet-stream");
+}
+
+#[test]
+fn test_file_type_mapping() {
+    assert_eq!(file_type_for("image/png"), FileType::Media);
+    assert_eq!(file_type_for("text/csv"), FileType::Spreadsheet);
+    assert_eq!(file_type_for("application/x-7z-compressed"), FileType::Archive);
+    assert_eq!(file_type_for("text/x-python"), FileType::Code);
+    assert_eq!(file_type_for("application/pdf"), FileType::Document);
+    assert_eq!(file_type_for("application/x-msdownload"), FileType::Executable);
+}
+
+#[test]
+fn test_mismatch_policy() {
+    let block = MismatchPolicy::BlockExecutables;
+    // executable passed off as an image
+    assert!(block.check("application/x-executable", "image/png", "png").is_err());
+    // honestly labelled, or nothing claimed at all
+    assert!(block.check("application/x-msdownload", "application/octet-stream", "exe").is_ok());
+    assert!(block.check("application/x-executable", "application/octet-stream", "").is_ok());
+    // a png named .jpg is not worth refusing unless strict
+    assert!(block.check("image/png", "image/jpeg", "jpg").is_ok());
+    assert!(MismatchPolicy::Strict.check("application/zip", "image/jpeg", "jpg").is_err());
+    assert!(MismatchPolicy::Strict.check("image/png", "image/jpeg", "jpg").is_ok());
+    assert!(MismatchPolicy::Allow.check("application/x-executable", "image/png", "png").is_ok());
+}
+
+#[test]
+fn test_safe_inline() {
+    assert!(is_safe_inline("image/png"));
+    assert!(is_safe_inline("application/pdf"));
+    assert!(is_safe_inline("text/plain"));
+    assert!(is_safe_inline("video/mp4"));
+    assert!(!is_safe_inline("text/html"));
+    assert!(!is_safe_inline("image/svg+xml"));
+    assert!(!is_safe_inline("application/xhtml+xml"));
+    assert!(!is_safe_inline("application/octet-stream"));
+}
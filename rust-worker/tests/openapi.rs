@@ -0,0 +1,248 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_app_with};
+use rust_worker::openapi::{spec, openapi_path, routes, Method, OPERATIONS};
+use uuid::Uuid;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+// the operations are the route table, so every route is in the spec. these
+// read the handlers' signatures so a body type changing without the spec
+// following fails here
+
+fn all_sources() -> String {
+    let dir = format!("{}/src", env!("CARGO_MANIFEST_DIR"));
+    let mut files: Vec<_> = std::fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "rs"))
+        .collect();
+    files.sort();
+    files.iter().map(|p| std::fs::read_to_string(p).unwrap()).collect()
+}
+
+// from just after an opening bracket to its match
+fn until_close(text: &str, open: char, close: char) -> &str {
+    let mut depth = 1;
+    for (i, c) in text.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return &text[..i];
+            }
+        }
+    }
+    text
+}
+
+// the arguments and return type of a handler, pub first as the storage
+// traits have methods with the same names
+fn signature(sources: &str, handler: &str) -> (String, String) {
+    let start = sources.find(&format!("pub async fn {}(", handler))
+        .or_else(|| sources.find(&format!("async fn {}(", handler)))
+        .unwrap_or_else(|| panic!("no handler {}", handler));
+    let rest = &sources[start..];
+    let rest = &rest[rest.find('(').unwrap() + 1..];
+    let args = until_close(rest, '(', ')');
+    let after = &rest[args.len() + 1..];
+    let ret = after[..after.find('{').unwrap()].trim().trim_start_matches("->").trim();
+    (args.split_whitespace().collect::<Vec<_>>().join(" "), ret.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+// the T in the first Json<T>
+fn json_type(text: &str) -> Option<String> {
+    let start = text.find("Json<")? + 5;
+    Some(until_close(&text[start..], '<', '>').trim().to_string())
+}
+
+fn ref_name(schema: &Value) -> Option<&str> {
+    schema["$ref"].as_str().and_then(|r| r.rsplit('/').next())
+}
+
+// what a rust type should look like in the spec
+fn check_schema(what: &str, rust: &str, schema: &Value) {
+    let last = |t: &str| t.rsplit("::").next().unwrap().trim().to_string();
+    if let Some(inner) = rust.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+        assert_eq!(schema["type"], "array", "{}", what);
+        check_schema(what, inner, &schema["items"]);
+    } else if let Some(inner) = rust.strip_prefix("HashMap<").and_then(|t| t.strip_suffix('>')) {
+        assert_eq!(schema["type"], "object", "{}", what);
+        check_schema(what, inner.split_once(',').unwrap().1.trim(), &schema["additionalProperties"]);
+    } else {
+        match last(rust).as_str() {
+            "Value" => {},
+            "String" => assert_eq!(schema["type"], "string", "{}", what),
+            "u64" | "i64" | "u32" | "i32" => assert_eq!(schema["type"], "integer", "{}", what),
+            name => assert_eq!(ref_name(schema), Some(name), "{}", what),
+        }
+    }
+}
+
+#[test]
+fn test_operations_are_unique() {
+    assert!(OPERATIONS.len() > 80);
+    let mut seen = BTreeSet::new();
+    for op in OPERATIONS {
+        for verb in op.method.verbs() {
+            assert!(seen.insert((verb.to_string(), openapi_path(op.path))), "{} {} is in twice", verb, op.path);
+        }
+    }
+    // axum panics on a path it can't take or two handlers for one method
+    let _ = routes();
+}
+
+#[tokio::test]
+async fn test_every_operation_is_routed() {
+    // the optional interfaces answer 404 while they're off
+    let app = spawn_app_with(&[("S3_GATEWAY", "true".to_string()), ("WEBDAV", "true".to_string())]).await;
+    for op in OPERATIONS {
+        let verb = op.method.verbs()[0];
+        let path: Vec<String> = op.path.split('/')
+            .map(|part| match part.starts_with('{') {
+                true => Uuid::nil().to_string(),
+                false => part.to_string(),
+            })
+            .collect();
+        for path in op.also.iter().map(|p| p.to_string()).chain([path.join("/")]) {
+            let res = app.client
+                .request(reqwest::Method::from_bytes(verb.to_uppercase().as_bytes()).unwrap(),
+                         format!("{}{}", app.base_url, path))
+                .send()
+                .await
+                .unwrap();
+            let status = res.status();
+            // axum's own answers when nothing is routed, handlers say why
+            let unrouted = status == 405 || (status == 404 && res.bytes().await.unwrap().is_empty());
+            assert!(!unrouted, "{} {} is documented but not routed", verb, path);
+        }
+    }
+}
+
+#[test]
+fn test_spec_matches_handlers() {
+    let spec = spec();
+    let sources = all_sources();
+    for op in OPERATIONS {
+        let verb = match op.method {
+            Method::Any(verbs) => verbs[0],
+            _ => op.method.router_name(),
+        };
+        let operation = &spec["paths"][openapi_path(op.path)][verb];
+        let what = format!("{} {}", verb, op.path);
+        assert!(operation.is_object(), "{} missing from the spec", what);
+        let (args, ret) = signature(&sources, op.handler);
+
+        // the request body
+        let body = &operation["requestBody"]["content"];
+        match json_type(&args) {
+            Some(form) => check_schema(&what, &form, &body["application/json"]["schema"]),
+            None => assert!(body["application/json"].is_null(), "{} has no json body", what),
+        }
+        assert_eq!(args.contains("Multipart"), body["multipart/form-data"].is_object(), "{} multipart", what);
+
+        // query strings and path params
+        let params = operation["parameters"].as_array().cloned().unwrap_or_default();
+        let has = |place: &str| params.iter().any(|p| p["in"] == place);
+        assert_eq!(args.contains("Query<"), has("query"), "{} query", what);
+        // webdav and s3 read the catch all from the uri
+        match op.path.contains("{*") {
+            true => assert!(has("path"), "{} path params", what),
+            false => assert_eq!(args.contains("Path<"), has("path"), "{} path params", what),
+        }
+
+        // the success response
+        let ok = &operation["responses"]["200"];
+        match json_type(&ret) {
+            Some(reply) => check_schema(&what, &reply, &ok["content"]["application/json"]["schema"]),
+            // sftp_methods has it as HttpStatus
+            None if ret.contains("StatusCode") || ret.contains("HttpStatus") => {
+                assert!(ok["content"].is_null(), "{} has no body", what)
+            },
+            None if ret.contains("CookieJar") => assert!(ok["headers"]["Set-Cookie"].is_object(), "{} sets a cookie", what),
+            None if ret.contains("Sse<") => assert!(ok["content"]["text/event-stream"].is_object(), "{} streams", what),
+            None if ret.contains("Html") => assert!(ok["content"]["text/html"].is_object(), "{} is html", what),
+            None if ret.contains("String") || ret.contains("str") => {
+                assert!(ok["content"]["text/plain"].is_object(), "{} is text", what)
+            },
+            None => assert!(ok["content"]["*/*"].is_object(), "{} returns {}", what, ret),
+        }
+        if ret.contains("ServerError") {
+            assert!(operation["responses"]["500"].is_object(), "{} errors", what);
+        }
+    }
+}
+
+#[test]
+fn test_refs_resolve() {
+    let spec = spec();
+    assert_eq!(spec["openapi"], "3.1.0");
+
+    fn walk(value: &Value, spec: &Value) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    let pointer = r.strip_prefix('#').unwrap();
+                    assert!(spec.pointer(pointer).is_some(), "{} does not resolve", r);
+                }
+                map.values().for_each(|v| walk(v, spec));
+            },
+            Value::Array(items) => items.iter().for_each(|v| walk(v, spec)),
+            _ => {},
+        }
+    }
+    walk(&spec, &spec);
+
+    // every scheme used is declared
+    for item in spec["paths"].as_object().unwrap().values() {
+        for operation in item.as_object().unwrap().values() {
+            for requirement in operation["security"].as_array().unwrap() {
+                for name in requirement.as_object().unwrap().keys() {
+                    assert!(spec["components"]["securitySchemes"][name].is_object(), "{}", name);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_openapi_path() {
+    assert_eq!(openapi_path("/s3/{*path}"), "/s3/{path}");
+    assert_eq!(openapi_path("/objects/{bucket}/{*key}"), "/objects/{bucket}/{key}");
+    assert_eq!(openapi_path("/get-files"), "/get-files");
+}
+
+#[tokio::test]
+async fn test_serves_spec_and_docs() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .get(format!("{}/openapi.json", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.json::<Value>().await.unwrap(), spec());
+
+    let res = app.client
+        .get(format!("{}/docs", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(res.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+    assert!(res.text().await.unwrap().contains("/openapi.json"));
+}
+
+#[tokio::test]
+async fn test_get_ssh_keys_unauthorized() {
+    let app = spawn_app().await;
+
+    // documented as needing a session, and it does
+    let res = app.client
+        .post(format!("{}/get-ssh-keys", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
@@ -0,0 +1,104 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+use rust_worker::models::ScanStatus;
+use rust_worker::scanner::{Scanner, ScanVerdict, ScanConfig, ClamdScanner, MockScanner,
+                           check_download, clamd::parse_reply, mock::EICAR};
+use rust_worker::storage::ObjectStream;
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// in pieces of `size`, the way objects come out of the store
+fn pieces(data: &[u8], size: usize) -> ObjectStream {
+    let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = data
+        .chunks(size)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    futures::stream::iter(chunks).boxed()
+}
+
+#[tokio::test]
+async fn test_mock_scanner() {
+    let scanner = MockScanner::new().with_signature("Test-Marker", b"BADBYTES");
+    let mut infected = b"some prefix ".to_vec();
+    infected.extend_from_slice(EICAR);
+    assert_eq!(scanner.scan(pieces(&infected, 1024)).await.unwrap(),
+               ScanVerdict::Infected("Eicar-Test-Signature".to_string()));
+    // split across chunks
+    assert_eq!(scanner.scan(pieces(&infected, 20)).await.unwrap(),
+               ScanVerdict::Infected("Eicar-Test-Signature".to_string()));
+    assert_eq!(scanner.scan(pieces(b"xxBADBYTESxx", 5)).await.unwrap(),
+               ScanVerdict::Infected("Test-Marker".to_string()));
+    assert_eq!(scanner.scan(pieces(b"Hello World", 4)).await.unwrap(), ScanVerdict::Clean);
+}
+
+#[test]
+fn test_parse_clamd_reply() {
+    assert_eq!(parse_reply("stream: OK").unwrap(), ScanVerdict::Clean);
+    assert_eq!(parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+               ScanVerdict::Infected("Win.Test.EICAR_HDB-1".to_string()));
+    assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+}
+
+// plays clamd's side of INSTREAM and flags anything containing EICAR
+#[tokio::test]
+async fn test_clamd_instream() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = format!("tcp://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            socket.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = socket.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                socket.read_exact(&mut chunk).await.unwrap();
+                data.extend_from_slice(&chunk);
+            }
+            let reply: &[u8] = if data.windows(EICAR.len()).any(|w| w == EICAR) {
+                b"stream: Eicar-Test-Signature FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            socket.write_all(reply).await.unwrap();
+        }
+    });
+
+    let scanner = ClamdScanner::new(&address);
+    // bigger than one chunk
+    let clean = vec![b'a'; 200 * 1024];
+    assert_eq!(scanner.scan(pieces(&clean, 100 * 1024)).await.unwrap(), ScanVerdict::Clean);
+    assert_eq!(scanner.scan(pieces(EICAR, 16)).await.unwrap(),
+               ScanVerdict::Infected("Eicar-Test-Signature".to_string()));
+}
+
+#[test]
+fn test_download_policy() {
+    let mut config = ScanConfig::disabled();
+    assert!(check_download(&config, &ScanStatus::Infected).is_err());
+    assert!(check_download(&config, &ScanStatus::Pending).is_err());
+    assert!(check_download(&config, &ScanStatus::Clean).is_ok());
+    assert!(check_download(&config, &ScanStatus::Unscanned).is_ok());
+    config.block_pending = false;
+    assert!(check_download(&config, &ScanStatus::Pending).is_ok());
+    assert!(check_download(&config, &ScanStatus::Infected).is_err());
+}
+
+#[tokio::test]
+async fn test_quarantine_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/admin/quarantine", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
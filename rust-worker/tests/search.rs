@@ -0,0 +1,65 @@
+use std::io::{Cursor, Write};
+use rust_worker::search::{text_kind, xml_text, extract_office_text, truncate_content,
+                          render_highlight, MATCH_START, MATCH_END, MAX_CONTENT_SIZE};
+
+fn office_file(parts: &[(&str, &str)]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, body) in parts {
+        zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(body.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn test_text_kind() {
+    assert_eq!(text_kind("text/markdown"), Some("text"));
+    assert_eq!(text_kind("application/pdf"), Some("pdf"));
+    assert_eq!(text_kind("application/vnd.oasis.opendocument.text"), Some("office"));
+    assert_eq!(text_kind("image/png"), None);
+    // legacy binary formats arent extracted
+    assert_eq!(text_kind("application/msword"), None);
+}
+
+#[test]
+fn test_xml_text() {
+    // runs split inside a word, paragraphs apart
+    let xml = r#"<w:body><w:p><w:r><w:t>Quar</w:t></w:r><w:r><w:t>terly</w:t></w:r></w:p><w:p><w:r><w:t>R&amp;D &#x263A;</w:t></w:r></w:p></w:body>"#;
+    assert_eq!(xml_text(xml).trim(), "Quarterly R&D ☺");
+    assert_eq!(xml_text("a & b"), "a & b");
+}
+
+#[test]
+fn test_extract_office_text() {
+    let docx = office_file(&[
+        ("[Content_Types].xml", "<Types/>"),
+        ("word/document.xml", "<w:document><w:p><w:t>budget forecast</w:t></w:p></w:document>"),
+        ("word/styles.xml", "<w:styles><w:t>not content</w:t></w:styles>"),
+    ]);
+    let text = extract_office_text(&docx).unwrap();
+    assert!(text.contains("budget forecast"));
+    assert!(!text.contains("not content"));
+
+    let pptx = office_file(&[
+        ("ppt/slides/slide10.xml", "<p:sld><a:p><a:t>last</a:t></a:p></p:sld>"),
+        ("ppt/slides/slide2.xml", "<p:sld><a:p><a:t>first</a:t></a:p></p:sld>"),
+    ]);
+    let text = extract_office_text(&pptx).unwrap();
+    assert!(text.find("first").unwrap() < text.find("last").unwrap());
+
+    assert!(extract_office_text(b"not a zip").is_err());
+}
+
+#[test]
+fn test_truncate_content() {
+    let long = "é".repeat(MAX_CONTENT_SIZE);
+    let cut = truncate_content(long);
+    assert!(cut.len() <= MAX_CONTENT_SIZE);
+    assert_eq!(truncate_content("a\0b".to_string()), "ab");
+}
+
+#[test]
+fn test_render_highlight() {
+    let headline = format!("<script> {}budget{} & more", MATCH_START, MATCH_END);
+    assert_eq!(render_highlight(&headline), "&lt;script&gt; <mark>budget</mark> &amp; more");
+}
@@ -0,0 +1,233 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_app_with, TestApp};
+use async_trait::async_trait;
+use rust_worker::methods::STORAGE_LIMIT;
+use rust_worker::models::{FileType, ScanStatus};
+use rust_worker::sftp::{sftp_path, display_path, permissions, parse_authorized_key, fingerprint};
+use rust_worker::webdav::{DavEntry, DavTree};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+// made with ssh-keygen -t ed25519, ssh-keygen -l gives the fingerprint
+const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIILRsXbTbieTQUc20JcDVxC7aBXisTfpCAuBWkVEkiWD partner@example";
+const FINGERPRINT: &str = "SHA256:ybMPsTKzQrF/ERTsNRTVgGr6hgNR5NGZpRcuADa88y0";
+
+fn entry(parent_id: Option<Uuid>, name: &str, file_type: FileType) -> DavEntry {
+    DavEntry {
+        file_id: Uuid::new_v4(),
+        parent_id,
+        file_name: name.to_string(),
+        extension: None,
+        size: 0,
+        file_type,
+        created_at: None,
+        last_modified: None,
+        checksum_sha256: None,
+        checksum_md5: None,
+        mime_type: None,
+        blob_hash: None,
+        key_id: None,
+        scan_status: ScanStatus::Unscanned,
+    }
+}
+
+#[test]
+fn test_sftp_path() {
+    assert_eq!(sftp_path("/"), Vec::<String>::new());
+    assert_eq!(sftp_path("."), Vec::<String>::new());
+    assert_eq!(sftp_path("/in/./a b.csv"), vec!["in".to_string(), "a b.csv".to_string()]);
+    assert_eq!(sftp_path("in//out/"), vec!["in".to_string(), "out".to_string()]);
+    // nothing above the root
+    assert_eq!(sftp_path("/../../etc"), vec!["etc".to_string()]);
+    assert_eq!(sftp_path("/in/../out"), vec!["out".to_string()]);
+    assert_eq!(display_path(&sftp_path("/in/../out/x")), "/out/x");
+    assert_eq!(display_path(&[]), "/");
+}
+
+#[test]
+fn test_permissions() {
+    assert_eq!(permissions(true, false), 0o40755);
+    assert_eq!(permissions(true, true), 0o40555);
+    assert_eq!(permissions(false, false), 0o100644);
+    assert_eq!(permissions(false, true), 0o100444);
+}
+
+#[test]
+fn test_authorized_key() {
+    let (algorithm, blob) = parse_authorized_key(KEY).unwrap();
+    assert_eq!(algorithm, "ssh-ed25519");
+    assert_eq!(fingerprint(&blob), FINGERPRINT);
+    // the comment is optional
+    assert!(parse_authorized_key(&KEY[..KEY.rfind(' ').unwrap()]).is_some());
+    // the named algorithm has to be the one in the blob
+    assert!(parse_authorized_key(&KEY.replacen("ssh-ed25519", "ssh-rsa", 1)).is_none());
+    assert!(parse_authorized_key("ssh-ed25519 not-base64").is_none());
+    assert!(parse_authorized_key("ssh-ed25519").is_none());
+    assert!(parse_authorized_key("").is_none());
+}
+
+#[test]
+fn test_chroot() {
+    let partners = entry(None, "partners", FileType::Folder);
+    let acme = entry(Some(partners.file_id), "acme", FileType::Folder);
+    let report = entry(Some(acme.file_id), "report", FileType::Document);
+    let other = entry(None, "private", FileType::Folder);
+    let (acme_id, report_id, other_id) = (acme.file_id, report.file_id, other.file_id);
+    let tree = DavTree::new(vec![partners, acme, report, other]);
+
+    let root = Some(acme_id);
+    assert_eq!(tree.resolve_from(root, &[]).unwrap().map(|e| e.file_id), Some(acme_id));
+    assert_eq!(tree.resolve_from(root, &["report".to_string()]).unwrap().map(|e| e.file_id), Some(report_id));
+    assert!(tree.resolve_from(root, &["private".to_string()]).is_none());
+    assert_eq!(tree.resolve(&["private".to_string()]).unwrap().map(|e| e.file_id), Some(other_id));
+    assert_eq!(tree.parent_from(root, &["new".to_string()]), Some(Some(acme_id)));
+    assert_eq!(tree.parent_of(&["new".to_string()]), Some(None));
+    assert_eq!(tree.parent_from(root, &["report".to_string(), "x".to_string()]), None);
+    // a root that is gone resolves nothing
+    assert!(tree.resolve_from(Some(Uuid::new_v4()), &[]).is_none());
+}
+
+async fn spawn_sftp_app(host_key: &std::path::Path, password_auth: bool) -> (TestApp, u16) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let app = spawn_app_with(&[
+        ("SFTP_ADDR", format!("127.0.0.1:{}", port)),
+        ("SFTP_HOST_KEY", host_key.to_string_lossy().to_string()),
+        ("SFTP_PASSWORD_AUTH", password_auth.to_string()),
+    ]).await;
+    (app, port)
+}
+
+async fn sign_up(app: &TestApp, pool: &sqlx::PgPool) -> (String, Uuid) {
+    let email = format!("sftp-{}@mail.com", Uuid::new_v4());
+    let res = app.client
+        .post(format!("{}/sign-up", app.base_url))
+        .json(&serde_json::json!({"email": email, "password": "12345678"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let user_id: Uuid = sqlx::query_scalar("SELECT user_id FROM users WHERE email = $1;")
+        .bind(&email)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    (email, user_id)
+}
+
+struct Client;
+
+#[async_trait]
+impl russh::client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _key: &russh_keys::key::PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+// the server starts in the background, so the first tries can be refused
+async fn connect(port: u16) -> russh::client::Handle<Client> {
+    let config = std::sync::Arc::new(russh::client::Config::default());
+    let mut session = None;
+    for _ in 0..50 {
+        match russh::client::connect(config.clone(), ("127.0.0.1", port), Client).await {
+            Ok(s) => {
+                session = Some(s);
+                break;
+            },
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+        }
+    }
+    session.expect("sftp server did not start")
+}
+
+// the ssh handle has to be kept for as long as the sftp session is used
+async fn sftp_login(port: u16, email: &str, password: &str)
+    -> (russh::client::Handle<Client>, russh_sftp::client::SftpSession) {
+    let mut session = connect(port).await;
+    assert!(session.authenticate_password(email, password).await.unwrap());
+    let channel = session.channel_open_session().await.unwrap();
+    channel.request_subsystem(true, "sftp").await.unwrap();
+    let sftp = russh_sftp::client::SftpSession::new(channel.into_stream()).await.unwrap();
+    (session, sftp)
+}
+
+async fn upload(sftp: &russh_sftp::client::SftpSession, path: &str, data: &[u8]) {
+    let mut file = sftp.create(path).await.unwrap();
+    file.write_all(data).await.unwrap();
+    file.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_sftp_overwrite_keeps_file() {
+    let host_key = std::env::temp_dir().join(format!("servr-test-host-key-{}", Uuid::new_v4()));
+    let (app, port) = spawn_sftp_app(&host_key, true).await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let (email, user_id) = sign_up(&app, &pool).await;
+
+    let (_ssh, sftp) = sftp_login(port, &email, "12345678").await;
+    upload(&sftp, "/notes.txt", b"first!!!").await;
+    let file_id: Uuid = sqlx::query_scalar("SELECT file_id FROM files WHERE owner_id = $1 AND file_name = 'notes';")
+        .bind(user_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    // as if other files took all but 5 bytes: only the 4 extra bytes of the
+    // new content should count, not both copies
+    sqlx::query("UPDATE users SET storage_used = $2 WHERE user_id = $1;")
+        .bind(user_id)
+        .bind(STORAGE_LIMIT - 5)
+        .execute(&pool)
+        .await
+        .unwrap();
+    upload(&sftp, "/notes.txt", b"second!!!!!!").await;
+
+    let files: Vec<(Uuid, i64)> = sqlx::query_as("SELECT file_id, size FROM files WHERE owner_id = $1 AND file_name = 'notes';")
+        .bind(user_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(files, vec![(file_id, 12)]);
+    let used: i64 = sqlx::query_scalar("SELECT storage_used FROM users WHERE user_id = $1;")
+        .bind(user_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(used, STORAGE_LIMIT - 1);
+    let _ = std::fs::remove_file(host_key);
+}
+
+#[tokio::test]
+async fn test_sftp_password_logins() {
+    let host_key = std::env::temp_dir().join(format!("servr-test-host-key-{}", Uuid::new_v4()));
+    let (app, port) = spawn_sftp_app(&host_key, false).await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let (email, _) = sign_up(&app, &pool).await;
+
+    // off unless asked for
+    let mut session = connect(port).await;
+    assert!(!session.authenticate_password(&email, "12345678").await.unwrap());
+
+    // on, the email matches exactly like it does for keys
+    let (_, port) = spawn_sftp_app(&host_key, true).await;
+    let mut session = connect(port).await;
+    assert!(!session.authenticate_password(email.to_uppercase(), "12345678").await.unwrap());
+    assert!(!session.authenticate_password(&email, "wrong password").await.unwrap());
+    assert!(session.authenticate_password(&email, "12345678").await.unwrap());
+    let _ = std::fs::remove_file(host_key);
+}
+
+#[tokio::test]
+async fn test_add_ssh_key_unauthorized() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/add-ssh-key", app.base_url))
+        .json(&serde_json::json!({"name": "partner", "public_key": KEY}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
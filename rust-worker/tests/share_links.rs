@@ -0,0 +1,145 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+use rust_worker::share_methods::{hash_share_password, verify_share_password,
+                                 SHARE_PASSWORD_ATTEMPTS, SHARE_PASSWORD_HEADER};
+
+#[tokio::test]
+async fn test_unknown_share_token() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .get(format!("{}/s/{}", app.base_url, "doesnotexist"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn test_create_share_link_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/create-share-link", app.base_url))
+        .json(&serde_json::json!({"file_id":"7c590022-c579-4e69-8eb4-92e67440f93f",
+                                  "mode":"download",
+                                  "max_downloads":3}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn test_share_password_hash() {
+    let hashed = hash_share_password("hunter2").unwrap();
+    assert!(hashed.starts_with("$argon2"));
+    assert!(verify_share_password("hunter2", &hashed));
+    assert!(!verify_share_password("hunter3", &hashed));
+    assert!(!verify_share_password("", &hashed));
+    // salted, so the same password hashes differently each time
+    assert_ne!(hashed, hash_share_password("hunter2").unwrap());
+    // a plain sha-256 hex string is not a hash this accepts
+    assert!(!verify_share_password("hunter2", "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7"));
+}
+
+// sessions only count for users already in the file cache, so the owner,
+// file and link are written straight to the db. returns the token and link id
+async fn seed_link(pool: &sqlx::PgPool,
+                   file_type: &str,
+                   extension: Option<&str>,
+                   mode: &str,
+                   password: Option<&str>,
+                   max_downloads: Option<i32>,
+) -> (String, uuid::Uuid) {
+    let owner_id = uuid::Uuid::new_v4();
+    let file_id = uuid::Uuid::new_v4();
+    let link_id = uuid::Uuid::new_v4();
+    let token = format!("share-{}", uuid::Uuid::new_v4().simple());
+    sqlx::query("INSERT INTO users (user_id, email, hashed_password) VALUES ($1, $2, '');")
+        .bind(owner_id)
+        .bind(format!("share-{}@mail.com", owner_id))
+        .execute(pool)
+        .await
+        .unwrap();
+    sqlx::query(r#"INSERT INTO files (file_id, owner_id, file_name, extension, size, file_type)
+                   VALUES ($1, $2, 'shared', $3, 0, $4::filetype);"#)
+        .bind(file_id)
+        .bind(owner_id)
+        .bind(extension)
+        .bind(file_type)
+        .execute(pool)
+        .await
+        .unwrap();
+    sqlx::query(r#"INSERT INTO share_links (link_id, token, file_id, owner_id, mode,
+                   hashed_password, max_downloads)
+                   VALUES ($1, $2, $3, $4, $5::sharemode, $6, $7);"#)
+        .bind(link_id)
+        .bind(&token)
+        .bind(file_id)
+        .bind(owner_id)
+        .bind(mode)
+        .bind(password.map(|p| hash_share_password(p).unwrap()))
+        .bind(max_downloads)
+        .execute(pool)
+        .await
+        .unwrap();
+    (token, link_id)
+}
+
+async fn pool() -> sqlx::PgPool {
+    sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_share_link_password() {
+    let app = spawn_app().await;
+    let (token, _) = seed_link(&pool().await, "folder", None, "view", Some("hunter2"), None).await;
+    let url = format!("{}/s/{}", app.base_url, token);
+
+    let res = app.client.get(&url).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = app.client.get(&url).header(SHARE_PASSWORD_HEADER, "wrong").send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    // the query string is not read any more
+    let res = app.client.get(format!("{}?password=hunter2", url)).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = app.client.get(&url).header(SHARE_PASSWORD_HEADER, "hunter2").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["file_name"], "shared");
+}
+
+#[tokio::test]
+async fn test_share_link_lockout() {
+    let app = spawn_app().await;
+    let (token, _) = seed_link(&pool().await, "folder", None, "view", Some("hunter2"), None).await;
+    let url = format!("{}/s/{}", app.base_url, token);
+
+    for _ in 0..SHARE_PASSWORD_ATTEMPTS {
+        let res = app.client.get(&url).header(SHARE_PASSWORD_HEADER, "wrong").send().await.unwrap();
+        assert_eq!(res.status(), 401);
+    }
+    // locked, even the right password waits out the lockout
+    let res = app.client.get(&url).header(SHARE_PASSWORD_HEADER, "hunter2").send().await.unwrap();
+    assert_eq!(res.status(), 429);
+}
+
+#[tokio::test]
+async fn test_failed_download_not_counted() {
+    let app = spawn_app().await;
+    let pool = pool().await;
+    // the row exists but its object was never stored
+    let (token, link_id) = seed_link(&pool, "document", Some("txt"), "download", None, Some(1)).await;
+    let url = format!("{}/s/{}", app.base_url, token);
+
+    let res = app.client.get(&url).send().await.unwrap();
+    assert!(!res.status().is_success());
+    let count: i32 = sqlx::query_scalar("SELECT download_count FROM share_links WHERE link_id = $1;")
+        .bind(link_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
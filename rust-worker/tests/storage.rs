@@ -0,0 +1,167 @@
+#[path = "common/mod.rs"]
+mod common;
+use bytes::Bytes;
+use common::{spawn_app_with, signed_in_user};
+use futures::StreamExt;
+use rust_worker::storage::{ObjectStore, MemoryStore, LocalStore, StorageLayout, object_signature, verify_object_signature};
+use rust_worker::storage::migrate::migrate_to_shared;
+
+async fn round_trip(store: &dyn ObjectStore) {
+    store.create_bucket("bucket").await.unwrap();
+    assert!(store.bucket_exists("bucket").await.unwrap());
+    assert!(!store.bucket_exists("missing").await.unwrap());
+
+    store.put("bucket", "a/file.txt", Bytes::from("Hello World"), "text/plain").await.unwrap();
+    let head = store.head("bucket", "a/file.txt").await.unwrap().unwrap();
+    assert_eq!(head.size, 11);
+    assert_eq!(head.content_type.as_deref(), Some("text/plain"));
+
+    let mut object = store.get_stream("bucket", "a/file.txt").await.unwrap();
+    let mut data = Vec::new();
+    while let Some(chunk) = object.stream.next().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(data, b"Hello World");
+
+    store.copy("bucket", "a/file.txt", "bucket", "b/copy.txt").await.unwrap();
+    let listed = store.list("bucket", "b/").await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].key, "b/copy.txt");
+
+    store.delete("bucket", "a/file.txt").await.unwrap();
+    assert!(store.head("bucket", "a/file.txt").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_memory_store() {
+    let store = MemoryStore::new("http://localhost".to_string(), "secret".to_string());
+    round_trip(&store).await;
+}
+
+#[tokio::test]
+async fn test_local_store() {
+    let root = std::env::temp_dir().join(format!("servr-{}", uuid::Uuid::new_v4()));
+    let store = LocalStore::new(&root, "http://localhost".to_string(), "secret".to_string());
+    round_trip(&store).await;
+
+    // a user's own .tmp file is an object like any other
+    store.put("bucket", "report.tmp", Bytes::from("draft"), "text/plain").await.unwrap();
+    let listed = store.list("bucket", "report").await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].key, "report.tmp");
+    assert!(store.create_bucket(".staging").await.is_err());
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[tokio::test]
+async fn test_migrate_to_shared_layout() {
+    let store = MemoryStore::new("http://localhost".to_string(), "secret".to_string());
+    let user = "7c590022-c579-4e69-8eb4-92e67440f93f".to_string();
+    store.create_bucket(&user).await.unwrap();
+    store.put(&user, "file.txt", Bytes::from("Hello World"), "text/plain").await.unwrap();
+
+    let report = migrate_to_shared(&store, std::slice::from_ref(&user), "shared", true, false).await.unwrap();
+    assert_eq!(report.copied, 1);
+    assert!(report.failed.is_empty());
+
+    let layout = StorageLayout::SharedBucket("shared".to_string());
+    let (bucket, key) = layout.location(&user, "file", &Some("txt".to_string()));
+    assert_eq!(key, format!("{}/file.txt", user));
+    assert!(store.head(&bucket, &key).await.unwrap().is_some());
+    assert!(store.head(&user, "file.txt").await.unwrap().is_none());
+
+    // second run has nothing left to do
+    let report = migrate_to_shared(&store, &[user], "shared", true, false).await.unwrap();
+    assert_eq!(report.copied, 0);
+}
+
+#[test]
+fn test_blob_location_is_content_addressed() {
+    use rust_worker::dedup::{DedupConfig, content_hash};
+
+    let hash = content_hash(b"Hello World");
+    assert_eq!(hash, "a591a6d40bf420404a011733cfb7b190d62c65bf0bcda32b57b277d9ad9f146e");
+    assert_eq!(hash, content_hash(b"Hello World"));
+
+    let config = DedupConfig::disabled();
+    let (_, key) = config.blob_location(&hash);
+    assert_eq!(key, format!("a5/{}", hash));
+}
+
+// two uploads of the same bytes share a blob, and it goes once neither is left
+#[tokio::test]
+async fn test_blob_refcounts_and_gc() {
+    use rust_worker::dedup::{DedupConfig, collect_garbage};
+    let app = spawn_app_with(&[("WEBDAV", "true".to_string()), ("DEDUP", "true".to_string())]).await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    let ref_count = |hash: String| {
+        let pool = pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i32>("SELECT ref_count FROM blobs WHERE hash = $1;")
+                .bind(hash).fetch_optional(&pool).await.unwrap()
+        }
+    };
+
+    let content = format!("the same bytes {}", uuid::Uuid::new_v4());
+    let a = app.upload_as(&user, &pool, "a.txt", content.clone()).await;
+    let b = app.upload_as(&user, &pool, "b.txt", content).await;
+    let hashes: Vec<Option<String>> = sqlx::query_scalar("SELECT blob_hash FROM files WHERE file_id = ANY($1);")
+        .bind(vec![a, b]).fetch_all(&pool).await.unwrap();
+    assert_eq!(hashes.len(), 2);
+    assert!(hashes[0].is_some() && hashes[0] == hashes[1]);
+    let hash = hashes[0].clone().unwrap();
+    assert_eq!(ref_count(hash.clone()).await, Some(2));
+
+    let delete = |file_id: uuid::Uuid| app.post_as(&user, "/delete-file",
+                                                   serde_json::json!({"owner_id": user.user_id.to_string(),
+                                                                      "file_id": file_id.to_string()}));
+    assert_eq!(delete(a).await.status(), 200);
+    assert_eq!(ref_count(hash.clone()).await, Some(1));
+
+    // gc runs against its own store here, holding just this blob
+    let store: std::sync::Arc<dyn ObjectStore> = std::sync::Arc::new(MemoryStore::new("".to_string(), "".to_string()));
+    let config = DedupConfig::disabled();
+    let (bucket, key) = config.blob_location(&hash);
+    store.create_bucket(&bucket).await.unwrap();
+    store.put(&bucket, &key, Bytes::from("blob"), "text/plain").await.unwrap();
+
+    collect_garbage(&pool, &store, &config, chrono::Duration::zero()).await.unwrap();
+    assert_eq!(ref_count(hash.clone()).await, Some(1));
+    assert!(store.head(&bucket, &key).await.unwrap().is_some());
+
+    assert_eq!(delete(b).await.status(), 200);
+    assert_eq!(ref_count(hash.clone()).await, Some(0));
+    collect_garbage(&pool, &store, &config, chrono::Duration::zero()).await.unwrap();
+    assert_eq!(ref_count(hash).await, None);
+    assert!(store.head(&bucket, &key).await.unwrap().is_none());
+}
+
+#[test]
+fn test_encrypted_blobs_are_per_owner() {
+    use rust_worker::dedup::{blob_hash, content_hash};
+
+    let hash = content_hash(b"Hello World");
+    let (a, b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    assert_eq!(blob_hash(&hash, &a, false), hash);
+    assert_eq!(blob_hash(&hash, &a, false), blob_hash(&hash, &b, false));
+    // each owner's copy is under their own key
+    assert_ne!(blob_hash(&hash, &a, true), blob_hash(&hash, &b, true));
+    assert_eq!(blob_hash(&hash, &a, true), blob_hash(&hash, &a, true));
+    assert_eq!(blob_hash(&hash, &a, true).len(), 64);
+}
+
+#[test]
+fn test_object_signature() {
+    let signature = object_signature("secret", "bucket", "a/file.txt", 1700000000);
+    assert_eq!(signature.len(), 64);
+    assert!(verify_object_signature("secret", "bucket", "a/file.txt", 1700000000, &signature));
+    // every part is covered
+    assert!(!verify_object_signature("other", "bucket", "a/file.txt", 1700000000, &signature));
+    assert!(!verify_object_signature("secret", "bucket", "a/file.txt", 1700000001, &signature));
+    assert!(!verify_object_signature("secret", "bucket", "b/file.txt", 1700000000, &signature));
+    assert!(!verify_object_signature("secret", "bucket", "a/file.txt", 1700000000, &signature[..63]));
+    assert!(!verify_object_signature("secret", "bucket", "a/file.txt", 1700000000, ""));
+    // a keyed mac, not a hash of the secret and the rest
+    assert_ne!(signature, rust_worker::msc_actions::hash_algorithm("secret:bucket:a/file.txt:1700000000"));
+}
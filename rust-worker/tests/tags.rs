@@ -0,0 +1,82 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_dav_app, signed_in_user};
+use serde_json::{json, Value};
+
+async fn tag_counts(app: &common::TestApp, user: &common::User) -> Vec<(String, i64)> {
+    let tags: Vec<Value> = app.post_as(user, "/get-tags", json!({})).await.json().await.unwrap();
+    let mut counts: Vec<(String, i64)> = tags.iter()
+        .map(|t| (t["name"].as_str().unwrap().to_string(), t["file_count"].as_i64().unwrap()))
+        .collect();
+    counts.sort();
+    counts
+}
+
+#[tokio::test]
+async fn test_tagging() {
+    let app = spawn_dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    let other = app.upload_as(&user, &pool, "other.txt", "other").await;
+    let files = json!([user.file_id, other]);
+
+    let res = app.post_as(&user, "/tag-files", json!({"file_ids": files, "tags": ["invoices", "2024"]})).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.json::<u64>().await.unwrap(), 4);
+    // already there
+    let res = app.post_as(&user, "/tag-files", json!({"file_ids": files, "tags": ["invoices"]})).await;
+    assert_eq!(res.json::<u64>().await.unwrap(), 0);
+    assert_eq!(tag_counts(&app, &user).await, vec![("2024".to_string(), 2), ("invoices".to_string(), 2)]);
+
+    let res = app.post_as(&user, "/untag-files", json!({"file_ids": [other], "tags": ["invoices"]})).await;
+    assert_eq!(res.json::<u64>().await.unwrap(), 1);
+    assert_eq!(tag_counts(&app, &user).await, vec![("2024".to_string(), 2), ("invoices".to_string(), 1)]);
+    let tagged: Value = app.post_as(&user, "/get-files-by-tag", json!({"tag": "invoices"})).await.json().await.unwrap();
+    let tagged = tagged.as_object().unwrap();
+    assert_eq!(tagged.len(), 1);
+    assert!(tagged.contains_key(&user.file_id.to_string()));
+
+    let res = app.post_as(&user, "/set-colour-label", json!({"file_ids": [other], "colour_label": "red"})).await;
+    assert_eq!(res.json::<u64>().await.unwrap(), 1);
+    let label: Option<String> = sqlx::query_scalar("SELECT colour_label::text FROM files WHERE file_id = $1;")
+        .bind(other)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(label.as_deref(), Some("red"));
+
+    // someone else's files are left alone
+    let stranger = signed_in_user(&app, &pool).await;
+    let res = app.post_as(&stranger, "/tag-files", json!({"file_ids": [user.file_id], "tags": ["mine"]})).await;
+    assert_eq!(res.json::<u64>().await.unwrap(), 0);
+    assert_eq!(tag_counts(&app, &stranger).await, vec![("mine".to_string(), 0)]);
+}
+
+#[tokio::test]
+async fn test_tag_files_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/tag-files", app.base_url))
+        .json(&serde_json::json!({"file_ids":["7c590022-c579-4e69-8eb4-92e67440f93f"],
+                                  "tags":["invoices","2024"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn test_unknown_colour_label() {
+    let app = spawn_app().await;
+
+    // rejected by the json extractor before auth
+    let res = app.client
+        .post(format!("{}/set-colour-label", app.base_url))
+        .json(&serde_json::json!({"file_ids":["7c590022-c579-4e69-8eb4-92e67440f93f"],
+                                  "colour_label":"magenta"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 422);
+}
@@ -0,0 +1,50 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+use image::{DynamicImage, RgbaImage};
+use rust_worker::thumbnails::{ThumbnailSize, ThumbnailFormat, RenderedThumbnail, render_thumbnails, decode_image,
+                              thumbnail_kind};
+
+#[tokio::test]
+async fn test_thumbnail_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .get(format!("{}/files/{}/thumbnail?size=small",
+                     app.base_url, "7c590022-c579-4e69-8eb4-92e67440f93f"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn test_render_thumbnails() {
+    let image = DynamicImage::ImageRgba8(RgbaImage::new(1024, 256));
+    let rendered = render_thumbnails(&image).unwrap();
+    assert_eq!(rendered.len(), 4);
+    for RenderedThumbnail { size, format, body, width, height } in rendered {
+        assert_eq!(width, size.edge());
+        assert_eq!(height, size.edge() / 4);
+        let decoded = decode_image(&body).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+        if format == ThumbnailFormat::Jpeg {
+            assert_eq!(&body[..2], &[0xff, 0xd8]);
+        }
+    }
+
+    // small images are kept as they are
+    let tiny = DynamicImage::ImageRgba8(RgbaImage::new(40, 30));
+    for thumbnail in render_thumbnails(&tiny).unwrap() {
+        assert_eq!((thumbnail.width, thumbnail.height), (40, 30));
+    }
+}
+
+#[test]
+fn test_thumbnail_kind() {
+    assert_eq!(thumbnail_kind("image/png"), Some("image"));
+    assert_eq!(thumbnail_kind("application/pdf"), Some("pdf"));
+    assert_eq!(thumbnail_kind("image/svg+xml"), None);
+    assert_eq!(thumbnail_kind("text/plain"), None);
+    assert_eq!(ThumbnailSize::parse("huge"), None);
+}
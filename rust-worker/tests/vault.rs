@@ -0,0 +1,44 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::spawn_app;
+use rust_worker::vault::{decode_wrapped_key, decode_metadata, encode_field,
+                         PARENT_WRAPPED_KEY_SIZE, USER_WRAPPED_KEY_SIZE};
+
+#[tokio::test]
+async fn test_create_vault_folder_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/create-vault-folder", app.base_url))
+        .json(&serde_json::json!({"parent_id":"",
+                                  "wrapped_key":encode_field(&[0u8; USER_WRAPPED_KEY_SIZE]),
+                                  "metadata":encode_field(&[0u8; 64])}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[tokio::test]
+async fn test_get_vault_items_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/get-vault-items", app.base_url))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn test_wrapped_key_sizes() {
+    let parent_wrapped = encode_field(&[7u8; PARENT_WRAPPED_KEY_SIZE]);
+    assert!(decode_wrapped_key(&parent_wrapped, PARENT_WRAPPED_KEY_SIZE).is_ok());
+    // a raw 32 byte key is what a client that forgot to wrap would send
+    assert!(decode_wrapped_key(&encode_field(&[7u8; 32]), PARENT_WRAPPED_KEY_SIZE).is_err());
+    assert!(decode_wrapped_key(&parent_wrapped, USER_WRAPPED_KEY_SIZE).is_err());
+    assert!(decode_wrapped_key("not base64!", USER_WRAPPED_KEY_SIZE).is_err());
+    assert!(decode_metadata(&encode_field(&[1u8; 8])).is_err());
+}
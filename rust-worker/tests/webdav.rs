@@ -0,0 +1,209 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_app_with, TestApp};
+use rust_worker::models::{FileType, ScanStatus};
+use rust_worker::webdav::{DavEntry, DavTree, dav_path, destination_path, element_inner, encode_segment,
+                          href, lock_timeout, percent_decode, split_name, MAX_LOCK_SECS};
+use uuid::Uuid;
+
+fn entry(parent_id: Option<Uuid>, name: &str, extension: Option<&str>, file_type: FileType) -> DavEntry {
+    DavEntry {
+        file_id: Uuid::new_v4(),
+        parent_id,
+        file_name: name.to_string(),
+        extension: extension.map(|e| e.to_string()),
+        size: 0,
+        file_type,
+        created_at: None,
+        last_modified: None,
+        checksum_sha256: None,
+        checksum_md5: None,
+        mime_type: None,
+        blob_hash: None,
+        key_id: None,
+        scan_status: ScanStatus::Unscanned,
+    }
+}
+
+#[test]
+fn test_dav_path() {
+    assert_eq!(dav_path("/dav"), Some(vec![]));
+    assert_eq!(dav_path("/dav/"), Some(vec![]));
+    assert_eq!(dav_path("/dav/Photos/a%20b.jpg"), Some(vec!["Photos".to_string(), "a b.jpg".to_string()]));
+    assert_eq!(dav_path("/dav/a/../b"), None);
+    assert_eq!(dav_path("/dav/a%2Fb"), None);
+    assert_eq!(dav_path("/davx/a"), None);
+    assert_eq!(dav_path("/files/a"), None);
+}
+
+#[test]
+fn test_destination_path() {
+    assert_eq!(destination_path("http://localhost:3000/dav/a/b.txt"),
+               Some(vec!["a".to_string(), "b.txt".to_string()]));
+    assert_eq!(destination_path("/dav/new%20name"), Some(vec!["new name".to_string()]));
+    assert_eq!(destination_path("https://elsewhere.example/other/b.txt"), None);
+    assert_eq!(destination_path("http://localhost:3000"), None);
+}
+
+#[test]
+fn test_encoding_round_trips() {
+    let name = "Résumé (final) #2.pdf";
+    assert_eq!(percent_decode(&encode_segment(name)).as_deref(), Some(name));
+    assert_eq!(href(&["a b".to_string()], true), "/dav/a%20b/");
+    assert_eq!(percent_decode("%zz"), None);
+}
+
+#[test]
+fn test_lock_timeout() {
+    assert_eq!(lock_timeout(Some("Second-600")), 600);
+    assert_eq!(lock_timeout(Some("Infinite, Second-4100000000")), MAX_LOCK_SECS);
+    assert_eq!(lock_timeout(Some("Second-99999999")), MAX_LOCK_SECS);
+    assert_eq!(lock_timeout(None), MAX_LOCK_SECS);
+}
+
+#[test]
+fn test_element_inner() {
+    let body = r#"<?xml version="1.0"?><a:lockinfo xmlns:a="DAV:"><a:lockscope><a:exclusive/></a:lockscope>
+                  <a:owner><a:href>mailto:ana@example.com</a:href></a:owner></a:lockinfo>"#;
+    let owner = element_inner(body, "owner").unwrap();
+    assert_eq!(element_inner(owner, "href"), Some("mailto:ana@example.com"));
+    assert_eq!(element_inner(body, "exclusive"), Some(""));
+    assert_eq!(element_inner(body, "shared"), None);
+}
+
+#[test]
+fn test_split_name() {
+    assert_eq!(split_name("report.final.pdf"), ("report.final".to_string(), "pdf".to_string()));
+    assert_eq!(split_name("Makefile"), ("Makefile".to_string(), String::new()));
+}
+
+#[test]
+fn test_tree_resolves_names_with_extensions() {
+    let photos = entry(None, "Photos", None, FileType::Folder);
+    let beach = entry(Some(photos.file_id), "beach", Some("jpg"), FileType::Media);
+    let notes = entry(None, "notes", Some("txt"), FileType::Document);
+    let tree = DavTree::new(vec![photos.clone(), beach.clone(), notes.clone()]);
+
+    let path = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    assert!(matches!(tree.resolve(&path(&[])), Some(None)));
+    assert_eq!(tree.resolve(&path(&["Photos", "beach.jpg"])).flatten().map(|e| e.file_id), Some(beach.file_id));
+    assert!(tree.resolve(&path(&["Photos", "beach"])).is_none());
+    // a file isnt a folder
+    assert!(tree.resolve(&path(&["notes.txt", "x"])).is_none());
+    assert_eq!(tree.parent_of(&path(&["Photos", "new.png"])), Some(Some(photos.file_id)));
+    assert_eq!(tree.parent_of(&path(&["new.png"])), Some(None));
+    assert_eq!(tree.parent_of(&path(&["Missing", "new.png"])), None);
+    assert_eq!(tree.descendants(photos.file_id).len(), 1);
+}
+
+async fn dav_app() -> TestApp {
+    spawn_app_with(&[("WEBDAV", "true".to_string())]).await
+}
+
+#[tokio::test]
+async fn test_webdav_off_by_default() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .request(reqwest::Method::OPTIONS, format!("{}/dav/", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn test_webdav_wo_credentials() {
+    let app = dav_app().await;
+
+    let res = app.client
+        .request(reqwest::Method::from_bytes(b"PROPFIND").unwrap(), format!("{}/dav/", app.base_url))
+        .header("Depth", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+    assert!(res.headers().get("www-authenticate").is_some());
+}
+
+#[tokio::test]
+async fn test_webdav_options() {
+    let app = dav_app().await;
+
+    let res = app.client
+        .request(reqwest::Method::OPTIONS, format!("{}/dav/", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers().get("dav").unwrap(), "1, 2");
+}
+
+async fn dav_user(app: &TestApp, pool: &sqlx::PgPool) -> (String, Uuid) {
+    let email = format!("dav-{}@mail.com", Uuid::new_v4());
+    let res = app.client
+        .post(format!("{}/sign-up", app.base_url))
+        .json(&serde_json::json!({"email": email, "password": "12345678"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 201);
+    let user_id = sqlx::query_scalar("SELECT user_id FROM users WHERE email = $1;")
+        .bind(&email)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    (email, user_id)
+}
+
+#[tokio::test]
+async fn test_webdav_overwrite_and_rename() {
+    let app = dav_app().await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let (email, user_id) = dav_user(&app, &pool).await;
+    let put = |body: &'static [u8]| app.client
+        .put(format!("{}/dav/notes.txt", app.base_url))
+        .basic_auth(&email, Some("12345678"))
+        .body(body)
+        .send();
+
+    assert_eq!(put(b"first").await.unwrap().status(), 201);
+    assert_eq!(put(b"second!").await.unwrap().status(), 204);
+    let files: Vec<(Uuid, i64)> = sqlx::query_as("SELECT file_id, size FROM files WHERE owner_id = $1;")
+        .bind(user_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].1, 7);
+    let res = app.client
+        .get(format!("{}/dav/notes.txt", app.base_url))
+        .basic_auth(&email, Some("12345678"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"second!");
+
+    // dropping the extension stores it the way uploads do
+    let res = app.client
+        .request(reqwest::Method::from_bytes(b"MOVE").unwrap(), format!("{}/dav/notes.txt", app.base_url))
+        .basic_auth(&email, Some("12345678"))
+        .header("Destination", format!("{}/dav/notes", app.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let (file_id, extension): (Uuid, Option<String>) = sqlx::query_as("SELECT file_id, extension FROM files WHERE owner_id = $1;")
+        .bind(user_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!((file_id, extension.as_deref()), (files[0].0, Some("")));
+    let res = app.client
+        .get(format!("{}/dav/notes", app.base_url))
+        .basic_auth(&email, Some("12345678"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"second!");
+}
@@ -0,0 +1,177 @@
+#[path = "common/mod.rs"]
+mod common;
+use common::{spawn_app, spawn_app_with, signed_in_user};
+use serde_json::{json, Value};
+use axum::{Router, routing::post, http::{HeaderMap, StatusCode}, body::Bytes};
+use rust_worker::crypto::sign;
+use rust_worker::webhooks::{signature_header, backoff_secs, valid_url, send,
+                            public_ip, check_target, MAX_BACKOFF_SECS};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// answers every POST with `status` and hands over what it got
+async fn spawn_receiver(status: StatusCode) -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: Bytes| {
+        let tx = tx.clone();
+        async move {
+            tx.send((headers, body)).unwrap();
+            status
+        }
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (format!("http://127.0.0.1:{}/hook", port), rx)
+}
+
+#[test]
+fn test_sign() {
+    // rfc 4231 cases 1 and 6
+    assert_eq!(sign(&[0x0b; 20], b"Hi There"),
+               "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+    assert_eq!(sign(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First"),
+               "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+}
+
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff_secs(1), 30);
+    assert_eq!(backoff_secs(2), 60);
+    assert_eq!(backoff_secs(4), 240);
+    assert_eq!(backoff_secs(100), MAX_BACKOFF_SECS);
+}
+
+#[test]
+fn test_valid_url() {
+    assert!(valid_url("https://ci.example.com/hooks/storage"));
+    assert!(valid_url("http://127.0.0.1:8080/"));
+    assert!(!valid_url("ftp://example.com/"));
+    assert!(!valid_url("not a url"));
+}
+
+#[test]
+fn test_public_ip() {
+    for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+               "100.64.0.1", "0.0.0.0", "::1", "fd00:ec2::254", "fe80::1", "::ffff:127.0.0.1"] {
+        assert!(!public_ip(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+        assert!(public_ip(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[tokio::test]
+async fn test_check_target() {
+    assert!(check_target("http://127.0.0.1:8080/", false).await.is_err());
+    assert!(check_target("http://169.254.169.254/latest/meta-data/", false).await.is_err());
+    assert!(check_target("http://[::1]:3000/", false).await.is_err());
+    assert!(check_target("http://localhost:3000/", false).await.is_err());
+    assert!(check_target("http://127.0.0.1:8080/", true).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_send_signed() {
+    let (url, mut received) = spawn_receiver(StatusCode::NO_CONTENT).await;
+    let delivery_id = Uuid::new_v4();
+    let payload = serde_json::json!({"event": "created", "data": {"file_name": "report.pdf"}});
+
+    let code = send(&reqwest::Client::new(), &url, "secret", "created", &delivery_id, &payload)
+        .await.unwrap();
+    assert_eq!(code, 204);
+
+    let (headers, body) = received.recv().await.unwrap();
+    let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+    assert_eq!(header("x-servr-event"), "created");
+    assert_eq!(header("x-servr-delivery"), delivery_id.to_string());
+    let timestamp: i64 = header("x-servr-timestamp").parse().unwrap();
+    assert_eq!(header("x-servr-signature"), signature_header("secret", timestamp, &body));
+    assert_ne!(header("x-servr-signature"), signature_header("other", timestamp, &body));
+    let sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(sent, payload);
+}
+
+#[tokio::test]
+async fn test_send_failing_receiver() {
+    let (url, _received) = spawn_receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+    let code = send(&reqwest::Client::new(), &url, "secret", "ping", &Uuid::new_v4(),
+                    &serde_json::json!({})).await.unwrap();
+    assert_eq!(code, 500);
+    // nothing listening
+    assert!(send(&reqwest::Client::new(), "http://127.0.0.1:9/hook", "secret", "ping",
+                 &Uuid::new_v4(), &serde_json::json!({})).await.is_err());
+}
+
+// what the receiver got next, checked against the webhook's secret
+async fn signed_delivery(received: &mut mpsc::UnboundedReceiver<(HeaderMap, Bytes)>, secret: &str)
+    -> (String, serde_json::Value) {
+    let (headers, body) = tokio::time::timeout(std::time::Duration::from_secs(15), received.recv()).await
+        .expect("nothing delivered in time").unwrap();
+    let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+    let timestamp: i64 = header("x-servr-timestamp").parse().unwrap();
+    assert_eq!(header("x-servr-signature"), signature_header(secret, timestamp, &body));
+    (header("x-servr-event"), serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_webhook_deliveries() {
+    let app = spawn_app_with(&[
+        ("WEBDAV", "true".to_string()),
+        ("WEBHOOK_ALLOW_PRIVATE", "true".to_string()),
+        ("WEBHOOK_INTERVAL_SECS", "1".to_string()),
+    ]).await;
+    let pool = sqlx::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let user = signed_in_user(&app, &pool).await;
+    let (url, mut received) = spawn_receiver(StatusCode::NO_CONTENT).await;
+
+    let res = app.post_as(&user, "/create-webhook", json!({"url": url, "event_types": ["created"]})).await;
+    assert_eq!(res.status(), 200);
+    let webhook: Value = res.json().await.unwrap();
+    let secret = webhook["secret"].as_str().unwrap().to_string();
+
+    let ping: Value = app.post_as(&user, "/test-webhook", json!({"webhook_id": webhook["webhook_id"]})).await
+        .json().await.unwrap();
+    assert_eq!((ping["status"].as_str(), ping["last_status_code"].as_i64()), (Some("delivered"), Some(204)));
+    let (event, body) = signed_delivery(&mut received, &secret).await;
+    assert_eq!(event, "ping");
+    assert_eq!(body["data"]["webhook_id"], webhook["webhook_id"]);
+
+    // an overwrite is a modified event, which the webhook didn't ask for
+    let other = app.upload_as(&user, &pool, "other.txt", "other").await;
+    app.upload_as(&user, &pool, "other.txt", "changed").await;
+    let third = app.upload_as(&user, &pool, "third.txt", "third").await;
+    // deliveries go out concurrently, so only the set is fixed
+    let mut created = Vec::new();
+    for _ in 0..2 {
+        let (event, body) = signed_delivery(&mut received, &secret).await;
+        assert_eq!(event, "created");
+        created.push(body["data"]["file_id"].as_str().unwrap().parse::<uuid::Uuid>().unwrap());
+    }
+    created.sort();
+    let mut expected = vec![other, third];
+    expected.sort();
+    assert_eq!(created, expected);
+
+    let deliveries: Vec<Value> = app.post_as(&user, "/get-webhook-deliveries",
+                                             json!({"webhook_id": webhook["webhook_id"]})).await
+        .json().await.unwrap();
+    assert_eq!(deliveries.len(), 3);
+    assert!(deliveries.iter().all(|d| d["status"] == "delivered" && d["attempts"] == 1));
+}
+
+#[tokio::test]
+async fn test_create_webhook_wo_session() {
+    let app = spawn_app().await;
+
+    let res = app.client
+        .post(format!("{}/create-webhook", app.base_url))
+        .json(&serde_json::json!({"url":"https://ci.example.com/hook",
+                                  "event_types":["created"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+}
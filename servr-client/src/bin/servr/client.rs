@@ -0,0 +1,185 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use servr_client::models::{ApiKeyIdForm,
+                           CreateApiKeyForm,
+                           CreateFolderForm,
+                           CreateShareLinkForm,
+                           DeleteFileForm,
+                           DownloadFileForm,
+                           MoveFileForm,
+                           RenameFileForm,
+                           ShareMode};
+use uuid::Uuid;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::tree::{RemoteFile, Tree};
+
+// where login keeps the server url and api key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    pub url: String,
+    pub api_key: String,
+}
+
+pub fn config_path() -> PathBuf {
+    if let Ok(path) = env::var("SERVR_CONFIG") {
+        return PathBuf::from(path);
+    }
+    let base = env::var("XDG_CONFIG_HOME").map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(env::var("HOME").unwrap_or_default()).join(".config"));
+    base.join("servr").join("config.json")
+}
+
+impl Config {
+    // SERVR_URL and SERVR_API_KEY win over the saved file
+    pub fn load() -> Result<Self, String> {
+        let saved: Option<Config> = std::fs::read(config_path()).ok()
+            .and_then(|data| serde_json::from_slice(&data).ok());
+        let url = env::var("SERVR_URL").ok().or(saved.as_ref().map(|c| c.url.clone()));
+        let api_key = env::var("SERVR_API_KEY").ok().or(saved.map(|c| c.api_key));
+        match (url, api_key) {
+            (Some(url), Some(api_key)) => Ok(Config { url, api_key }),
+            _ => Err("Not logged in, run `servr login <url> <email>` or set SERVR_URL and SERVR_API_KEY".to_string()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = config_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let data = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(&path, data).map_err(|e| e.to_string())?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600));
+        }
+        Ok(())
+    }
+}
+
+// the sha-256 sync compares against, read the same way uploads send it
+pub async fn sha256_file(path: &Path) -> Result<String, String> {
+    servr_client::sha256_file(path).await
+        .map(|(checksum, _)| checksum)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// servr_client with what the commands need on top: the tree, remote files
+// instead of forms, and errors as the messages that get printed
+pub struct Client {
+    api: servr_client::Client,
+    api_key: Option<String>,
+}
+
+impl Client {
+    pub async fn sign_in(base: &str, email: &str, password: &str) -> Result<Self, String> {
+        let api = servr_client::Client::new(base);
+        api.sign_in(email, password).await.map_err(|e| e.to_string())?;
+        Ok(Client { api, api_key: None })
+    }
+
+    // signs in with the key on the first request and again when the session runs out
+    pub fn from_config(config: &Config) -> Self {
+        Client {
+            api: servr_client::Client::with_api_key(&config.url, &config.api_key),
+            api_key: Some(config.api_key.clone()),
+        }
+    }
+
+    pub async fn tree(&self) -> Result<Tree, String> {
+        let files = self.api.get_files().await.map_err(|e| e.to_string())?;
+        Ok(Tree::new(files.into_iter().map(|(id, f)| (id, RemoteFile::from(f))).collect()))
+    }
+
+    // the server answers without the new folder, so it is looked up after
+    pub async fn mkdir(&self, parent_id: Option<Uuid>, name: &str) -> Result<Uuid, String> {
+        self.api.create_folder(&CreateFolderForm {
+            folder_name: name.to_string(),
+            parent_id: parent_id.map(|id| id.to_string()).unwrap_or_default(),
+        }).await.map_err(|e| e.to_string())?;
+        self.tree().await?
+            .child(parent_id, name)
+            .map(|f| f.file_id)
+            .ok_or(format!("{}: created but not listed", name))
+    }
+
+    pub async fn upload(&self, parent_id: Option<Uuid>, path: &Path) -> Result<(), String> {
+        self.api.upload_file(parent_id, path).await.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub async fn download(&self, file: &RemoteFile, dest: &Path) -> Result<(), String> {
+        self.api.download_to(&DownloadFileForm {
+            owner_id: file.owner_id.to_string(),
+            file_id: file.file_id.to_string(),
+            file_extension: file.extension.clone(),
+        }, dest).await.map_err(|e| format!("{}: {}", dest.display(), e))?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, file: &RemoteFile) -> Result<(), String> {
+        self.api.delete_file(&DeleteFileForm {
+            owner_id: file.owner_id.to_string(),
+            file_id: file.file_id.to_string(),
+        }).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn rename(&self, file: &RemoteFile, name: &str) -> Result<(), String> {
+        self.api.rename_file(&RenameFileForm {
+            owner_id: file.owner_id.to_string(),
+            file_id: file.file_id.to_string(),
+            file_name: name.to_string(),
+        }).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn move_to(&self, file: &RemoteFile, parent_id: Option<Uuid>) -> Result<(), String> {
+        self.api.move_file(&MoveFileForm {
+            file_id: file.file_id.to_string(),
+            parent_id: parent_id.map(|id| id.to_string()).unwrap_or_default(),
+        }).await.map_err(|e| e.to_string())
+    }
+
+    // the public link
+    pub async fn share(&self, file: &RemoteFile, mode: &str, expires: Option<&str>, password: Option<&str>)
+        -> Result<String, String> {
+        let mode = match mode {
+            "view" => ShareMode::View,
+            "download" => ShareMode::Download,
+            _ => return Err(format!("{}: the mode is view or download", mode)),
+        };
+        let expires_at = match expires {
+            Some(e) => Some(DateTime::parse_from_rfc3339(e)
+                .map_err(|_| format!("{}: not an RFC 3339 time", e))?
+                .with_timezone(&Utc)),
+            None => None,
+        };
+        let link = self.api.create_share_link(&CreateShareLinkForm {
+            file_id: file.file_id.to_string(),
+            mode,
+            expires_at,
+            password: password.map(|p| p.to_string()),
+            max_downloads: None,
+        }).await.map_err(|e| e.to_string())?;
+        Ok(self.api.share_url(&link))
+    }
+
+    pub async fn create_api_key(&self, name: &str) -> Result<String, String> {
+        self.api.create_api_key(&CreateApiKeyForm { name: name.to_string() }).await
+            .map_err(|e| e.to_string())?
+            .key
+            .ok_or("No key returned".to_string())
+    }
+
+    // the key this client signed in with
+    pub async fn revoke_own_key(&self) -> Result<(), String> {
+        let api_key = self.api_key.as_ref().ok_or("Not signed in with a key")?;
+        let keys = self.api.get_api_keys().await.map_err(|e| e.to_string())?;
+        let own = keys.iter()
+            .find(|k| api_key.starts_with(&k.prefix))
+            .ok_or("Key not found")?;
+        self.api.revoke_api_key(&ApiKeyIdForm { key_id: own.key_id.to_string() }).await
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,399 @@
+// command line client
+// usage: servr <command> [options]
+//   login <url> <email>    password from SERVR_PASSWORD or stdin, saves an api key
+//   logout                 revokes the saved key
+//   ls [path]
+//   upload [-j N] <local>... <remote folder>     folders are uploaded recursively
+//   download <remote> [local]
+//   mkdir <path>           creates missing parents too
+//   mv <from> <to>         into <to> if it is a folder, otherwise moves and renames
+//   rm <path>
+//   share [--mode view|download] [--expires RFC3339] [--password PW] <path>
+//   sync [-j N] [--delete] [--dry-run] <local folder> <remote folder>
+// SERVR_URL and SERVR_API_KEY override the saved login, SERVR_CONFIG where it is saved
+mod client;
+mod tree;
+
+use futures::stream::{self, StreamExt};
+use uuid::Uuid;
+use std::env;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use client::{Client, Config, config_path, sha256_file};
+use tree::{RemoteFile, Tree, split_path};
+
+// uploads in flight unless -j says otherwise
+const DEFAULT_JOBS: usize = 4;
+
+fn usage() -> ! {
+    eprintln!("usage: servr login <url> <email> | logout | ls [path] | upload [-j N] <local>... <remote>");
+    eprintln!("       download <remote> [local] | mkdir <path> | mv <from> <to> | rm <path>");
+    eprintln!("       share [--mode view|download] [--expires RFC3339] [--password PW] <path>");
+    eprintln!("       sync [-j N] [--delete] [--dry-run] <local> <remote>");
+    std::process::exit(2);
+}
+
+// flags with a value come out as (name, value), the rest stay positional
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: &[String], with_value: &[&str]) -> Self {
+        let mut parsed = Args { positional: Vec::new(), options: Vec::new(), flags: Vec::new() };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if with_value.contains(&arg.as_str()) {
+                match iter.next() {
+                    Some(value) => parsed.options.push((arg.clone(), value.clone())),
+                    None => usage(),
+                }
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                parsed.flags.push(arg.clone());
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        parsed
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    fn jobs(&self) -> usize {
+        self.option("-j").and_then(|j| j.parse().ok()).filter(|j| *j > 0).unwrap_or(DEFAULT_JOBS)
+    }
+}
+
+fn human_size(size: i64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}{}", size, units[0]) } else { format!("{:.1}{}", size, units[unit]) }
+}
+
+fn parent_and_name(path: &str) -> Result<(String, String), String> {
+    let mut parts = split_path(path);
+    let name = parts.pop().ok_or(format!("{}: needs a name", path))?.to_string();
+    Ok((parts.join("/"), name))
+}
+
+async fn login(args: &Args) -> Result<(), String> {
+    let (url, email) = match args.positional.as_slice() {
+        [url, email] => (url, email),
+        _ => usage(),
+    };
+    let password = match env::var("SERVR_PASSWORD") {
+        Ok(p) => p,
+        Err(_) => {
+            eprint!("Password: ");
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        },
+    };
+    let client = Client::sign_in(url, email, &password).await?;
+    let host = env::var("HOSTNAME").unwrap_or("cli".to_string());
+    let api_key = client.create_api_key(&format!("servr on {}", host)).await?;
+    Config { url: url.trim_end_matches('/').to_string(), api_key }.save()?;
+    println!("Logged in, key saved to {}", config_path().display());
+    Ok(())
+}
+
+async fn logout() -> Result<(), String> {
+    let client = Client::from_config(&Config::load()?);
+    client.revoke_own_key().await?;
+    let _ = std::fs::remove_file(config_path());
+    println!("Logged out");
+    Ok(())
+}
+
+async fn ls(client: &Client, args: &Args) -> Result<(), String> {
+    let path = args.positional.first().map(|p| p.as_str()).unwrap_or("/");
+    let tree = client.tree().await?;
+    let entries: Vec<&RemoteFile> = match tree.resolve(path)? {
+        Some(f) if !f.is_folder() => vec![f],
+        folder => tree.children(folder.map(|f| f.file_id)),
+    };
+    for f in entries {
+        let modified = f.last_modified.map(|d| d.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let name = if f.is_folder() { format!("{}/", f.name()) } else { f.name() };
+        println!("{:>8}  {:16}  {}", human_size(f.size), modified, name);
+    }
+    Ok(())
+}
+
+// the folder at path, creating whatever is missing on the way
+async fn ensure_folder(client: &Client, tree: &mut Tree, path: &str) -> Result<Option<Uuid>, String> {
+    let mut parent_id: Option<Uuid> = None;
+    for part in split_path(path) {
+        parent_id = match tree.child(parent_id, part) {
+            Some(f) if f.is_folder() => Some(f.file_id),
+            Some(_) => return Err(format!("{}: {} is not a folder", path, part)),
+            None => {
+                let folder_id = client.mkdir(parent_id, part).await?;
+                *tree = client.tree().await?;
+                Some(folder_id)
+            },
+        };
+    }
+    Ok(parent_id)
+}
+
+// local files under path (or path itself), with the remote folder each goes into
+fn walk(path: &Path, remote: &str, out: &mut Vec<(PathBuf, String)>) -> Result<(), String> {
+    if path.is_dir() {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let remote = format!("{}/{}", remote.trim_end_matches('/'), name);
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        entries.sort();
+        for entry in entries {
+            walk(&entry, &remote, out)?;
+        }
+    } else {
+        out.push((path.to_path_buf(), remote.to_string()));
+    }
+    Ok(())
+}
+
+// creates the folders first, one at a time, then uploads jobs files at once.
+// a file that replaces a remote one only deletes it once its upload went through
+async fn upload_all(client: &Client, files: Vec<(PathBuf, String, Option<RemoteFile>)>, jobs: usize)
+    -> Result<(), String> {
+    let mut tree = client.tree().await?;
+    let mut targets = Vec::new();
+    for (local, remote, replaces) in files {
+        let parent_id = ensure_folder(client, &mut tree, &remote).await?;
+        targets.push((local, parent_id, replaces));
+    }
+    let results: Vec<Result<(), String>> = stream::iter(targets)
+        .map(|(local, parent_id, replaces)| async move {
+            client.upload(parent_id, &local).await?;
+            if let Some(old) = replaces {
+                client.delete(&old).await?;
+            }
+            println!("uploaded {}", local.display());
+            Ok::<(), String>(())
+        })
+        .buffer_unordered(jobs)
+        .collect()
+        .await;
+    let failed: Vec<String> = results.into_iter().filter_map(|r| r.err()).collect();
+    for e in &failed {
+        eprintln!("Error {}", e);
+    }
+    if failed.is_empty() { Ok(()) } else { Err(format!("{} uploads failed", failed.len())) }
+}
+
+async fn upload(client: &Client, args: &Args) -> Result<(), String> {
+    let (remote, locals) = match args.positional.split_last() {
+        Some((remote, locals)) if !locals.is_empty() => (remote, locals),
+        _ => usage(),
+    };
+    let mut files = Vec::new();
+    for local in locals {
+        walk(Path::new(local), remote, &mut files)?;
+    }
+    let files = files.into_iter().map(|(local, remote)| (local, remote, None)).collect();
+    upload_all(client, files, args.jobs()).await
+}
+
+async fn download_into(client: &Client, tree: &Tree, file: &RemoteFile, dest: &Path) -> Result<(), String> {
+    if file.is_folder() {
+        std::fs::create_dir_all(dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+        for child in tree.children(Some(file.file_id)) {
+            Box::pin(download_into(client, tree, child, &dest.join(child.name()))).await?;
+        }
+        return Ok(());
+    }
+    client.download(file, dest).await?;
+    println!("downloaded {}", tree.path_of(&file.file_id));
+    Ok(())
+}
+
+async fn download(client: &Client, args: &Args) -> Result<(), String> {
+    let remote = args.positional.first().unwrap_or_else(|| usage());
+    let tree = client.tree().await?;
+    let file = tree.resolve(remote)?.ok_or("Give a file or folder, not the top level")?;
+    let dest = match args.positional.get(1) {
+        Some(local) if Path::new(local).is_dir() => Path::new(local).join(file.name()),
+        Some(local) => PathBuf::from(local),
+        None => PathBuf::from(file.name()),
+    };
+    download_into(client, &tree, file, &dest).await
+}
+
+async fn mkdir(client: &Client, args: &Args) -> Result<(), String> {
+    let path = args.positional.first().unwrap_or_else(|| usage());
+    let mut tree = client.tree().await?;
+    ensure_folder(client, &mut tree, path).await?;
+    Ok(())
+}
+
+async fn mv(client: &Client, args: &Args) -> Result<(), String> {
+    let (from, to) = match args.positional.as_slice() {
+        [from, to] => (from, to),
+        _ => usage(),
+    };
+    let tree = client.tree().await?;
+    let file = tree.resolve(from)?.ok_or("Cannot move the top level")?.clone();
+    let (parent_id, name) = match tree.resolve(to) {
+        Ok(Some(target)) if target.is_folder() => (Some(target.file_id), file.name()),
+        Ok(None) => (None, file.name()),
+        Ok(Some(_)) => return Err(format!("{}: already exists", to)),
+        Err(_) => {
+            let (parent, name) = parent_and_name(to)?;
+            (tree.resolve_folder(&parent)?, name)
+        },
+    };
+    if parent_id != file.parent_id {
+        client.move_to(&file, parent_id).await?;
+    }
+    if name != file.name() {
+        // renames only touch the name, the extension stays what it was
+        let stem = match file.extension.as_deref() {
+            Some(ext) if !ext.is_empty() => name.strip_suffix(&format!(".{}", ext))
+                .ok_or(format!("{}: the extension .{} cannot be changed", to, ext))?,
+            _ => name.as_str(),
+        };
+        client.rename(&file, stem).await?;
+    }
+    Ok(())
+}
+
+async fn rm(client: &Client, args: &Args) -> Result<(), String> {
+    let path = args.positional.first().unwrap_or_else(|| usage());
+    let tree = client.tree().await?;
+    let file = tree.resolve(path)?.ok_or("Cannot remove the top level")?;
+    client.delete(file).await
+}
+
+async fn share(client: &Client, args: &Args) -> Result<(), String> {
+    let path = args.positional.first().unwrap_or_else(|| usage());
+    let tree = client.tree().await?;
+    let file = tree.resolve(path)?.ok_or("Cannot share the top level")?;
+    let url = client.share(file, args.option("--mode").unwrap_or("download"),
+                           args.option("--expires"), args.option("--password")).await?;
+    println!("{}", url);
+    Ok(())
+}
+
+// one way, local wins: uploads what is new or changed (by size and sha-256),
+// replaces changed files, and with --delete removes what is only remote.
+// nothing remote is deleted before what replaces it is up, and files only in
+// the remote go once every upload has succeeded
+async fn sync(client: &Client, args: &Args) -> Result<(), String> {
+    let (local, remote) = match args.positional.as_slice() {
+        [local, remote] => (PathBuf::from(local), remote.clone()),
+        _ => usage(),
+    };
+    if !local.is_dir() {
+        return Err(format!("{}: not a folder", local.display()));
+    }
+    let dry_run = args.flag("--dry-run");
+    let mut tree = client.tree().await?;
+    let root = if dry_run { tree.resolve_folder(&remote).ok().flatten() }
+               else { ensure_folder(client, &mut tree, &remote).await? };
+
+    let mut uploads = Vec::new();
+    let mut remote_only: Vec<RemoteFile> = Vec::new();
+    let mut folders = vec![(local.clone(), remote.trim_end_matches('/').to_string(), root)];
+    while let Some((dir, remote_dir, folder_id)) = folders.pop() {
+        let mut seen = Vec::new();
+        let entries = std::fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            let existing = folder_id.and_then(|id| tree.child(Some(id), &name).cloned());
+            seen.push(name.clone());
+            if path.is_dir() {
+                let child_id = existing.filter(|f| f.is_folder()).map(|f| f.file_id);
+                folders.push((path, format!("{}/{}", remote_dir, name), child_id));
+                continue;
+            }
+            let size = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?.len() as i64;
+            let unchanged = match &existing {
+                Some(f) if f.size == size => match &f.checksum_sha256 {
+                    Some(checksum) => *checksum == sha256_file(&path).await?,
+                    None => true,
+                },
+                _ => false,
+            };
+            match existing {
+                Some(_) if unchanged => {},
+                Some(f) => {
+                    println!("update {}", path.display());
+                    uploads.push((path, remote_dir.clone(), Some(f)));
+                },
+                None => {
+                    println!("upload {}", path.display());
+                    uploads.push((path, remote_dir.clone(), None));
+                },
+            }
+        }
+        if args.flag("--delete") && let Some(id) = folder_id {
+            for f in tree.children(Some(id)) {
+                if !seen.contains(&f.name()) {
+                    println!("delete {}", tree.path_of(&f.file_id));
+                    remote_only.push(f.clone());
+                }
+            }
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    upload_all(client, uploads, args.jobs()).await?;
+    for f in &remote_only {
+        client.delete(f).await?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let (command, rest) = argv.split_first().unwrap_or_else(|| usage());
+    let args = Args::parse(rest, &["-j", "--mode", "--expires", "--password"]);
+
+    let result = match command.as_str() {
+        "login" => login(&args).await,
+        "logout" => logout().await,
+        _ => match Config::load() {
+            Ok(config) => {
+                let client = Client::from_config(&config);
+                match command.as_str() {
+                    "ls" => ls(&client, &args).await,
+                    "upload" => upload(&client, &args).await,
+                    "download" => download(&client, &args).await,
+                    "mkdir" => mkdir(&client, &args).await,
+                    "mv" => mv(&client, &args).await,
+                    "rm" => rm(&client, &args).await,
+                    "share" => share(&client, &args).await,
+                    "sync" => sync(&client, &args).await,
+                    _ => usage(),
+                }
+            },
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("Error {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use servr_client::models::{FileResponse, FileType};
+use uuid::Uuid;
+use std::collections::HashMap;
+
+// the parts of a get_files entry the cli needs
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub file_id: Uuid,
+    pub owner_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub file_name: String,
+    pub extension: Option<String>,
+    pub size: i64,
+    pub file_type: FileType,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub checksum_sha256: Option<String>,
+    pub vault: bool,
+}
+
+impl From<FileResponse> for RemoteFile {
+    fn from(f: FileResponse) -> Self {
+        RemoteFile {
+            file_id: f.file_id,
+            owner_id: f.owner_id,
+            parent_id: f.parent_id,
+            file_name: f.file_name,
+            extension: f.extension,
+            size: f.size,
+            file_type: f.file_type,
+            last_modified: f.last_modified,
+            checksum_sha256: f.checksum_sha256,
+            vault: f.vault,
+        }
+    }
+}
+
+impl RemoteFile {
+    pub fn is_folder(&self) -> bool {
+        self.file_type == FileType::Folder
+    }
+
+    // the server keeps the extension apart from the name
+    pub fn name(&self) -> String {
+        match self.extension.as_deref() {
+            Some(ext) if !ext.is_empty() => format!("{}.{}", self.file_name, ext),
+            _ => self.file_name.clone(),
+        }
+    }
+}
+
+// the user's files as paths. vault items have no plaintext names and are left out
+pub struct Tree {
+    files: HashMap<Uuid, RemoteFile>,
+}
+
+pub fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|p| !p.is_empty() && *p != ".").collect()
+}
+
+impl Tree {
+    pub fn new(files: HashMap<Uuid, RemoteFile>) -> Self {
+        Tree { files: files.into_iter().filter(|(_, f)| !f.vault).collect() }
+    }
+
+    // sorted folders first, then by name
+    pub fn children(&self, parent_id: Option<Uuid>) -> Vec<&RemoteFile> {
+        let mut children: Vec<&RemoteFile> = self.files.values()
+            .filter(|f| f.parent_id == parent_id)
+            .collect();
+        children.sort_by(|a, b| b.is_folder().cmp(&a.is_folder()).then(a.name().cmp(&b.name())));
+        children
+    }
+
+    pub fn child(&self, parent_id: Option<Uuid>, name: &str) -> Option<&RemoteFile> {
+        self.files.values().find(|f| f.parent_id == parent_id && f.name() == name)
+    }
+
+    // Ok(None) is the top level, which isnt a file of its own
+    pub fn resolve(&self, path: &str) -> Result<Option<&RemoteFile>, String> {
+        let mut current: Option<&RemoteFile> = None;
+        for part in split_path(path) {
+            let parent_id = current.map(|f| f.file_id);
+            current = Some(self.child(parent_id, part)
+                .ok_or(format!("{}: no such file or folder", path))?);
+        }
+        Ok(current)
+    }
+
+    // like resolve but it has to be a folder, None again for the top level
+    pub fn resolve_folder(&self, path: &str) -> Result<Option<Uuid>, String> {
+        match self.resolve(path)? {
+            Some(f) if f.is_folder() => Ok(Some(f.file_id)),
+            Some(_) => Err(format!("{}: not a folder", path)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn path_of(&self, file_id: &Uuid) -> String {
+        let mut parts = Vec::new();
+        let mut next = self.files.get(file_id);
+        while let Some(f) = next {
+            parts.push(f.name());
+            next = f.parent_id.and_then(|id| self.files.get(&id));
+        }
+        parts.reverse();
+        format!("/{}", parts.join("/"))
+    }
+}
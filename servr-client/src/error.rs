@@ -0,0 +1,78 @@
+use reqwest::StatusCode;
+use std::fmt;
+
+// the worker's ServerError as seen from the other side. database, s3 and
+// internal errors all come back as a 500 so they end up in Internal
+#[derive(Debug)]
+pub enum Error {
+    NotFound(String),
+    Unauthorized(String),
+    Forbidden(String),
+    BadRequest(String),
+    Internal(String),
+    // any other status, e.g. a 502 from a proxy in front of the worker
+    Status(StatusCode, String),
+    // never got an answer
+    Http(reqwest::Error),
+    // got one but not the shape we expected
+    Decode(String),
+    Io(std::io::Error),
+}
+
+impl Error {
+    pub fn from_status(status: StatusCode, body: String) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => Error::NotFound(body),
+            StatusCode::UNAUTHORIZED => Error::Unauthorized(body),
+            StatusCode::FORBIDDEN => Error::Forbidden(body),
+            StatusCode::BAD_REQUEST => Error::BadRequest(body),
+            StatusCode::INTERNAL_SERVER_ERROR => Error::Internal(body),
+            _ => Error::Status(status, body),
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::NotFound(_) => Some(StatusCode::NOT_FOUND),
+            Error::Unauthorized(_) => Some(StatusCode::UNAUTHORIZED),
+            Error::Forbidden(_) => Some(StatusCode::FORBIDDEN),
+            Error::BadRequest(_) => Some(StatusCode::BAD_REQUEST),
+            Error::Internal(_) => Some(StatusCode::INTERNAL_SERVER_ERROR),
+            Error::Status(status, _) => Some(*status),
+            Error::Http(e) => e.status(),
+            Error::Decode(_) | Error::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "unauthorized: {}", msg),
+            Error::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+            Error::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            Error::Internal(msg) => write!(f, "server error: {}", msg),
+            Error::Status(status, msg) => write!(f, "{}: {}", status, msg),
+            Error::Http(e) => write!(f, "request failed: {}", e),
+            Error::Decode(msg) => write!(f, "unexpected response: {}", msg),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() { Error::Decode(e.to_string()) } else { Error::Http(e) }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
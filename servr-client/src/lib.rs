@@ -0,0 +1,353 @@
+// typed client for the worker's http api. requests and responses are the
+// worker's own types from servr_models, so a changed form or response breaks
+// here at compile time instead of at runtime
+mod error;
+mod retry;
+
+pub use error::{Error, Result};
+pub use retry::RetryPolicy;
+pub use servr_models as models;
+
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use reqwest::{RequestBuilder, Response, header};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use sha2::{Sha256, Digest};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::RwLock;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::path::Path;
+
+use models::{ApiKeyIdForm,
+             ApiKeyResponse,
+             ApiKeySignInForm,
+             ChangesQuery,
+             ChangesResponse,
+             CreateApiKeyForm,
+             CreateFolderForm,
+             CreateShareLinkForm,
+             DeleteFileForm,
+             DownloadFileForm,
+             FileResponse,
+             MoveFileForm,
+             RenameFileForm,
+             RevokeShareLinkForm,
+             SearchForm,
+             SearchResponse,
+             ShareLinkResponse,
+             SignInForm,
+             SignUpForm};
+
+// what /download-file and /download-vault-file answer with
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadLink {
+    // presigned, or a worker link that needs the session
+    pub url: String,
+    pub file_name: String,
+}
+
+// the session cookie out of a sign in response
+fn session_cookie(res: &Response) -> Option<String> {
+    res.headers().get_all(header::SET_COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| v.split(';').next())
+        .find_map(|v| v.strip_prefix("session=").map(|s| s.to_string()))
+}
+
+async fn check(res: Response) -> Result<Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    Err(Error::from_status(status, body.trim().to_string()))
+}
+
+// the checksum upload_file sends and the length it reads, without holding the file
+pub async fn sha256_file(path: &Path) -> Result<(String, u64)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut len = 0u64;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+    Ok((hasher.finalize().iter().map(|a| format!("{:02x}", a)).collect(), len))
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    base: String,
+    // with a key, sessions are signed in for and renewed when they run out
+    api_key: Option<String>,
+    session: RwLock<Option<String>>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    // nothing signed in yet, call sign_in or use one of the constructors below
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            base: base_url.trim_end_matches('/').to_string(),
+            api_key: None,
+            session: RwLock::new(None),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_api_key(base_url: &str, api_key: &str) -> Self {
+        Client { api_key: Some(api_key.to_string()), ..Client::new(base_url) }
+    }
+
+    // an existing session token, e.g. from a browser cookie
+    pub fn with_session(base_url: &str, token: &str) -> Self {
+        Client { session: RwLock::new(Some(token.to_string())), ..Client::new(base_url) }
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // for timeouts, proxies and the like
+    pub fn http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            format!("{}{}", self.base, path)
+        }
+    }
+
+    pub async fn session(&self) -> Option<String> {
+        self.session.read().await.clone()
+    }
+
+    pub async fn sign_in(&self, email: &str, password: &str) -> Result<()> {
+        let form = SignInForm { email: email.to_string(), password: password.to_string() };
+        let res = self.http.post(self.url("/sign-in")).json(&form).send().await?;
+        let res = check(res).await?;
+        *self.session.write().await = Some(session_cookie(&res)
+            .ok_or(Error::Decode("No session returned".to_string()))?);
+        Ok(())
+    }
+
+    pub async fn sign_up(&self, email: &str, password: &str) -> Result<()> {
+        let form = SignUpForm { email: email.to_string(), password: password.to_string() };
+        check(self.http.post(self.url("/sign-up")).json(&form).send().await?).await?;
+        Ok(())
+    }
+
+    pub async fn sign_out(&self) -> Result<()> {
+        self.send(|http| Ok(http.post(self.url("/sign-out")))).await?;
+        *self.session.write().await = None;
+        Ok(())
+    }
+
+    async fn renew(&self) -> Result<()> {
+        let api_key = self.api_key.as_ref()
+            .ok_or(Error::Unauthorized("Session expired and no API key to renew it".to_string()))?;
+        let form = ApiKeySignInForm { api_key: api_key.clone() };
+        let res = self.http.post(self.url("/sign-in-key")).json(&form).send().await?;
+        let res = check(res).await?;
+        *self.session.write().await = Some(session_cookie(&res)
+            .ok_or(Error::Decode("No session returned".to_string()))?);
+        Ok(())
+    }
+
+    async fn send_once<F>(&self, build: &F) -> Result<Response>
+    where F: Fn(&reqwest::Client) -> Result<RequestBuilder> {
+        if self.api_key.is_some() && self.session.read().await.is_none() {
+            self.renew().await?;
+        }
+        let mut request = build(&self.http)?;
+        if let Some(session) = self.session.read().await.as_ref() {
+            request = request.header(header::COOKIE, format!("session={}", session));
+        }
+        check(request.send().await?).await
+    }
+
+    // build is called again for every attempt, so bodies are made fresh each time.
+    // a 401 renews the session from the api key once, the rest is up to the policy
+    async fn send<F>(&self, build: F) -> Result<Response>
+    where F: Fn(&reqwest::Client) -> Result<RequestBuilder> {
+        let mut attempt = 0;
+        let mut renewed = false;
+        loop {
+            match self.send_once(&build).await {
+                Err(Error::Unauthorized(_)) if !renewed && self.api_key.is_some() => {
+                    renewed = true;
+                    self.renew().await?;
+                },
+                Err(e) if self.retry.should_retry(attempt, &e) => {
+                    tokio::time::sleep(self.retry.delay(attempt)).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    // any json route, for the ones without a method here
+    pub async fn post<B, R>(&self, path: &str, body: &B) -> Result<R>
+    where B: Serialize + ?Sized, R: DeserializeOwned {
+        let url = self.url(path);
+        let res = self.send(|http| Ok(http.post(&url).json(body))).await?;
+        Ok(res.json().await?)
+    }
+
+    // for routes that answer with a status or a plain message
+    async fn post_unit<B: Serialize + ?Sized>(&self, path: &str, body: &B) -> Result<()> {
+        let url = self.url(path);
+        self.send(|http| Ok(http.post(&url).json(body))).await?;
+        Ok(())
+    }
+
+    // files
+
+    pub async fn get_files(&self) -> Result<HashMap<Uuid, FileResponse>> {
+        let url = self.url("/get-files");
+        Ok(self.send(|http| Ok(http.post(&url))).await?.json().await?)
+    }
+
+    pub async fn create_folder(&self, form: &CreateFolderForm) -> Result<()> {
+        self.post_unit("/create-folder", form).await
+    }
+
+    pub async fn delete_file(&self, form: &DeleteFileForm) -> Result<()> {
+        self.post_unit("/delete-file", form).await
+    }
+
+    pub async fn rename_file(&self, form: &RenameFileForm) -> Result<()> {
+        self.post_unit("/rename-file", form).await
+    }
+
+    pub async fn move_file(&self, form: &MoveFileForm) -> Result<()> {
+        self.post_unit("/move-file", form).await
+    }
+
+    pub async fn search(&self, form: &SearchForm) -> Result<SearchResponse> {
+        self.post("/search", form).await
+    }
+
+    // streamed from disk, with the sha-256 sent along so the worker checks it.
+    // the file is read twice, once to hash and once to send
+    pub async fn upload_file(&self, parent_id: Option<Uuid>, path: &Path) -> Result<()> {
+        let (checksum, len) = sha256_file(path).await?;
+        let name = path.file_name().and_then(|n| n.to_str())
+            .ok_or(Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a valid file name")))?
+            .to_string();
+        let url = self.url("/upload-file");
+        self.send(|http| {
+            let file = tokio::fs::File::from_std(std::fs::File::open(path)?);
+            let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+            let part = reqwest::multipart::Part::stream_with_length(body, len).file_name(name.clone());
+            Ok(http.post(&url)
+                .header("X-Content-SHA256", &checksum)
+                .multipart(upload_form(part, parent_id)))
+        }).await?;
+        Ok(())
+    }
+
+    pub async fn upload_bytes(&self, parent_id: Option<Uuid>, name: &str, data: Bytes) -> Result<()> {
+        let checksum: String = Sha256::digest(&data).iter().map(|a| format!("{:02x}", a)).collect();
+        let url = self.url("/upload-file");
+        self.send(|http| {
+            let part = reqwest::multipart::Part::stream(data.clone()).file_name(name.to_string());
+            Ok(http.post(&url)
+                .header("X-Content-SHA256", &checksum)
+                .multipart(upload_form(part, parent_id)))
+        }).await?;
+        Ok(())
+    }
+
+    pub async fn download_link(&self, form: &DownloadFileForm) -> Result<DownloadLink> {
+        self.post("/download-file", form).await
+    }
+
+    // the content as it arrives, nothing is buffered
+    pub async fn download(&self, form: &DownloadFileForm)
+        -> Result<impl Stream<Item = Result<Bytes>> + use<>> {
+        let link = self.download_link(form).await?;
+        let url = self.url(&link.url);
+        let res = self.send(|http| Ok(http.get(&url))).await?;
+        Ok(res.bytes_stream().map_err(Error::from))
+    }
+
+    pub async fn download_to(&self, form: &DownloadFileForm, dest: &Path) -> Result<u64> {
+        let stream = self.download(form).await?;
+        futures::pin_mut!(stream);
+        let mut out = tokio::fs::File::create(dest).await?;
+        let mut written = 0u64;
+        while let Some(chunk) = stream.try_next().await? {
+            out.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        out.flush().await?;
+        Ok(written)
+    }
+
+    // share links
+
+    pub async fn create_share_link(&self, form: &CreateShareLinkForm) -> Result<ShareLinkResponse> {
+        self.post("/create-share-link", form).await
+    }
+
+    pub async fn get_share_links(&self) -> Result<Vec<ShareLinkResponse>> {
+        let url = self.url("/get-share-links");
+        Ok(self.send(|http| Ok(http.post(&url))).await?.json().await?)
+    }
+
+    pub async fn revoke_share_link(&self, form: &RevokeShareLinkForm) -> Result<()> {
+        self.post_unit("/revoke-share-link", form).await
+    }
+
+    // the public address of a link
+    pub fn share_url(&self, link: &ShareLinkResponse) -> String {
+        self.url(&format!("/s/{}", link.token))
+    }
+
+    // sync
+
+    // the change journal, see GET /changes. wait is in seconds, so the request
+    // timeout of a custom http_client has to be longer than that
+    pub async fn changes(&self, query: &ChangesQuery) -> Result<ChangesResponse> {
+        let url = self.url("/changes");
+        Ok(self.send(|http| Ok(http.get(&url).query(query))).await?.json().await?)
+    }
+
+    // api keys
+
+    pub async fn create_api_key(&self, form: &CreateApiKeyForm) -> Result<ApiKeyResponse> {
+        self.post("/create-api-key", form).await
+    }
+
+    pub async fn get_api_keys(&self) -> Result<Vec<ApiKeyResponse>> {
+        let url = self.url("/get-api-keys");
+        Ok(self.send(|http| Ok(http.post(&url))).await?.json().await?)
+    }
+
+    pub async fn revoke_api_key(&self, form: &ApiKeyIdForm) -> Result<()> {
+        self.post_unit("/revoke-api-key", form).await
+    }
+}
+
+fn upload_form(part: reqwest::multipart::Part, parent_id: Option<Uuid>) -> reqwest::multipart::Form {
+    let form = reqwest::multipart::Form::new().part("file", part);
+    match parent_id {
+        Some(id) => form.text("parent_id", id.to_string()),
+        None => form,
+    }
+}
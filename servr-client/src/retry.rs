@@ -0,0 +1,49 @@
+use reqwest::StatusCode;
+use std::time::Duration;
+
+use crate::error::Error;
+
+// how often a request is tried again when the worker or the network hiccups.
+// only failures where the request can't have gone through are retried: it
+// never connected, or a proxy answered 502/503/504. a 500 came from the worker
+// and may have half done the job, so it is returned as is
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy { max_retries: 0, ..Default::default() }
+    }
+
+    // doubles every attempt, attempt 0 is the first retry
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(1u32 << attempt.min(16)).min(self.max_delay)
+    }
+
+    pub fn should_retry(&self, attempt: u32, error: &Error) -> bool {
+        if attempt >= self.max_retries {
+            return false;
+        }
+        match error {
+            Error::Http(e) => e.is_connect(),
+            Error::Status(status, _) => matches!(*status, StatusCode::BAD_GATEWAY
+                                                        | StatusCode::SERVICE_UNAVAILABLE
+                                                        | StatusCode::GATEWAY_TIMEOUT),
+            _ => false,
+        }
+    }
+}
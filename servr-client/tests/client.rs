@@ -0,0 +1,120 @@
+use axum::{Router, routing::post, http::{StatusCode, HeaderMap, header}, response::IntoResponse};
+use servr_client::{Client, Error, RetryPolicy};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+async fn spawn_stub(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn fast_retries() -> RetryPolicy {
+    RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(5) }
+}
+
+#[test]
+fn test_retry_delay_doubles_up_to_max() {
+    let policy = RetryPolicy {
+        max_retries: 5,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(500),
+    };
+    assert_eq!(policy.delay(0), Duration::from_millis(100));
+    assert_eq!(policy.delay(1), Duration::from_millis(200));
+    assert_eq!(policy.delay(2), Duration::from_millis(400));
+    assert_eq!(policy.delay(3), Duration::from_millis(500));
+    assert_eq!(policy.delay(40), Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_status_maps_to_error() {
+    let app = Router::new()
+        .route("/get-files", post(|| async { (StatusCode::NOT_FOUND, "User not found") }))
+        .route("/delete-file", post(|| async { (StatusCode::FORBIDDEN, "Not yours") }));
+    let base = spawn_stub(app).await;
+    let client = Client::with_session(&base, "token");
+
+    match client.get_files().await {
+        Err(Error::NotFound(msg)) => assert_eq!(msg, "User not found"),
+        other => panic!("expected NotFound, got {:?}", other.map(|_| ())),
+    }
+    let form = servr_client::models::DeleteFileForm { owner_id: String::new(), file_id: String::new() };
+    assert!(matches!(client.delete_file(&form).await, Err(Error::Forbidden(_))));
+}
+
+#[tokio::test]
+async fn test_unavailable_is_retried() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let app = Router::new().route("/get-share-links", post(move || {
+        let counter = counter.clone();
+        async move {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                (StatusCode::SERVICE_UNAVAILABLE, "busy".to_string()).into_response()
+            } else {
+                (StatusCode::OK, "[]".to_string()).into_response()
+            }
+        }
+    }));
+    let base = spawn_stub(app).await;
+
+    let client = Client::with_session(&base, "token").retry_policy(fast_retries());
+    assert!(client.get_share_links().await.unwrap().is_empty());
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+    hits.store(0, Ordering::SeqCst);
+    let client = Client::with_session(&base, "token").retry_policy(RetryPolicy::none());
+    match client.get_share_links().await {
+        Err(Error::Status(status, _)) => assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE),
+        other => panic!("expected a 503, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_server_error_is_not_retried() {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let app = Router::new().route("/get-share-links", post(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async { (StatusCode::INTERNAL_SERVER_ERROR, "boom") }
+    }));
+    let base = spawn_stub(app).await;
+
+    let client = Client::with_session(&base, "token").retry_policy(fast_retries());
+    assert!(matches!(client.get_share_links().await, Err(Error::Internal(_))));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+// sessions from the key are renewed once when they are rejected
+#[tokio::test]
+async fn test_api_key_session_is_renewed() {
+    let sign_ins = Arc::new(AtomicUsize::new(0));
+    let counter = sign_ins.clone();
+    let app = Router::new()
+        .route("/sign-in-key", post(move || {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let cookie = format!("session=s{}; Path=/; HttpOnly", n);
+                (StatusCode::OK, [(header::SET_COOKIE, cookie)])
+            }
+        }))
+        // only the second session is still good
+        .route("/get-api-keys", post(|headers: HeaderMap| async move {
+            match headers.get(header::COOKIE).and_then(|c| c.to_str().ok()) {
+                Some("session=s1") => (StatusCode::OK, "[]"),
+                _ => (StatusCode::UNAUTHORIZED, "No session token found"),
+            }
+        }));
+    let base = spawn_stub(app).await;
+
+    let client = Client::with_api_key(&base, "srv_test");
+    assert!(client.get_api_keys().await.unwrap().is_empty());
+    assert_eq!(sign_ins.load(Ordering::SeqCst), 2);
+    assert_eq!(client.session().await.as_deref(), Some("s1"));
+}